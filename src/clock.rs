@@ -0,0 +1,34 @@
+// Musical time, shared by every expression.  Beats are counted in the time
+// signature's beat unit from when the VM started.
+#[derive(Copy)]
+pub struct Clock {
+    // Beats per minute
+    pub tempo: f64,
+    pub beats_per_bar: u32,
+    pub beat_unit: u32,
+    // The position at the start of the current block
+    pub beat: f64
+}
+
+impl Clock {
+    pub fn new() -> Clock {
+        Clock {
+            tempo: 120.0,
+            beats_per_bar: 4,
+            beat_unit: 4,
+            beat: 0.0
+        }
+    }
+
+    pub fn beats_per_second(&self) -> f64 {
+        self.tempo / 60.0
+    }
+
+    pub fn bar(&self) -> f64 {
+        self.beat / self.beats_per_bar as f64
+    }
+
+    pub fn advance(&mut self, time: f64) {
+        self.beat += time * self.beats_per_second();
+    }
+}
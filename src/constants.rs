@@ -7,5 +7,6 @@ pub struct Constants {
     pub audio_rate: f32,
     pub audio_rate_inverse: f32,
     pub control_rate: f32,
-    pub control_rate_inverse: f32
+    pub control_rate_inverse: f32,
+    pub seed: u32
 }
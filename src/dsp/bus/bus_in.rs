@@ -1,10 +1,10 @@
 use std::num::Float;
 
-use types::{ArtResult, Rate};
+use types::{ArtResult, Rate, Curve};
 
 use unit::{Unit, UnitDefinition, UnitKind, ChannelLayout, DataSize,
            TickAdjuncts};
-use parameter::{ParameterDefinition, ParameterMode};
+use parameter::{ParameterDefinition, ParameterMode, UNBOUNDED};
 use channel_stack::ChannelStack;
 use leap::Leap;
 use constants::Constants;
@@ -14,7 +14,13 @@ pub static PARAMETERS: [ParameterDefinition; 1] = [
         name: "bus_id",
         default: 0f32,
         rate: Rate::Control,
-        mode: ParameterMode::Normal
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: UNBOUNDED,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "The bus to read from"
     }
 ];
 
@@ -59,7 +65,7 @@ pub struct BusInAr;
 
 impl BusInAr {
     pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
-               _: &mut Leap<f32>)
+               _: &[f32], _: &mut Leap<f32>)
             -> Unit {
         Unit {
             definition: &DEFINITION_AR,
@@ -92,7 +98,7 @@ pub struct BusInKr;
 
 impl BusInKr {
     pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
-               _: &mut Leap<f32>)
+               _: &[f32], _: &mut Leap<f32>)
             -> Unit {
         Unit {
             definition: &DEFINITION_KR,
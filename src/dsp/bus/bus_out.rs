@@ -1,11 +1,11 @@
 use std::num::Float;
 
-use types::{ArtResult, Rate};
+use types::{ArtResult, Rate, Curve};
 use errors::ArtError;
 
 use unit::{Unit, UnitDefinition, UnitKind, ChannelLayout, DataSize,
            TickAdjuncts};
-use parameter::{ParameterDefinition, ParameterMode};
+use parameter::{ParameterDefinition, ParameterMode, UNBOUNDED};
 use channel_stack::ChannelStack;
 use leap::Leap;
 use constants::Constants;
@@ -15,7 +15,13 @@ pub static PARAMETERS: [ParameterDefinition; 1] = [
         name: "bus_id",
         default: 0f32,
         rate: Rate::Control,
-        mode: ParameterMode::Normal
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: UNBOUNDED,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "The bus to write to"
     }
 ];
 
@@ -26,7 +32,19 @@ impl BusOut {
     fn tick(unit: &mut Unit, block: &mut[f32], parameters: &mut ChannelStack,
             adjuncts: &mut TickAdjuncts, constants: &Constants)
             -> ArtResult<()> {
+        // Measured before the gain is applied, so muted and fading
+        // expressions still report how loud they are
+        adjuncts.measure_level(block);
+
+        if adjuncts.gain == 0.0 && adjuncts.gain_delta == 0.0 {
+            // Silent or muted, so discard the output
+            return Ok(());
+        }
+
         let bus_id = parameters.data[0].round() as u32;
+        let channels = unit.layout.input as usize;
+
+        adjuncts.apply_gain(block, channels);
 
         if let Some(&bus_index) = adjuncts.bus_map.get(&bus_id) {
             adjuncts.busses.add(bus_index, block);
@@ -36,7 +54,6 @@ impl BusOut {
                 return Err(ArtError::BufferOverflow);
             }
 
-            let channels = unit.layout.input as usize;
             let samples = match unit.definition.input_rate {
                 Some(Rate::Audio) => channels * constants.block_size,
                 Some(Rate::Control) => channels,
@@ -72,7 +89,7 @@ pub struct BusOutAr;
 
 impl BusOutAr {
     pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
-               _: &mut Leap<f32>)
+               _: &[f32], _: &mut Leap<f32>)
             -> Unit {
         Unit {
             definition: &DEFINITION_AR,
@@ -105,7 +122,7 @@ pub struct BusOutKr;
 
 impl BusOutKr {
     pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
-               _: &mut Leap<f32>)
+               _: &[f32], _: &mut Leap<f32>)
             -> Unit {
         Unit {
             definition: &DEFINITION_KR,
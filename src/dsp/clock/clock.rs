@@ -0,0 +1,158 @@
+use std::num::Float;
+
+use types::{ArtResult, Rate, Curve};
+
+use unit::{Unit, UnitDefinition, ChannelLayout, UnitKind, DataSize,
+           TickAdjuncts};
+use parameter::{ParameterDefinition, ParameterMode};
+use channel_stack::ChannelStack;
+use constants::Constants;
+use leap::Leap;
+
+// Both units output the phase through the current division of the beat,
+// and a tick which is 1 when a new division starts.  Channels alternate
+// between the phase and the tick.
+pub static PARAMETERS_AR: [ParameterDefinition; 1] = [
+    ParameterDefinition {
+        name: "division",
+        default: 1f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 64.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Number of divisions of each beat"
+    }
+];
+
+pub static DEFINITION_AR: UnitDefinition = UnitDefinition {
+    name: "clock_ar",
+    kind: UnitKind::Source,
+    input_rate: None,
+    output_rate: Some(Rate::Audio),
+    default_layout: ChannelLayout {
+        input: 0,
+        output: 2
+    },
+    parameters: &PARAMETERS_AR,
+    tick: ClockAr::tick,
+    data_size: DataSize::None
+};
+
+#[derive(Copy)]
+pub struct ClockAr;
+
+impl ClockAr {
+    pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
+               _: &[f32], _: &mut Leap<f32>)
+            -> Unit {
+        Unit {
+            definition: &DEFINITION_AR,
+            id: id,
+            layout: ChannelLayout {
+                input: input_channels,
+                output: output_channels
+            },
+            data_index: None
+        }
+    }
+
+    fn tick(unit: &mut Unit, block: &mut[f32], parameters: &mut ChannelStack,
+            adjuncts: &mut TickAdjuncts, constants: &Constants)
+            -> ArtResult<()> {
+        let division = parameters.data[0].max(0.0) as f64;
+        let step = adjuncts.clock.beats_per_second() *
+                   constants.audio_rate_inverse as f64 * division;
+        let mut position = adjuncts.clock.beat * division;
+
+        let channels = unit.layout.output as usize;
+
+        for i in range(0, constants.block_size) {
+            let (phase, tick) = phase_and_tick(position, step);
+            for j in range(0, channels) {
+                block[i * channels + j] = if j % 2 == 0 { phase } else { tick };
+            }
+            position += step;
+        }
+        Ok(())
+    }
+}
+
+pub static PARAMETERS_KR: [ParameterDefinition; 1] = [
+    ParameterDefinition {
+        name: "division",
+        default: 1f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 64.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Number of divisions of each beat"
+    }
+];
+
+pub static DEFINITION_KR: UnitDefinition = UnitDefinition {
+    name: "clock_kr",
+    kind: UnitKind::Source,
+    input_rate: None,
+    output_rate: Some(Rate::Control),
+    default_layout: ChannelLayout {
+        input: 0,
+        output: 2
+    },
+    parameters: &PARAMETERS_KR,
+    tick: ClockKr::tick,
+    data_size: DataSize::None
+};
+
+#[derive(Copy)]
+pub struct ClockKr;
+
+impl ClockKr {
+    pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
+               _: &[f32], _: &mut Leap<f32>)
+            -> Unit {
+        Unit {
+            definition: &DEFINITION_KR,
+            id: id,
+            layout: ChannelLayout {
+                input: input_channels,
+                output: output_channels
+            },
+            data_index: None
+        }
+    }
+
+    fn tick(unit: &mut Unit, block: &mut[f32], parameters: &mut ChannelStack,
+            adjuncts: &mut TickAdjuncts, constants: &Constants)
+            -> ArtResult<()> {
+        let division = parameters.data[0].max(0.0) as f64;
+        let step = adjuncts.clock.beats_per_second() *
+                   constants.control_rate_inverse as f64 * division;
+        let position = adjuncts.clock.beat * division;
+
+        let channels = unit.layout.output as usize;
+
+        let (phase, tick) = phase_and_tick(position, step);
+        for i in range(0, channels) {
+            block[i] = if i % 2 == 0 { phase } else { tick };
+        }
+        Ok(())
+    }
+}
+
+// The tick is set if a division started since the previous step
+fn phase_and_tick(position: f64, step: f64) -> (f32, f32) {
+    let phase = position - position.floor();
+    let tick = if (position - step).floor() < position.floor() {
+        1.0
+    }
+    else {
+        0.0
+    };
+    (phase as f32, tick)
+}
@@ -0,0 +1,549 @@
+use std::num::FromPrimitive;
+
+use types::{ArtResult, Rate, Curve};
+
+use unit::{Unit, UnitDefinition, ChannelLayout, UnitKind, DataSize,
+           TickAdjuncts, DoneAction};
+use parameter::{ParameterDefinition, ParameterMode};
+use channel_stack::ChannelStack;
+use leap::{Leap, IterMut};
+use constants::Constants;
+use util::interpolate_curve;
+
+// The gate is held, so the envelope sustains until it is set back to zero
+pub static PARAMETERS_AR: [ParameterDefinition; 9] = [
+    ParameterDefinition {
+        name: "gate",
+        default: 1f32,
+        rate: Rate::Audio,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 1.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Starts the envelope when it rises above zero, and \
+                      releases it when it falls back to zero"
+    },
+    ParameterDefinition {
+        name: "attack",
+        default: 0.01f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.001,
+        maximum: 60.0,
+        clamped: true,
+        unit: "s",
+        curve: Curve::Exponential,
+        description: "Time taken to reach the peak"
+    },
+    ParameterDefinition {
+        name: "decay",
+        default: 0.1f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.001,
+        maximum: 60.0,
+        clamped: true,
+        unit: "s",
+        curve: Curve::Exponential,
+        description: "Time taken to fall to the sustain level"
+    },
+    ParameterDefinition {
+        name: "sustain",
+        default: 0.5f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 1.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Level held while the gate is open"
+    },
+    ParameterDefinition {
+        name: "release",
+        default: 1f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.001,
+        maximum: 60.0,
+        clamped: true,
+        unit: "s",
+        curve: Curve::Exponential,
+        description: "Time taken to fall to zero once released"
+    },
+    ParameterDefinition {
+        name: "attack_curve",
+        default: 0f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: -20.0,
+        maximum: 20.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Shape of the attack.  Zero is linear, negative values \
+                      move quickly at first and positive values slowly"
+    },
+    ParameterDefinition {
+        name: "decay_curve",
+        default: -4f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: -20.0,
+        maximum: 20.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Shape of the decay, in the same way as attack_curve"
+    },
+    ParameterDefinition {
+        name: "release_curve",
+        default: -4f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: -20.0,
+        maximum: 20.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Shape of the release, in the same way as attack_curve"
+    },
+    ParameterDefinition {
+        name: "done_action",
+        default: 0f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 2.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "What happens to the expression once the envelope \
+                      finishes: 0 does nothing, 1 pauses it and 2 frees it"
+    }
+];
+
+pub static DEFINITION_AR: UnitDefinition = UnitDefinition {
+    name: "adsr_envelope_ar",
+    kind: UnitKind::Source,
+    input_rate: None,
+    output_rate: Some(Rate::Audio),
+    default_layout: ChannelLayout {
+        input: 0,
+        output: 1
+    },
+    parameters: &PARAMETERS_AR,
+    tick: AdsrEnvelopeAr::tick,
+    data_size: DataSize::Fixed(5)
+};
+
+#[derive(Copy)]
+pub struct AdsrEnvelopeAr;
+
+impl AdsrEnvelopeAr {
+    pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
+               _: &[f32], data: &mut Leap<f32>)
+            -> Unit {
+        Unit {
+            definition: &DEFINITION_AR,
+            id: id,
+            layout: ChannelLayout {
+                input: input_channels,
+                output: output_channels
+            },
+            data_index: Some(AdsrState::push(data))
+        }
+    }
+
+    fn tick(unit: &mut Unit, block: &mut[f32], parameters: &mut ChannelStack,
+            adjuncts: &mut TickAdjuncts, constants: &Constants)
+            -> ArtResult<()> {
+        debug_assert!(unit.data_index.is_some());
+        let mut data = adjuncts.data.iter_mut(unit.data_index.unwrap());
+        let mut state = AdsrState::load(&mut data);
+
+        let (mut gate_stack, others) = parameters.split_at_mut(
+            constants.block_size
+        );
+        let gate = gate_stack.get_mut(0, constants.block_size);
+        let settings = AdsrSettings::from_parameters(&others.data[..]);
+        let done_action = others.data[7];
+
+        let channels = unit.layout.output as usize;
+        let mut finished = false;
+
+        for i in range(0, constants.block_size) {
+            if state.step(gate[i], &settings, constants.audio_rate_inverse) {
+                finished = true;
+            }
+
+            for j in range(0, channels) {
+                block[i * channels + j] = *state.value;
+            }
+        }
+
+        if finished {
+            adjuncts.done(DoneAction::from_parameter(done_action));
+        }
+        Ok(())
+    }
+}
+
+pub static PARAMETERS_KR: [ParameterDefinition; 9] = [
+    ParameterDefinition {
+        name: "gate",
+        default: 1f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 1.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Starts the envelope when it rises above zero, and \
+                      releases it when it falls back to zero"
+    },
+    ParameterDefinition {
+        name: "attack",
+        default: 0.01f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.001,
+        maximum: 60.0,
+        clamped: true,
+        unit: "s",
+        curve: Curve::Exponential,
+        description: "Time taken to reach the peak"
+    },
+    ParameterDefinition {
+        name: "decay",
+        default: 0.1f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.001,
+        maximum: 60.0,
+        clamped: true,
+        unit: "s",
+        curve: Curve::Exponential,
+        description: "Time taken to fall to the sustain level"
+    },
+    ParameterDefinition {
+        name: "sustain",
+        default: 0.5f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 1.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Level held while the gate is open"
+    },
+    ParameterDefinition {
+        name: "release",
+        default: 1f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.001,
+        maximum: 60.0,
+        clamped: true,
+        unit: "s",
+        curve: Curve::Exponential,
+        description: "Time taken to fall to zero once released"
+    },
+    ParameterDefinition {
+        name: "attack_curve",
+        default: 0f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: -20.0,
+        maximum: 20.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Shape of the attack.  Zero is linear, negative values \
+                      move quickly at first and positive values slowly"
+    },
+    ParameterDefinition {
+        name: "decay_curve",
+        default: -4f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: -20.0,
+        maximum: 20.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Shape of the decay, in the same way as attack_curve"
+    },
+    ParameterDefinition {
+        name: "release_curve",
+        default: -4f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: -20.0,
+        maximum: 20.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Shape of the release, in the same way as attack_curve"
+    },
+    ParameterDefinition {
+        name: "done_action",
+        default: 0f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 2.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "What happens to the expression once the envelope \
+                      finishes: 0 does nothing, 1 pauses it and 2 frees it"
+    }
+];
+
+pub static DEFINITION_KR: UnitDefinition = UnitDefinition {
+    name: "adsr_envelope_kr",
+    kind: UnitKind::Source,
+    input_rate: None,
+    output_rate: Some(Rate::Control),
+    default_layout: ChannelLayout {
+        input: 0,
+        output: 1
+    },
+    parameters: &PARAMETERS_KR,
+    tick: AdsrEnvelopeKr::tick,
+    data_size: DataSize::Fixed(5)
+};
+
+#[derive(Copy)]
+pub struct AdsrEnvelopeKr;
+
+impl AdsrEnvelopeKr {
+    pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
+               _: &[f32], data: &mut Leap<f32>)
+            -> Unit {
+        Unit {
+            definition: &DEFINITION_KR,
+            id: id,
+            layout: ChannelLayout {
+                input: input_channels,
+                output: output_channels
+            },
+            data_index: Some(AdsrState::push(data))
+        }
+    }
+
+    fn tick(unit: &mut Unit, block: &mut[f32], parameters: &mut ChannelStack,
+            adjuncts: &mut TickAdjuncts, constants: &Constants)
+            -> ArtResult<()> {
+        debug_assert!(unit.data_index.is_some());
+        let mut data = adjuncts.data.iter_mut(unit.data_index.unwrap());
+        let mut state = AdsrState::load(&mut data);
+
+        let gate = parameters.data[0];
+        let settings = AdsrSettings::from_parameters(&parameters.data[1..]);
+        let done_action = parameters.data[8];
+
+        let finished = state.step(gate, &settings,
+                                  constants.control_rate_inverse);
+
+        let channels = unit.layout.output as usize;
+        for i in range(0, channels) {
+            block[i] = *state.value;
+        }
+
+        if finished {
+            adjuncts.done(DoneAction::from_parameter(done_action));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Copy, PartialEq, FromPrimitive)]
+enum Stage {
+    Idle,
+    Attack,
+    Decay,
+    Sustain,
+    Release
+}
+
+#[derive(Copy)]
+struct AdsrSettings {
+    attack: f32,
+    decay: f32,
+    sustain: f32,
+    release: f32,
+    attack_curve: f32,
+    decay_curve: f32,
+    release_curve: f32
+}
+
+impl AdsrSettings {
+    // Reads the parameters which follow the gate
+    fn from_parameters(parameters: &[f32]) -> AdsrSettings {
+        AdsrSettings {
+            attack: parameters[0],
+            decay: parameters[1],
+            sustain: parameters[2],
+            release: parameters[3],
+            attack_curve: parameters[4],
+            decay_curve: parameters[5],
+            release_curve: parameters[6]
+        }
+    }
+}
+
+struct AdsrState<'a> {
+    stage: &'a mut f32,
+    value: &'a mut f32,
+    // The value at the start of the stage
+    start: &'a mut f32,
+    // How far through the stage the envelope is, from 0 to 1
+    position: &'a mut f32,
+    last_gate: &'a mut f32
+}
+
+impl<'a> AdsrState<'a> {
+    fn push(data: &mut Leap<f32>) -> usize {
+        let data_index = data.tail;
+        data.push(Stage::Idle as u32 as f32).unwrap(); // stage
+        data.push(0.0).unwrap(); // value
+        data.push(0.0).unwrap(); // start
+        data.push(0.0).unwrap(); // position
+        data.push(0.0).unwrap(); // last_gate
+        data_index
+    }
+
+    fn load(data: &mut IterMut<'a, f32>) -> AdsrState<'a> {
+        AdsrState {
+            stage: data.next().unwrap(),
+            value: data.next().unwrap(),
+            start: data.next().unwrap(),
+            position: data.next().unwrap(),
+            last_gate: data.next().unwrap()
+        }
+    }
+
+    fn begin(&mut self, stage: Stage) {
+        *self.stage = stage as u32 as f32;
+        *self.start = *self.value;
+        *self.position = 0.0;
+    }
+
+    // Moves the envelope on by time seconds, returning whether it has just
+    // finished releasing
+    fn step(&mut self, gate: f32, settings: &AdsrSettings, time: f32)
+            -> bool {
+        let stage = FromPrimitive::from_u32(*self.stage as u32).unwrap_or(
+            Stage::Idle
+        );
+
+        if gate > 0.0 && *self.last_gate <= 0.0 {
+            self.begin(Stage::Attack);
+        }
+        else if gate <= 0.0 && *self.last_gate > 0.0 &&
+                stage != Stage::Idle {
+            self.begin(Stage::Release);
+        }
+        *self.last_gate = gate;
+
+        let stage = FromPrimitive::from_u32(*self.stage as u32).unwrap_or(
+            Stage::Idle
+        );
+        let (target, duration, curve) = match stage {
+            Stage::Attack => (1.0, settings.attack, settings.attack_curve),
+            Stage::Decay => (settings.sustain, settings.decay,
+                             settings.decay_curve),
+            Stage::Release => (0.0, settings.release, settings.release_curve),
+            Stage::Sustain => {
+                *self.value = settings.sustain;
+                return false;
+            },
+            Stage::Idle => return false
+        };
+
+        if duration > 0.0 {
+            *self.position += time / duration;
+        }
+        else {
+            *self.position = 1.0;
+        }
+
+        if *self.position < 1.0 {
+            *self.value = interpolate_curve(*self.start, target,
+                                            *self.position, curve);
+            return false;
+        }
+
+        *self.value = target;
+        match stage {
+            Stage::Attack => {
+                self.begin(Stage::Decay);
+                false
+            },
+            Stage::Decay => {
+                self.begin(Stage::Sustain);
+                false
+            },
+            _ => {
+                self.begin(Stage::Idle);
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use unit::DoneAction;
+    use test_util::UnitHarness;
+
+    use super::AdsrEnvelopeAr;
+
+    const GATE: u32 = 0;
+    const ATTACK: u32 = 1;
+    const DECAY: u32 = 2;
+    const SUSTAIN: u32 = 3;
+    const RELEASE: u32 = 4;
+    const DONE_ACTION: u32 = 8;
+
+    // Runs a short envelope to its end, returning the done action it took
+    fn finish(done_action: DoneAction) -> DoneAction {
+        let mut harness = UnitHarness::new(AdsrEnvelopeAr::new, 0, 1, 0,
+                                           &[]);
+        harness.set(ATTACK, 0.001);
+        harness.set(DECAY, 0.001);
+        harness.set(SUSTAIN, 0.5);
+        harness.set(RELEASE, 0.001);
+        harness.set(DONE_ACTION, done_action as u32 as f32);
+
+        // Holding at the sustain level doesn't finish the envelope
+        harness.tick(&[]).unwrap();
+        assert_eq!(harness.tick(&[]).unwrap()[63], 0.5);
+        assert_eq!(harness.done_action, DoneAction::None);
+
+        harness.set(GATE, 0.0);
+        assert_eq!(harness.tick(&[]).unwrap()[63], 0.0);
+        harness.done_action
+    }
+
+    #[test]
+    fn done_action_none() {
+        assert_eq!(finish(DoneAction::None), DoneAction::None);
+    }
+
+    #[test]
+    fn done_action_pause() {
+        assert_eq!(finish(DoneAction::Pause), DoneAction::Pause);
+    }
+
+    #[test]
+    fn done_action_free() {
+        assert_eq!(finish(DoneAction::Free), DoneAction::Free);
+    }
+}
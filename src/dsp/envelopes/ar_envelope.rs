@@ -1,30 +1,62 @@
-use types::{ArtResult, Rate};
+use types::{ArtResult, Rate, Curve};
 
 use unit::{Unit, UnitDefinition, ChannelLayout, UnitKind, DataSize,
-           TickAdjuncts};
+           TickAdjuncts, DoneAction};
 use parameter::{ParameterDefinition, ParameterMode};
 use channel_stack::ChannelStack;
 use leap::Leap;
 use constants::Constants;
 
-pub static PARAMETERS_AR: [ParameterDefinition; 3] = [
+pub static PARAMETERS_AR: [ParameterDefinition; 4] = [
     ParameterDefinition {
         name: "gate",
         default: 1f32,
         rate: Rate::Audio,
-        mode: ParameterMode::Trigger
+        mode: ParameterMode::Trigger,
+        minimum: 0.0,
+        maximum: 1.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Starts the envelope when it rises above zero, and \
+                      releases it when it falls back to zero"
     },
     ParameterDefinition {
         name: "attack",
         default: 1f32,
         rate: Rate::Control,
-        mode: ParameterMode::Normal
+        mode: ParameterMode::Normal,
+        minimum: 0.001,
+        maximum: 60.0,
+        clamped: true,
+        unit: "s",
+        curve: Curve::Exponential,
+        description: "Time taken to reach the peak"
     },
     ParameterDefinition {
         name: "release",
         default: 1f32,
         rate: Rate::Control,
-        mode: ParameterMode::Normal
+        mode: ParameterMode::Normal,
+        minimum: 0.001,
+        maximum: 60.0,
+        clamped: true,
+        unit: "s",
+        curve: Curve::Exponential,
+        description: "Time taken to fall to zero once released"
+    },
+    ParameterDefinition {
+        name: "done_action",
+        default: 0f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 2.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "What happens to the expression once the envelope \
+                      finishes: 0 does nothing, 1 pauses it and 2 frees it"
     }
 ];
 
@@ -47,7 +79,7 @@ pub struct ArEnvelopeAr;
 
 impl ArEnvelopeAr {
     pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
-               data: &mut Leap<f32>)
+               _: &[f32], data: &mut Leap<f32>)
             -> Unit {
         let data_index = data.tail;
         data.push(0.0).unwrap(); // value
@@ -80,12 +112,15 @@ impl ArEnvelopeAr {
         let gate_chock = gate_stack.get_mut(0, constants.block_size);
         let attack = others.data[0];
         let release = others.data[1];
+        let done_action = others.data[2];
 
         let channels = unit.layout.output as usize;
+        let mut finished = false;
 
         for i in range(0, constants.block_size) {
             let gate = gate_chock[i];
             if gate > 0.0 && *last_gate <= 0.0 {
+                finished = false;
                 if attack == 0.0 {
                     *delta = 1.0 - *value;
                 }
@@ -108,6 +143,10 @@ impl ArEnvelopeAr {
             }
 
             if *value <= 0.0 {
+                // Reaching zero while releasing means the envelope is done
+                if *delta < 0.0 {
+                    finished = true;
+                }
                 *value = 0.0;
                 *delta = 0.0;
             }
@@ -118,28 +157,64 @@ impl ArEnvelopeAr {
                 block[i * channels + j] = *value;
             }
         }
+
+        if finished {
+            adjuncts.done(DoneAction::from_parameter(done_action));
+        }
         Ok(())
     }
 }
 
-pub static PARAMETERS_KR: [ParameterDefinition; 3] = [
+pub static PARAMETERS_KR: [ParameterDefinition; 4] = [
     ParameterDefinition {
         name: "gate",
         default: 1f32,
         rate: Rate::Control,
-        mode: ParameterMode::Trigger
+        mode: ParameterMode::Trigger,
+        minimum: 0.0,
+        maximum: 1.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Starts the envelope when it rises above zero, and \
+                      releases it when it falls back to zero"
     },
     ParameterDefinition {
         name: "attack",
         default: 1f32,
         rate: Rate::Control,
-        mode: ParameterMode::Normal
+        mode: ParameterMode::Normal,
+        minimum: 0.001,
+        maximum: 60.0,
+        clamped: true,
+        unit: "s",
+        curve: Curve::Exponential,
+        description: "Time taken to reach the peak"
     },
     ParameterDefinition {
         name: "release",
         default: 1f32,
         rate: Rate::Control,
-        mode: ParameterMode::Normal
+        mode: ParameterMode::Normal,
+        minimum: 0.001,
+        maximum: 60.0,
+        clamped: true,
+        unit: "s",
+        curve: Curve::Exponential,
+        description: "Time taken to fall to zero once released"
+    },
+    ParameterDefinition {
+        name: "done_action",
+        default: 0f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 2.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "What happens to the expression once the envelope \
+                      finishes: 0 does nothing, 1 pauses it and 2 frees it"
     }
 ];
 
@@ -162,7 +237,7 @@ pub struct ArEnvelopeKr;
 
 impl ArEnvelopeKr {
     pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
-               data: &mut Leap<f32>)
+               _: &[f32], data: &mut Leap<f32>)
             -> Unit {
         let data_index = data.tail;
         data.push(0.0).unwrap(); // value
@@ -192,6 +267,7 @@ impl ArEnvelopeKr {
         let gate = parameters.data[0];
         let attack = parameters.data[1];
         let release = parameters.data[2];
+        let done_action = parameters.data[3];
 
         let channels = unit.layout.output as usize;
 
@@ -218,6 +294,9 @@ impl ArEnvelopeKr {
         }
 
         if *value <= 0.0 {
+            if *delta < 0.0 {
+                adjuncts.done(DoneAction::from_parameter(done_action));
+            }
             *value = 0.0;
             *delta = 0.0;
         }
@@ -230,3 +309,45 @@ impl ArEnvelopeKr {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use unit::DoneAction;
+    use test_util::UnitHarness;
+
+    use super::ArEnvelopeAr;
+
+    const ATTACK: u32 = 1;
+    const RELEASE: u32 = 2;
+    const DONE_ACTION: u32 = 3;
+
+    // Runs a short envelope to its end, returning the done action it took
+    fn finish(done_action: DoneAction) -> DoneAction {
+        let mut harness = UnitHarness::new(ArEnvelopeAr::new, 0, 1, 0, &[]);
+        harness.set(ATTACK, 0.001);
+        harness.set(RELEASE, 0.001);
+        harness.set(DONE_ACTION, done_action as u32 as f32);
+
+        // Still releasing at the end of the first block
+        assert!(harness.tick(&[]).unwrap()[63] > 0.0);
+        assert_eq!(harness.done_action, DoneAction::None);
+
+        assert_eq!(harness.tick(&[]).unwrap()[63], 0.0);
+        harness.done_action
+    }
+
+    #[test]
+    fn done_action_none() {
+        assert_eq!(finish(DoneAction::None), DoneAction::None);
+    }
+
+    #[test]
+    fn done_action_pause() {
+        assert_eq!(finish(DoneAction::Pause), DoneAction::Pause);
+    }
+
+    #[test]
+    fn done_action_free() {
+        assert_eq!(finish(DoneAction::Free), DoneAction::Free);
+    }
+}
@@ -0,0 +1,469 @@
+use types::{ArtResult, Rate, Curve};
+
+use unit::{Unit, UnitDefinition, ChannelLayout, UnitKind, DataSize,
+           TickAdjuncts, DoneAction};
+use parameter::{ParameterDefinition, ParameterMode};
+use channel_stack::ChannelStack;
+use leap::Leap;
+use constants::Constants;
+use util::interpolate_curve;
+
+// The envelope is defined by its construction arguments:
+//
+//     [initial_level, release_node, loop_node,
+//      level, time, curve, level, time, curve, ...]
+//
+// Each (level, time, curve) triple is a segment moving from the previous
+// level to its own.  Node n is the level reached at the end of segment n - 1,
+// with node 0 being the initial level.  While the gate is held the envelope
+// waits at the release node, or jumps back to the loop node if it is before
+// the release node.  When the gate falls the envelope moves on from the
+// release node.  Nodes are disabled by setting them to -1.
+pub static PARAMETERS_AR: [ParameterDefinition; 2] = [
+    ParameterDefinition {
+        name: "gate",
+        default: 1f32,
+        rate: Rate::Audio,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 1.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Starts the envelope when it rises above zero, and \
+                      releases it when it falls back to zero"
+    },
+    ParameterDefinition {
+        name: "done_action",
+        default: 0f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 2.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "What happens to the expression once the envelope \
+                      finishes: 0 does nothing, 1 pauses it and 2 frees it"
+    }
+];
+
+pub static DEFINITION_AR: UnitDefinition = UnitDefinition {
+    name: "breakpoint_envelope_ar",
+    kind: UnitKind::Source,
+    input_rate: None,
+    output_rate: Some(Rate::Audio),
+    default_layout: ChannelLayout {
+        input: 0,
+        output: 1
+    },
+    parameters: &PARAMETERS_AR,
+    tick: BreakpointEnvelopeAr::tick,
+    data_size: DataSize::Arguments(STATE_SIZE)
+};
+
+#[derive(Copy)]
+pub struct BreakpointEnvelopeAr;
+
+impl BreakpointEnvelopeAr {
+    pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
+               arguments: &[f32], data: &mut Leap<f32>)
+            -> Unit {
+        Unit {
+            definition: &DEFINITION_AR,
+            id: id,
+            layout: ChannelLayout {
+                input: input_channels,
+                output: output_channels
+            },
+            data_index: Some(BreakpointState::push(arguments, data))
+        }
+    }
+
+    fn tick(unit: &mut Unit, block: &mut[f32], parameters: &mut ChannelStack,
+            adjuncts: &mut TickAdjuncts, constants: &Constants)
+            -> ArtResult<()> {
+        debug_assert!(unit.data_index.is_some());
+        let index = unit.data_index.unwrap();
+        let mut state = BreakpointState::load(adjuncts.data, index);
+
+        let (mut gate_stack, others) = parameters.split_at_mut(
+            constants.block_size
+        );
+        let gate = gate_stack.get_mut(0, constants.block_size);
+        let done_action = others.data[0];
+
+        let channels = unit.layout.output as usize;
+        let mut finished = false;
+
+        {
+            let mut breakpoints = Breakpoints::load(
+                adjuncts.data, index, state.num_arguments as usize
+            );
+            for i in range(0, constants.block_size) {
+                if state.step(gate[i], &mut breakpoints,
+                              constants.audio_rate_inverse) {
+                    finished = true;
+                }
+
+                for j in range(0, channels) {
+                    block[i * channels + j] = state.value;
+                }
+            }
+        }
+        state.store(adjuncts.data, index);
+
+        if finished {
+            adjuncts.done(DoneAction::from_parameter(done_action));
+        }
+        Ok(())
+    }
+}
+
+pub static PARAMETERS_KR: [ParameterDefinition; 2] = [
+    ParameterDefinition {
+        name: "gate",
+        default: 1f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 1.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Starts the envelope when it rises above zero, and \
+                      releases it when it falls back to zero"
+    },
+    ParameterDefinition {
+        name: "done_action",
+        default: 0f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 2.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "What happens to the expression once the envelope \
+                      finishes: 0 does nothing, 1 pauses it and 2 frees it"
+    }
+];
+
+pub static DEFINITION_KR: UnitDefinition = UnitDefinition {
+    name: "breakpoint_envelope_kr",
+    kind: UnitKind::Source,
+    input_rate: None,
+    output_rate: Some(Rate::Control),
+    default_layout: ChannelLayout {
+        input: 0,
+        output: 1
+    },
+    parameters: &PARAMETERS_KR,
+    tick: BreakpointEnvelopeKr::tick,
+    data_size: DataSize::Arguments(STATE_SIZE)
+};
+
+#[derive(Copy)]
+pub struct BreakpointEnvelopeKr;
+
+impl BreakpointEnvelopeKr {
+    pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
+               arguments: &[f32], data: &mut Leap<f32>)
+            -> Unit {
+        Unit {
+            definition: &DEFINITION_KR,
+            id: id,
+            layout: ChannelLayout {
+                input: input_channels,
+                output: output_channels
+            },
+            data_index: Some(BreakpointState::push(arguments, data))
+        }
+    }
+
+    fn tick(unit: &mut Unit, block: &mut[f32], parameters: &mut ChannelStack,
+            adjuncts: &mut TickAdjuncts, constants: &Constants)
+            -> ArtResult<()> {
+        debug_assert!(unit.data_index.is_some());
+        let index = unit.data_index.unwrap();
+        let mut state = BreakpointState::load(adjuncts.data, index);
+
+        let gate = parameters.data[0];
+        let done_action = parameters.data[1];
+
+        let finished = {
+            let mut breakpoints = Breakpoints::load(
+                adjuncts.data, index, state.num_arguments as usize
+            );
+            state.step(gate, &mut breakpoints, constants.control_rate_inverse)
+        };
+        state.store(adjuncts.data, index);
+
+        let channels = unit.layout.output as usize;
+        for i in range(0, channels) {
+            block[i] = state.value;
+        }
+
+        if finished {
+            adjuncts.done(DoneAction::from_parameter(done_action));
+        }
+        Ok(())
+    }
+}
+
+// The number of values stored before the copy of the arguments in the
+// unit's data.  Only these values are carried over when an expression is
+// replaced.
+const STATE_SIZE: usize = 6;
+
+// The segments, read from the copy of the arguments in the unit's data.  The
+// data isn't contiguous, so the current segment is kept to save walking the
+// arguments on every step.
+struct Breakpoints<'a> {
+    data: &'a Leap<f32>,
+    // The index of the first argument
+    index: usize,
+    num_segments: usize,
+    release_node: Option<usize>,
+    loop_node: Option<usize>,
+    current: Option<(usize, (f32, f32, f32))>
+}
+
+impl<'a> Breakpoints<'a> {
+    fn load(data: &'a Leap<f32>, data_index: usize, num_arguments: usize)
+            -> Breakpoints<'a> {
+        let mut index = data_index;
+        for _ in range(0, STATE_SIZE) {
+            index = data.next(index);
+        }
+
+        let mut nodes = [-1f32; 3];
+        for (node, value) in nodes.iter_mut().zip(
+                data.iter(index).take(num_arguments)) {
+            *node = *value;
+        }
+        let node = |value: f32| {
+            if value >= 0.0 {
+                Some(value as usize)
+            }
+            else {
+                None
+            }
+        };
+
+        Breakpoints {
+            data: data,
+            index: index,
+            num_segments: if num_arguments > 3 {
+                (num_arguments - 3) / 3
+            }
+            else {
+                0
+            },
+            release_node: node(nodes[1]),
+            loop_node: node(nodes[2]),
+            current: None
+        }
+    }
+
+    // The (level, time, curve) of a segment
+    fn segment(&mut self, index: usize) -> (f32, f32, f32) {
+        if let Some((current, segment)) = self.current {
+            if current == index {
+                return segment;
+            }
+        }
+
+        let mut values = self.data.iter(self.index).skip(3 + index * 3);
+        let level = *values.next().unwrap();
+        let time = *values.next().unwrap();
+        let curve = *values.next().unwrap();
+        let segment = (level, time, curve);
+        self.current = Some((index, segment));
+        segment
+    }
+}
+
+#[derive(Copy)]
+struct BreakpointState {
+    // The current segment, or -1 when the envelope isn't running
+    segment: f32,
+    value: f32,
+    // The value at the start of the segment
+    start: f32,
+    // How far through the segment the envelope is, from 0 to 1, or -1 while
+    // waiting at the release node
+    position: f32,
+    last_gate: f32,
+    num_arguments: f32
+}
+
+impl BreakpointState {
+    fn push(arguments: &[f32], data: &mut Leap<f32>) -> usize {
+        let initial_level = if arguments.len() > 0 {
+            arguments[0]
+        }
+        else {
+            0.0
+        };
+
+        let data_index = data.tail;
+        data.push(-1.0).unwrap(); // segment
+        data.push(initial_level).unwrap(); // value
+        data.push(initial_level).unwrap(); // start
+        data.push(0.0).unwrap(); // position
+        data.push(0.0).unwrap(); // last_gate
+        data.push(arguments.len() as f32).unwrap(); // num_arguments
+        for argument in arguments.iter() {
+            data.push(*argument).unwrap();
+        }
+        data_index
+    }
+
+    fn load(data: &Leap<f32>, index: usize) -> BreakpointState {
+        let mut values = data.iter(index);
+        BreakpointState {
+            segment: *values.next().unwrap(),
+            value: *values.next().unwrap(),
+            start: *values.next().unwrap(),
+            position: *values.next().unwrap(),
+            last_gate: *values.next().unwrap(),
+            num_arguments: *values.next().unwrap()
+        }
+    }
+
+    fn store(&self, data: &mut Leap<f32>, index: usize) {
+        let mut values = data.iter_mut(index);
+        *values.next().unwrap() = self.segment;
+        *values.next().unwrap() = self.value;
+        *values.next().unwrap() = self.start;
+        *values.next().unwrap() = self.position;
+        *values.next().unwrap() = self.last_gate;
+        *values.next().unwrap() = self.num_arguments;
+    }
+
+    fn begin(&mut self, segment: usize) {
+        self.segment = segment as f32;
+        self.start = self.value;
+        self.position = 0.0;
+    }
+
+    fn stop(&mut self) {
+        self.segment = -1.0;
+        self.position = 0.0;
+    }
+
+    // Moves the envelope on by time seconds, returning whether it has just
+    // finished
+    fn step(&mut self, gate: f32, breakpoints: &mut Breakpoints, time: f32)
+            -> bool {
+        if breakpoints.num_segments == 0 {
+            return false;
+        }
+
+        let running = self.segment >= 0.0;
+        if gate > 0.0 && self.last_gate <= 0.0 {
+            self.begin(0);
+        }
+        else if gate <= 0.0 && self.last_gate > 0.0 && running {
+            if let Some(release_node) = breakpoints.release_node {
+                if self.segment as usize <= release_node {
+                    if release_node >= breakpoints.num_segments {
+                        self.last_gate = gate;
+                        self.stop();
+                        return true;
+                    }
+                    self.begin(release_node);
+                }
+            }
+        }
+        self.last_gate = gate;
+
+        if self.segment < 0.0 || self.position < 0.0 {
+            return false;
+        }
+
+        let segment = self.segment as usize;
+        let (level, duration, curve) = breakpoints.segment(segment);
+        if duration > 0.0 {
+            self.position += time / duration;
+        }
+        else {
+            self.position = 1.0;
+        }
+
+        if self.position < 1.0 {
+            self.value = interpolate_curve(self.start, level,
+                                            self.position, curve);
+            return false;
+        }
+
+        self.value = level;
+        let next = segment + 1;
+        if gate > 0.0 && breakpoints.release_node == Some(next) {
+            match breakpoints.loop_node {
+                Some(loop_node) if loop_node < next => {
+                    self.begin(loop_node);
+                },
+                _ => {
+                    // Wait at the release node until the gate falls
+                    self.segment = next as f32;
+                    self.position = -1.0;
+                }
+            }
+            return false;
+        }
+
+        if next >= breakpoints.num_segments {
+            self.stop();
+            return true;
+        }
+
+        self.begin(next);
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use unit::DoneAction;
+    use test_util::UnitHarness;
+
+    use super::BreakpointEnvelopeAr;
+
+    const DONE_ACTION: u32 = 1;
+
+    // Up to one and back down again, without release or loop nodes
+    const ARGUMENTS: [f32; 9] = [0.0, -1.0, -1.0,
+                                 1.0, 0.001, 0.0,
+                                 0.0, 0.001, 0.0];
+
+    // Runs a short envelope to its end, returning the done action it took
+    fn finish(done_action: DoneAction) -> DoneAction {
+        let mut harness = UnitHarness::new(BreakpointEnvelopeAr::new, 0, 1, 0,
+                                           &ARGUMENTS);
+        harness.set(DONE_ACTION, done_action as u32 as f32);
+
+        // Still on the second segment at the end of the first block
+        assert!(harness.tick(&[]).unwrap()[63] > 0.0);
+        assert_eq!(harness.done_action, DoneAction::None);
+
+        assert_eq!(harness.tick(&[]).unwrap()[63], 0.0);
+        harness.done_action
+    }
+
+    #[test]
+    fn done_action_none() {
+        assert_eq!(finish(DoneAction::None), DoneAction::None);
+    }
+
+    #[test]
+    fn done_action_pause() {
+        assert_eq!(finish(DoneAction::Pause), DoneAction::Pause);
+    }
+
+    #[test]
+    fn done_action_free() {
+        assert_eq!(finish(DoneAction::Free), DoneAction::Free);
+    }
+}
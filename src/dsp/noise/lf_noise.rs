@@ -0,0 +1,217 @@
+use std::num::Float;
+
+use types::{ArtResult, Rate, Curve};
+
+use unit::{Unit, UnitDefinition, ChannelLayout, UnitKind, DataSize,
+           TickAdjuncts};
+use parameter::{ParameterDefinition, ParameterMode};
+use channel_stack::ChannelStack;
+use leap::Leap;
+use constants::Constants;
+use random::Random;
+
+use util::modulo;
+
+// Holds a random value, choosing a new one frequency times per second
+pub static PARAMETERS_AR: [ParameterDefinition; 2] = [
+    ParameterDefinition {
+        name: "frequency",
+        default: 1f32,
+        rate: Rate::Audio,
+        mode: ParameterMode::Interpolate,
+        minimum: 0.01,
+        maximum: 20000.0,
+        clamped: false,
+        unit: "Hz",
+        curve: Curve::Exponential,
+        description: "Number of new values chosen each second"
+    },
+    ParameterDefinition {
+        name: "seed",
+        default: 0f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 16777216.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Seeds the random number generator.  Zero uses the seed \
+                      set in the VM's options"
+    }
+];
+
+pub static DEFINITION_AR: UnitDefinition = UnitDefinition {
+    name: "lf_noise_ar",
+    kind: UnitKind::Source,
+    input_rate: None,
+    output_rate: Some(Rate::Audio),
+    default_layout: ChannelLayout {
+        input: 0,
+        output: 1
+    },
+    parameters: &PARAMETERS_AR,
+    tick: LfNoiseAr::tick,
+    data_size: DataSize::Fixed(5)
+};
+
+#[derive(Copy)]
+pub struct LfNoiseAr;
+
+impl LfNoiseAr {
+    pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
+               _: &[f32], data: &mut Leap<f32>)
+            -> Unit {
+        let data_index = data.tail;
+        data.push(0.0).unwrap(); // state_high
+        data.push(0.0).unwrap(); // state_low
+        data.push(-1.0).unwrap(); // last_seed
+        // Start at the end of a step so a value is chosen straight away
+        data.push(1.0).unwrap(); // position
+        data.push(0.0).unwrap(); // value
+
+        Unit {
+            definition: &DEFINITION_AR,
+            id: id,
+            layout: ChannelLayout {
+                input: input_channels,
+                output: output_channels
+            },
+            data_index: Some(data_index)
+        }
+    }
+
+    fn tick(unit: &mut Unit, block: &mut[f32], parameters: &mut ChannelStack,
+            adjuncts: &mut TickAdjuncts, constants: &Constants)
+            -> ArtResult<()> {
+        debug_assert!(unit.data_index.is_some());
+        let mut data = adjuncts.data.iter_mut(unit.data_index.unwrap());
+        let state_high = data.next().unwrap();
+        let state_low = data.next().unwrap();
+        let last_seed = data.next().unwrap();
+        let position = data.next().unwrap();
+        let value = data.next().unwrap();
+        let (mut frequency_stack, others) = parameters.split_at_mut(
+            constants.block_size
+        );
+        let frequency = frequency_stack.get_mut(0, constants.block_size);
+        let mut random = Random::load(state_high, state_low, last_seed,
+                                      others.data[0], unit.id, constants);
+
+        let channels = unit.layout.output as usize;
+
+        for i in range(0, constants.block_size) {
+            if *position >= 1.0 {
+                *position = modulo(*position, 1.0);
+                *value = random.next_f32();
+            }
+
+            for j in range(0, channels) {
+                block[i * channels + j] = *value;
+            }
+            *position += frequency[i].abs() * constants.audio_rate_inverse;
+        }
+
+        random.store(state_high, state_low);
+        Ok(())
+    }
+}
+
+pub static PARAMETERS_KR: [ParameterDefinition; 2] = [
+    ParameterDefinition {
+        name: "frequency",
+        default: 1f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.01,
+        maximum: 20000.0,
+        clamped: false,
+        unit: "Hz",
+        curve: Curve::Exponential,
+        description: "Number of new values chosen each second"
+    },
+    ParameterDefinition {
+        name: "seed",
+        default: 0f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 16777216.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Seeds the random number generator.  Zero uses the seed \
+                      set in the VM's options"
+    }
+];
+
+pub static DEFINITION_KR: UnitDefinition = UnitDefinition {
+    name: "lf_noise_kr",
+    kind: UnitKind::Source,
+    input_rate: None,
+    output_rate: Some(Rate::Control),
+    default_layout: ChannelLayout {
+        input: 0,
+        output: 1
+    },
+    parameters: &PARAMETERS_KR,
+    tick: LfNoiseKr::tick,
+    data_size: DataSize::Fixed(5)
+};
+
+#[derive(Copy)]
+pub struct LfNoiseKr;
+
+impl LfNoiseKr {
+    pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
+               _: &[f32], data: &mut Leap<f32>)
+            -> Unit {
+        let data_index = data.tail;
+        data.push(0.0).unwrap(); // state_high
+        data.push(0.0).unwrap(); // state_low
+        data.push(-1.0).unwrap(); // last_seed
+        // Start at the end of a step so a value is chosen straight away
+        data.push(1.0).unwrap(); // position
+        data.push(0.0).unwrap(); // value
+
+        Unit {
+            definition: &DEFINITION_KR,
+            id: id,
+            layout: ChannelLayout {
+                input: input_channels,
+                output: output_channels
+            },
+            data_index: Some(data_index)
+        }
+    }
+
+    fn tick(unit: &mut Unit, block: &mut[f32], parameters: &mut ChannelStack,
+            adjuncts: &mut TickAdjuncts, constants: &Constants)
+            -> ArtResult<()> {
+        debug_assert!(unit.data_index.is_some());
+        let mut data = adjuncts.data.iter_mut(unit.data_index.unwrap());
+        let state_high = data.next().unwrap();
+        let state_low = data.next().unwrap();
+        let last_seed = data.next().unwrap();
+        let position = data.next().unwrap();
+        let value = data.next().unwrap();
+        let frequency = parameters.data[0];
+        let mut random = Random::load(state_high, state_low, last_seed,
+                                      parameters.data[1], unit.id, constants);
+
+        let channels = unit.layout.output as usize;
+
+        if *position >= 1.0 {
+            *position = modulo(*position, 1.0);
+            *value = random.next_f32();
+        }
+
+        for i in range(0, channels) {
+            block[i] = *value;
+        }
+        *position += frequency.abs() * constants.control_rate_inverse;
+
+        random.store(state_high, state_low);
+        Ok(())
+    }
+}
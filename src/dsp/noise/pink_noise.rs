@@ -0,0 +1,197 @@
+use types::{ArtResult, Rate, Curve};
+
+use unit::{Unit, UnitDefinition, ChannelLayout, UnitKind, DataSize,
+           TickAdjuncts};
+use parameter::{ParameterDefinition, ParameterMode};
+use channel_stack::ChannelStack;
+use leap::Leap;
+use constants::Constants;
+use random::Random;
+
+const NUM_POLES: usize = 7;
+
+// Paul Kellet's refined pink noise filter, which sums a bank of one pole
+// filters to approximate a -3dB/octave slope
+fn filter(poles: &mut [&mut f32; NUM_POLES], white: f32) -> f32 {
+    *poles[0] = 0.99886 * *poles[0] + white * 0.0555179;
+    *poles[1] = 0.99332 * *poles[1] + white * 0.0750759;
+    *poles[2] = 0.96900 * *poles[2] + white * 0.1538520;
+    *poles[3] = 0.86650 * *poles[3] + white * 0.3104856;
+    *poles[4] = 0.55000 * *poles[4] + white * 0.5329522;
+    *poles[5] = -0.7616 * *poles[5] - white * 0.0168980;
+    let pink = *poles[0] + *poles[1] + *poles[2] + *poles[3] + *poles[4] +
+               *poles[5] + *poles[6] + white * 0.5362;
+    *poles[6] = white * 0.115926;
+    // Roughly normalise to [-1, 1]
+    pink * 0.11
+}
+
+pub static PARAMETERS_AR: [ParameterDefinition; 1] = [
+    ParameterDefinition {
+        name: "seed",
+        default: 0f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 16777216.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Seeds the random number generator.  Zero uses the seed \
+                      set in the VM's options"
+    }
+];
+
+pub static DEFINITION_AR: UnitDefinition = UnitDefinition {
+    name: "pink_noise_ar",
+    kind: UnitKind::Source,
+    input_rate: None,
+    output_rate: Some(Rate::Audio),
+    default_layout: ChannelLayout {
+        input: 0,
+        output: 1
+    },
+    parameters: &PARAMETERS_AR,
+    tick: PinkNoiseAr::tick,
+    data_size: DataSize::Fixed(10)
+};
+
+#[derive(Copy)]
+pub struct PinkNoiseAr;
+
+impl PinkNoiseAr {
+    pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
+               _: &[f32], data: &mut Leap<f32>)
+            -> Unit {
+        let data_index = data.tail;
+        data.push(0.0).unwrap(); // state_high
+        data.push(0.0).unwrap(); // state_low
+        data.push(-1.0).unwrap(); // last_seed
+        for _ in range(0, NUM_POLES) {
+            data.push(0.0).unwrap(); // pole
+        }
+
+        Unit {
+            definition: &DEFINITION_AR,
+            id: id,
+            layout: ChannelLayout {
+                input: input_channels,
+                output: output_channels
+            },
+            data_index: Some(data_index)
+        }
+    }
+
+    fn tick(unit: &mut Unit, block: &mut[f32], parameters: &mut ChannelStack,
+            adjuncts: &mut TickAdjuncts, constants: &Constants)
+            -> ArtResult<()> {
+        debug_assert!(unit.data_index.is_some());
+        let mut data = adjuncts.data.iter_mut(unit.data_index.unwrap());
+        let state_high = data.next().unwrap();
+        let state_low = data.next().unwrap();
+        let last_seed = data.next().unwrap();
+        let mut poles = [
+            data.next().unwrap(), data.next().unwrap(), data.next().unwrap(),
+            data.next().unwrap(), data.next().unwrap(), data.next().unwrap(),
+            data.next().unwrap()
+        ];
+        let mut random = Random::load(state_high, state_low, last_seed,
+                                      parameters.data[0], unit.id, constants);
+
+        let channels = unit.layout.output as usize;
+
+        for i in range(0, constants.block_size) {
+            let value = filter(&mut poles, random.next_f32());
+            for j in range(0, channels) {
+                block[i * channels + j] = value;
+            }
+        }
+
+        random.store(state_high, state_low);
+        Ok(())
+    }
+}
+
+pub static PARAMETERS_KR: [ParameterDefinition; 1] = [
+    ParameterDefinition {
+        name: "seed",
+        default: 0f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 16777216.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Seeds the random number generator.  Zero uses the seed \
+                      set in the VM's options"
+    }
+];
+
+pub static DEFINITION_KR: UnitDefinition = UnitDefinition {
+    name: "pink_noise_kr",
+    kind: UnitKind::Source,
+    input_rate: None,
+    output_rate: Some(Rate::Control),
+    default_layout: ChannelLayout {
+        input: 0,
+        output: 1
+    },
+    parameters: &PARAMETERS_KR,
+    tick: PinkNoiseKr::tick,
+    data_size: DataSize::Fixed(10)
+};
+
+#[derive(Copy)]
+pub struct PinkNoiseKr;
+
+impl PinkNoiseKr {
+    pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
+               _: &[f32], data: &mut Leap<f32>)
+            -> Unit {
+        let data_index = data.tail;
+        data.push(0.0).unwrap(); // state_high
+        data.push(0.0).unwrap(); // state_low
+        data.push(-1.0).unwrap(); // last_seed
+        for _ in range(0, NUM_POLES) {
+            data.push(0.0).unwrap(); // pole
+        }
+
+        Unit {
+            definition: &DEFINITION_KR,
+            id: id,
+            layout: ChannelLayout {
+                input: input_channels,
+                output: output_channels
+            },
+            data_index: Some(data_index)
+        }
+    }
+
+    fn tick(unit: &mut Unit, block: &mut[f32], parameters: &mut ChannelStack,
+            adjuncts: &mut TickAdjuncts, constants: &Constants)
+            -> ArtResult<()> {
+        debug_assert!(unit.data_index.is_some());
+        let mut data = adjuncts.data.iter_mut(unit.data_index.unwrap());
+        let state_high = data.next().unwrap();
+        let state_low = data.next().unwrap();
+        let last_seed = data.next().unwrap();
+        let mut poles = [
+            data.next().unwrap(), data.next().unwrap(), data.next().unwrap(),
+            data.next().unwrap(), data.next().unwrap(), data.next().unwrap(),
+            data.next().unwrap()
+        ];
+        let mut random = Random::load(state_high, state_low, last_seed,
+                                      parameters.data[0], unit.id, constants);
+
+        let channels = unit.layout.output as usize;
+
+        let value = filter(&mut poles, random.next_f32());
+        for i in range(0, channels) {
+            block[i] = value;
+        }
+
+        random.store(state_high, state_low);
+        Ok(())
+    }
+}
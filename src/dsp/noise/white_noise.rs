@@ -0,0 +1,163 @@
+use types::{ArtResult, Rate, Curve};
+
+use unit::{Unit, UnitDefinition, ChannelLayout, UnitKind, DataSize,
+           TickAdjuncts};
+use parameter::{ParameterDefinition, ParameterMode};
+use channel_stack::ChannelStack;
+use leap::Leap;
+use constants::Constants;
+use random::Random;
+
+pub static PARAMETERS_AR: [ParameterDefinition; 1] = [
+    ParameterDefinition {
+        name: "seed",
+        default: 0f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 16777216.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Seeds the random number generator.  Zero uses the seed \
+                      set in the VM's options"
+    }
+];
+
+pub static DEFINITION_AR: UnitDefinition = UnitDefinition {
+    name: "white_noise_ar",
+    kind: UnitKind::Source,
+    input_rate: None,
+    output_rate: Some(Rate::Audio),
+    default_layout: ChannelLayout {
+        input: 0,
+        output: 1
+    },
+    parameters: &PARAMETERS_AR,
+    tick: WhiteNoiseAr::tick,
+    data_size: DataSize::Fixed(3)
+};
+
+#[derive(Copy)]
+pub struct WhiteNoiseAr;
+
+impl WhiteNoiseAr {
+    pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
+               _: &[f32], data: &mut Leap<f32>)
+            -> Unit {
+        let data_index = data.tail;
+        data.push(0.0).unwrap(); // state_high
+        data.push(0.0).unwrap(); // state_low
+        data.push(-1.0).unwrap(); // last_seed
+
+        Unit {
+            definition: &DEFINITION_AR,
+            id: id,
+            layout: ChannelLayout {
+                input: input_channels,
+                output: output_channels
+            },
+            data_index: Some(data_index)
+        }
+    }
+
+    fn tick(unit: &mut Unit, block: &mut[f32], parameters: &mut ChannelStack,
+            adjuncts: &mut TickAdjuncts, constants: &Constants)
+            -> ArtResult<()> {
+        debug_assert!(unit.data_index.is_some());
+        let mut data = adjuncts.data.iter_mut(unit.data_index.unwrap());
+        let state_high = data.next().unwrap();
+        let state_low = data.next().unwrap();
+        let last_seed = data.next().unwrap();
+        let mut random = Random::load(state_high, state_low, last_seed,
+                                      parameters.data[0], unit.id, constants);
+
+        let channels = unit.layout.output as usize;
+
+        for i in range(0, constants.block_size) {
+            let value = random.next_f32();
+            for j in range(0, channels) {
+                block[i * channels + j] = value;
+            }
+        }
+
+        random.store(state_high, state_low);
+        Ok(())
+    }
+}
+
+pub static PARAMETERS_KR: [ParameterDefinition; 1] = [
+    ParameterDefinition {
+        name: "seed",
+        default: 0f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 16777216.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Seeds the random number generator.  Zero uses the seed \
+                      set in the VM's options"
+    }
+];
+
+pub static DEFINITION_KR: UnitDefinition = UnitDefinition {
+    name: "white_noise_kr",
+    kind: UnitKind::Source,
+    input_rate: None,
+    output_rate: Some(Rate::Control),
+    default_layout: ChannelLayout {
+        input: 0,
+        output: 1
+    },
+    parameters: &PARAMETERS_KR,
+    tick: WhiteNoiseKr::tick,
+    data_size: DataSize::Fixed(3)
+};
+
+#[derive(Copy)]
+pub struct WhiteNoiseKr;
+
+impl WhiteNoiseKr {
+    pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
+               _: &[f32], data: &mut Leap<f32>)
+            -> Unit {
+        let data_index = data.tail;
+        data.push(0.0).unwrap(); // state_high
+        data.push(0.0).unwrap(); // state_low
+        data.push(-1.0).unwrap(); // last_seed
+
+        Unit {
+            definition: &DEFINITION_KR,
+            id: id,
+            layout: ChannelLayout {
+                input: input_channels,
+                output: output_channels
+            },
+            data_index: Some(data_index)
+        }
+    }
+
+    fn tick(unit: &mut Unit, block: &mut[f32], parameters: &mut ChannelStack,
+            adjuncts: &mut TickAdjuncts, constants: &Constants)
+            -> ArtResult<()> {
+        debug_assert!(unit.data_index.is_some());
+        let mut data = adjuncts.data.iter_mut(unit.data_index.unwrap());
+        let state_high = data.next().unwrap();
+        let state_low = data.next().unwrap();
+        let last_seed = data.next().unwrap();
+        let mut random = Random::load(state_high, state_low, last_seed,
+                                      parameters.data[0], unit.id, constants);
+
+        let channels = unit.layout.output as usize;
+
+        let value = random.next_f32();
+        for i in range(0, channels) {
+            block[i] = value;
+        }
+
+        random.store(state_high, state_low);
+        Ok(())
+    }
+}
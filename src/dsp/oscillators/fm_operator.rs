@@ -0,0 +1,228 @@
+use std::num::Float;
+use std::f32::consts::PI_2;
+
+use types::{ArtResult, Rate, Curve};
+
+use unit::{Unit, UnitDefinition, ChannelLayout, UnitKind, DataSize,
+           TickAdjuncts};
+use parameter::{ParameterDefinition, ParameterMode};
+use channel_stack::ChannelStack;
+use leap::Leap;
+use constants::Constants;
+
+use util::{modulo, expand_channels};
+
+pub static PARAMETERS_AR: [ParameterDefinition; 4] = [
+    ParameterDefinition {
+        name: "frequency",
+        default: 440f32,
+        rate: Rate::Audio,
+        mode: ParameterMode::Interpolate,
+        minimum: 20.0,
+        maximum: 20000.0,
+        clamped: false,
+        unit: "Hz",
+        curve: Curve::Exponential,
+        description: "Frequency of the oscillator"
+    },
+    ParameterDefinition {
+        name: "ratio",
+        default: 1f32,
+        rate: Rate::Audio,
+        mode: ParameterMode::Interpolate,
+        minimum: 0.0,
+        maximum: 32.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Multiplies the frequency"
+    },
+    ParameterDefinition {
+        name: "index",
+        default: 1f32,
+        rate: Rate::Audio,
+        mode: ParameterMode::Interpolate,
+        minimum: 0.0,
+        maximum: 20.0,
+        clamped: true,
+        unit: "rad",
+        curve: Curve::Linear,
+        description: "Depth of the phase modulation"
+    },
+    ParameterDefinition {
+        name: "feedback",
+        default: 0f32,
+        rate: Rate::Audio,
+        mode: ParameterMode::Interpolate,
+        minimum: 0.0,
+        maximum: 2.0,
+        clamped: true,
+        unit: "rad",
+        curve: Curve::Linear,
+        description: "Depth of the phase modulation by the operator's own \
+                      output"
+    }
+];
+
+// A sine oscillator whose phase is modulated by its audio rate input, in the
+// style of the operators in DX synthesizers.  Operators are chained by
+// feeding the output of one into the next, and modulators are combined
+// either by adding them or by passing them as separate input channels,
+// which are summed.  The input can be left out for operators at the top of
+// a chain.
+pub static DEFINITION_AR: UnitDefinition = UnitDefinition {
+    name: "fm_operator_ar",
+    kind: UnitKind::Processor,
+    input_rate: Some(Rate::Audio),
+    output_rate: Some(Rate::Audio),
+    default_layout: ChannelLayout {
+        input: 1,
+        output: 1
+    },
+    parameters: &PARAMETERS_AR,
+    tick: FmOperatorAr::tick,
+    data_size: DataSize::Fixed(3)
+};
+
+#[derive(Copy)]
+pub struct FmOperatorAr;
+
+impl FmOperatorAr {
+    pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
+               _: &[f32], data: &mut Leap<f32>)
+            -> Unit {
+        let data_index = data.tail;
+        data.push(0.0).unwrap(); // position
+        data.push(0.0).unwrap(); // last_value
+        data.push(0.0).unwrap(); // previous_value
+
+        Unit {
+            definition: &DEFINITION_AR,
+            id: id,
+            layout: ChannelLayout {
+                input: input_channels,
+                output: output_channels
+            },
+            data_index: Some(data_index)
+        }
+    }
+
+    fn tick(unit: &mut Unit, block: &mut[f32], parameters: &mut ChannelStack,
+            adjuncts: &mut TickAdjuncts, constants: &Constants)
+            -> ArtResult<()> {
+        debug_assert!(unit.data_index.is_some());
+        let mut data = adjuncts.data.iter_mut(unit.data_index.unwrap());
+        let position = data.next().unwrap();
+        let last_value = data.next().unwrap();
+        let previous_value = data.next().unwrap();
+
+        let (mut frequency_stack, mut others) = parameters.split_at_mut(
+           constants.block_size
+        );
+        let (mut ratio_stack, mut others) = others.split_at_mut(
+           constants.block_size
+        );
+        let (mut index_stack, mut feedback_stack) = others.split_at_mut(
+           constants.block_size
+        );
+        let frequency = frequency_stack.get_mut(0, constants.block_size);
+        let ratio = ratio_stack.get_mut(0, constants.block_size);
+        let index = index_stack.get_mut(0, constants.block_size);
+        let feedback = feedback_stack.get_mut(0, constants.block_size);
+
+        let input_channels = unit.layout.input as usize;
+        let channels = unit.layout.output as usize;
+
+        // Generate a single channel in place, then copy it to the others
+        for i in range(0, constants.block_size) {
+            let mut modulator = 0.0;
+            for j in range(0, input_channels) {
+                modulator += block[i * input_channels + j];
+            }
+
+            // Feedback uses the average of the last two samples, which stops
+            // it from oscillating at high amounts
+            let feedback = feedback[i] * (*last_value + *previous_value) / 2.0;
+            let value = (*position + index[i] * modulator + feedback).sin();
+            block[i] = value;
+
+            *previous_value = *last_value;
+            *last_value = value;
+            *position += frequency[i] * ratio[i] * PI_2 *
+                         constants.audio_rate_inverse;
+            *position = modulo(*position, PI_2);
+        }
+
+        expand_channels(block, channels, constants.block_size);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::Float;
+    use std::f32::consts::PI_2;
+
+    use util::modulo;
+    use test_util::{UnitHarness, assert_near};
+
+    use super::FmOperatorAr;
+
+    const INDEX: u32 = 2;
+    const FEEDBACK: u32 = 3;
+
+    // A plain sine at the default frequency, stepped in the same way as the
+    // operator
+    fn sine(samples: usize) -> Vec<f32> {
+        let increment = 440.0 * 1.0 * PI_2 * (1.0 / 44100.0);
+        let mut position = 0f32;
+        range(0, samples).map(|_| {
+            let value = position.sin();
+            position = modulo(position + increment, PI_2);
+            value
+        }).collect()
+    }
+
+    fn operator() -> UnitHarness {
+        UnitHarness::new(FmOperatorAr::new, 1, 1, 0, &[])
+    }
+
+    #[test]
+    fn zero_modulator_gives_plain_sine() {
+        let mut harness = operator();
+        harness.set(INDEX, 5.0);
+        let output = harness.tick(&[0.0; 64]).unwrap().to_vec();
+
+        for (value, expected) in output.iter().zip(sine(64).iter()) {
+            assert_near(*value, *expected);
+        }
+    }
+
+    #[test]
+    fn phase_is_continuous_across_blocks() {
+        let mut harness = operator();
+        let mut output = harness.tick(&[0.0; 64]).unwrap().to_vec();
+        output.push_all(harness.tick(&[0.0; 64]).unwrap());
+
+        for (value, expected) in output.iter().zip(sine(128).iter()) {
+            assert_near(*value, *expected);
+        }
+    }
+
+    #[test]
+    fn feedback_stays_bounded() {
+        let mut harness = operator();
+        harness.set(FEEDBACK, 2.0);
+
+        // Feeding back the last sample alone makes the output flip between
+        // the extremes at this amount
+        let mut last = 0.0;
+        for _ in range(0, 200) {
+            for value in harness.tick(&[0.0; 64]).unwrap().iter() {
+                assert!(value.abs() <= 1.0);
+                assert!((*value - last).abs() < 1.0);
+                last = *value;
+            }
+        }
+    }
+}
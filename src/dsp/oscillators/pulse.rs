@@ -0,0 +1,292 @@
+use std::num::Float;
+
+use types::{ArtResult, Rate, Curve};
+
+use unit::{Unit, UnitDefinition, ChannelLayout, UnitKind, DataSize,
+           TickAdjuncts};
+use parameter::{ParameterDefinition, ParameterMode};
+use channel_stack::ChannelStack;
+use constants::Constants;
+use leap::Leap;
+
+use util::{modulo, poly_blep, edge_fraction, expand_channels};
+
+pub static PARAMETERS_AR: [ParameterDefinition; 3] = [
+    ParameterDefinition {
+        name: "frequency",
+        default: 440f32,
+        rate: Rate::Audio,
+        mode: ParameterMode::Interpolate,
+        minimum: 20.0,
+        maximum: 20000.0,
+        clamped: false,
+        unit: "Hz",
+        curve: Curve::Exponential,
+        description: "Frequency of the oscillator"
+    },
+    ParameterDefinition {
+        name: "phase",
+        default: 0f32,
+        rate: Rate::Audio,
+        mode: ParameterMode::Interpolate,
+        minimum: 0.0,
+        maximum: 1.0,
+        clamped: false,
+        unit: "cycles",
+        curve: Curve::Linear,
+        description: "Offset added to the position in the cycle"
+    },
+    ParameterDefinition {
+        name: "width",
+        default: 0.5f32,
+        rate: Rate::Audio,
+        mode: ParameterMode::Interpolate,
+        minimum: 0.0,
+        maximum: 1.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Fraction of the cycle spent high"
+    }
+];
+
+// Optionally takes a single audio rate input channel for hard sync, in the
+// same way as saw_ar
+pub static DEFINITION_AR: UnitDefinition = UnitDefinition {
+    name: "pulse_ar",
+    kind: UnitKind::Processor,
+    input_rate: Some(Rate::Audio),
+    output_rate: Some(Rate::Audio),
+    default_layout: ChannelLayout {
+        input: 0,
+        output: 1
+    },
+    parameters: &PARAMETERS_AR,
+    tick: PulseAr::tick,
+    data_size: DataSize::Fixed(2)
+};
+
+#[derive(Copy)]
+pub struct PulseAr;
+
+impl PulseAr {
+    pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
+               _: &[f32], data: &mut Leap<f32>)
+            -> Unit {
+        let data_index = data.tail;
+        data.push(0.0).unwrap(); // position
+        data.push(0.0).unwrap(); // last_sync
+
+        Unit {
+            definition: &DEFINITION_AR,
+            id: id,
+            layout: ChannelLayout {
+                input: input_channels,
+                output: output_channels
+            },
+            data_index: Some(data_index)
+        }
+    }
+
+    fn tick(unit: &mut Unit, block: &mut[f32], parameters: &mut ChannelStack,
+            adjuncts: &mut TickAdjuncts, constants: &Constants)
+            -> ArtResult<()> {
+        debug_assert!(unit.data_index.is_some());
+        let mut data = adjuncts.data.iter_mut(unit.data_index.unwrap());
+        let position = data.next().unwrap();
+        let last_sync = data.next().unwrap();
+        let (mut frequency_stack, mut others) = parameters.split_at_mut(
+           constants.block_size
+        );
+        let (mut phase_stack, mut width_stack) = others.split_at_mut(
+           constants.block_size
+        );
+        let frequency = frequency_stack.get_mut(0, constants.block_size);
+        let phase = phase_stack.get_mut(0, constants.block_size);
+        let width = width_stack.get_mut(0, constants.block_size);
+
+        let sync_channels = unit.layout.input as usize;
+        let channels = unit.layout.output as usize;
+
+        // Generate a single channel in place, then copy it to the others
+        for i in range(0, constants.block_size) {
+            let increment = frequency[i] * constants.audio_rate_inverse;
+            let dt = increment.abs();
+            let width = width[i].max(0.0).min(1.0);
+
+            // The distance moved since a sync, and the jump it caused
+            let mut synced = None;
+            if sync_channels > 0 {
+                let sync = block[i * sync_channels];
+                if sync > 0.0 && *last_sync <= 0.0 {
+                    let fraction = edge_fraction(*last_sync, sync);
+                    let before = pulse(modulo(*position + phase[i], 1.0),
+                                       width);
+                    *position = fraction * increment;
+                    synced = Some((fraction * dt,
+                                   pulse(modulo(phase[i], 1.0), width) -
+                                   before));
+                }
+                *last_sync = sync;
+            }
+
+            let t = modulo(*position + phase[i], 1.0);
+            block[i] = match synced {
+                Some((distance, step)) => {
+                    // The sync replaces the edges close to it, in the same
+                    // way as saw_ar
+                    if i > 0 {
+                        block[i - 1] += 0.5 * step *
+                                        poly_blep(1.0 + distance - dt, dt);
+                    }
+                    pulse(t, width) + 0.5 * step * poly_blep(distance, dt)
+                },
+                None => {
+                    // Rising edge at the start of the cycle, falling edge at
+                    // width
+                    pulse(t, width) + poly_blep(t, dt) -
+                    poly_blep(modulo(t + 1.0 - width, 1.0), dt)
+                }
+            };
+            *position += increment;
+        }
+        *position = modulo(*position, 1.0);
+
+        expand_channels(block, channels, constants.block_size);
+        Ok(())
+    }
+}
+
+// The naive pulse, without any smoothing
+fn pulse(t: f32, width: f32) -> f32 {
+    if t < width { 1.0 } else { -1.0 }
+}
+
+pub static PARAMETERS_KR: [ParameterDefinition; 3] = [
+    ParameterDefinition {
+        name: "frequency",
+        default: 440f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.01,
+        maximum: 100.0,
+        clamped: false,
+        unit: "Hz",
+        curve: Curve::Exponential,
+        description: "Frequency of the oscillator"
+    },
+    ParameterDefinition {
+        name: "phase",
+        default: 0f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 1.0,
+        clamped: false,
+        unit: "cycles",
+        curve: Curve::Linear,
+        description: "Offset added to the position in the cycle"
+    },
+    ParameterDefinition {
+        name: "width",
+        default: 0.5f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 1.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Fraction of the cycle spent high"
+    }
+];
+
+pub static DEFINITION_KR: UnitDefinition = UnitDefinition {
+    name: "pulse_kr",
+    kind: UnitKind::Source,
+    input_rate: None,
+    output_rate: Some(Rate::Control),
+    default_layout: ChannelLayout {
+        input: 0,
+        output: 1
+    },
+    parameters: &PARAMETERS_KR,
+    tick: PulseKr::tick,
+    data_size: DataSize::Fixed(1)
+};
+
+#[derive(Copy)]
+pub struct PulseKr;
+
+impl PulseKr {
+    pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
+               _: &[f32], data: &mut Leap<f32>)
+            -> Unit {
+        let data_index = data.tail;
+        data.push(0.0).unwrap(); // position
+
+        Unit {
+            definition: &DEFINITION_KR,
+            id: id,
+            layout: ChannelLayout {
+                input: input_channels,
+                output: output_channels
+            },
+            data_index: Some(data_index)
+        }
+    }
+
+    fn tick(unit: &mut Unit, block: &mut[f32], parameters: &mut ChannelStack,
+            adjuncts: &mut TickAdjuncts, constants: &Constants)
+            -> ArtResult<()> {
+        debug_assert!(unit.data_index.is_some());
+        let mut data = adjuncts.data.iter_mut(unit.data_index.unwrap());
+        let position = data.next().unwrap();
+        let frequency = parameters.data[0];
+        let phase = parameters.data[1];
+        let width = parameters.data[2];
+
+        let channels = unit.layout.output as usize;
+
+        let value = if modulo(*position + phase, 1.0) < width {
+            1.0
+        }
+        else {
+            -1.0
+        };
+        for i in range(0, channels) {
+            block[i] = value;
+        }
+        *position += frequency * constants.control_rate_inverse;
+        *position = modulo(*position, 1.0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_util::{UnitHarness, assert_near};
+
+    use super::{PulseAr, pulse};
+
+    const SYNC: usize = 60;
+
+    // Rises through zero half way through the sample before SYNC
+    fn sync_input() -> Vec<f32> {
+        range(0, 64).map(|i| if i < SYNC { -1.0 } else { 1.0 }).collect()
+    }
+
+    #[test]
+    fn sync_smooths_the_jump() {
+        let mut harness = UnitHarness::new(PulseAr::new, 1, 1, 0, &[]);
+        let output = harness.tick(&sync_input()[..]).unwrap().to_vec();
+
+        // Synced while low, so the pulse jumps straight back up
+        let dt = 440.0 / 44100.0;
+        let step = pulse(0.0, 0.5) - pulse(SYNC as f32 * dt, 0.5);
+        assert_eq!(step, 2.0);
+        assert_near(output[SYNC - 1], -1.0 + step / 8.0);
+        assert_near(output[SYNC], 1.0 - step / 8.0);
+        assert_near(output[SYNC + 1], 1.0);
+    }
+}
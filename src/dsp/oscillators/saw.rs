@@ -1,4 +1,6 @@
-use types::{ArtResult, Rate};
+use std::num::Float;
+
+use types::{ArtResult, Rate, Curve};
 
 use unit::{Unit, UnitDefinition, ChannelLayout, UnitKind, DataSize,
            TickAdjuncts};
@@ -7,27 +9,42 @@ use channel_stack::ChannelStack;
 use constants::Constants;
 use leap::Leap;
 
-use util::modulo;
+use util::{modulo, poly_blep, edge_fraction, expand_channels};
 
 pub static PARAMETERS_AR: [ParameterDefinition; 2] = [
     ParameterDefinition {
         name: "frequency",
         default: 440f32,
         rate: Rate::Audio,
-        mode: ParameterMode::Interpolate
+        mode: ParameterMode::Interpolate,
+        minimum: 20.0,
+        maximum: 20000.0,
+        clamped: false,
+        unit: "Hz",
+        curve: Curve::Exponential,
+        description: "Frequency of the oscillator"
     },
     ParameterDefinition {
         name: "phase",
         default: 0f32,
         rate: Rate::Audio,
-        mode: ParameterMode::Interpolate
+        mode: ParameterMode::Interpolate,
+        minimum: 0.0,
+        maximum: 1.0,
+        clamped: false,
+        unit: "cycles",
+        curve: Curve::Linear,
+        description: "Offset added to the position in the cycle"
     }
 ];
 
+// Optionally takes a single audio rate input channel.  The oscillator is
+// reset to the start of its cycle whenever the input rises above zero, for
+// hard sync.
 pub static DEFINITION_AR: UnitDefinition = UnitDefinition {
     name: "saw_ar",
-    kind: UnitKind::Source,
-    input_rate: None,
+    kind: UnitKind::Processor,
+    input_rate: Some(Rate::Audio),
     output_rate: Some(Rate::Audio),
     default_layout: ChannelLayout {
         input: 0,
@@ -35,7 +52,7 @@ pub static DEFINITION_AR: UnitDefinition = UnitDefinition {
     },
     parameters: &PARAMETERS_AR,
     tick: SawAr::tick,
-    data_size: DataSize::Fixed(1)
+    data_size: DataSize::Fixed(2)
 };
 
 #[derive(Copy)]
@@ -43,10 +60,11 @@ pub struct SawAr;
 
 impl SawAr {
     pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
-               data: &mut Leap<f32>)
+               _: &[f32], data: &mut Leap<f32>)
             -> Unit {
         let data_index = data.tail;
         data.push(0.0).unwrap(); // position
+        data.push(0.0).unwrap(); // last_sync
 
         Unit {
             definition: &DEFINITION_AR,
@@ -65,6 +83,7 @@ impl SawAr {
         debug_assert!(unit.data_index.is_some());
         let mut data = adjuncts.data.iter_mut(unit.data_index.unwrap());
         let position = data.next().unwrap();
+        let last_sync = data.next().unwrap();
         let (mut frequency_stack,
              mut phase_stack) = parameters.split_at_mut(
            constants.block_size
@@ -72,32 +91,81 @@ impl SawAr {
         let frequency = frequency_stack.get_mut(0, constants.block_size);
         let phase = phase_stack.get_mut(0, constants.block_size);
 
+        let sync_channels = unit.layout.input as usize;
         let channels = unit.layout.output as usize;
 
+        // Generate a single channel in place, then copy it to the others
         for i in range(0, constants.block_size) {
-            let value = modulo(*position + phase[i], 1.0) * 2.0 - 1.0;
-            for j in range(0, channels) {
-                block[i * channels + j] = value;
+            let increment = frequency[i] * constants.audio_rate_inverse;
+            let dt = increment.abs();
+
+            // The distance moved since a sync, and the jump it caused
+            let mut synced = None;
+            if sync_channels > 0 {
+                let sync = block[i * sync_channels];
+                if sync > 0.0 && *last_sync <= 0.0 {
+                    let fraction = edge_fraction(*last_sync, sync);
+                    let before = saw(modulo(*position + phase[i], 1.0));
+                    *position = fraction * increment;
+                    synced = Some((fraction * dt,
+                                   saw(modulo(phase[i], 1.0)) - before));
+                }
+                *last_sync = sync;
             }
-            *position += frequency[i] * constants.audio_rate_inverse;
+
+            let t = modulo(*position + phase[i], 1.0);
+            block[i] = match synced {
+                Some((distance, step)) => {
+                    // The sync replaces the step at the start of the cycle.
+                    // If it falls at the start of the block the sample
+                    // before it has already gone, so only this side is
+                    // smoothed.
+                    if i > 0 {
+                        block[i - 1] += 0.5 * step *
+                                        poly_blep(1.0 + distance - dt, dt);
+                    }
+                    saw(t) + 0.5 * step * poly_blep(distance, dt)
+                },
+                None => saw(t) - poly_blep(t, dt)
+            };
+            *position += increment;
         }
         *position = modulo(*position, 1.0);
+
+        expand_channels(block, channels, constants.block_size);
         Ok(())
     }
 }
 
+// The naive ramp, without any smoothing
+fn saw(t: f32) -> f32 {
+    2.0 * t - 1.0
+}
+
 pub static PARAMETERS_KR: [ParameterDefinition; 2] = [
     ParameterDefinition {
         name: "frequency",
         default: 440f32,
         rate: Rate::Control,
-        mode: ParameterMode::Normal
+        mode: ParameterMode::Normal,
+        minimum: 0.01,
+        maximum: 100.0,
+        clamped: false,
+        unit: "Hz",
+        curve: Curve::Exponential,
+        description: "Frequency of the oscillator"
     },
     ParameterDefinition {
         name: "phase",
         default: 0f32,
         rate: Rate::Control,
-        mode: ParameterMode::Normal
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 1.0,
+        clamped: false,
+        unit: "cycles",
+        curve: Curve::Linear,
+        description: "Offset added to the position in the cycle"
     }
 ];
 
@@ -120,7 +188,7 @@ pub struct SawKr;
 
 impl SawKr {
     pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
-               data: &mut Leap<f32>)
+               _: &[f32], data: &mut Leap<f32>)
             -> Unit {
         let data_index = data.tail;
         data.push(0.0).unwrap(); // position
@@ -157,3 +225,44 @@ impl SawKr {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use test_util::{UnitHarness, assert_near};
+
+    use super::{SawAr, saw};
+
+    const SYNC: usize = 60;
+
+    // Rises through zero half way through the sample before SYNC
+    fn sync_input() -> Vec<f32> {
+        range(0, 64).map(|i| if i < SYNC { -1.0 } else { 1.0 }).collect()
+    }
+
+    #[test]
+    fn sync_smooths_the_jump() {
+        let mut harness = UnitHarness::new(SawAr::new, 1, 1, 0, &[]);
+        let output = harness.tick(&sync_input()[..]).unwrap().to_vec();
+
+        // The distance moved per sample at the default frequency
+        let dt = 440.0 / 44100.0;
+        let step = saw(0.0) - saw(SYNC as f32 * dt);
+        // Half a sample from the jump the residual is a quarter of its
+        // height each side, so each sample moves an eighth of the way
+        // towards the other
+        assert_near(output[SYNC - 1], saw((SYNC - 1) as f32 * dt) +
+                                      step / 8.0);
+        assert_near(output[SYNC], saw(0.5 * dt) - step / 8.0);
+        assert_near(output[SYNC + 1], saw(1.5 * dt));
+    }
+
+    #[test]
+    fn sync_held_high_does_not_restart() {
+        let mut harness = UnitHarness::new(SawAr::new, 1, 1, 0, &[]);
+        harness.tick(&sync_input()[..]).unwrap();
+        let output = harness.tick(&[1.0; 64]).unwrap();
+
+        let dt = 440.0 / 44100.0;
+        assert_near(output[0], saw((64 - SYNC) as f32 * dt + 0.5 * dt));
+    }
+}
@@ -1,7 +1,7 @@
 use std::num::Float;
 use std::f32::consts::PI_2;
 
-use types::{ArtResult, Rate};
+use types::{ArtResult, Rate, Curve};
 
 use unit::{Unit, UnitDefinition, ChannelLayout, UnitKind, DataSize,
            TickAdjuncts};
@@ -17,13 +17,25 @@ pub static PARAMETERS_AR: [ParameterDefinition; 2] = [
         name: "frequency",
         default: 440f32,
         rate: Rate::Audio,
-        mode: ParameterMode::Interpolate
+        mode: ParameterMode::Interpolate,
+        minimum: 20.0,
+        maximum: 20000.0,
+        clamped: false,
+        unit: "Hz",
+        curve: Curve::Exponential,
+        description: "Frequency of the oscillator"
     },
     ParameterDefinition {
         name: "phase",
         default: 0f32,
         rate: Rate::Audio,
-        mode: ParameterMode::Interpolate
+        mode: ParameterMode::Interpolate,
+        minimum: 0.0,
+        maximum: PI_2,
+        clamped: false,
+        unit: "rad",
+        curve: Curve::Linear,
+        description: "Offset added to the position in the cycle"
     }
 ];
 
@@ -46,7 +58,7 @@ pub struct SineAr;
 
 impl SineAr {
     pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
-               data: &mut Leap<f32>)
+               _: &[f32], data: &mut Leap<f32>)
             -> Unit {
         let data_index = data.tail;
         data.push(0.0).unwrap();
@@ -96,13 +108,25 @@ pub static PARAMETERS_KR: [ParameterDefinition; 2] = [
         name: "frequency",
         default: 440f32,
         rate: Rate::Control,
-        mode: ParameterMode::Normal
+        mode: ParameterMode::Normal,
+        minimum: 0.01,
+        maximum: 100.0,
+        clamped: false,
+        unit: "Hz",
+        curve: Curve::Exponential,
+        description: "Frequency of the oscillator"
     },
     ParameterDefinition {
         name: "phase",
         default: 0f32,
         rate: Rate::Control,
-        mode: ParameterMode::Normal
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: PI_2,
+        clamped: false,
+        unit: "rad",
+        curve: Curve::Linear,
+        description: "Offset added to the position in the cycle"
     }
 ];
 
@@ -125,7 +149,7 @@ pub struct SineKr;
 
 impl SineKr {
     pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
-               data: &mut Leap<f32>)
+               _: &[f32], data: &mut Leap<f32>)
             -> Unit {
         let data_index = data.tail;
         data.push(0.0).unwrap();
@@ -0,0 +1,274 @@
+use std::num::Float;
+
+use types::{ArtResult, Rate, Curve};
+
+use unit::{Unit, UnitDefinition, ChannelLayout, UnitKind, DataSize,
+           TickAdjuncts};
+use parameter::{ParameterDefinition, ParameterMode};
+use channel_stack::ChannelStack;
+use constants::Constants;
+use leap::Leap;
+
+use util::{modulo, poly_blep, poly_blamp, edge_fraction, expand_channels};
+
+pub static PARAMETERS_AR: [ParameterDefinition; 2] = [
+    ParameterDefinition {
+        name: "frequency",
+        default: 440f32,
+        rate: Rate::Audio,
+        mode: ParameterMode::Interpolate,
+        minimum: 20.0,
+        maximum: 20000.0,
+        clamped: false,
+        unit: "Hz",
+        curve: Curve::Exponential,
+        description: "Frequency of the oscillator"
+    },
+    ParameterDefinition {
+        name: "phase",
+        default: 0f32,
+        rate: Rate::Audio,
+        mode: ParameterMode::Interpolate,
+        minimum: 0.0,
+        maximum: 1.0,
+        clamped: false,
+        unit: "cycles",
+        curve: Curve::Linear,
+        description: "Offset added to the position in the cycle"
+    }
+];
+
+// Optionally takes a single audio rate input channel for hard sync, in the
+// same way as saw_ar
+pub static DEFINITION_AR: UnitDefinition = UnitDefinition {
+    name: "triangle_ar",
+    kind: UnitKind::Processor,
+    input_rate: Some(Rate::Audio),
+    output_rate: Some(Rate::Audio),
+    default_layout: ChannelLayout {
+        input: 0,
+        output: 1
+    },
+    parameters: &PARAMETERS_AR,
+    tick: TriangleAr::tick,
+    data_size: DataSize::Fixed(2)
+};
+
+#[derive(Copy)]
+pub struct TriangleAr;
+
+impl TriangleAr {
+    pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
+               _: &[f32], data: &mut Leap<f32>)
+            -> Unit {
+        let data_index = data.tail;
+        data.push(0.0).unwrap(); // position
+        data.push(0.0).unwrap(); // last_sync
+
+        Unit {
+            definition: &DEFINITION_AR,
+            id: id,
+            layout: ChannelLayout {
+                input: input_channels,
+                output: output_channels
+            },
+            data_index: Some(data_index)
+        }
+    }
+
+    fn tick(unit: &mut Unit, block: &mut[f32], parameters: &mut ChannelStack,
+            adjuncts: &mut TickAdjuncts, constants: &Constants)
+            -> ArtResult<()> {
+        debug_assert!(unit.data_index.is_some());
+        let mut data = adjuncts.data.iter_mut(unit.data_index.unwrap());
+        let position = data.next().unwrap();
+        let last_sync = data.next().unwrap();
+        let (mut frequency_stack,
+             mut phase_stack) = parameters.split_at_mut(
+           constants.block_size
+        );
+        let frequency = frequency_stack.get_mut(0, constants.block_size);
+        let phase = phase_stack.get_mut(0, constants.block_size);
+
+        let sync_channels = unit.layout.input as usize;
+        let channels = unit.layout.output as usize;
+
+        // Generate a single channel in place, then copy it to the others
+        for i in range(0, constants.block_size) {
+            let increment = frequency[i] * constants.audio_rate_inverse;
+            let dt = increment.abs();
+
+            // The distance moved since a sync, and the jumps in value and
+            // slope it caused
+            let mut synced = None;
+            if sync_channels > 0 {
+                let sync = block[i * sync_channels];
+                if sync > 0.0 && *last_sync <= 0.0 {
+                    let fraction = edge_fraction(*last_sync, sync);
+                    let before = modulo(*position + phase[i], 1.0);
+                    let after = modulo(phase[i], 1.0);
+                    *position = fraction * increment;
+                    synced = Some((fraction * dt,
+                                   triangle(after) - triangle(before),
+                                   slope(after) - slope(before)));
+                }
+                *last_sync = sync;
+            }
+
+            let t = modulo(*position + phase[i], 1.0);
+            block[i] = match synced {
+                Some((distance, step, bend)) => {
+                    // The sync replaces the corners close to it, in the same
+                    // way as saw_ar replaces its step
+                    if i > 0 {
+                        let t = 1.0 + distance - dt;
+                        block[i - 1] += 0.5 * step * poly_blep(t, dt) +
+                                        0.5 * bend * dt * poly_blamp(t, dt);
+                    }
+                    triangle(t) + 0.5 * step * poly_blep(distance, dt) +
+                    0.5 * bend * dt * poly_blamp(distance, dt)
+                },
+                None => {
+                    // The slope changes by 8 per cycle at the trough at the
+                    // start of the cycle, and by -8 at the peak half way
+                    // through
+                    triangle(t) + 4.0 * dt * poly_blamp(t, dt) -
+                    4.0 * dt * poly_blamp(modulo(t + 0.5, 1.0), dt)
+                }
+            };
+            *position += increment;
+        }
+        *position = modulo(*position, 1.0);
+
+        expand_channels(block, channels, constants.block_size);
+        Ok(())
+    }
+}
+
+// The naive triangle, without any smoothing
+fn triangle(t: f32) -> f32 {
+    1.0 - 4.0 * (t - 0.5).abs()
+}
+
+// The slope of the naive triangle in units per cycle
+fn slope(t: f32) -> f32 {
+    if t < 0.5 { 4.0 } else { -4.0 }
+}
+
+pub static PARAMETERS_KR: [ParameterDefinition; 2] = [
+    ParameterDefinition {
+        name: "frequency",
+        default: 440f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.01,
+        maximum: 100.0,
+        clamped: false,
+        unit: "Hz",
+        curve: Curve::Exponential,
+        description: "Frequency of the oscillator"
+    },
+    ParameterDefinition {
+        name: "phase",
+        default: 0f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 1.0,
+        clamped: false,
+        unit: "cycles",
+        curve: Curve::Linear,
+        description: "Offset added to the position in the cycle"
+    }
+];
+
+pub static DEFINITION_KR: UnitDefinition = UnitDefinition {
+    name: "triangle_kr",
+    kind: UnitKind::Source,
+    input_rate: None,
+    output_rate: Some(Rate::Control),
+    default_layout: ChannelLayout {
+        input: 0,
+        output: 1
+    },
+    parameters: &PARAMETERS_KR,
+    tick: TriangleKr::tick,
+    data_size: DataSize::Fixed(1)
+};
+
+#[derive(Copy)]
+pub struct TriangleKr;
+
+impl TriangleKr {
+    pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
+               _: &[f32], data: &mut Leap<f32>)
+            -> Unit {
+        let data_index = data.tail;
+        data.push(0.0).unwrap(); // position
+
+        Unit {
+            definition: &DEFINITION_KR,
+            id: id,
+            layout: ChannelLayout {
+                input: input_channels,
+                output: output_channels
+            },
+            data_index: Some(data_index)
+        }
+    }
+
+    fn tick(unit: &mut Unit, block: &mut[f32], parameters: &mut ChannelStack,
+            adjuncts: &mut TickAdjuncts, constants: &Constants)
+            -> ArtResult<()> {
+        debug_assert!(unit.data_index.is_some());
+        let mut data = adjuncts.data.iter_mut(unit.data_index.unwrap());
+        let position = data.next().unwrap();
+        let frequency = parameters.data[0];
+        let phase = parameters.data[1];
+
+        let channels = unit.layout.output as usize;
+
+        let t = modulo(*position + phase, 1.0);
+        let value = 1.0 - 4.0 * (t - 0.5).abs();
+        for i in range(0, channels) {
+            block[i] = value;
+        }
+        *position += frequency * constants.control_rate_inverse;
+        *position = modulo(*position, 1.0);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_util::{UnitHarness, assert_near};
+
+    use super::{TriangleAr, triangle, slope};
+
+    const SYNC: usize = 60;
+
+    // Rises through zero half way through the sample before SYNC
+    fn sync_input() -> Vec<f32> {
+        range(0, 64).map(|i| if i < SYNC { -1.0 } else { 1.0 }).collect()
+    }
+
+    #[test]
+    fn sync_smooths_the_jump_and_corner() {
+        let mut harness = UnitHarness::new(TriangleAr::new, 1, 1, 0, &[]);
+        let output = harness.tick(&sync_input()[..]).unwrap().to_vec();
+
+        // Synced while falling, so both the value and the slope jump
+        let dt = 440.0 / 44100.0;
+        let before = SYNC as f32 * dt;
+        let step = triangle(0.0) - triangle(before);
+        let bend = slope(0.0) - slope(before);
+        assert_eq!(bend, 8.0);
+        // Half a sample from the jump the step residual is a quarter of its
+        // height and the corner residual is a twenty fourth of it, each side
+        let corner = 0.5 * bend * dt / 24.0;
+        assert_near(output[SYNC - 1], triangle((SYNC - 1) as f32 * dt) +
+                                      step / 8.0 + corner);
+        assert_near(output[SYNC], triangle(0.5 * dt) - step / 8.0 + corner);
+        assert_near(output[SYNC + 1], triangle(1.5 * dt));
+    }
+}
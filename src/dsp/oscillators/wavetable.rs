@@ -0,0 +1,161 @@
+use std::num::Float;
+
+use types::{ArtResult, Rate, Curve};
+
+use unit::{Unit, UnitDefinition, ChannelLayout, UnitKind, DataSize,
+           TickAdjuncts};
+use parameter::{ParameterDefinition, ParameterMode, UNBOUNDED};
+use channel_stack::ChannelStack;
+use constants::Constants;
+use leap::Leap;
+
+use util::modulo;
+
+pub static PARAMETERS_AR: [ParameterDefinition; 4] = [
+    ParameterDefinition {
+        name: "frequency",
+        default: 440f32,
+        rate: Rate::Audio,
+        mode: ParameterMode::Interpolate,
+        minimum: 20.0,
+        maximum: 20000.0,
+        clamped: false,
+        unit: "Hz",
+        curve: Curve::Exponential,
+        description: "Frequency of the oscillator"
+    },
+    ParameterDefinition {
+        name: "phase",
+        default: 0f32,
+        rate: Rate::Audio,
+        mode: ParameterMode::Interpolate,
+        minimum: 0.0,
+        maximum: 1.0,
+        clamped: false,
+        unit: "cycles",
+        curve: Curve::Linear,
+        description: "Offset added to the position in the cycle"
+    },
+    ParameterDefinition {
+        name: "position",
+        default: 0f32,
+        rate: Rate::Audio,
+        mode: ParameterMode::Interpolate,
+        minimum: 0.0,
+        maximum: UNBOUNDED,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Morphs between adjacent tables, from zero to the number \
+                      of tables minus one"
+    },
+    ParameterDefinition {
+        name: "wavetable",
+        default: 0f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: UNBOUNDED,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "The wavetable to play"
+    }
+];
+
+pub static DEFINITION_AR: UnitDefinition = UnitDefinition {
+    name: "wavetable_ar",
+    kind: UnitKind::Source,
+    input_rate: None,
+    output_rate: Some(Rate::Audio),
+    default_layout: ChannelLayout {
+        input: 0,
+        output: 1
+    },
+    parameters: &PARAMETERS_AR,
+    tick: WavetableAr::tick,
+    data_size: DataSize::Fixed(1)
+};
+
+#[derive(Copy)]
+pub struct WavetableAr;
+
+impl WavetableAr {
+    pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
+               _: &[f32], data: &mut Leap<f32>)
+            -> Unit {
+        let data_index = data.tail;
+        data.push(0.0).unwrap(); // position
+
+        Unit {
+            definition: &DEFINITION_AR,
+            id: id,
+            layout: ChannelLayout {
+                input: input_channels,
+                output: output_channels
+            },
+            data_index: Some(data_index)
+        }
+    }
+
+    fn tick(unit: &mut Unit, block: &mut[f32], parameters: &mut ChannelStack,
+            adjuncts: &mut TickAdjuncts, constants: &Constants)
+            -> ArtResult<()> {
+        debug_assert!(unit.data_index.is_some());
+        let mut data = adjuncts.data.iter_mut(unit.data_index.unwrap());
+        let position = data.next().unwrap();
+        let (mut frequency_stack, mut others) = parameters.split_at_mut(
+           constants.block_size
+        );
+        let (mut phase_stack, mut others) = others.split_at_mut(
+           constants.block_size
+        );
+        let (mut table_stack, others) = others.split_at_mut(
+           constants.block_size
+        );
+        let frequency = frequency_stack.get_mut(0, constants.block_size);
+        let phase = phase_stack.get_mut(0, constants.block_size);
+        let table_position = table_stack.get_mut(0, constants.block_size);
+        let wavetable_id = others.data[0] as u32;
+
+        let channels = unit.layout.output as usize;
+
+        // Missing wavetables are silent, so they can be loaded after the
+        // expressions which use them
+        let wavetable = match adjuncts.wavetables.get(wavetable_id) {
+            Some(wavetable) => *wavetable,
+            None => {
+                for sample in block.iter_mut() {
+                    *sample = 0.0;
+                }
+                return Ok(());
+            }
+        };
+        let last_table = (wavetable.num_tables - 1) as f32;
+
+        for i in range(0, constants.block_size) {
+            let t = modulo(*position + phase[i], 1.0);
+            let level = wavetable.level(frequency[i], constants.audio_rate);
+
+            let table_position = table_position[i].max(0.0).min(last_table);
+            let table = table_position.floor();
+            let mix = table_position - table;
+            let table = table as usize;
+
+            let mut value = adjuncts.wavetables.read(&wavetable, table, level,
+                                                     t);
+            if mix > 0.0 {
+                let next = adjuncts.wavetables.read(&wavetable, table + 1,
+                                                    level, t);
+                value += (next - value) * mix;
+            }
+
+            for j in range(0, channels) {
+                block[i * channels + j] = value;
+            }
+            *position += frequency[i] * constants.audio_rate_inverse;
+        }
+        *position = modulo(*position, 1.0);
+        Ok(())
+    }
+}
@@ -1,8 +1,8 @@
-use types::{ArtResult, Rate};
+use types::{ArtResult, Rate, Curve};
 
 use unit::{Unit, UnitDefinition, ChannelLayout, UnitKind, DataSize,
            TickAdjuncts};
-use parameter::{ParameterDefinition, ParameterMode};
+use parameter::{ParameterDefinition, ParameterMode, UNBOUNDED};
 use channel_stack::ChannelStack;
 use leap::Leap;
 use constants::Constants;
@@ -12,7 +12,13 @@ pub static PARAMETERS_AR: [ParameterDefinition; 1] = [
         name: "value",
         default: 0f32,
         rate: Rate::Audio,
-        mode: ParameterMode::Interpolate
+        mode: ParameterMode::Interpolate,
+        minimum: -UNBOUNDED,
+        maximum: UNBOUNDED,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "The value to output"
     }
 ];
 
@@ -35,7 +41,7 @@ pub struct ParameterAr;
 
 impl ParameterAr {
     pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
-               _: &mut Leap<f32>)
+               _: &[f32], _: &mut Leap<f32>)
             -> Unit {
         Unit {
             definition: &DEFINITION_AR,
@@ -69,7 +75,13 @@ pub static PARAMETERS_KR: [ParameterDefinition; 1] = [
         name: "value",
         default: 0f32,
         rate: Rate::Control,
-        mode: ParameterMode::Normal
+        mode: ParameterMode::Normal,
+        minimum: -UNBOUNDED,
+        maximum: UNBOUNDED,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "The value to output"
     }
 ];
 
@@ -92,7 +104,7 @@ pub struct ParameterKr;
 
 impl ParameterKr {
     pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
-               _: &mut Leap<f32>)
+               _: &[f32], _: &mut Leap<f32>)
             -> Unit {
         Unit {
             definition: &DEFINITION_KR,
@@ -1,11 +1,11 @@
 use std::num::Float;
 
-use types::{ArtResult, Rate};
+use types::{ArtResult, Rate, Curve};
 use errors::ArtError;
 
 use unit::{Unit, UnitDefinition, ChannelLayout, UnitKind, DataSize,
            TickAdjuncts};
-use parameter::{ParameterDefinition, ParameterMode};
+use parameter::{ParameterDefinition, ParameterMode, UNBOUNDED};
 use channel_stack::ChannelStack;
 use leap::Leap;
 use constants::Constants;
@@ -15,19 +15,37 @@ pub static PARAMETERS: [ParameterDefinition; 3] = [
         name: "eid",
         default: 0f32,
         rate: Rate::Control,
-        mode: ParameterMode::Normal
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: UNBOUNDED,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Expression id of the parameter to write to"
     },
     ParameterDefinition {
         name: "uid",
         default: 0f32,
         rate: Rate::Control,
-        mode: ParameterMode::Normal
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: UNBOUNDED,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Unit id of the parameter to write to"
     },
     ParameterDefinition {
         name: "pid",
         default: 0f32,
         rate: Rate::Control,
-        mode: ParameterMode::Normal
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: UNBOUNDED,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Id of the parameter to write to"
     }
 ];
 
@@ -50,7 +68,7 @@ pub struct ParameterWriterAr;
 
 impl ParameterWriterAr {
     pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
-               _: &mut Leap<f32>)
+               _: &[f32], _: &mut Leap<f32>)
             -> Unit {
         Unit {
             definition: &DEFINITION_AR,
@@ -115,7 +133,7 @@ pub struct ParameterWriterKr;
 
 impl ParameterWriterKr {
     pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
-               _: &mut Leap<f32>)
+               _: &[f32], _: &mut Leap<f32>)
             -> Unit {
         Unit {
             definition: &DEFINITION_KR,
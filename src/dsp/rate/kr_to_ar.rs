@@ -29,7 +29,7 @@ pub struct KrToAr;
 
 impl KrToAr {
     pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
-               _: &mut Leap<f32>)
+               _: &[f32], _: &mut Leap<f32>)
             -> Unit {
         Unit {
             definition: &DEFINITION,
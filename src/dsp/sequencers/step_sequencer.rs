@@ -0,0 +1,391 @@
+use std::num::Float;
+
+use types::{ArtResult, Rate, Curve};
+
+use unit::{Unit, UnitDefinition, ChannelLayout, UnitKind, DataSize,
+           TickAdjuncts};
+use parameter::{ParameterDefinition, ParameterMode, UNBOUNDED};
+use channel_stack::ChannelStack;
+use leap::Leap;
+use constants::Constants;
+
+// The number of steps stored by each sequencer.  The length parameter
+// chooses how many of them are played.
+pub const MAX_STEPS: usize = 16;
+
+// Steps are set by choosing a step, a value and a gate, then triggering
+// write.  Writes to steps outside of the sequence are ignored.
+pub static PARAMETERS: [ParameterDefinition; 7] = [
+    ParameterDefinition {
+        name: "reset",
+        default: 0f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Trigger,
+        minimum: 0.0,
+        maximum: 1.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Moves back to the first step when it rises above zero"
+    },
+    ParameterDefinition {
+        name: "length",
+        default: 16f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 1.0,
+        maximum: 16.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Number of steps played"
+    },
+    ParameterDefinition {
+        name: "gate_length",
+        default: 0.5f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 1.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Fraction of the time between clocks for which the gate \
+                      is open"
+    },
+    ParameterDefinition {
+        name: "step",
+        default: 0f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 15.0,
+        clamped: false,
+        unit: "",
+        curve: Curve::Linear,
+        description: "The step changed by write"
+    },
+    ParameterDefinition {
+        name: "value",
+        default: 0f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: -UNBOUNDED,
+        maximum: UNBOUNDED,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Value written to the step"
+    },
+    ParameterDefinition {
+        name: "gate",
+        default: 1f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: 0.0,
+        maximum: 1.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Whether the step opens the gate, written with the value"
+    },
+    ParameterDefinition {
+        name: "write",
+        default: 0f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Trigger,
+        minimum: 0.0,
+        maximum: 1.0,
+        clamped: true,
+        unit: "",
+        curve: Curve::Linear,
+        description: "Writes the value and gate to the step when triggered"
+    }
+];
+
+// Moves to the next step on the rising edge of the last input channel, so it
+// can be driven directly by a clock unit, whose tick follows its phase.  Any
+// other input channels are ignored.  To reset the sequence from another
+// unit, read the reset parameter from the stack.  Outputs the gate and the
+// value of the current step.
+pub static DEFINITION: UnitDefinition = UnitDefinition {
+    name: "step_sequencer",
+    kind: UnitKind::Processor,
+    input_rate: Some(Rate::Control),
+    output_rate: Some(Rate::Control),
+    default_layout: ChannelLayout {
+        input: 1,
+        output: 2
+    },
+    parameters: &PARAMETERS,
+    tick: StepSequencer::tick,
+    data_size: DataSize::Fixed(5 + 2 * MAX_STEPS)
+};
+
+#[derive(Copy)]
+pub struct StepSequencer;
+
+impl StepSequencer {
+    pub fn new(id: (u32, u32), input_channels: u32, output_channels: u32,
+               _: &[f32], data: &mut Leap<f32>)
+            -> Unit {
+        let data_index = data.tail;
+        data.push(-1.0).unwrap(); // position
+        data.push(0.0).unwrap(); // last_input
+        data.push(0.0).unwrap(); // last_reset
+        data.push(0.0).unwrap(); // elapsed
+        data.push(0.0).unwrap(); // interval
+        for _ in range(0, MAX_STEPS) {
+            data.push(0.0).unwrap(); // value
+            data.push(0.0).unwrap(); // gate
+        }
+
+        Unit {
+            definition: &DEFINITION,
+            id: id,
+            layout: ChannelLayout {
+                input: input_channels,
+                output: output_channels
+            },
+            data_index: Some(data_index)
+        }
+    }
+
+    fn tick(unit: &mut Unit, block: &mut[f32], parameters: &mut ChannelStack,
+            adjuncts: &mut TickAdjuncts, _: &Constants)
+            -> ArtResult<()> {
+        debug_assert!(unit.data_index.is_some());
+        let mut data = adjuncts.data.iter_mut(unit.data_index.unwrap());
+        let position = data.next().unwrap();
+        let last_input = data.next().unwrap();
+        let last_reset = data.next().unwrap();
+        let elapsed = data.next().unwrap();
+        let interval = data.next().unwrap();
+
+        let reset = parameters.data[0];
+        let length = parameters.data[1];
+        let gate_length = parameters.data[2];
+        let write_step = parameters.data[3];
+        let write_value = parameters.data[4];
+        let write_gate = parameters.data[5];
+        let write = parameters.data[6];
+
+        let input_channels = unit.layout.input as usize;
+        let input = if input_channels > 0 {
+            block[input_channels - 1]
+        }
+        else {
+            0.0
+        };
+
+        let reset_edge = reset > 0.0 && *last_reset <= 0.0;
+        *last_reset = reset;
+        if reset_edge {
+            // The next clock starts from the first step
+            *position = -1.0;
+        }
+
+        let length = length.round().max(1.0).min(MAX_STEPS as f32);
+        *elapsed += 1.0;
+        if input > 0.0 && *last_input <= 0.0 {
+            if *position >= 0.0 {
+                *interval = *elapsed;
+            }
+            *elapsed = 0.0;
+            *position = (*position + 1.0) % length;
+        }
+        *last_input = input;
+
+        let write_step = write_step.round();
+        let write = write > 0.0 && write_step >= 0.0 &&
+                    write_step < MAX_STEPS as f32;
+        let write_step = write_step as usize;
+        let current = *position;
+
+        let mut value = 0.0;
+        let mut gate = 0.0;
+        for i in range(0, MAX_STEPS) {
+            let step_value = data.next().unwrap();
+            let step_gate = data.next().unwrap();
+            if write && i == write_step {
+                *step_value = write_value;
+                *step_gate = write_gate;
+            }
+
+            if current >= 0.0 && i == current as usize {
+                value = *step_value;
+                gate = *step_gate;
+            }
+        }
+
+        // The gate is held for a fraction of the time between clocks, and
+        // falls before the next step so consecutive steps retrigger
+        let gate_blocks = (gate_length * *interval).max(1.0);
+        let gate = if gate > 0.0 && *elapsed < gate_blocks {
+            1.0
+        }
+        else {
+            0.0
+        };
+
+        let channels = unit.layout.output as usize;
+        for i in range(0, channels) {
+            block[i] = if i % 2 == 0 { gate } else { value };
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_util::UnitHarness;
+
+    use super::StepSequencer;
+
+    const RESET: u32 = 0;
+    const LENGTH: u32 = 1;
+    const GATE_LENGTH: u32 = 2;
+    const STEP: u32 = 3;
+    const VALUE: u32 = 4;
+    const GATE: u32 = 5;
+    const WRITE: u32 = 6;
+
+    // Writes a step during a block in which the stack holds idle
+    fn write(harness: &mut UnitHarness, idle: &[f32], step: f32, value: f32,
+             gate: f32) {
+        harness.set(STEP, step);
+        harness.set(VALUE, value);
+        harness.set(GATE, gate);
+        harness.set(WRITE, 1.0);
+        harness.tick(idle).unwrap();
+    }
+
+    // A sequencer with a single clock input, playing values
+    fn sequencer(values: &[f32]) -> UnitHarness {
+        let mut harness = UnitHarness::new(StepSequencer::new, 1, 2, 0, &[]);
+        harness.set(LENGTH, values.len() as f32);
+        for (step, value) in values.iter().enumerate() {
+            write(&mut harness, &[0.0], step as f32, *value, 1.0);
+        }
+        harness
+    }
+
+    // Ticks with a rising edge on the clock, then lets it fall.  Returns the
+    // value from the first block.
+    fn clock(harness: &mut UnitHarness) -> f32 {
+        let value = harness.tick(&[1.0]).unwrap()[1];
+        harness.tick(&[0.0]).unwrap();
+        value
+    }
+
+    #[test]
+    fn advances_on_rising_edge() {
+        let mut harness = sequencer(&[1.0, 2.0, 3.0]);
+        assert_eq!(harness.tick(&[0.0]).unwrap(), [0.0, 0.0]);
+
+        assert_eq!(harness.tick(&[1.0]).unwrap()[1], 1.0);
+        // Staying high doesn't advance
+        assert_eq!(harness.tick(&[1.0]).unwrap()[1], 1.0);
+        assert_eq!(harness.tick(&[0.0]).unwrap()[1], 1.0);
+        assert_eq!(harness.tick(&[1.0]).unwrap()[1], 2.0);
+    }
+
+    #[test]
+    fn wraps_around_after_length() {
+        let mut harness = sequencer(&[1.0, 2.0, 3.0]);
+        let values: Vec<f32> = range(0, 7).map(|_| clock(&mut harness))
+                                          .collect();
+        assert_eq!(values, [1.0, 2.0, 3.0, 1.0, 2.0, 3.0, 1.0]);
+    }
+
+    #[test]
+    fn reset_returns_to_first_step() {
+        let mut harness = sequencer(&[1.0, 2.0, 3.0]);
+        assert_eq!(clock(&mut harness), 1.0);
+        assert_eq!(clock(&mut harness), 2.0);
+
+        harness.set(RESET, 1.0);
+        harness.tick(&[0.0]).unwrap();
+        assert_eq!(clock(&mut harness), 1.0);
+        assert_eq!(clock(&mut harness), 2.0);
+    }
+
+    #[test]
+    fn clock_phase_does_not_reset() {
+        // Wired to a clock, which outputs its phase before its tick
+        let mut harness = UnitHarness::new(StepSequencer::new, 2, 2, 0, &[]);
+        harness.set(LENGTH, 2.0);
+        write(&mut harness, &[0.0, 0.0], 0.0, 1.0, 1.0);
+        write(&mut harness, &[0.0, 0.0], 1.0, 2.0, 1.0);
+
+        assert_eq!(harness.tick(&[0.0, 1.0]).unwrap()[1], 1.0);
+        assert_eq!(harness.tick(&[0.5, 0.0]).unwrap()[1], 1.0);
+        assert_eq!(harness.tick(&[0.0, 1.0]).unwrap()[1], 2.0);
+        assert_eq!(harness.tick(&[0.5, 0.0]).unwrap()[1], 2.0);
+    }
+
+    #[test]
+    fn stacked_reset_triggers_on_rising_edge() {
+        // The clock input, followed by the reset parameter
+        let mut harness = UnitHarness::new(StepSequencer::new, 1, 2,
+                                           1 << RESET, &[]);
+        harness.set(LENGTH, 3.0);
+        for step in range(0, 3) {
+            write(&mut harness, &[0.0, 0.0], step as f32, step as f32 + 1.0,
+                  1.0);
+        }
+
+        assert_eq!(harness.tick(&[1.0, 0.0]).unwrap()[1], 1.0);
+        assert_eq!(harness.tick(&[0.0, 0.0]).unwrap()[1], 1.0);
+        assert_eq!(harness.tick(&[1.0, 0.0]).unwrap()[1], 2.0);
+
+        // The reset only takes effect on its rising edge, so holding it high
+        // lets the sequence play on from the first step
+        assert_eq!(harness.tick(&[0.0, 1.0]).unwrap(), [0.0, 0.0]);
+        assert_eq!(harness.tick(&[1.0, 1.0]).unwrap()[1], 1.0);
+        assert_eq!(harness.tick(&[0.0, 1.0]).unwrap()[1], 1.0);
+        assert_eq!(harness.tick(&[1.0, 1.0]).unwrap()[1], 2.0);
+    }
+
+    #[test]
+    fn gate_length_is_fraction_of_clock_interval() {
+        let mut harness = sequencer(&[1.0, 2.0]);
+        harness.set(GATE_LENGTH, 0.5);
+
+        // The clock rises every four blocks
+        let mut gates = Vec::new();
+        for _ in range(0, 3) {
+            for block in range(0, 4) {
+                let clock = if block == 0 { 1.0 } else { 0.0 };
+                gates.push(harness.tick(&[clock]).unwrap()[0]);
+            }
+        }
+
+        // The gate is held for a single block until the interval is known
+        assert_eq!(&gates[..4], [1.0, 0.0, 0.0, 0.0]);
+        assert_eq!(&gates[4..8], [1.0, 1.0, 0.0, 0.0]);
+        assert_eq!(&gates[8..], [1.0, 1.0, 0.0, 0.0]);
+    }
+
+    #[test]
+    fn closed_steps_keep_the_gate_closed() {
+        let mut harness = sequencer(&[1.0, 2.0]);
+        write(&mut harness, &[0.0], 1.0, 2.0, 0.0);
+
+        assert_eq!(harness.tick(&[1.0]).unwrap(), [1.0, 1.0]);
+        harness.tick(&[0.0]).unwrap();
+        assert_eq!(harness.tick(&[1.0]).unwrap(), [0.0, 2.0]);
+    }
+
+    #[test]
+    fn writes_outside_sequence_are_ignored() {
+        let mut harness = sequencer(&[1.0, 2.0]);
+        harness.set(LENGTH, 16.0);
+        write(&mut harness, &[0.0], 16.0, 5.0, 1.0);
+        write(&mut harness, &[0.0], -1.0, 5.0, 1.0);
+
+        let values: Vec<f32> = range(0, 16).map(|_| clock(&mut harness))
+                                           .collect();
+        assert!(values.iter().all(|value| *value != 5.0));
+    }
+}
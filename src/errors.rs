@@ -7,6 +7,7 @@ use portaudio::pa::PaError;
 
 use types::Rate;
 use opcode::Opcode;
+use name::Name;
 
 #[derive(Debug)]
 pub enum ArtError {
@@ -14,11 +15,18 @@ pub enum ArtError {
     UndefinedUnit { type_id: u32 },
     IndexError,
     ExpressionNotFound { expression_id: u32 },
+    TemplateNotFound { template_id: u32 },
+    SequenceNotFound { sequence_id: u32 },
+    WavetableNotFound { wavetable_id: u32 },
     UnitNotFound { expression_id: u32, unit_id: u32 },
     ParameterNotFound { expression_id: u32, unit_id: u32, parameter_id: u32 },
+    NameNotFound { name: Name },
+    DuplicateName { name: Name },
     ChannelMismatch { expected: u32, actual: u32 },
     RateMismatch { expected: Rate, actual: Rate },
     InvalidByteCode,
+    IncompleteMessage { expected_sequence: u32, actual_sequence: u32 },
+    ProtocolMismatch { field: &'static str, expected: u32, actual: u32 },
     IoError { error: io::Error },
     StackOverflow,
     StackUnderflow,
@@ -41,6 +49,15 @@ impl ArtError {
             ArtError::ExpressionNotFound { expression_id } => {
                 Some(format!("expression_id={}", expression_id))
             },
+            ArtError::TemplateNotFound { template_id } => {
+                Some(format!("template_id={}", template_id))
+            },
+            ArtError::SequenceNotFound { sequence_id } => {
+                Some(format!("sequence_id={}", sequence_id))
+            },
+            ArtError::WavetableNotFound { wavetable_id } => {
+                Some(format!("wavetable_id={}", wavetable_id))
+            },
             ArtError::UnitNotFound { expression_id, unit_id } => {
                 Some(format!("expression_id: {}, unit_id={}",
                              expression_id, unit_id))
@@ -50,12 +67,25 @@ impl ArtError {
                 Some(format!("expression_id={}, unit_id={}, parameter_id={}",
                              expression_id, unit_id, parameter_id))
             },
+            ArtError::NameNotFound { name } |
+            ArtError::DuplicateName { name } => {
+                Some(format!("name={}", name))
+            },
             ArtError::ChannelMismatch{ expected, actual } => {
                 Some(format!("expected={}, actual={}", expected, actual))
             },
             ArtError::RateMismatch{ expected, actual } => {
                 Some(format!("expected={:?}, actual={:?}", expected, actual))
             },
+            ArtError::IncompleteMessage { expected_sequence,
+                                          actual_sequence } => {
+                Some(format!("expected_sequence={}, actual_sequence={}",
+                             expected_sequence, actual_sequence))
+            },
+            ArtError::ProtocolMismatch { field, expected, actual } => {
+                Some(format!("field={}, expected={:#x}, actual={:#x}",
+                             field, expected, actual))
+            },
             ArtError::IoError { ref error } => {
                 Some(format!("error={}", error))
             },
@@ -78,10 +108,17 @@ impl Error for ArtError {
             ArtError::IndexError => "Index error",
             ArtError::UnitNotFound { .. } => "Unit not found",
             ArtError::ParameterNotFound { .. } => "Parameter not found",
+            ArtError::NameNotFound { .. } => "Name not found",
+            ArtError::DuplicateName { .. } => "Duplicate name",
             ArtError::ExpressionNotFound { .. } => "Expression not found",
+            ArtError::TemplateNotFound { .. } => "Template not found",
+            ArtError::SequenceNotFound { .. } => "Sequence not found",
+            ArtError::WavetableNotFound { .. } => "Wavetable not found",
             ArtError::ChannelMismatch { .. } => "Channel mismatch",
             ArtError::RateMismatch { .. } => "Rate mismatch",
             ArtError::InvalidByteCode => "Invalid byte code",
+            ArtError::IncompleteMessage { .. } => "Incomplete message",
+            ArtError::ProtocolMismatch { .. } => "Protocol mismatch",
             ArtError::StackOverflow => "Stack overflow",
             ArtError::StackUnderflow => "Stack underflow",
             ArtError::BufferOverflow => "Buffer overflow",
@@ -1,49 +1,161 @@
 use types::{ArtResult, UnitMap, ParameterMap};
-use unit::TickAdjuncts;
+use unit::{TickAdjuncts, MAX_ARGUMENTS};
 use constants::Constants;
 use opcode::{DspOpcode};
 use unit_factory::UnitFactory;
 use channel_stack::ChannelStack;
 use leap::Leap;
 use operators;
+use name::Name;
 
-#[derive(Copy, PartialEq)]
+// Expression ids with this bit set are reserved for expressions created by the
+// VM itself
+pub const INTERNAL_ID_FLAG: u32 = 1 << 31;
+
+pub fn is_internal_id(id: u32) -> bool {
+    id & INTERNAL_ID_FLAG != 0
+}
+
+#[derive(Copy, PartialEq, FromPrimitive, Debug)]
 pub enum ExpressionState {
     Run,
+    // Not ticked, but keeps its units and their state
+    Paused,
     Free
 }
 
 #[derive(Copy)]
 pub struct Expression {
     pub id: u32,
+    pub name: Name,
     pub index: usize,
     pub num_opcodes: usize,
     pub incoming_edges: u32,
-    pub state: ExpressionState
+    pub state: ExpressionState,
+    // When any expression is soloed, all other expressions are muted
+    pub solo: bool,
+    // Muted expressions are ticked, but nothing is written to the busses
+    pub muted: bool,
+    // Gain applied to everything the expression writes to a bus, which ramps
+    // by gain_delta each sample until it reaches target_gain
+    pub gain: f32,
+    pub target_gain: f32,
+    pub gain_delta: f32,
+    // The peak level written to the busses during the last tick, before the
+    // gain was applied
+    pub level: f32
 }
 
 impl Expression {
     pub fn new(id: u32, index: usize, num_opcodes: usize) -> Expression {
         Expression {
             id: id,
+            name: Name::empty(),
             index: index,
             num_opcodes: num_opcodes,
             incoming_edges: 0,
-            state: ExpressionState::Run
+            state: ExpressionState::Run,
+            solo: false,
+            muted: false,
+            gain: 1.0,
+            target_gain: 1.0,
+            gain_delta: 0.0,
+            level: 0.0
+        }
+    }
+
+    pub fn fade(&mut self, gain: f32, time: f32, constants: &Constants) {
+        self.target_gain = gain;
+        if time <= 0.0 {
+            self.gain = gain;
+            self.gain_delta = 0.0;
+
+            if gain == 0.0 {
+                self.state = ExpressionState::Free;
+            }
+        }
+        else {
+            self.gain_delta = (gain - self.gain) * constants.audio_rate_inverse /
+                              time;
+        }
+    }
+
+    // Moves the fade on by a block.  Expressions which have faded out are
+    // freed.
+    pub fn advance_fade(&mut self, constants: &Constants) {
+        if self.gain_delta == 0.0 {
+            return;
+        }
+
+        if self.state == ExpressionState::Paused {
+            // A paused expression is silent already, so a fade out can finish
+            // straight away.  Other fades wait until it is resumed.
+            if self.target_gain == 0.0 {
+                self.gain = 0.0;
+                self.gain_delta = 0.0;
+                self.state = ExpressionState::Free;
+            }
+            return;
+        }
+
+        self.gain += self.gain_delta * constants.block_size as f32;
+        if (self.gain_delta > 0.0 && self.gain >= self.target_gain) ||
+                (self.gain_delta < 0.0 && self.gain <= self.target_gain) {
+            self.gain = self.target_gain;
+            self.gain_delta = 0.0;
+
+            if self.target_gain == 0.0 {
+                self.state = ExpressionState::Free;
+            }
+        }
+    }
+
+    // Moves the expression, its units and their parameters to a new id
+    pub fn rename(&mut self, id: u32, store: &Leap<DspOpcode>,
+                  factory: &UnitFactory, units: &mut UnitMap,
+                  parameters: &mut ParameterMap) {
+        for opcode in store.iter(self.index).take(self.num_opcodes) {
+            if let &DspOpcode::Unit { unit_id, type_id, .. } = opcode {
+                debug_assert!(units.contains_key(&(self.id, unit_id)));
+                let mut unit = units.remove(&(self.id, unit_id)).unwrap();
+                unit.id = (id, unit_id);
+                units.insert((id, unit_id), unit);
+
+                let definition = factory.get_definition(type_id);
+                for pid in range(0, definition.parameters.len() as u32) {
+                    let old_key = (self.id, unit_id, pid);
+                    debug_assert!(parameters.contains_key(&old_key));
+                    let parameter = parameters.remove(&old_key).unwrap();
+                    parameters.insert((id, unit_id, pid), parameter);
+                }
+            }
         }
+        self.id = id;
     }
 
     pub fn construct_units(&self, store: &Leap<DspOpcode>,
                            factory: &mut UnitFactory, units: &mut UnitMap,
                            parameters: &mut ParameterMap,
                            data: &mut Leap<f32>) {
-        for opcode in store.iter(self.index).take(self.num_opcodes) {
+        let mut arguments = [0f32; MAX_ARGUMENTS];
+        let mut opcodes = store.iter(self.index).take(self.num_opcodes);
+        while let Some(opcode) = opcodes.next() {
             if let &DspOpcode::Unit { unit_id, type_id, input_channels,
-                                      output_channels } = opcode {
+                                      output_channels, stack_parameters,
+                                      num_arguments, .. } = opcode {
+                // The arguments follow the unit
+                let num_arguments = num_arguments as usize;
+                for (argument, opcode) in arguments.iter_mut().zip(
+                        opcodes.by_ref().take(num_arguments)) {
+                    if let &DspOpcode::Argument { value } = opcode {
+                        *argument = value;
+                    }
+                }
+
                 let unit = factory.create((self.id, unit_id), type_id,
                                           input_channels, output_channels,
-                                          data);
-                unit.construct_parameters(parameters);
+                                          &arguments[..num_arguments], data);
+                unit.construct_parameters(parameters, stack_parameters);
                 debug_assert!(units.len() < units.capacity());
                 units.insert((self.id, unit_id), unit);
             }
@@ -54,11 +166,11 @@ impl Expression {
                       units: &mut UnitMap, parameters: &mut ParameterMap,
                       data: &mut Leap<f32>) {
         for opcode in store.iter(self.index).take(self.num_opcodes) {
-            if let &DspOpcode::Unit { unit_id, .. } = opcode {
+            if let &DspOpcode::Unit { unit_id, num_arguments, .. } = opcode {
                 debug_assert!(units.contains_key(&(self.id, unit_id)));
                 let unit = units.remove(&(self.id, unit_id)).unwrap();
                 unit.free_parameters(parameters);
-                unit.free_data(data);
+                unit.free_data(data, num_arguments as usize);
             }
         }
     }
@@ -66,8 +178,9 @@ impl Expression {
     pub fn tick(&self, store: &Leap<DspOpcode>, stack: &mut ChannelStack,
                 units: &mut UnitMap, adjuncts: &mut TickAdjuncts,
                 constants: &Constants) -> ArtResult<()> {
-        if self.state != ExpressionState::Run {
-            return Ok(())
+        match self.state {
+            ExpressionState::Run => {},
+            ExpressionState::Paused | ExpressionState::Free => return Ok(())
         }
 
         for opcode in store.iter(self.index).take(self.num_opcodes) {
@@ -85,7 +198,9 @@ impl Expression {
                 },
                 &DspOpcode::Multiply { channels, rate } => {
                     try!(operators::multiply(stack, channels, rate, constants));
-                }
+                },
+                // Only used when constructing units
+                &DspOpcode::Argument { .. } => {}
             }
         }
         Ok(())
@@ -1,9 +1,9 @@
 use types::ExpressionMap;
 
 #[derive(Debug)]
-struct Edge {
-    from: u32,
-    to: u32
+pub struct Edge {
+    pub from: u32,
+    pub to: u32
 }
 
 impl Edge {
@@ -48,9 +48,43 @@ impl Graph {
     }
 
     pub fn add_edge(&mut self, from: u32, to: u32) {
+        debug_assert!(self.edges.len() < self.edges.capacity());
         self.edges.push(Edge::new(from, to));
     }
 
+    // The number of edges into or out of an expression
+    pub fn count_edges(&self, id: u32) -> usize {
+        self.edges.iter().filter(|edge| {
+            edge.from == id || edge.to == id
+        }).count()
+    }
+
+    // Gives copy_id the same edges as id, so a copy of an expression is
+    // ordered in the same way as the original
+    pub fn copy_edges(&mut self, id: u32, copy_id: u32) {
+        for i in range(0, self.edges.len()) {
+            let (from, to) = (self.edges[i].from, self.edges[i].to);
+            if from == id {
+                self.add_edge(copy_id, to);
+            }
+            else if to == id {
+                self.add_edge(from, copy_id);
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.edges.len()
+    }
+
+    pub fn edges(&self) -> &[Edge] {
+        &self.edges[..]
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.edges.capacity()
+    }
+
     pub fn clear(&mut self, freed_expression_ids: &[u32]) {
         self.edges.retain(|edge| {
             let from = freed_expression_ids.iter().position(
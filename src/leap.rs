@@ -53,6 +53,7 @@ impl<T> Leap<T> {
 
         let tail = self.tail;
         self.tail = self.set(tail, value);
+        self.length += 1;
         Ok(self.tail)
     }
 
@@ -63,6 +64,8 @@ impl<T> Leap<T> {
     }
 
     pub fn free(&mut self, mut index: usize, count: usize) {
+        debug_assert!(count <= self.length);
+        self.length -= count;
         let old_tail = self.tail;
         self.tail = index;
         for i in range(0, count) {
@@ -76,6 +79,18 @@ impl<T> Leap<T> {
         }
     }
 
+    pub fn get(&self, index: usize) -> Option<&T> {
+        match self.nodes[index].content {
+            Content::Full(ref value) => Some(value),
+            Content::Empty => None
+        }
+    }
+
+    // The index of the value which follows index
+    pub fn next(&self, index: usize) -> usize {
+        self.nodes[index].next
+    }
+
     pub fn len(&self) -> usize {
         self.length
     }
@@ -99,6 +114,32 @@ impl<T> Leap<T> {
     }
 }
 
+impl<T: Copy> Leap<T> {
+    // Pushes a copy of count values starting from index, returning the index
+    // of the first copy
+    pub fn copy(&mut self, index: usize, count: usize) -> ArtResult<usize> {
+        let start = self.tail;
+        let mut source = index;
+        for i in range(0, count) {
+            let (value, next) = match self.nodes[source] {
+                Node { content: Content::Full(value), next } => (value, next),
+                Node { content: Content::Empty, .. } => {
+                    self.free(start, i);
+                    return Err(ArtError::IndexError);
+                }
+            };
+            source = next;
+
+            let result = self.push(value);
+            if result.is_err() {
+                self.free(start, i);
+                return Err(result.err().unwrap());
+            }
+        }
+        Ok(start)
+    }
+}
+
 pub struct Iter<'a, T: 'a> {
     index: usize,
     nodes: &'a [Node<T>]
@@ -136,3 +177,71 @@ impl<'a, T: 'a> Iterator for IterMut<'a, T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use errors::ArtError;
+
+    use super::Leap;
+
+    #[test]
+    fn push_fails_when_full() {
+        let mut leap = Leap::with_capacity(3);
+        for i in range(0u32, 3) {
+            leap.push(i).unwrap();
+        }
+        assert_eq!(leap.len(), 3);
+        match leap.push(3) {
+            Err(ArtError::BufferOverflow) => {},
+            _ => panic!("Expected overflow")
+        }
+    }
+
+    #[test]
+    fn free_makes_space() {
+        let mut leap = Leap::with_capacity(3);
+        let start = leap.tail;
+        for i in range(0u32, 3) {
+            leap.push(i).unwrap();
+        }
+        leap.free(start, 2);
+        assert_eq!(leap.len(), 1);
+
+        leap.push(3).unwrap();
+        leap.push(4).unwrap();
+        assert!(leap.push(5).is_err());
+    }
+
+    #[test]
+    fn copy_fails_when_full() {
+        let mut leap = Leap::with_capacity(5);
+        let start = leap.tail;
+        for i in range(0u32, 3) {
+            leap.push(i).unwrap();
+        }
+
+        match leap.copy(start, 3) {
+            Err(ArtError::BufferOverflow) => {},
+            _ => panic!("Expected overflow")
+        }
+
+        // The partial copy is freed, and the original values are untouched
+        assert_eq!(leap.len(), 3);
+        assert_eq!(leap.iter(start).take(3).cloned().collect::<Vec<u32>>(),
+                   vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn copy_duplicates_values() {
+        let mut leap = Leap::with_capacity(6);
+        let start = leap.tail;
+        for i in range(0u32, 3) {
+            leap.push(i).unwrap();
+        }
+
+        let copy = leap.copy(start, 3).unwrap();
+        assert_eq!(leap.len(), 6);
+        assert_eq!(leap.iter(copy).take(3).cloned().collect::<Vec<u32>>(),
+                   vec![0, 1, 2]);
+    }
+}
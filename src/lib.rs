@@ -15,32 +15,62 @@ pub mod constants;
 pub mod options;
 pub mod vm;
 pub mod vm_inner;
+pub mod vm_voices;
+pub mod vm_midi;
+pub mod vm_scheduling;
+pub mod vm_snapshot;
 
+pub mod protocol;
 pub mod opcode;
 pub mod opcode_reader;
+pub mod opcode_writer;
+pub mod notification;
+pub mod snapshot;
 
 pub mod device;
 pub mod unit_factory;
 
 pub mod validator;
+pub mod transaction;
 
 pub mod expression;
 pub mod unit;
 pub mod parameter;
+pub mod name;
 
 pub mod graph;
 pub mod leap;
 pub mod expression_store;
+pub mod voice_allocator;
+pub mod midi;
+pub mod smf;
+pub mod sequencer;
+pub mod clock;
+pub mod scheduler;
+pub mod wavetable;
 pub mod channel_stack;
+pub mod ring_buffer;
+pub mod message_buffer;
 
 pub mod operators;
 
 pub mod util;
+pub mod random;
+
+#[cfg(feature = "realtime_audit")]
+pub mod realtime_audit;
+
+#[cfg(test)]
+mod test_util;
 
 pub mod dsp {
     pub mod oscillators {
         pub mod sine;
         pub mod saw;
+        pub mod pulse;
+        pub mod triangle;
+        pub mod wavetable;
+        pub mod fm_operator;
     }
     pub mod parameter {
         pub mod parameter;
@@ -55,6 +85,20 @@ pub mod dsp {
     }
     pub mod envelopes {
         pub mod ar_envelope;
+        pub mod adsr_envelope;
+        pub mod breakpoint_envelope;
+    }
+    pub mod clock {
+        pub mod clock;
+    }
+    pub mod sequencers {
+        pub mod step_sequencer;
+    }
+    pub mod noise {
+        pub mod white_noise;
+        pub mod pink_noise;
+        pub mod brown_noise;
+        pub mod lf_noise;
     }
 }
 
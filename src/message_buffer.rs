@@ -0,0 +1,176 @@
+use types::ArtResult;
+use errors::ArtError;
+
+// Reassembles messages which have been split across several ByteCode chunks
+pub struct MessageBuffer {
+    data: Vec<u8>,
+    size: usize,
+    next_sequence: u32,
+    complete: bool
+}
+
+impl MessageBuffer {
+    pub fn new() -> MessageBuffer {
+        MessageBuffer {
+            data: Vec::with_capacity(0),
+            size: 0,
+            next_sequence: 0,
+            complete: false
+        }
+    }
+
+    pub fn with_capacity(capacity: usize) -> MessageBuffer {
+        let mut data = Vec::with_capacity(capacity);
+        data.resize(capacity, 0u8);
+
+        MessageBuffer {
+            data: data,
+            size: 0,
+            next_sequence: 0,
+            complete: false
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.complete
+    }
+
+    pub fn clear(&mut self) {
+        self.size = 0;
+        self.next_sequence = 0;
+        self.complete = false;
+    }
+
+    // Adds a chunk to the message, returning whether the message is now
+    // complete.  A partial message is discarded when the chunk doesn't
+    // follow on from it.
+    pub fn push_chunk(&mut self, chunk: &[u8], sequence: u32, last: bool)
+            -> ArtResult<bool> {
+        if self.complete {
+            self.clear();
+        }
+
+        if sequence == 0 && self.next_sequence != 0 {
+            error!("Discarding incomplete message: chunks={}",
+                   self.next_sequence);
+            self.clear();
+        }
+
+        if sequence != self.next_sequence {
+            let expected = self.next_sequence;
+            self.clear();
+            return Err(
+                ArtError::IncompleteMessage {
+                    expected_sequence: expected,
+                    actual_sequence: sequence
+                }
+            );
+        }
+
+        if self.size + chunk.len() > self.data.len() {
+            self.clear();
+            return Err(ArtError::BufferOverflow);
+        }
+
+        let end = self.size + chunk.len();
+        (&mut self.data[self.size..end]).clone_from_slice(chunk);
+        self.size = end;
+        self.next_sequence += 1;
+        self.complete = last;
+        Ok(last)
+    }
+
+    // Gives access to the whole buffer so a complete message can be written
+    // directly into it
+    pub fn write_complete<F>(&mut self, write: F) -> ArtResult<Option<usize>>
+            where F: FnOnce(&mut [u8]) -> ArtResult<Option<usize>> {
+        self.clear();
+
+        let size = try!(write(&mut self.data));
+        if let Some(size) = size {
+            self.size = size;
+            self.complete = true;
+        }
+        Ok(size)
+    }
+
+    pub fn get(&self) -> &[u8] {
+        debug_assert!(self.complete);
+        &self.data[..self.size]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use errors::ArtError;
+
+    use super::MessageBuffer;
+
+    #[test]
+    fn reassembles_chunks() {
+        let mut message = MessageBuffer::with_capacity(8);
+        assert!(!message.push_chunk(&[1, 2], 0, false).unwrap());
+        assert!(!message.push_chunk(&[3], 1, false).unwrap());
+        assert!(message.push_chunk(&[4, 5], 2, true).unwrap());
+        assert_eq!(message.get(), [1, 2, 3, 4, 5]);
+
+        // The next chunk starts a new message
+        assert!(message.push_chunk(&[6], 0, true).unwrap());
+        assert_eq!(message.get(), [6]);
+    }
+
+    #[test]
+    fn discards_partial_message_on_new_message() {
+        let mut message = MessageBuffer::with_capacity(8);
+        message.push_chunk(&[1, 2], 0, false).unwrap();
+        assert!(message.push_chunk(&[3], 0, true).unwrap());
+        assert_eq!(message.get(), [3]);
+    }
+
+    #[test]
+    fn discards_partial_message_on_missing_chunk() {
+        let mut message = MessageBuffer::with_capacity(8);
+        message.push_chunk(&[1, 2], 0, false).unwrap();
+        match message.push_chunk(&[3], 2, false) {
+            Err(ArtError::IncompleteMessage { expected_sequence: 1,
+                                              actual_sequence: 2 }) => {},
+            _ => panic!("Expected incomplete message")
+        }
+
+        // The stale chunks are gone, so the rest of the old message is
+        // rejected too
+        assert!(message.push_chunk(&[4], 3, true).is_err());
+        assert!(message.push_chunk(&[5], 0, true).unwrap());
+        assert_eq!(message.get(), [5]);
+    }
+
+    #[test]
+    fn rejects_message_larger_than_capacity() {
+        let mut message = MessageBuffer::with_capacity(4);
+        message.push_chunk(&[0; 3], 0, false).unwrap();
+        match message.push_chunk(&[0; 2], 1, true) {
+            Err(ArtError::BufferOverflow) => {},
+            _ => panic!("Expected overflow")
+        }
+    }
+
+    #[test]
+    fn writes_complete_message() {
+        let mut message = MessageBuffer::with_capacity(4);
+        message.push_chunk(&[1], 0, false).unwrap();
+        let size = message.write_complete(|data| {
+            data[0] = 9;
+            Ok(Some(1))
+        });
+        assert_eq!(size.unwrap(), Some(1));
+        assert!(message.is_complete());
+        assert_eq!(message.get(), [9]);
+
+        assert_eq!(message.write_complete(|_| Ok(None)).unwrap(), None);
+        assert!(!message.is_complete());
+    }
+}
@@ -0,0 +1,227 @@
+use std::collections::HashMap;
+use std::num::Float;
+
+use types::{ArtResult, Curve};
+use errors::ArtError;
+
+pub const NUM_CHANNELS: u32 = 16;
+
+// Pitch bend and aftertouch are mapped in the same way as control changes,
+// using these controller numbers
+pub const PITCH_BEND: u32 = 128;
+pub const PRESSURE: u32 = 129;
+pub const NUM_CONTROLLERS: u32 = 130;
+
+// Unit id used in MapNotes when the pitch or velocity isn't set
+pub const NO_UNIT: u32 = !0;
+
+// Note ids with this bit set are reserved for voices started by MIDI
+// messages, so they can't be released by notes from other sources
+pub const MIDI_NOTE_ID_FLAG: u32 = 1 << 31;
+
+#[derive(Copy, Debug)]
+pub enum MidiMessage {
+    NoteOn {
+        channel: u32,
+        note: u32,
+        velocity: u32
+    },
+    NoteOff {
+        channel: u32,
+        note: u32
+    },
+    // The value is scaled to [0, 1]
+    Controller {
+        channel: u32,
+        controller: u32,
+        value: f32
+    }
+}
+
+impl MidiMessage {
+    // Returns None for messages which aren't supported, including
+    // polyphonic aftertouch
+    pub fn parse(status: u8, data_1: u8, data_2: u8) -> Option<MidiMessage> {
+        let channel = (status & 0x0F) as u32;
+        let data_1 = (data_1 & 0x7F) as u32;
+        let data_2 = (data_2 & 0x7F) as u32;
+
+        match status & 0xF0 {
+            0x80 => {
+                Some(
+                    MidiMessage::NoteOff {
+                        channel: channel,
+                        note: data_1
+                    }
+                )
+            },
+            // Note on with zero velocity is a note off
+            0x90 if data_2 == 0 => {
+                Some(
+                    MidiMessage::NoteOff {
+                        channel: channel,
+                        note: data_1
+                    }
+                )
+            },
+            0x90 => {
+                Some(
+                    MidiMessage::NoteOn {
+                        channel: channel,
+                        note: data_1,
+                        velocity: data_2
+                    }
+                )
+            },
+            0xB0 => {
+                Some(
+                    MidiMessage::Controller {
+                        channel: channel,
+                        controller: data_1,
+                        value: data_2 as f32 / 127.0
+                    }
+                )
+            },
+            0xD0 => {
+                Some(
+                    MidiMessage::Controller {
+                        channel: channel,
+                        controller: PRESSURE,
+                        value: data_1 as f32 / 127.0
+                    }
+                )
+            },
+            0xE0 => {
+                Some(
+                    MidiMessage::Controller {
+                        channel: channel,
+                        controller: PITCH_BEND,
+                        value: (data_2 << 7 | data_1) as f32 / 16383.0
+                    }
+                )
+            },
+            _ => None
+        }
+    }
+}
+
+// Voices started by MIDI use the channel and note number as their note id
+pub fn note_id(channel: u32, note: u32) -> u32 {
+    MIDI_NOTE_ID_FLAG | channel << 7 | note
+}
+
+pub fn is_midi_note_id(note_id: u32) -> bool {
+    note_id & MIDI_NOTE_ID_FLAG != 0
+}
+
+pub fn note_to_frequency(note: u32) -> f32 {
+    440.0 * 2f32.powf((note as f32 - 69.0) / 12.0)
+}
+
+#[derive(Copy)]
+pub struct ControllerMapping {
+    pub expression_id: u32,
+    pub unit_id: u32,
+    pub parameter_id: u32,
+    pub minimum: f32,
+    pub maximum: f32,
+    pub curve: Curve
+}
+
+#[derive(Copy)]
+pub struct NoteMapping {
+    pub template_id: u32,
+    // The (unit_id, parameter_id) pairs which are set when a note starts
+    pub pitch: Option<(u32, u32)>,
+    pub velocity: Option<(u32, u32)>
+}
+
+pub struct MidiMap {
+    pub controllers: HashMap<(u32, u32), ControllerMapping>,
+    pub notes: [Option<NoteMapping>; NUM_CHANNELS as usize]
+}
+
+impl MidiMap {
+    pub fn with_capacity(capacity: usize) -> MidiMap {
+        MidiMap {
+            controllers: HashMap::with_capacity(capacity),
+            notes: [None; NUM_CHANNELS as usize]
+        }
+    }
+
+    pub fn map_controller(&mut self, channel: u32, controller: u32,
+                          mapping: ControllerMapping) -> ArtResult<()> {
+        let key = (channel, controller);
+        if !self.controllers.contains_key(&key) &&
+                self.controllers.len() >= self.controllers.capacity() {
+            return Err(ArtError::BufferOverflow);
+        }
+        self.controllers.insert(key, mapping);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MidiMessage, note_id, is_midi_note_id, PITCH_BEND, PRESSURE};
+
+    #[test]
+    fn parses_notes() {
+        match MidiMessage::parse(0x93, 60, 100) {
+            Some(MidiMessage::NoteOn { channel: 3, note: 60,
+                                       velocity: 100 }) => {},
+            message => panic!("Unexpected message: {:?}", message)
+        }
+
+        match MidiMessage::parse(0x83, 60, 10) {
+            Some(MidiMessage::NoteOff { channel: 3, note: 60 }) => {},
+            message => panic!("Unexpected message: {:?}", message)
+        }
+
+        // Zero velocity note ons are note offs
+        match MidiMessage::parse(0x90, 61, 0) {
+            Some(MidiMessage::NoteOff { channel: 0, note: 61 }) => {},
+            message => panic!("Unexpected message: {:?}", message)
+        }
+    }
+
+    #[test]
+    fn parses_controllers() {
+        match MidiMessage::parse(0xB1, 7, 127) {
+            Some(MidiMessage::Controller { channel: 1, controller: 7,
+                                           value }) => {
+                assert_eq!(value, 1.0);
+            },
+            message => panic!("Unexpected message: {:?}", message)
+        }
+
+        match MidiMessage::parse(0xD2, 0, 0) {
+            Some(MidiMessage::Controller { channel: 2, controller,
+                                           value }) => {
+                assert_eq!(controller, PRESSURE);
+                assert_eq!(value, 0.0);
+            },
+            message => panic!("Unexpected message: {:?}", message)
+        }
+
+        match MidiMessage::parse(0xE0, 0x7F, 0x7F) {
+            Some(MidiMessage::Controller { controller, value, .. }) => {
+                assert_eq!(controller, PITCH_BEND);
+                assert_eq!(value, 1.0);
+            },
+            message => panic!("Unexpected message: {:?}", message)
+        }
+    }
+
+    #[test]
+    fn ignores_polyphonic_aftertouch() {
+        assert!(MidiMessage::parse(0xA0, 60, 64).is_none());
+    }
+
+    #[test]
+    fn note_ids_are_reserved() {
+        assert!(is_midi_note_id(note_id(0, 0)));
+        assert!(note_id(0, 60) != note_id(1, 60));
+        assert!(!is_midi_note_id(60));
+    }
+}
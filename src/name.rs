@@ -0,0 +1,89 @@
+use std::fmt;
+use std::str;
+
+// Names are stored inline so they can be copied and compared on the audio
+// thread without allocating.  An empty name means that no name was given.
+pub const MAX_NAME_LENGTH: usize = 32;
+
+// Separates the expression, unit and parameter names in a parameter path
+pub const PATH_SEPARATOR: u8 = b'/';
+
+#[derive(Copy, PartialEq, Eq)]
+pub struct Name {
+    length: usize,
+    bytes: [u8; MAX_NAME_LENGTH]
+}
+
+impl Name {
+    pub fn empty() -> Name {
+        Name {
+            length: 0,
+            bytes: [0u8; MAX_NAME_LENGTH]
+        }
+    }
+
+    // Returns None if the name is too long, isn't valid UTF-8, or contains
+    // the path separator
+    pub fn from_bytes(bytes: &[u8]) -> Option<Name> {
+        if bytes.len() > MAX_NAME_LENGTH ||
+                bytes.iter().any(|&byte| byte == PATH_SEPARATOR) ||
+                str::from_utf8(bytes).is_err() {
+            return None;
+        }
+
+        let mut name = Name::empty();
+        for (a, b) in name.bytes.iter_mut().zip(bytes.iter()) {
+            *a = *b;
+        }
+        name.length = bytes.len();
+        Some(name)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.length == 0
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.length]
+    }
+
+    pub fn as_str(&self) -> &str {
+        // Checked when the name was created
+        str::from_utf8(self.as_bytes()).unwrap()
+    }
+}
+
+impl fmt::Debug for Name {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{:?}", self.as_str())
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "{}", self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Name, MAX_NAME_LENGTH};
+
+    #[test]
+    fn from_bytes_keeps_the_name() {
+        let name = Name::from_bytes(b"lead").unwrap();
+        assert_eq!(name.as_str(), "lead");
+        assert!(!name.is_empty());
+        assert!(name == Name::from_bytes(b"lead").unwrap());
+        assert!(name != Name::from_bytes(b"bass").unwrap());
+        assert!(Name::from_bytes(b"").unwrap().is_empty());
+    }
+
+    #[test]
+    fn from_bytes_rejects_invalid_names() {
+        assert!(Name::from_bytes(&[b'a'; MAX_NAME_LENGTH]).is_some());
+        assert!(Name::from_bytes(&[b'a'; MAX_NAME_LENGTH + 1]).is_none());
+        assert!(Name::from_bytes(b"lead/gain").is_none());
+        assert!(Name::from_bytes(&[0xff, 0xfe]).is_none());
+    }
+}
@@ -0,0 +1,405 @@
+use std::io::{self, Cursor};
+use std::num::FromPrimitive;
+
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+
+use types::Rate;
+use parameter::ParameterMode;
+use expression::ExpressionState;
+use opcode::DspOpcode;
+use opcode_reader::OpcodeReader;
+use opcode_writer::OpcodeWriter;
+use name::Name;
+
+// Messages sent from the VM back to the client on the output channel
+pub const MAX_NOTIFICATION_SIZE: usize = 128;
+// The number of bytes of a snapshot sent in each SnapshotData notification
+pub const SNAPSHOT_CHUNK_SIZE: usize = 32;
+
+#[derive(FromPrimitive, Copy, Debug)]
+pub enum NotificationType {
+    ExpressionFreed,
+    VoiceFreed,
+    SequenceEnded,
+    ParameterValue,
+    ExpressionInfo,
+    ExpressionOpcode,
+    SnapshotData,
+    SnapshotEnded,
+    SnapshotFailed,
+    ExpressionFailed
+}
+
+#[derive(Copy, Debug)]
+pub enum Notification {
+    ExpressionFreed {
+        expression_id: u32
+    },
+    VoiceFreed {
+        note_id: u32
+    },
+    SequenceEnded {
+        sequence_id: u32
+    },
+    // Reply to GetParameter.  The mapped bus is written as a flag followed
+    // by the bus id and rate, which are zero when the parameter isn't
+    // mapped.
+    ParameterValue {
+        expression_id: u32,
+        unit_id: u32,
+        parameter_id: u32,
+        value: f32,
+        current_value: f32,
+        mode: ParameterMode,
+        mapped_bus: Option<(u32, Rate)>
+    },
+    // Reply to GetExpression, followed by num_opcodes ExpressionOpcode
+    // notifications
+    ExpressionInfo {
+        expression_id: u32,
+        name: Name,
+        state: ExpressionState,
+        muted: bool,
+        solo: bool,
+        gain: f32,
+        level: f32,
+        num_opcodes: u32
+    },
+    // The opcode is written in the same format as it is sent to the VM
+    ExpressionOpcode {
+        expression_id: u32,
+        index: u32,
+        opcode: DspOpcode
+    },
+    // Reply to SaveSnapshot.  The snapshot is split into chunks with
+    // consecutive sequence numbers, starting from zero, and is followed by
+    // SnapshotEnded once every chunk has been sent.
+    SnapshotData {
+        snapshot_id: u32,
+        sequence: u32,
+        size: u32,
+        data: [u8; SNAPSHOT_CHUNK_SIZE]
+    },
+    SnapshotEnded {
+        snapshot_id: u32,
+        size: u32
+    },
+    // The snapshot didn't fit in the snapshot buffer, or another snapshot
+    // was still being sent
+    SnapshotFailed {
+        snapshot_id: u32
+    },
+    // Sent in place of the reply to GetExpression when the whole reply
+    // doesn't fit in the output channel
+    ExpressionFailed {
+        expression_id: u32
+    }
+}
+
+pub trait NotificationWriter: OpcodeWriter {
+    fn write_notification(&mut self, notification: &Notification)
+            -> Result<(), io::Error> {
+        match *notification {
+            Notification::ExpressionFreed { expression_id } => {
+                try!(
+                    self.write_u32::<BigEndian>(
+                        NotificationType::ExpressionFreed as u32
+                    )
+                );
+                try!(self.write_u32::<BigEndian>(expression_id));
+            },
+            Notification::VoiceFreed { note_id } => {
+                try!(
+                    self.write_u32::<BigEndian>(
+                        NotificationType::VoiceFreed as u32
+                    )
+                );
+                try!(self.write_u32::<BigEndian>(note_id));
+            },
+            Notification::SequenceEnded { sequence_id } => {
+                try!(
+                    self.write_u32::<BigEndian>(
+                        NotificationType::SequenceEnded as u32
+                    )
+                );
+                try!(self.write_u32::<BigEndian>(sequence_id));
+            },
+            Notification::ParameterValue { expression_id, unit_id,
+                                           parameter_id, value,
+                                           current_value, mode,
+                                           mapped_bus } => {
+                try!(
+                    self.write_u32::<BigEndian>(
+                        NotificationType::ParameterValue as u32
+                    )
+                );
+                try!(self.write_u32::<BigEndian>(expression_id));
+                try!(self.write_u32::<BigEndian>(unit_id));
+                try!(self.write_u32::<BigEndian>(parameter_id));
+                try!(self.write_f32::<BigEndian>(value));
+                try!(self.write_f32::<BigEndian>(current_value));
+                try!(self.write_u32::<BigEndian>(mode as u32));
+                let (mapped, bus_id, rate) = match mapped_bus {
+                    Some((bus_id, rate)) => (1, bus_id, rate as u32),
+                    None => (0, 0, 0)
+                };
+                try!(self.write_u32::<BigEndian>(mapped));
+                try!(self.write_u32::<BigEndian>(bus_id));
+                try!(self.write_u32::<BigEndian>(rate));
+            },
+            Notification::ExpressionInfo { expression_id, name, state, muted,
+                                           solo, gain, level,
+                                           num_opcodes } => {
+                try!(
+                    self.write_u32::<BigEndian>(
+                        NotificationType::ExpressionInfo as u32
+                    )
+                );
+                try!(self.write_u32::<BigEndian>(expression_id));
+                try!(self.write_name(&name));
+                try!(self.write_u32::<BigEndian>(state as u32));
+                try!(self.write_u32::<BigEndian>(muted as u32));
+                try!(self.write_u32::<BigEndian>(solo as u32));
+                try!(self.write_f32::<BigEndian>(gain));
+                try!(self.write_f32::<BigEndian>(level));
+                try!(self.write_u32::<BigEndian>(num_opcodes));
+            },
+            Notification::ExpressionOpcode { expression_id, index,
+                                             opcode } => {
+                try!(
+                    self.write_u32::<BigEndian>(
+                        NotificationType::ExpressionOpcode as u32
+                    )
+                );
+                try!(self.write_u32::<BigEndian>(expression_id));
+                try!(self.write_u32::<BigEndian>(index));
+                try!(self.write_dsp_opcode(&opcode));
+            },
+            Notification::SnapshotData { snapshot_id, sequence, size,
+                                         data } => {
+                try!(
+                    self.write_u32::<BigEndian>(
+                        NotificationType::SnapshotData as u32
+                    )
+                );
+                try!(self.write_u32::<BigEndian>(snapshot_id));
+                try!(self.write_u32::<BigEndian>(sequence));
+                try!(self.write_u32::<BigEndian>(size));
+                try!(self.write_all(&data[..size as usize]));
+            },
+            Notification::SnapshotEnded { snapshot_id, size } => {
+                try!(
+                    self.write_u32::<BigEndian>(
+                        NotificationType::SnapshotEnded as u32
+                    )
+                );
+                try!(self.write_u32::<BigEndian>(snapshot_id));
+                try!(self.write_u32::<BigEndian>(size));
+            },
+            Notification::SnapshotFailed { snapshot_id } => {
+                try!(
+                    self.write_u32::<BigEndian>(
+                        NotificationType::SnapshotFailed as u32
+                    )
+                );
+                try!(self.write_u32::<BigEndian>(snapshot_id));
+            },
+            Notification::ExpressionFailed { expression_id } => {
+                try!(
+                    self.write_u32::<BigEndian>(
+                        NotificationType::ExpressionFailed as u32
+                    )
+                );
+                try!(self.write_u32::<BigEndian>(expression_id));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> NotificationWriter for Cursor<&'a mut [u8]> {
+}
+
+pub trait NotificationReader: OpcodeReader {
+    fn read_notification(&mut self) -> Result<Notification, io::Error> {
+        let notification_value = try!(self.read_u32::<BigEndian>());
+        let notification_type = try!(
+            FromPrimitive::from_u32(notification_value).ok_or(
+                io::Error::new(io::ErrorKind::InvalidInput,
+                               "Unknown notification", None)
+            )
+        );
+
+        match notification_type {
+            NotificationType::ExpressionFreed => {
+                let expression_id = try!(self.read_u32::<BigEndian>());
+                Ok(
+                    Notification::ExpressionFreed {
+                        expression_id: expression_id
+                    }
+                )
+            },
+            NotificationType::VoiceFreed => {
+                let note_id = try!(self.read_u32::<BigEndian>());
+                Ok(
+                    Notification::VoiceFreed {
+                        note_id: note_id
+                    }
+                )
+            },
+            NotificationType::SequenceEnded => {
+                let sequence_id = try!(self.read_u32::<BigEndian>());
+                Ok(
+                    Notification::SequenceEnded {
+                        sequence_id: sequence_id
+                    }
+                )
+            },
+            NotificationType::ParameterValue => {
+                self.read_parameter_value()
+            },
+            NotificationType::ExpressionInfo => {
+                self.read_expression_info()
+            },
+            NotificationType::ExpressionOpcode => {
+                let expression_id = try!(self.read_u32::<BigEndian>());
+                let index = try!(self.read_u32::<BigEndian>());
+                let opcode = try!(self.read_dsp_opcode());
+                Ok(
+                    Notification::ExpressionOpcode {
+                        expression_id: expression_id,
+                        index: index,
+                        opcode: opcode
+                    }
+                )
+            },
+            NotificationType::SnapshotData => {
+                self.read_snapshot_data()
+            },
+            NotificationType::SnapshotEnded => {
+                let snapshot_id = try!(self.read_u32::<BigEndian>());
+                let size = try!(self.read_u32::<BigEndian>());
+                Ok(
+                    Notification::SnapshotEnded {
+                        snapshot_id: snapshot_id,
+                        size: size
+                    }
+                )
+            },
+            NotificationType::SnapshotFailed => {
+                let snapshot_id = try!(self.read_u32::<BigEndian>());
+                Ok(
+                    Notification::SnapshotFailed {
+                        snapshot_id: snapshot_id
+                    }
+                )
+            },
+            NotificationType::ExpressionFailed => {
+                let expression_id = try!(self.read_u32::<BigEndian>());
+                Ok(
+                    Notification::ExpressionFailed {
+                        expression_id: expression_id
+                    }
+                )
+            }
+        }
+    }
+
+    fn read_snapshot_data(&mut self) -> Result<Notification, io::Error> {
+        let snapshot_id = try!(self.read_u32::<BigEndian>());
+        let sequence = try!(self.read_u32::<BigEndian>());
+        let size = try!(self.read_u32::<BigEndian>());
+        if size as usize > SNAPSHOT_CHUNK_SIZE {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidInput,
+                               "Snapshot chunk too large", None)
+            );
+        }
+
+        let mut data = [0u8; SNAPSHOT_CHUNK_SIZE];
+        for i in range(0, size as usize) {
+            data[i] = try!(self.read_u8());
+        }
+        Ok(
+            Notification::SnapshotData {
+                snapshot_id: snapshot_id,
+                sequence: sequence,
+                size: size,
+                data: data
+            }
+        )
+    }
+
+    fn read_parameter_value(&mut self) -> Result<Notification, io::Error> {
+        let expression_id = try!(self.read_u32::<BigEndian>());
+        let unit_id = try!(self.read_u32::<BigEndian>());
+        let parameter_id = try!(self.read_u32::<BigEndian>());
+        let value = try!(self.read_f32::<BigEndian>());
+        let current_value = try!(self.read_f32::<BigEndian>());
+        let raw_mode = try!(self.read_u32::<BigEndian>());
+        let mode = try!(
+            FromPrimitive::from_u32(raw_mode).ok_or(
+                io::Error::new(io::ErrorKind::InvalidInput,
+                               "Unknown parameter mode", None)
+            )
+        );
+        let mapped = try!(self.read_u32::<BigEndian>()) != 0;
+        let bus_id = try!(self.read_u32::<BigEndian>());
+        let raw_rate = try!(self.read_u32::<BigEndian>());
+        let mapped_bus = if mapped {
+            let rate = try!(
+                FromPrimitive::from_u32(raw_rate).ok_or(
+                    io::Error::new(io::ErrorKind::InvalidInput,
+                                   "Unknown rate", None)
+                )
+            );
+            Some((bus_id, rate))
+        }
+        else {
+            None
+        };
+        Ok(
+            Notification::ParameterValue {
+                expression_id: expression_id,
+                unit_id: unit_id,
+                parameter_id: parameter_id,
+                value: value,
+                current_value: current_value,
+                mode: mode,
+                mapped_bus: mapped_bus
+            }
+        )
+    }
+
+    fn read_expression_info(&mut self) -> Result<Notification, io::Error> {
+        let expression_id = try!(self.read_u32::<BigEndian>());
+        let name = try!(self.read_name());
+        let raw_state = try!(self.read_u32::<BigEndian>());
+        let state = try!(
+            FromPrimitive::from_u32(raw_state).ok_or(
+                io::Error::new(io::ErrorKind::InvalidInput,
+                               "Unknown expression state", None)
+            )
+        );
+        let muted = try!(self.read_u32::<BigEndian>()) != 0;
+        let solo = try!(self.read_u32::<BigEndian>()) != 0;
+        let gain = try!(self.read_f32::<BigEndian>());
+        let level = try!(self.read_f32::<BigEndian>());
+        let num_opcodes = try!(self.read_u32::<BigEndian>());
+        Ok(
+            Notification::ExpressionInfo {
+                expression_id: expression_id,
+                name: name,
+                state: state,
+                muted: muted,
+                solo: solo,
+                gain: gain,
+                level: level,
+                num_opcodes: num_opcodes
+            }
+        )
+    }
+}
+
+impl<'a> NotificationReader for Cursor<&'a [u8]> {
+}
@@ -1,4 +1,7 @@
-use types::Rate;
+use types::{Rate, Curve};
+use voice_allocator::StealMode;
+use parameter::Smoothing;
+use name::Name;
 
 #[derive(FromPrimitive, Copy, Debug)]
 pub enum ControlOpcodeType {
@@ -6,13 +9,49 @@ pub enum ControlOpcodeType {
     AddExpression,
     RemoveExpression,
     AddEdge,
+    // Numbered after the DSP opcodes so the two sets don't overlap
+    ReplaceExpression = 7,
+    PauseExpression,
+    ResumeExpression,
+    MuteExpression,
+    SoloExpression,
+    RegisterTemplate,
+    UnregisterTemplate,
+    NoteOn,
+    NoteOff,
+    Midi,
+    MapController,
+    UnmapController,
+    MapNotes,
+    UnmapNotes,
+    AddSequence,
+    RemoveSequence,
+    StartSequence,
+    StopSequence,
+    LoopSequence,
+    SetTempo,
+    SetTimeSignature,
+    ScheduleMessage,
+    LoadWavetable,
+    RemoveWavetable,
+    SetSmoothing,
+    RampParameter,
+    MapParameter,
+    UnmapParameter,
+    GetParameter,
+    GetExpression,
+    SetParameterByName,
+    SaveSnapshot,
+    SetUnitData
 }
 
 #[derive(FromPrimitive, Copy, Debug)]
 pub enum DspOpcodeType {
     Unit = 4,
     Add,
-    Multiply
+    Multiply,
+    // Kept clear of the control opcodes, which are numbered upwards
+    Argument = 256
 }
 
 #[derive(Copy, Debug)]
@@ -23,9 +62,12 @@ pub enum ControlOpcode {
         parameter_id: u32,
         value: f32
     },
+    // The name is optional, and can be used in place of the id by
+    // SetParameterByName.  Names must be unique.
     AddExpression {
         expression_id: u32,
-        num_opcodes: u32
+        num_opcodes: u32,
+        name: Name
     },
     RemoveExpression {
         expression_id: u32
@@ -33,16 +75,204 @@ pub enum ControlOpcode {
     AddEdge {
         from: u32,
         to: u32
+    },
+    ReplaceExpression {
+        expression_id: u32,
+        num_opcodes: u32,
+        crossfade: f32,
+        carry_state: bool
+    },
+    PauseExpression {
+        expression_id: u32
+    },
+    ResumeExpression {
+        expression_id: u32
+    },
+    MuteExpression {
+        expression_id: u32,
+        mute: bool
+    },
+    SoloExpression {
+        expression_id: u32,
+        solo: bool
+    },
+    // Followed by num_opcodes DSP opcodes, like AddExpression
+    RegisterTemplate {
+        template_id: u32,
+        num_opcodes: u32,
+        max_voices: u32,
+        steal_mode: StealMode,
+        gate_unit_id: u32,
+        gate_parameter_id: u32
+    },
+    UnregisterTemplate {
+        template_id: u32
+    },
+    // Followed by num_parameters (unit_id, parameter_id, value) triples
+    NoteOn {
+        template_id: u32,
+        note_id: u32,
+        num_parameters: u32
+    },
+    NoteOff {
+        note_id: u32
+    },
+    // A single raw MIDI message.  Messages with one data byte still send a
+    // second, which is ignored.
+    Midi {
+        status: u8,
+        data_1: u8,
+        data_2: u8
+    },
+    MapController {
+        channel: u32,
+        controller: u32,
+        expression_id: u32,
+        unit_id: u32,
+        parameter_id: u32,
+        minimum: f32,
+        maximum: f32,
+        curve: Curve
+    },
+    UnmapController {
+        channel: u32,
+        controller: u32
+    },
+    MapNotes {
+        channel: u32,
+        template_id: u32,
+        pitch_unit_id: u32,
+        pitch_parameter_id: u32,
+        velocity_unit_id: u32,
+        velocity_parameter_id: u32
+    },
+    UnmapNotes {
+        channel: u32
+    },
+    // Followed by num_events (tick, message) pairs, in time order
+    AddSequence {
+        sequence_id: u32,
+        ticks_per_quarter: u32,
+        length: u32,
+        num_events: u32
+    },
+    RemoveSequence {
+        sequence_id: u32
+    },
+    StartSequence {
+        sequence_id: u32,
+        restart: bool
+    },
+    // Pauses the sequence, and releases any notes it is playing
+    StopSequence {
+        sequence_id: u32
+    },
+    LoopSequence {
+        sequence_id: u32,
+        looping: bool
+    },
+    SetTempo {
+        tempo: f32
+    },
+    SetTimeSignature {
+        beats_per_bar: u32,
+        beat_unit: u32
+    },
+    // Followed by size bytes containing the opcodes of the message, without
+    // a header
+    ScheduleMessage {
+        beat: f64,
+        size: u32
+    },
+    // Followed by num_tables tables of table_size samples.  The table size
+    // must be a power of two.
+    LoadWavetable {
+        wavetable_id: u32,
+        num_tables: u32,
+        table_size: u32
+    },
+    RemoveWavetable {
+        wavetable_id: u32
+    },
+    // A time of zero turns smoothing off
+    SetSmoothing {
+        expression_id: u32,
+        unit_id: u32,
+        parameter_id: u32,
+        time: f32,
+        smoothing: Smoothing
+    },
+    // Moves the parameter linearly to value over duration seconds
+    RampParameter {
+        expression_id: u32,
+        unit_id: u32,
+        parameter_id: u32,
+        value: f32,
+        duration: f32
+    },
+    // The parameter follows the first channel of the bus for as long as the
+    // bus is written to, then goes back to its own value.  The rate is the
+    // rate of the bus, which can differ from the parameter's.
+    MapParameter {
+        expression_id: u32,
+        unit_id: u32,
+        parameter_id: u32,
+        bus_id: u32,
+        rate: Rate
+    },
+    UnmapParameter {
+        expression_id: u32,
+        unit_id: u32,
+        parameter_id: u32
+    },
+    // Replies with a ParameterValue notification
+    GetParameter {
+        expression_id: u32,
+        unit_id: u32,
+        parameter_id: u32
+    },
+    // Replies with an ExpressionInfo notification, followed by an
+    // ExpressionOpcode notification for each of the expression's opcodes
+    GetExpression {
+        expression_id: u32
+    },
+    // Sent as a single expression/unit/parameter path, which is resolved
+    // to the ids of the parameter when the message is prepared
+    SetParameterByName {
+        expression: Name,
+        unit: Name,
+        parameter: Name,
+        value: f32
+    },
+    // Replies with the state of the VM, written as a message which rebuilds
+    // it when sent back.  See snapshot.rs.
+    SaveSnapshot {
+        snapshot_id: u32,
+        unit_data: bool
+    },
+    // Followed by num_values values, which replace the unit's state.  The
+    // number of values must match the size of the unit's data.
+    SetUnitData {
+        expression_id: u32,
+        unit_id: u32,
+        num_values: u32
     }
 }
 
 #[derive(Copy, Debug)]
 pub enum DspOpcode {
+    // Followed by num_arguments Argument opcodes, which are passed to the
+    // unit when it is constructed.  Bit n of stack_parameters is set when
+    // parameter n is read from the stack rather than from its value.  The
+    // name is optional, and must be unique within the expression.
     Unit {
         unit_id: u32,
         type_id: u32,
         input_channels: u32,
-        output_channels: u32
+        output_channels: u32,
+        stack_parameters: u32,
+        num_arguments: u32,
+        name: Name
     },
     Add {
         channels: u32,
@@ -51,6 +281,9 @@ pub enum DspOpcode {
     Multiply {
         channels: u32,
         rate: Rate
+    },
+    Argument {
+        value: f32
     }
 }
 
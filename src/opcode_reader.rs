@@ -5,6 +5,8 @@ use byteorder::{ReadBytesExt, BigEndian};
 
 use types::Rate;
 use opcode::{ControlOpcodeType, DspOpcodeType, ControlOpcode, DspOpcode};
+use sequencer::SequenceEvent;
+use name::{Name, MAX_NAME_LENGTH, PATH_SEPARATOR};
 
 pub trait OpcodeReader: Read {
     fn read_control_opcode(&mut self) -> Result<ControlOpcode, io::Error> {
@@ -48,6 +50,193 @@ pub trait OpcodeReader: Read {
             },
             ControlOpcodeType::AddEdge => {
                 self.read_add_edge()
+            },
+            ControlOpcodeType::ReplaceExpression => {
+                self.read_replace_expression()
+            },
+            ControlOpcodeType::PauseExpression => {
+                let expression_id = try!(self.read_u32::<BigEndian>());
+                Ok(ControlOpcode::PauseExpression {
+                    expression_id: expression_id
+                })
+            },
+            ControlOpcodeType::ResumeExpression => {
+                let expression_id = try!(self.read_u32::<BigEndian>());
+                Ok(ControlOpcode::ResumeExpression {
+                    expression_id: expression_id
+                })
+            },
+            ControlOpcodeType::MuteExpression => {
+                self.read_mute_expression()
+            },
+            ControlOpcodeType::SoloExpression => {
+                self.read_solo_expression()
+            },
+            ControlOpcodeType::RegisterTemplate => {
+                self.read_register_template()
+            },
+            ControlOpcodeType::UnregisterTemplate => {
+                let template_id = try!(self.read_u32::<BigEndian>());
+                Ok(ControlOpcode::UnregisterTemplate {
+                    template_id: template_id
+                })
+            },
+            ControlOpcodeType::NoteOn => {
+                self.read_note_on()
+            },
+            ControlOpcodeType::NoteOff => {
+                let note_id = try!(self.read_u32::<BigEndian>());
+                Ok(ControlOpcode::NoteOff {
+                    note_id: note_id
+                })
+            },
+            ControlOpcodeType::Midi => {
+                self.read_midi()
+            },
+            ControlOpcodeType::MapController => {
+                self.read_map_controller()
+            },
+            ControlOpcodeType::UnmapController => {
+                let channel = try!(self.read_u32::<BigEndian>());
+                let controller = try!(self.read_u32::<BigEndian>());
+                Ok(ControlOpcode::UnmapController {
+                    channel: channel,
+                    controller: controller
+                })
+            },
+            ControlOpcodeType::MapNotes => {
+                self.read_map_notes()
+            },
+            ControlOpcodeType::UnmapNotes => {
+                let channel = try!(self.read_u32::<BigEndian>());
+                Ok(ControlOpcode::UnmapNotes {
+                    channel: channel
+                })
+            },
+            ControlOpcodeType::AddSequence => {
+                self.read_add_sequence()
+            },
+            ControlOpcodeType::RemoveSequence => {
+                let sequence_id = try!(self.read_u32::<BigEndian>());
+                Ok(ControlOpcode::RemoveSequence {
+                    sequence_id: sequence_id
+                })
+            },
+            ControlOpcodeType::StartSequence => {
+                let sequence_id = try!(self.read_u32::<BigEndian>());
+                let restart = try!(self.read_u32::<BigEndian>());
+                Ok(ControlOpcode::StartSequence {
+                    sequence_id: sequence_id,
+                    restart: restart != 0
+                })
+            },
+            ControlOpcodeType::StopSequence => {
+                let sequence_id = try!(self.read_u32::<BigEndian>());
+                Ok(ControlOpcode::StopSequence {
+                    sequence_id: sequence_id
+                })
+            },
+            ControlOpcodeType::LoopSequence => {
+                let sequence_id = try!(self.read_u32::<BigEndian>());
+                let looping = try!(self.read_u32::<BigEndian>());
+                Ok(ControlOpcode::LoopSequence {
+                    sequence_id: sequence_id,
+                    looping: looping != 0
+                })
+            },
+            ControlOpcodeType::SetTempo => {
+                let tempo = try!(self.read_f32::<BigEndian>());
+                Ok(ControlOpcode::SetTempo {
+                    tempo: tempo
+                })
+            },
+            ControlOpcodeType::SetTimeSignature => {
+                let beats_per_bar = try!(self.read_u32::<BigEndian>());
+                let beat_unit = try!(self.read_u32::<BigEndian>());
+                Ok(ControlOpcode::SetTimeSignature {
+                    beats_per_bar: beats_per_bar,
+                    beat_unit: beat_unit
+                })
+            },
+            ControlOpcodeType::ScheduleMessage => {
+                let beat = try!(self.read_f64::<BigEndian>());
+                let size = try!(self.read_u32::<BigEndian>());
+                Ok(ControlOpcode::ScheduleMessage {
+                    beat: beat,
+                    size: size
+                })
+            },
+            ControlOpcodeType::LoadWavetable => {
+                let wavetable_id = try!(self.read_u32::<BigEndian>());
+                let num_tables = try!(self.read_u32::<BigEndian>());
+                let table_size = try!(self.read_u32::<BigEndian>());
+                Ok(ControlOpcode::LoadWavetable {
+                    wavetable_id: wavetable_id,
+                    num_tables: num_tables,
+                    table_size: table_size
+                })
+            },
+            ControlOpcodeType::RemoveWavetable => {
+                let wavetable_id = try!(self.read_u32::<BigEndian>());
+                Ok(ControlOpcode::RemoveWavetable {
+                    wavetable_id: wavetable_id
+                })
+            },
+            ControlOpcodeType::SetSmoothing => {
+                self.read_set_smoothing()
+            },
+            ControlOpcodeType::RampParameter => {
+                self.read_ramp_parameter()
+            },
+            ControlOpcodeType::MapParameter => {
+                self.read_map_parameter()
+            },
+            ControlOpcodeType::UnmapParameter => {
+                let expression_id = try!(self.read_u32::<BigEndian>());
+                let unit_id = try!(self.read_u32::<BigEndian>());
+                let parameter_id = try!(self.read_u32::<BigEndian>());
+                Ok(ControlOpcode::UnmapParameter {
+                    expression_id: expression_id,
+                    unit_id: unit_id,
+                    parameter_id: parameter_id
+                })
+            },
+            ControlOpcodeType::GetParameter => {
+                let expression_id = try!(self.read_u32::<BigEndian>());
+                let unit_id = try!(self.read_u32::<BigEndian>());
+                let parameter_id = try!(self.read_u32::<BigEndian>());
+                Ok(ControlOpcode::GetParameter {
+                    expression_id: expression_id,
+                    unit_id: unit_id,
+                    parameter_id: parameter_id
+                })
+            },
+            ControlOpcodeType::GetExpression => {
+                let expression_id = try!(self.read_u32::<BigEndian>());
+                Ok(ControlOpcode::GetExpression {
+                    expression_id: expression_id
+                })
+            },
+            ControlOpcodeType::SetParameterByName => {
+                self.read_set_parameter_by_name()
+            },
+            ControlOpcodeType::SaveSnapshot => {
+                let snapshot_id = try!(self.read_u32::<BigEndian>());
+                let unit_data = try!(self.read_u32::<BigEndian>());
+                Ok(ControlOpcode::SaveSnapshot {
+                    snapshot_id: snapshot_id,
+                    unit_data: unit_data != 0
+                })
+            },
+            ControlOpcodeType::SetUnitData => {
+                let expression_id = try!(self.read_u32::<BigEndian>());
+                let unit_id = try!(self.read_u32::<BigEndian>());
+                let num_values = try!(self.read_u32::<BigEndian>());
+                Ok(ControlOpcode::SetUnitData {
+                    expression_id: expression_id,
+                    unit_id: unit_id,
+                    num_values: num_values
+                })
             }
         }
     }
@@ -64,6 +253,12 @@ pub trait OpcodeReader: Read {
             DspOpcodeType::Multiply=> {
                 self.read_multiply()
             },
+            DspOpcodeType::Argument => {
+                let value = try!(self.read_f32::<BigEndian>());
+                Ok(DspOpcode::Argument {
+                    value: value
+                })
+            }
         }
     }
 
@@ -82,14 +277,95 @@ pub trait OpcodeReader: Read {
         )
     }
 
+    fn read_set_smoothing(&mut self) -> Result<ControlOpcode, io::Error> {
+        let expression_id = try!(self.read_u32::<BigEndian>());
+        let unit_id = try!(self.read_u32::<BigEndian>());
+        let parameter_id = try!(self.read_u32::<BigEndian>());
+        let time = try!(self.read_f32::<BigEndian>());
+        let raw_smoothing = try!(self.read_u32::<BigEndian>());
+        let smoothing = try!(
+            FromPrimitive::from_u32(raw_smoothing).ok_or(
+                io::Error::new(io::ErrorKind::InvalidInput,
+                               "Unknown smoothing", None)
+            )
+        );
+
+        Ok(
+            ControlOpcode::SetSmoothing {
+                expression_id: expression_id,
+                unit_id: unit_id,
+                parameter_id: parameter_id,
+                time: time,
+                smoothing: smoothing
+            }
+        )
+    }
+
+    fn read_ramp_parameter(&mut self) -> Result<ControlOpcode, io::Error> {
+        let expression_id = try!(self.read_u32::<BigEndian>());
+        let unit_id = try!(self.read_u32::<BigEndian>());
+        let parameter_id = try!(self.read_u32::<BigEndian>());
+        let value = try!(self.read_f32::<BigEndian>());
+        let duration = try!(self.read_f32::<BigEndian>());
+        Ok(
+            ControlOpcode::RampParameter {
+                expression_id: expression_id,
+                unit_id: unit_id,
+                parameter_id: parameter_id,
+                value: value,
+                duration: duration
+            }
+        )
+    }
+
+    fn read_map_parameter(&mut self) -> Result<ControlOpcode, io::Error> {
+        let expression_id = try!(self.read_u32::<BigEndian>());
+        let unit_id = try!(self.read_u32::<BigEndian>());
+        let parameter_id = try!(self.read_u32::<BigEndian>());
+        let bus_id = try!(self.read_u32::<BigEndian>());
+        let raw_rate = try!(self.read_u32::<BigEndian>());
+        let rate = try!(
+            FromPrimitive::from_u32(raw_rate).ok_or(
+                io::Error::new(io::ErrorKind::InvalidInput, "Unknown rate",
+                               None)
+            )
+        );
+
+        Ok(
+            ControlOpcode::MapParameter {
+                expression_id: expression_id,
+                unit_id: unit_id,
+                parameter_id: parameter_id,
+                bus_id: bus_id,
+                rate: rate
+            }
+        )
+    }
+
+    fn read_set_parameter_by_name(&mut self)
+            -> Result<ControlOpcode, io::Error> {
+        let (expression, unit, parameter) = try!(self.read_parameter_path());
+        let value = try!(self.read_f32::<BigEndian>());
+        Ok(
+            ControlOpcode::SetParameterByName {
+                expression: expression,
+                unit: unit,
+                parameter: parameter,
+                value: value
+            }
+        )
+    }
+
     fn read_add_expression(&mut self) -> Result<ControlOpcode, io::Error> {
         let expression_id = try!(self.read_u32::<BigEndian>());
         let num_opcodes = try!(self.read_u32::<BigEndian>());
+        let name = try!(self.read_name());
 
         Ok(
             ControlOpcode::AddExpression {
                 expression_id: expression_id,
-                num_opcodes: num_opcodes
+                num_opcodes: num_opcodes,
+                name: name
             }
         )
     }
@@ -116,21 +392,284 @@ pub trait OpcodeReader: Read {
         )
     }
 
+    fn read_replace_expression(&mut self) -> Result<ControlOpcode, io::Error> {
+        let expression_id = try!(self.read_u32::<BigEndian>());
+        let num_opcodes = try!(self.read_u32::<BigEndian>());
+        let crossfade = try!(self.read_f32::<BigEndian>());
+        let carry_state = try!(self.read_u32::<BigEndian>());
+
+        Ok(
+            ControlOpcode::ReplaceExpression {
+                expression_id: expression_id,
+                num_opcodes: num_opcodes,
+                crossfade: crossfade,
+                carry_state: carry_state != 0
+            }
+        )
+    }
+
+    fn read_mute_expression(&mut self) -> Result<ControlOpcode, io::Error> {
+        let expression_id = try!(self.read_u32::<BigEndian>());
+        let mute = try!(self.read_u32::<BigEndian>());
+
+        Ok(
+            ControlOpcode::MuteExpression {
+                expression_id: expression_id,
+                mute: mute != 0
+            }
+        )
+    }
+
+    fn read_solo_expression(&mut self) -> Result<ControlOpcode, io::Error> {
+        let expression_id = try!(self.read_u32::<BigEndian>());
+        let solo = try!(self.read_u32::<BigEndian>());
+
+        Ok(
+            ControlOpcode::SoloExpression {
+                expression_id: expression_id,
+                solo: solo != 0
+            }
+        )
+    }
+
+    fn read_register_template(&mut self) -> Result<ControlOpcode, io::Error> {
+        let template_id = try!(self.read_u32::<BigEndian>());
+        let num_opcodes = try!(self.read_u32::<BigEndian>());
+        let max_voices = try!(self.read_u32::<BigEndian>());
+        let raw_steal_mode = try!(self.read_u32::<BigEndian>());
+        let steal_mode = try!(
+            FromPrimitive::from_u32(raw_steal_mode).ok_or(
+                io::Error::new(io::ErrorKind::InvalidInput,
+                               "Unknown steal mode", None)
+            )
+        );
+        let gate_unit_id = try!(self.read_u32::<BigEndian>());
+        let gate_parameter_id = try!(self.read_u32::<BigEndian>());
+
+        Ok(
+            ControlOpcode::RegisterTemplate {
+                template_id: template_id,
+                num_opcodes: num_opcodes,
+                max_voices: max_voices,
+                steal_mode: steal_mode,
+                gate_unit_id: gate_unit_id,
+                gate_parameter_id: gate_parameter_id
+            }
+        )
+    }
+
+    fn read_note_on(&mut self) -> Result<ControlOpcode, io::Error> {
+        let template_id = try!(self.read_u32::<BigEndian>());
+        let note_id = try!(self.read_u32::<BigEndian>());
+        let num_parameters = try!(self.read_u32::<BigEndian>());
+
+        Ok(
+            ControlOpcode::NoteOn {
+                template_id: template_id,
+                note_id: note_id,
+                num_parameters: num_parameters
+            }
+        )
+    }
+
+    // The (unit_id, parameter_id, value) triples which follow a NoteOn
+    fn read_note_parameter(&mut self) -> Result<(u32, u32, f32), io::Error> {
+        let unit_id = try!(self.read_u32::<BigEndian>());
+        let parameter_id = try!(self.read_u32::<BigEndian>());
+        let value = try!(self.read_f32::<BigEndian>());
+        Ok((unit_id, parameter_id, value))
+    }
+
+    fn read_midi(&mut self) -> Result<ControlOpcode, io::Error> {
+        let status = try!(self.read_u8());
+        let data_1 = try!(self.read_u8());
+        let data_2 = try!(self.read_u8());
+
+        Ok(
+            ControlOpcode::Midi {
+                status: status,
+                data_1: data_1,
+                data_2: data_2
+            }
+        )
+    }
+
+    fn read_map_controller(&mut self) -> Result<ControlOpcode, io::Error> {
+        let channel = try!(self.read_u32::<BigEndian>());
+        let controller = try!(self.read_u32::<BigEndian>());
+        let expression_id = try!(self.read_u32::<BigEndian>());
+        let unit_id = try!(self.read_u32::<BigEndian>());
+        let parameter_id = try!(self.read_u32::<BigEndian>());
+        let minimum = try!(self.read_f32::<BigEndian>());
+        let maximum = try!(self.read_f32::<BigEndian>());
+        let raw_curve = try!(self.read_u32::<BigEndian>());
+        let curve = try!(
+            FromPrimitive::from_u32(raw_curve).ok_or(
+                io::Error::new(io::ErrorKind::InvalidInput, "Unknown curve",
+                               None)
+            )
+        );
+
+        Ok(
+            ControlOpcode::MapController {
+                channel: channel,
+                controller: controller,
+                expression_id: expression_id,
+                unit_id: unit_id,
+                parameter_id: parameter_id,
+                minimum: minimum,
+                maximum: maximum,
+                curve: curve
+            }
+        )
+    }
+
+    fn read_map_notes(&mut self) -> Result<ControlOpcode, io::Error> {
+        let channel = try!(self.read_u32::<BigEndian>());
+        let template_id = try!(self.read_u32::<BigEndian>());
+        let pitch_unit_id = try!(self.read_u32::<BigEndian>());
+        let pitch_parameter_id = try!(self.read_u32::<BigEndian>());
+        let velocity_unit_id = try!(self.read_u32::<BigEndian>());
+        let velocity_parameter_id = try!(self.read_u32::<BigEndian>());
+
+        Ok(
+            ControlOpcode::MapNotes {
+                channel: channel,
+                template_id: template_id,
+                pitch_unit_id: pitch_unit_id,
+                pitch_parameter_id: pitch_parameter_id,
+                velocity_unit_id: velocity_unit_id,
+                velocity_parameter_id: velocity_parameter_id
+            }
+        )
+    }
+
+    fn read_add_sequence(&mut self) -> Result<ControlOpcode, io::Error> {
+        let sequence_id = try!(self.read_u32::<BigEndian>());
+        let ticks_per_quarter = try!(self.read_u32::<BigEndian>());
+        let length = try!(self.read_u32::<BigEndian>());
+        let num_events = try!(self.read_u32::<BigEndian>());
+
+        Ok(
+            ControlOpcode::AddSequence {
+                sequence_id: sequence_id,
+                ticks_per_quarter: ticks_per_quarter,
+                length: length,
+                num_events: num_events
+            }
+        )
+    }
+
+    // The (tick, message) pairs which follow an AddSequence
+    fn read_sequence_event(&mut self) -> Result<SequenceEvent, io::Error> {
+        let tick = try!(self.read_u32::<BigEndian>());
+        let message = try!(self.read_u32::<BigEndian>());
+        Ok(
+            SequenceEvent {
+                tick: tick,
+                message: message
+            }
+        )
+    }
+
     fn read_unit(&mut self) -> Result<DspOpcode, io::Error> {
         let unit_id = try!(self.read_u32::<BigEndian>());
         let type_id = try!(self.read_u32::<BigEndian>());
         let input_channels = try!(self.read_u32::<BigEndian>());
         let output_channels = try!(self.read_u32::<BigEndian>());
+        let stack_parameters = try!(self.read_u32::<BigEndian>());
+        let num_arguments = try!(self.read_u32::<BigEndian>());
+        let name = try!(self.read_name());
         Ok(
             DspOpcode::Unit {
                 unit_id: unit_id,
                 type_id: type_id,
                 input_channels: input_channels,
-                output_channels: output_channels
+                output_channels: output_channels,
+                stack_parameters: stack_parameters,
+                num_arguments: num_arguments,
+                name: name
             }
         )
     }
 
+    // Names are sent as a byte count followed by UTF-8 bytes.  A count of
+    // zero means that no name was given.
+    fn read_name(&mut self) -> Result<Name, io::Error> {
+        let length = try!(self.read_u32::<BigEndian>()) as usize;
+        if length > MAX_NAME_LENGTH {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidInput, "Name too long",
+                               None)
+            );
+        }
+
+        let mut bytes = [0u8; MAX_NAME_LENGTH];
+        for i in range(0, length) {
+            bytes[i] = try!(self.read_u8());
+        }
+        Name::from_bytes(&bytes[..length]).ok_or(
+            io::Error::new(io::ErrorKind::InvalidInput, "Invalid name", None)
+        )
+    }
+
+    // Reads an expression/unit/parameter path, sent in the same way as a
+    // name
+    fn read_parameter_path(&mut self)
+            -> Result<(Name, Name, Name), io::Error> {
+        let length = try!(self.read_u32::<BigEndian>()) as usize;
+        let mut names = [Name::empty(); 3];
+        let mut num_names = 0;
+        let mut bytes = [0u8; MAX_NAME_LENGTH];
+        let mut num_bytes = 0;
+
+        // The end of the path acts as a final separator
+        for i in range(0, length + 1) {
+            let byte = if i < length {
+                try!(self.read_u8())
+            }
+            else {
+                PATH_SEPARATOR
+            };
+
+            if byte != PATH_SEPARATOR {
+                if num_bytes == MAX_NAME_LENGTH {
+                    return Err(
+                        io::Error::new(io::ErrorKind::InvalidInput,
+                                       "Name too long", None)
+                    );
+                }
+                bytes[num_bytes] = byte;
+                num_bytes += 1;
+                continue;
+            }
+
+            let name = try!(
+                Name::from_bytes(&bytes[..num_bytes]).ok_or(
+                    io::Error::new(io::ErrorKind::InvalidInput,
+                                   "Invalid name", None)
+                )
+            );
+            if name.is_empty() || num_names == names.len() {
+                return Err(
+                    io::Error::new(io::ErrorKind::InvalidInput,
+                                   "Invalid parameter path", None)
+                );
+            }
+            names[num_names] = name;
+            num_names += 1;
+            num_bytes = 0;
+        }
+
+        if num_names != names.len() {
+            return Err(
+                io::Error::new(io::ErrorKind::InvalidInput,
+                               "Invalid parameter path", None)
+            );
+        }
+        Ok((names[0], names[1], names[2]))
+    }
+
     fn read_operator(&mut self) -> Result<(u32, Rate), io::Error> {
         let channels = try!(self.read_u32::<BigEndian>());
         let raw_rate = try!(self.read_u32::<BigEndian>());
@@ -169,3 +708,52 @@ impl<'a> OpcodeReader for Cursor<&'a [u8]> {
 
 impl<'a> OpcodeReader for Cursor<&'a mut [u8]> {
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use byteorder::{WriteBytesExt, BigEndian};
+
+    use super::OpcodeReader;
+
+    fn read_path(path: &[u8]) -> Option<(String, String, String)> {
+        let mut byte_code = Vec::new();
+        byte_code.write_u32::<BigEndian>(path.len() as u32).unwrap();
+        byte_code.extend(path.iter().cloned());
+
+        let mut reader = Cursor::new(&byte_code[..]);
+        reader.read_parameter_path().ok().map(|(expression, unit, parameter)|
+            (expression.as_str().to_string(), unit.as_str().to_string(),
+             parameter.as_str().to_string())
+        )
+    }
+
+    #[test]
+    fn reads_parameter_path() {
+        let (expression, unit, parameter) =
+            read_path(b"lead/filter/cutoff").unwrap();
+        assert_eq!(expression, "lead");
+        assert_eq!(unit, "filter");
+        assert_eq!(parameter, "cutoff");
+    }
+
+    #[test]
+    fn rejects_invalid_parameter_paths() {
+        assert!(read_path(b"").is_none());
+        assert!(read_path(b"lead/filter").is_none());
+        assert!(read_path(b"lead/filter/cutoff/extra").is_none());
+        assert!(read_path(b"lead//cutoff").is_none());
+        assert!(read_path(b"lead/filter/").is_none());
+        assert!(read_path(&[b'a'; 33]).is_none());
+    }
+
+    #[test]
+    fn rejects_truncated_parameter_path() {
+        let mut byte_code = Vec::new();
+        byte_code.write_u32::<BigEndian>(10).unwrap();
+        byte_code.extend(b"a/b".iter().cloned());
+        let mut reader = Cursor::new(&byte_code[..]);
+        assert!(reader.read_parameter_path().is_err());
+    }
+}
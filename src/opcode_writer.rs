@@ -0,0 +1,173 @@
+use std::io::{self, Write, Cursor};
+
+use byteorder::{WriteBytesExt, BigEndian};
+
+use opcode::{ControlOpcodeType, DspOpcodeType, ControlOpcode, DspOpcode};
+use name::Name;
+
+// Writes opcodes in the format read by OpcodeReader.  Only the control
+// opcodes which the VM writes itself are supported.
+pub trait OpcodeWriter: Write {
+    fn write_control_opcode(&mut self, opcode: &ControlOpcode)
+            -> Result<(), io::Error> {
+        match *opcode {
+            ControlOpcode::SetParameter { expression_id, unit_id,
+                                          parameter_id, value } => {
+                try!(
+                    self.write_control_opcode_type(
+                        ControlOpcodeType::SetParameter
+                    )
+                );
+                try!(self.write_u32::<BigEndian>(expression_id));
+                try!(self.write_u32::<BigEndian>(unit_id));
+                try!(self.write_u32::<BigEndian>(parameter_id));
+                try!(self.write_f32::<BigEndian>(value));
+            },
+            ControlOpcode::AddExpression { expression_id, num_opcodes,
+                                           name } => {
+                try!(
+                    self.write_control_opcode_type(
+                        ControlOpcodeType::AddExpression
+                    )
+                );
+                try!(self.write_u32::<BigEndian>(expression_id));
+                try!(self.write_u32::<BigEndian>(num_opcodes));
+                try!(self.write_name(&name));
+            },
+            ControlOpcode::AddEdge { from, to } => {
+                try!(
+                    self.write_control_opcode_type(ControlOpcodeType::AddEdge)
+                );
+                try!(self.write_u32::<BigEndian>(from));
+                try!(self.write_u32::<BigEndian>(to));
+            },
+            ControlOpcode::PauseExpression { expression_id } => {
+                try!(
+                    self.write_control_opcode_type(
+                        ControlOpcodeType::PauseExpression
+                    )
+                );
+                try!(self.write_u32::<BigEndian>(expression_id));
+            },
+            ControlOpcode::MuteExpression { expression_id, mute } => {
+                try!(
+                    self.write_control_opcode_type(
+                        ControlOpcodeType::MuteExpression
+                    )
+                );
+                try!(self.write_u32::<BigEndian>(expression_id));
+                try!(self.write_u32::<BigEndian>(mute as u32));
+            },
+            ControlOpcode::SoloExpression { expression_id, solo } => {
+                try!(
+                    self.write_control_opcode_type(
+                        ControlOpcodeType::SoloExpression
+                    )
+                );
+                try!(self.write_u32::<BigEndian>(expression_id));
+                try!(self.write_u32::<BigEndian>(solo as u32));
+            },
+            ControlOpcode::SetSmoothing { expression_id, unit_id,
+                                          parameter_id, time, smoothing } => {
+                try!(
+                    self.write_control_opcode_type(
+                        ControlOpcodeType::SetSmoothing
+                    )
+                );
+                try!(self.write_u32::<BigEndian>(expression_id));
+                try!(self.write_u32::<BigEndian>(unit_id));
+                try!(self.write_u32::<BigEndian>(parameter_id));
+                try!(self.write_f32::<BigEndian>(time));
+                try!(self.write_u32::<BigEndian>(smoothing as u32));
+            },
+            ControlOpcode::MapParameter { expression_id, unit_id,
+                                          parameter_id, bus_id, rate } => {
+                try!(
+                    self.write_control_opcode_type(
+                        ControlOpcodeType::MapParameter
+                    )
+                );
+                try!(self.write_u32::<BigEndian>(expression_id));
+                try!(self.write_u32::<BigEndian>(unit_id));
+                try!(self.write_u32::<BigEndian>(parameter_id));
+                try!(self.write_u32::<BigEndian>(bus_id));
+                try!(self.write_u32::<BigEndian>(rate as u32));
+            },
+            ControlOpcode::SetUnitData { expression_id, unit_id,
+                                         num_values } => {
+                try!(
+                    self.write_control_opcode_type(
+                        ControlOpcodeType::SetUnitData
+                    )
+                );
+                try!(self.write_u32::<BigEndian>(expression_id));
+                try!(self.write_u32::<BigEndian>(unit_id));
+                try!(self.write_u32::<BigEndian>(num_values));
+            },
+            _ => {
+                return Err(
+                    io::Error::new(io::ErrorKind::InvalidInput,
+                                   "Unsupported opcode", None)
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn write_control_opcode_type(&mut self, opcode_type: ControlOpcodeType)
+            -> Result<(), io::Error> {
+        self.write_u32::<BigEndian>(opcode_type as u32)
+    }
+
+    fn write_dsp_opcode(&mut self, opcode: &DspOpcode)
+            -> Result<(), io::Error> {
+        match *opcode {
+            DspOpcode::Unit { unit_id, type_id, input_channels,
+                              output_channels, stack_parameters,
+                              num_arguments, name } => {
+                try!(self.write_u32::<BigEndian>(DspOpcodeType::Unit as u32));
+                try!(self.write_u32::<BigEndian>(unit_id));
+                try!(self.write_u32::<BigEndian>(type_id));
+                try!(self.write_u32::<BigEndian>(input_channels));
+                try!(self.write_u32::<BigEndian>(output_channels));
+                try!(self.write_u32::<BigEndian>(stack_parameters));
+                try!(self.write_u32::<BigEndian>(num_arguments));
+                try!(self.write_name(&name));
+            },
+            DspOpcode::Add { channels, rate } => {
+                try!(self.write_u32::<BigEndian>(DspOpcodeType::Add as u32));
+                try!(self.write_u32::<BigEndian>(channels));
+                try!(self.write_u32::<BigEndian>(rate as u32));
+            },
+            DspOpcode::Multiply { channels, rate } => {
+                try!(
+                    self.write_u32::<BigEndian>(
+                        DspOpcodeType::Multiply as u32
+                    )
+                );
+                try!(self.write_u32::<BigEndian>(channels));
+                try!(self.write_u32::<BigEndian>(rate as u32));
+            },
+            DspOpcode::Argument { value } => {
+                try!(
+                    self.write_u32::<BigEndian>(
+                        DspOpcodeType::Argument as u32
+                    )
+                );
+                try!(self.write_f32::<BigEndian>(value));
+            }
+        }
+        Ok(())
+    }
+
+    fn write_name(&mut self, name: &Name) -> Result<(), io::Error> {
+        try!(self.write_u32::<BigEndian>(name.as_bytes().len() as u32));
+        self.write_all(name.as_bytes())
+    }
+}
+
+impl<'a> OpcodeWriter for Cursor<&'a mut [u8]> {
+}
+
+impl OpcodeWriter for Vec<u8> {
+}
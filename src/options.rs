@@ -16,12 +16,26 @@ pub struct Options {
     pub max_bus_depth: u32,
     pub stack_size: usize,
     pub bus_stack_size: usize,
+    pub max_message_size: usize,
+    pub output_buffer_size: usize,
 
     pub max_opcodes: u32,
     pub max_expressions: u32,
     pub max_units: u32,
     pub max_parameters: u32,
-    pub max_edges: u32
+    pub max_edges: u32,
+    pub max_templates: u32,
+    pub max_midi_mappings: u32,
+    pub max_sequences: u32,
+    pub max_sequence_events: u32,
+    pub max_scheduled_messages: u32,
+    pub schedule_size: usize,
+    pub max_wavetables: u32,
+    pub wavetable_size: usize,
+    pub max_transaction_opcodes: u32,
+
+    // Seeds the random number generators of units which don't set their own
+    pub seed: u32
 }
 
 impl Default for Options {
@@ -37,11 +51,23 @@ impl Default for Options {
             max_bus_depth: 32,
             stack_size: 32 * 64,
             bus_stack_size: 32 * 64,
+            max_message_size: 16 * 1024,
+            output_buffer_size: 16 * 1024,
             max_opcodes: 1024,
             max_expressions: 32,
             max_units: 128,
             max_parameters: 256,
-            max_edges: 32
+            max_edges: 32,
+            max_templates: 16,
+            max_midi_mappings: 128,
+            max_sequences: 8,
+            max_sequence_events: 16 * 1024,
+            max_scheduled_messages: 64,
+            schedule_size: 16 * 1024,
+            max_wavetables: 16,
+            wavetable_size: 1024 * 1024,
+            max_transaction_opcodes: 256,
+            seed: 1
         }
     }
 }
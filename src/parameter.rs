@@ -1,15 +1,40 @@
-use types::{ArtResult, Rate};
+use std::f32;
+use std::num::Float;
+
+use types::{ArtResult, Rate, Curve, BusMap};
 use constants::Constants;
 
 use channel_stack::ChannelStack;
 
 
+// How a parameter moves towards new values when it has a smoothing time.
+// Linear smoothing ramps to each new value over the smoothing time, and
+// exponential smoothing follows it with a one pole filter, with the
+// smoothing time as its time constant.
+#[derive(Copy, PartialEq, FromPrimitive, Debug)]
+pub enum Smoothing {
+    Linear,
+    Exponential
+}
+
 #[derive(Copy)]
 pub struct Parameter {
     pub definition: &'static ParameterDefinition,
     pub value: f32,
     last_value: f32,
-    pub bus: Option<usize>
+    pub bus: Option<usize>,
+    // Whether the parameter is read from the stack when the unit is ticked
+    pub stacked: bool,
+    // The id and rate of a bus which the parameter follows when it is
+    // written to
+    pub mapped_bus: Option<(u32, Rate)>,
+    pub smoothing: Smoothing,
+    pub smoothing_time: f32,
+    // The change per step of a ramp towards value, and the number of steps
+    // left.  Steps are samples for audio rate parameters and blocks for
+    // control rate parameters.
+    ramp_delta: f32,
+    ramp_steps: u32
 }
 
 impl Parameter {
@@ -18,22 +43,110 @@ impl Parameter {
             definition: definition,
             value: definition.default,
             last_value: definition.default,
-            bus: None
+            bus: None,
+            stacked: false,
+            mapped_bus: None,
+            smoothing: Smoothing::Linear,
+            smoothing_time: 0.0,
+            ramp_delta: 0.0,
+            ramp_steps: 0
+        }
+    }
+
+    // The value most recently read by the unit, which lags behind value
+    // while the parameter is smoothing or mapped to a bus
+    pub fn current_value(&self) -> f32 {
+        self.last_value
+    }
+
+    // Sets the value, smoothing the change if the parameter has a smoothing
+    // time
+    pub fn set(&mut self, value: f32, constants: &Constants) {
+        let value = self.definition.clamp(value);
+        if self.smoothing == Smoothing::Linear && self.smoothing_time > 0.0 {
+            let time = self.smoothing_time;
+            self.ramp(value, time, constants);
+            return;
+        }
+
+        self.value = value;
+        self.ramp_steps = 0;
+    }
+
+    // Moves linearly to value over duration seconds
+    pub fn ramp(&mut self, value: f32, duration: f32,
+                constants: &Constants) {
+        self.value = self.definition.clamp(value);
+        let steps = (duration * self.step_rate(constants)).round();
+        if steps < 1.0 {
+            self.ramp_steps = 0;
+            return;
+        }
+
+        self.ramp_steps = steps as u32;
+        self.ramp_delta = (self.value - self.last_value) / steps;
+    }
+
+    fn step_rate(&self, constants: &Constants) -> f32 {
+        match self.definition.rate {
+            Rate::Audio => constants.audio_rate,
+            Rate::Control => constants.control_rate
+        }
+    }
+
+    fn is_smoothing(&self) -> bool {
+        match self.definition.mode {
+            ParameterMode::Trigger => false,
+            _ => {
+                self.ramp_steps > 0 ||
+                (self.smoothing == Smoothing::Exponential &&
+                 self.smoothing_time > 0.0)
+            }
         }
     }
 
+    // The one pole coefficient for exponential smoothing
+    fn coefficient(&self, constants: &Constants) -> f32 {
+        if self.smoothing_time <= 0.0 {
+            return 1.0;
+        }
+        1.0 - (-1.0 / (self.smoothing_time * self.step_rate(constants))).exp()
+    }
+
+    // Moves one step closer to value, returning the new value
+    fn step(&mut self, coefficient: f32) -> f32 {
+        if self.ramp_steps > 0 {
+            self.ramp_steps -= 1;
+            if self.ramp_steps == 0 {
+                self.last_value = self.value;
+            }
+            else {
+                self.last_value += self.ramp_delta;
+            }
+        }
+        else {
+            self.last_value += (self.value - self.last_value) * coefficient;
+        }
+        self.last_value
+    }
+
     pub fn read(&mut self, stack: &mut ChannelStack, busses: &mut ChannelStack,
-               constants: &Constants) -> ArtResult<usize> {
-        let samples = match self.definition.rate {
-            Rate::Audio => constants.block_size,
-            Rate::Control => 1
-        };
+                bus_map: &BusMap, constants: &Constants) -> ArtResult<usize> {
+        let samples = self.definition.samples(constants);
         let index = try!(stack.push(samples));
         let block = stack.get_mut(index, samples);
 
+        if let Some((bus_id, rate)) = self.mapped_bus {
+            if let Some(&bus_index) = bus_map.get(&bus_id) {
+                self.read_mapped_bus(block, busses, bus_index, rate,
+                                     constants);
+                return Ok(index);
+            }
+        }
+
         match self.definition.rate {
             Rate::Control => {
-                self.read_control(block);
+                self.read_control(block, constants);
             },
             Rate::Audio => {
                 self.read_audio(block, busses, constants);
@@ -43,8 +156,39 @@ impl Parameter {
         Ok(index)
     }
 
-    fn read_control(&mut self, block: &mut [f32]) {
+    // Control rate busses are interpolated across the block when read by
+    // audio rate parameters, and audio rate busses are sampled at the start
+    // of the block when read by control rate parameters
+    fn read_mapped_bus(&mut self, block: &mut [f32], busses: &ChannelStack,
+                       bus_index: usize, rate: Rate, constants: &Constants) {
+        match (self.definition.rate, rate) {
+            (Rate::Audio, Rate::Audio) => {
+                busses.read(bus_index, block);
+            },
+            (Rate::Audio, Rate::Control) => {
+                let value = busses.data[bus_index];
+                let delta = (value - self.last_value) *
+                            constants.block_size_inverse;
+                for i in range(0, constants.block_size) {
+                    block[i] = self.last_value + (i + 1) as f32 * delta;
+                }
+            },
+            (Rate::Control, _) => {
+                block[0] = busses.data[bus_index];
+            }
+        }
+        self.last_value = block[block.len() - 1];
+    }
+
+    fn read_control(&mut self, block: &mut [f32], constants: &Constants) {
+        if self.is_smoothing() {
+            let coefficient = self.coefficient(constants);
+            block[0] = self.step(coefficient);
+            return;
+        }
+
         block[0] = self.value;
+        self.last_value = self.value;
         if let ParameterMode::Trigger = self.definition.mode {
             self.value = 0.0;
         }
@@ -57,6 +201,11 @@ impl Parameter {
             return;
         }
 
+        if self.is_smoothing() {
+            self.read_audio_smoothed(block, constants);
+            return;
+        }
+
         match self.definition.mode {
             ParameterMode::Normal => {
                 self.read_audio_normal(block, constants);
@@ -76,6 +225,14 @@ impl Parameter {
         self.last_value = block[block.len() - 1];
     }
 
+    fn read_audio_smoothed(&mut self, block: &mut [f32],
+                           constants: &Constants) {
+        let coefficient = self.coefficient(constants);
+        for i in range(0, constants.block_size) {
+            block[i] = self.step(coefficient);
+        }
+    }
+
     fn read_audio_normal(&mut self, block: &mut [f32], constants: &Constants) {
         for i in range(0, constants.block_size) {
             block[i] = self.value;
@@ -102,18 +259,168 @@ impl Parameter {
     }
 }
 
+// Used as the range of parameters which have no natural limit
+pub const UNBOUNDED: f32 = f32::MAX;
+
 #[derive(Copy, RustcEncodable)]
 pub struct ParameterDefinition {
     pub name: &'static str,
     pub default: f32,
     pub rate: Rate,
-    pub mode: ParameterMode
+    pub mode: ParameterMode,
+    // The metadata is mostly for building user interfaces
+    pub minimum: f32,
+    pub maximum: f32,
+    // Whether values set with SetParameter are kept within the range.
+    // Parameters such as frequencies have a range for display, but the unit
+    // accepts values outside it.
+    pub clamped: bool,
+    // The unit the value is displayed in, such as "Hz" or "s"
+    pub unit: &'static str,
+    pub curve: Curve,
+    pub description: &'static str
 }
 
-#[derive(Copy, RustcEncodable)]
+impl ParameterDefinition {
+    // The number of samples the parameter takes up on the stack
+    pub fn samples(&self, constants: &Constants) -> usize {
+        match self.rate {
+            Rate::Audio => constants.block_size,
+            Rate::Control => 1
+        }
+    }
+
+    pub fn clamp(&self, value: f32) -> f32 {
+        if !self.clamped {
+            return value;
+        }
+        value.max(self.minimum).min(self.maximum)
+    }
+}
+
+#[derive(Copy, RustcEncodable, FromPrimitive, Debug)]
 pub enum ParameterMode {
     Normal,
     Trigger,
     Interpolate
 }
 
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use types::{Rate, Curve};
+    use channel_stack::ChannelStack;
+    use test_util::constants;
+
+    use super::{Parameter, ParameterDefinition, ParameterMode, Smoothing,
+                UNBOUNDED};
+
+    static AUDIO: ParameterDefinition = ParameterDefinition {
+        name: "audio",
+        default: 0f32,
+        rate: Rate::Audio,
+        mode: ParameterMode::Normal,
+        minimum: -UNBOUNDED,
+        maximum: UNBOUNDED,
+        clamped: false,
+        unit: "",
+        curve: Curve::Linear,
+        description: ""
+    };
+
+    static CONTROL: ParameterDefinition = ParameterDefinition {
+        name: "control",
+        default: 0f32,
+        rate: Rate::Control,
+        mode: ParameterMode::Normal,
+        minimum: -UNBOUNDED,
+        maximum: UNBOUNDED,
+        clamped: false,
+        unit: "",
+        curve: Curve::Linear,
+        description: ""
+    };
+
+    // The duration of a number of audio rate samples
+    fn samples(samples: usize) -> f32 {
+        samples as f32 / constants().audio_rate
+    }
+
+    // Reads a block from the parameter
+    fn read(parameter: &mut Parameter) -> Vec<f32> {
+        let constants = constants();
+        let samples = parameter.definition.samples(&constants);
+        let mut stack_data = [0f32; 64];
+        let mut bus_data = [0f32; 0];
+        let mut stack = ChannelStack::new(&mut stack_data);
+        let mut busses = ChannelStack::new(&mut bus_data);
+        let index = parameter.read(&mut stack, &mut busses, &HashMap::new(),
+                                   &constants).unwrap();
+        stack.get_mut(index, samples).to_vec()
+    }
+
+    #[test]
+    fn ramp_finishes_mid_block() {
+        let mut parameter = Parameter::new(&AUDIO);
+        parameter.ramp(32.0, samples(32), &constants());
+
+        let block = read(&mut parameter);
+        for i in range(0, 32) {
+            assert_eq!(block[i], (i + 1) as f32);
+        }
+        for i in range(32, 64) {
+            assert_eq!(block[i], 32.0);
+        }
+        assert_eq!(read(&mut parameter), [32.0; 64].to_vec());
+    }
+
+    #[test]
+    fn control_rate_ramp_steps_once_per_block() {
+        let mut parameter = Parameter::new(&CONTROL);
+        parameter.ramp(3.0, samples(3 * 64), &constants());
+
+        let values: Vec<f32> = range(0, 4).map(|_| read(&mut parameter)[0])
+                                          .collect();
+        assert_eq!(values, [1.0, 2.0, 3.0, 3.0]);
+    }
+
+    #[test]
+    fn set_interrupts_ramp() {
+        let mut parameter = Parameter::new(&AUDIO);
+        parameter.ramp(128.0, samples(128), &constants());
+        assert_eq!(read(&mut parameter)[63], 64.0);
+
+        parameter.set(10.0, &constants());
+        assert_eq!(read(&mut parameter), [10.0; 64].to_vec());
+        assert_eq!(parameter.current_value(), 10.0);
+    }
+
+    #[test]
+    fn smoothed_set_ramps_from_current_value() {
+        let mut parameter = Parameter::new(&AUDIO);
+        parameter.smoothing = Smoothing::Linear;
+        parameter.smoothing_time = samples(64);
+        parameter.ramp(128.0, samples(128), &constants());
+        assert_eq!(read(&mut parameter)[63], 64.0);
+
+        // Half way up, turn back down towards zero
+        parameter.set(0.0, &constants());
+        let block = read(&mut parameter);
+        for i in range(0, 64) {
+            assert_eq!(block[i], (63 - i) as f32);
+        }
+    }
+
+    #[test]
+    fn zero_time_ramp_jumps() {
+        let mut parameter = Parameter::new(&AUDIO);
+        parameter.ramp(5.0, 0.0, &constants());
+        assert_eq!(read(&mut parameter), [5.0; 64].to_vec());
+
+        let mut parameter = Parameter::new(&CONTROL);
+        parameter.ramp(5.0, 0.0, &constants());
+        assert_eq!(read(&mut parameter), [5.0]);
+    }
+}
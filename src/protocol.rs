@@ -0,0 +1,131 @@
+use std::io::Cursor;
+
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+
+use types::ArtResult;
+use errors::ArtError;
+
+// Every message starts with a header containing the magic number, the
+// protocol version, and a CRC32 of the rest of the message.  The version
+// should be bumped whenever the opcode numbering or layout changes.
+pub const MAGIC: u32 = 0x41525421; // "ART!"
+pub const VERSION: u32 = 5;
+pub const HEADER_SIZE: usize = 12;
+
+pub fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+    for byte in data.iter() {
+        crc ^= *byte as u32;
+        for _ in range(0, 8) {
+            if crc & 1 == 1 {
+                crc = (crc >> 1) ^ 0xEDB88320;
+            }
+            else {
+                crc = crc >> 1;
+            }
+        }
+    }
+    !crc
+}
+
+// Checks the header, returning the payload of the message
+pub fn read_header(message: &[u8]) -> ArtResult<&[u8]> {
+    if message.len() < HEADER_SIZE {
+        return Err(ArtError::InvalidByteCode);
+    }
+
+    let mut reader = Cursor::new(message);
+    let magic = try!(reader.read_u32::<BigEndian>());
+    let version = try!(reader.read_u32::<BigEndian>());
+    let checksum = try!(reader.read_u32::<BigEndian>());
+
+    try!(check_field("magic", MAGIC, magic));
+    try!(check_field("version", VERSION, version));
+
+    let payload = &message[HEADER_SIZE..];
+    try!(check_field("checksum", crc32(payload), checksum));
+    Ok(payload)
+}
+
+pub fn write_header(payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(HEADER_SIZE + payload.len());
+    message.write_u32::<BigEndian>(MAGIC).unwrap();
+    message.write_u32::<BigEndian>(VERSION).unwrap();
+    message.write_u32::<BigEndian>(crc32(payload)).unwrap();
+    message.extend(payload.iter().cloned());
+    message
+}
+
+fn check_field(field: &'static str, expected: u32, actual: u32)
+        -> ArtResult<()> {
+    if expected != actual {
+        return Err(
+            ArtError::ProtocolMismatch {
+                field: field,
+                expected: expected,
+                actual: actual
+            }
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use errors::ArtError;
+
+    use super::{crc32, read_header, write_header, HEADER_SIZE};
+
+    #[test]
+    fn crc32_matches_reference_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF43926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"),
+                   0x414FA339);
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let message = write_header(&[1, 2, 3]);
+        assert_eq!(message.len(), HEADER_SIZE + 3);
+        assert_eq!(read_header(&message[..]).unwrap(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn rejects_short_message() {
+        match read_header(&[0; HEADER_SIZE - 1]) {
+            Err(ArtError::InvalidByteCode) => {},
+            _ => panic!("Expected invalid byte code")
+        }
+    }
+
+    fn expect_mismatch(message: &[u8], expected_field: &'static str) {
+        match read_header(message) {
+            Err(ArtError::ProtocolMismatch { field, .. }) => {
+                assert_eq!(field, expected_field);
+            },
+            _ => panic!("Expected protocol mismatch")
+        }
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        let mut message = write_header(&[1, 2, 3]);
+        message[0] ^= 0xFF;
+        expect_mismatch(&message[..], "magic");
+    }
+
+    #[test]
+    fn rejects_other_version() {
+        let mut message = write_header(&[1, 2, 3]);
+        message[7] += 1;
+        expect_mismatch(&message[..], "version");
+    }
+
+    #[test]
+    fn rejects_corrupt_payload() {
+        let mut message = write_header(&[1, 2, 3]);
+        message[HEADER_SIZE + 1] = 7;
+        expect_mismatch(&message[..], "checksum");
+    }
+}
@@ -0,0 +1,133 @@
+use std::num::Float;
+
+use constants::Constants;
+
+// A xorshift generator.  Units keep the state in their data, so it is
+// stored as two f32 values each holding 16 bits.  Both are exact integers,
+// so the data never holds a NaN or denormal bit pattern.
+#[derive(Copy)]
+pub struct Random {
+    state: u32
+}
+
+impl Random {
+    pub fn new(seed: u32) -> Random {
+        // Scramble the seed so that nearby seeds give unrelated sequences,
+        // and avoid the all zero state which xorshift never leaves
+        let mut state = seed.wrapping_add(0x9e3779b9);
+        state = (state ^ (state >> 16)).wrapping_mul(0x85ebca6b);
+        state = (state ^ (state >> 13)).wrapping_mul(0xc2b2ae35);
+        state = state ^ (state >> 16);
+        if state == 0 {
+            state = 0x9e3779b9;
+        }
+
+        Random {
+            state: state
+        }
+    }
+
+    // Loads the generator from a unit's data, reseeding it whenever the seed
+    // parameter changes.  A seed of zero uses the VM-wide seed from the
+    // options, mixed with the unit's id so each unit has its own sequence.
+    // last_seed starts at -1 so the first tick always seeds the generator.
+    pub fn load(state_high: &f32, state_low: &f32, last_seed: &mut f32,
+                seed: f32, id: (u32, u32), constants: &Constants) -> Random {
+        let seed = seed.max(0.0).floor();
+        if seed == *last_seed {
+            return Random {
+                state: (*state_high as u32) << 16 | *state_low as u32
+            };
+        }
+
+        *last_seed = seed;
+        if seed > 0.0 {
+            Random::new(seed as u32)
+        }
+        else {
+            let (expression_id, unit_id) = id;
+            Random::new(
+                constants.seed ^ expression_id.wrapping_mul(0x27d4eb2d) ^
+                unit_id.wrapping_mul(0x165667b1)
+            )
+        }
+    }
+
+    pub fn store(&self, state_high: &mut f32, state_low: &mut f32) {
+        *state_high = (self.state >> 16) as f32;
+        *state_low = (self.state & 0xffff) as f32;
+    }
+
+    pub fn next_u32(&mut self) -> u32 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        self.state
+    }
+
+    // A uniformly distributed value in [-1, 1)
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u32() >> 8) as f32 / (1u32 << 23) as f32 - 1.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use constants::Constants;
+
+    use super::Random;
+
+    fn constants() -> Constants {
+        Constants {
+            input_channels: 0,
+            output_channels: 2,
+            block_size: 64,
+            block_size_inverse: 1.0 / 64.0,
+            audio_rate: 44100.0,
+            audio_rate_inverse: 1.0 / 44100.0,
+            control_rate: 44100.0 / 64.0,
+            control_rate_inverse: 64.0 / 44100.0,
+            seed: 1
+        }
+    }
+
+    #[test]
+    fn store_and_load_keep_the_sequence() {
+        let constants = constants();
+        let mut random = Random::new(1234);
+        random.next_u32();
+
+        let (mut high, mut low) = (0.0, 0.0);
+        random.store(&mut high, &mut low);
+        assert!(!high.is_nan() && !low.is_nan());
+        assert!(high < 65536.0 && low < 65536.0);
+
+        let mut last_seed = 5.0;
+        let mut loaded = Random::load(&high, &low, &mut last_seed, 5.0,
+                                      (0, 0), &constants);
+        for _ in range(0, 16) {
+            assert_eq!(loaded.next_u32(), random.next_u32());
+        }
+    }
+
+    #[test]
+    fn changing_the_seed_reseeds() {
+        let constants = constants();
+        let (high, low) = (0.0, 0.0);
+        let mut last_seed = -1.0;
+        let mut a = Random::load(&high, &low, &mut last_seed, 7.0, (0, 0),
+                                 &constants);
+        assert_eq!(last_seed, 7.0);
+        let mut b = Random::new(7);
+        assert_eq!(a.next_u32(), b.next_u32());
+    }
+
+    #[test]
+    fn next_f32_stays_in_range() {
+        let mut random = Random::new(1);
+        for _ in range(0, 1000) {
+            let value = random.next_f32();
+            assert!(value >= -1.0 && value < 1.0);
+        }
+    }
+}
@@ -0,0 +1,362 @@
+use std::cmp;
+use std::cell::Cell;
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+// Realtime safety auditing for the audio callback.  Build with the
+// `realtime_audit` feature to wrap the C allocator and pthread mutex locking.
+// Any allocation, reallocation, deallocation or lock which happens while a
+// TickGuard is alive on the current thread is recorded as a violation, along
+// with the return addresses of the offending call site.
+//
+// The wrappers call through to the real functions using the internal names
+// which glibc exports for them, and the call site is captured with glibc's
+// backtrace, so the wrappers are only built for Linux with glibc.  On other
+// targets the feature compiles, but nothing is recorded.
+//
+// Violations are recorded into a fixed size buffer without allocating or
+// locking.  Violations beyond the size of the buffer are counted, but not
+// kept.
+
+pub const MAX_VIOLATIONS: usize = 256;
+// The number of return addresses kept for each violation
+pub const MAX_FRAMES: usize = 8;
+// Frames for record and the wrapper which called it, which are left out
+const SKIPPED_FRAMES: usize = 2;
+
+#[derive(Copy, Debug, PartialEq)]
+pub enum ViolationKind {
+    Allocate,
+    Reallocate,
+    Deallocate,
+    Lock
+}
+
+#[derive(Copy, Debug)]
+pub struct Violation {
+    pub kind: ViolationKind,
+    pub size: usize,
+    // Return addresses of the call site, innermost first
+    pub frames: [usize; MAX_FRAMES],
+    pub num_frames: usize
+}
+
+thread_local!(static IN_TICK: Cell<bool> = Cell::new(false));
+
+// Slots in VIOLATIONS are claimed by incrementing the count, and are only
+// read once the audio callback has stopped
+static VIOLATION_COUNT: AtomicUsize = ATOMIC_USIZE_INIT;
+static mut VIOLATIONS: [Violation; MAX_VIOLATIONS] = [
+    Violation {
+        kind: ViolationKind::Allocate,
+        size: 0,
+        frames: [0; MAX_FRAMES],
+        num_frames: 0
+    };
+    MAX_VIOLATIONS
+];
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+extern {
+    fn __libc_malloc(size: usize) -> *mut u8;
+    fn __libc_calloc(count: usize, size: usize) -> *mut u8;
+    fn __libc_realloc(ptr: *mut u8, size: usize) -> *mut u8;
+    fn __libc_memalign(alignment: usize, size: usize) -> *mut u8;
+    fn __libc_free(ptr: *mut u8);
+    fn __pthread_mutex_lock(mutex: *mut u8) -> i32;
+    fn backtrace(buffer: *mut *mut u8, size: i32) -> i32;
+    fn backtrace_symbols_fd(buffer: *const *mut u8, size: i32, fd: i32);
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+#[no_mangle]
+pub unsafe extern fn malloc(size: usize) -> *mut u8 {
+    record(ViolationKind::Allocate, size);
+    __libc_malloc(size)
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+#[no_mangle]
+pub unsafe extern fn calloc(count: usize, size: usize) -> *mut u8 {
+    record(ViolationKind::Allocate, count * size);
+    __libc_calloc(count, size)
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+#[no_mangle]
+pub unsafe extern fn realloc(ptr: *mut u8, size: usize) -> *mut u8 {
+    record(ViolationKind::Reallocate, size);
+    __libc_realloc(ptr, size)
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+#[no_mangle]
+pub unsafe extern fn memalign(alignment: usize, size: usize) -> *mut u8 {
+    record(ViolationKind::Allocate, size);
+    __libc_memalign(alignment, size)
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+#[no_mangle]
+pub unsafe extern fn aligned_alloc(alignment: usize, size: usize) -> *mut u8 {
+    record(ViolationKind::Allocate, size);
+    __libc_memalign(alignment, size)
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+#[no_mangle]
+pub unsafe extern fn posix_memalign(pointer: *mut *mut u8, alignment: usize,
+                                    size: usize) -> i32 {
+    use std::mem;
+
+    const EINVAL: i32 = 22;
+    const ENOMEM: i32 = 12;
+
+    record(ViolationKind::Allocate, size);
+    let word_size = mem::size_of::<usize>();
+    if alignment % word_size != 0 || alignment & (alignment - 1) != 0 {
+        return EINVAL;
+    }
+
+    let allocation = __libc_memalign(alignment, size);
+    if allocation.is_null() {
+        return ENOMEM;
+    }
+    *pointer = allocation;
+    0
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+#[no_mangle]
+pub unsafe extern fn free(ptr: *mut u8) {
+    if !ptr.is_null() {
+        record(ViolationKind::Deallocate, 0);
+    }
+    __libc_free(ptr)
+}
+
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+#[no_mangle]
+pub unsafe extern fn pthread_mutex_lock(mutex: *mut u8) -> i32 {
+    record(ViolationKind::Lock, 0);
+    __pthread_mutex_lock(mutex)
+}
+
+// Fills frames with the return addresses of the call site, returning how
+// many were found
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn call_site(frames: &mut [usize; MAX_FRAMES]) -> usize {
+    let mut buffer = [0 as *mut u8; MAX_FRAMES + SKIPPED_FRAMES];
+    let depth = unsafe {
+        backtrace(buffer.as_mut_ptr(), buffer.len() as i32)
+    } as usize;
+    let depth = cmp::max(depth, SKIPPED_FRAMES) - SKIPPED_FRAMES;
+    for (frame, address) in frames.iter_mut().zip(
+            buffer[SKIPPED_FRAMES..].iter()).take(depth) {
+        *frame = *address as usize;
+    }
+    depth
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn call_site(_: &mut [usize; MAX_FRAMES]) -> usize {
+    0
+}
+
+fn record(kind: ViolationKind, size: usize) {
+    if !IN_TICK.with(|flag| flag.get()) {
+        return;
+    }
+
+    let index = VIOLATION_COUNT.fetch_add(1, Ordering::SeqCst);
+    if index >= MAX_VIOLATIONS {
+        return;
+    }
+
+    // glibc's backtrace allocates the first time it loads the unwinder, so
+    // auditing is switched off while the call site is captured
+    let mut frames = [0; MAX_FRAMES];
+    let num_frames = {
+        let _pause = pause_tick();
+        call_site(&mut frames)
+    };
+
+    unsafe {
+        VIOLATIONS[index] = Violation {
+            kind: kind,
+            size: size,
+            frames: frames,
+            num_frames: num_frames
+        };
+    }
+}
+
+pub struct TickGuard {
+    previous: bool
+}
+
+impl Drop for TickGuard {
+    fn drop(&mut self) {
+        let previous = self.previous;
+        IN_TICK.with(|flag| flag.set(previous));
+    }
+}
+
+pub fn enter_tick() -> TickGuard {
+    let previous = IN_TICK.with(|flag| {
+        let previous = flag.get();
+        flag.set(true);
+        previous
+    });
+
+    TickGuard {
+        previous: previous
+    }
+}
+
+// Stops recording violations on the current thread until the guard is
+// dropped
+fn pause_tick() -> TickGuard {
+    let previous = IN_TICK.with(|flag| {
+        let previous = flag.get();
+        flag.set(false);
+        previous
+    });
+
+    TickGuard {
+        previous: previous
+    }
+}
+
+pub fn violation_count() -> usize {
+    VIOLATION_COUNT.load(Ordering::SeqCst)
+}
+
+// The violations which were kept.  Should only be called once the audio
+// callback has stopped.
+pub fn violations() -> &'static [Violation] {
+    let count = cmp::min(violation_count(), MAX_VIOLATIONS);
+    unsafe {
+        &VIOLATIONS[..count]
+    }
+}
+
+// Should only be called once the audio callback has stopped
+pub fn report() {
+    let count = violation_count();
+    if count == 0 {
+        debug!("Realtime audit: no violations in the audio callback");
+        return;
+    }
+
+    error!("Realtime audit: {} violations in the audio callback", count);
+    for violation in violations().iter() {
+        error!("Realtime audit: {:?} in audio callback: size={}",
+               violation.kind, violation.size);
+        print_call_site(violation);
+    }
+}
+
+// Writes the symbolised call site straight to stderr
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+fn print_call_site(violation: &Violation) {
+    let mut buffer = [0 as *mut u8; MAX_FRAMES];
+    for (pointer, frame) in buffer.iter_mut().zip(violation.frames.iter()) {
+        *pointer = *frame as *mut u8;
+    }
+    unsafe {
+        backtrace_symbols_fd(buffer.as_ptr(), violation.num_frames as i32, 2);
+    }
+}
+
+#[cfg(not(all(target_os = "linux", target_env = "gnu")))]
+fn print_call_site(_: &Violation) {
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::{enter_tick, violation_count, violations, ViolationKind};
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    use super::{malloc, posix_memalign, free};
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    fn records_allocation_in_tick() {
+        let count = violation_count();
+        {
+            let _audit = enter_tick();
+            unsafe {
+                let ptr = malloc(16);
+                free(ptr);
+            }
+        }
+        assert!(violation_count() >= count + 2);
+        assert!(violations().iter().any(|violation| {
+            violation.kind == ViolationKind::Allocate && violation.size == 16
+        }));
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    fn records_aligned_allocation_in_tick() {
+        let count = violation_count();
+        {
+            let _audit = enter_tick();
+            unsafe {
+                let mut ptr = 0 as *mut u8;
+                assert_eq!(posix_memalign(&mut ptr, 64, 48), 0);
+                assert_eq!(ptr as usize % 64, 0);
+                free(ptr);
+            }
+        }
+        assert!(violation_count() >= count + 2);
+        assert!(violations().iter().any(|violation| {
+            violation.kind == ViolationKind::Allocate && violation.size == 48
+        }));
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    fn records_call_site() {
+        {
+            let _audit = enter_tick();
+            unsafe {
+                let ptr = malloc(24);
+                free(ptr);
+            }
+        }
+        let violation = violations().iter().find(|violation| {
+            violation.kind == ViolationKind::Allocate && violation.size == 24
+        }).unwrap();
+        assert!(violation.num_frames > 0);
+        assert!(violation.frames[0] != 0);
+    }
+
+    #[test]
+    #[cfg(all(target_os = "linux", target_env = "gnu"))]
+    fn records_lock_in_tick() {
+        let mutex = Mutex::new(0u32);
+        let count = violation_count();
+        {
+            let _audit = enter_tick();
+            *mutex.lock().unwrap() += 1;
+        }
+        assert!(violation_count() >= count + 1);
+        assert!(violations().iter().any(|violation| {
+            violation.kind == ViolationKind::Lock
+        }));
+    }
+
+    #[test]
+    fn guard_restores_previous_state() {
+        {
+            let _outer = enter_tick();
+            {
+                let _inner = enter_tick();
+            }
+            assert!(super::IN_TICK.with(|flag| flag.get()));
+        }
+        assert!(!super::IN_TICK.with(|flag| flag.get()));
+    }
+}
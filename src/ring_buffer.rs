@@ -0,0 +1,251 @@
+use std::cell::UnsafeCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::old_io::timer;
+use std::time::Duration;
+
+use byteorder::{ByteOrder, BigEndian};
+
+use types::ArtResult;
+use errors::ArtError;
+
+// Each message is prefixed with its size as a big-endian u32
+pub const HEADER_SIZE: usize = 4;
+
+struct RingBuffer {
+    data: UnsafeCell<Vec<u8>>,
+    // Total number of bytes written and read.  Only the sender updates
+    // write_position, and only the receiver updates read_position.
+    write_position: AtomicUsize,
+    read_position: AtomicUsize
+}
+
+// The sender and receiver only touch disjoint regions of data, which are
+// handed over using the positions
+unsafe impl Sync for RingBuffer {}
+
+impl RingBuffer {
+    fn capacity(&self) -> usize {
+        unsafe { (*self.data.get()).len() }
+    }
+
+    unsafe fn write_bytes(&self, position: usize, bytes: &[u8]) {
+        let data = &mut *self.data.get();
+        let capacity = data.len();
+        for (i, byte) in bytes.iter().enumerate() {
+            data[(position + i) % capacity] = *byte;
+        }
+    }
+
+    unsafe fn read_bytes(&self, position: usize, bytes: &mut [u8]) {
+        let data = &*self.data.get();
+        let capacity = data.len();
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = data[(position + i) % capacity];
+        }
+    }
+}
+
+pub struct RingBufferSender {
+    buffer: Arc<RingBuffer>
+}
+
+impl RingBufferSender {
+    pub fn send(&mut self, message: &[u8]) -> ArtResult<()> {
+        let buffer = &*self.buffer;
+        let write_position = buffer.write_position.load(Ordering::Relaxed);
+        let read_position = buffer.read_position.load(Ordering::Acquire);
+
+        let used = write_position - read_position;
+        let size = HEADER_SIZE + message.len();
+        if used + size > buffer.capacity() {
+            return Err(ArtError::BufferOverflow);
+        }
+
+        let mut header = [0u8; HEADER_SIZE];
+        BigEndian::write_u32(&mut header, message.len() as u32);
+
+        unsafe {
+            buffer.write_bytes(write_position, &header);
+            buffer.write_bytes(write_position + HEADER_SIZE, message);
+        }
+
+        buffer.write_position.store(write_position + size, Ordering::Release);
+        Ok(())
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.buffer.capacity() - HEADER_SIZE
+    }
+
+    // The number of bytes which can be sent before the buffer is full,
+    // including the header of each message.  The receiver only ever frees
+    // space, so messages which fit now will still fit when they are sent.
+    pub fn free_space(&self) -> usize {
+        let buffer = &*self.buffer;
+        let write_position = buffer.write_position.load(Ordering::Relaxed);
+        let read_position = buffer.read_position.load(Ordering::Acquire);
+        buffer.capacity() - (write_position - read_position)
+    }
+}
+
+pub struct RingBufferReceiver {
+    buffer: Arc<RingBuffer>
+}
+
+impl RingBufferReceiver {
+    // Copies the next message into the start of message, returning its size
+    pub fn try_recv(&mut self, message: &mut [u8])
+            -> ArtResult<Option<usize>> {
+        let buffer = &*self.buffer;
+        let read_position = buffer.read_position.load(Ordering::Relaxed);
+        let write_position = buffer.write_position.load(Ordering::Acquire);
+
+        if read_position == write_position {
+            return Ok(None);
+        }
+
+        let mut header = [0u8; HEADER_SIZE];
+        unsafe {
+            buffer.read_bytes(read_position, &mut header);
+        }
+        let size = BigEndian::read_u32(&header) as usize;
+        let next_position = read_position + HEADER_SIZE + size;
+
+        if size > message.len() {
+            // Drop the message so we don't get stuck on it
+            buffer.read_position.store(next_position, Ordering::Release);
+            return Err(ArtError::BufferOverflow);
+        }
+
+        unsafe {
+            buffer.read_bytes(read_position + HEADER_SIZE,
+                              &mut message[..size]);
+        }
+
+        buffer.read_position.store(next_position, Ordering::Release);
+        Ok(Some(size))
+    }
+}
+
+pub fn ring_buffer(capacity: usize) -> (RingBufferSender, RingBufferReceiver) {
+    let mut data = Vec::with_capacity(capacity);
+    data.resize(capacity, 0u8);
+
+    let buffer = Arc::new(
+        RingBuffer {
+            data: UnsafeCell::new(data),
+            write_position: AtomicUsize::new(0),
+            read_position: AtomicUsize::new(0)
+        }
+    );
+
+    (
+        RingBufferSender { buffer: buffer.clone() },
+        RingBufferReceiver { buffer: buffer }
+    )
+}
+
+pub type FunnelSender = Sender<Vec<u8>>;
+
+// Allows any number of producers to feed a single ring buffer.  Messages are
+// forwarded by a non-realtime thread, which waits for space to become
+// available rather than dropping messages.
+pub fn funnel(capacity: usize) -> (FunnelSender, RingBufferReceiver) {
+    let (mut ring_sender, ring_receiver) = ring_buffer(capacity);
+    let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+
+    thread::spawn(move || {
+        for message in receiver.iter() {
+            if message.len() > ring_sender.capacity() {
+                error!("Message too large for ring buffer: size={}",
+                       message.len());
+                continue;
+            }
+
+            while ring_sender.send(&message).is_err() {
+                timer::sleep(Duration::milliseconds(1));
+            }
+        }
+    });
+
+    (sender, ring_receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use errors::ArtError;
+
+    use super::{ring_buffer, HEADER_SIZE};
+
+    #[test]
+    fn sends_and_receives_in_order() {
+        let (mut sender, mut receiver) = ring_buffer(64);
+        sender.send(&[1, 2, 3]).unwrap();
+        sender.send(&[4, 5]).unwrap();
+
+        let mut message = [0u8; 16];
+        assert_eq!(receiver.try_recv(&mut message).unwrap(), Some(3));
+        assert_eq!(&message[..3], [1, 2, 3]);
+        assert_eq!(receiver.try_recv(&mut message).unwrap(), Some(2));
+        assert_eq!(&message[..2], [4, 5]);
+        assert_eq!(receiver.try_recv(&mut message).unwrap(), None);
+    }
+
+    #[test]
+    fn rejects_message_when_full() {
+        let (mut sender, mut receiver) = ring_buffer(2 * (HEADER_SIZE + 4));
+        sender.send(&[0; 4]).unwrap();
+        sender.send(&[0; 4]).unwrap();
+        match sender.send(&[0; 1]) {
+            Err(ArtError::BufferOverflow) => {},
+            _ => panic!("Expected overflow")
+        }
+
+        // Reading makes space again
+        let mut message = [0u8; 4];
+        receiver.try_recv(&mut message).unwrap();
+        sender.send(&[0; 4]).unwrap();
+    }
+
+    #[test]
+    fn free_space_counts_headers() {
+        let (mut sender, mut receiver) = ring_buffer(32);
+        assert_eq!(sender.free_space(), 32);
+        sender.send(&[0; 4]).unwrap();
+        assert_eq!(sender.free_space(), 32 - HEADER_SIZE - 4);
+
+        let mut message = [0u8; 4];
+        receiver.try_recv(&mut message).unwrap();
+        assert_eq!(sender.free_space(), 32);
+    }
+
+    #[test]
+    fn wraps_around_end_of_buffer() {
+        let (mut sender, mut receiver) = ring_buffer(HEADER_SIZE + 6);
+        let mut message = [0u8; 6];
+        for i in range(0u8, 10) {
+            let sent = [i, i + 1, i + 2, i + 3, i + 4];
+            sender.send(&sent).unwrap();
+            assert_eq!(receiver.try_recv(&mut message).unwrap(), Some(5));
+            assert_eq!(&message[..5], sent);
+        }
+    }
+
+    #[test]
+    fn drops_message_larger_than_destination() {
+        let (mut sender, mut receiver) = ring_buffer(64);
+        sender.send(&[0; 8]).unwrap();
+        sender.send(&[7]).unwrap();
+
+        let mut message = [0u8; 4];
+        match receiver.try_recv(&mut message) {
+            Err(ArtError::BufferOverflow) => {},
+            _ => panic!("Expected overflow")
+        }
+        assert_eq!(receiver.try_recv(&mut message).unwrap(), Some(1));
+        assert_eq!(message[0], 7);
+    }
+}
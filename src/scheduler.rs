@@ -0,0 +1,158 @@
+use std::io::Read;
+
+use byteorder::ReadBytesExt;
+
+use types::ArtResult;
+use leap::Leap;
+
+// A message which is processed once the clock reaches its beat
+#[derive(Copy)]
+pub struct ScheduledMessage {
+    pub beat: f64,
+    // Where the message's bytes are kept in the data store
+    pub index: usize,
+    pub size: usize
+}
+
+pub struct Scheduler {
+    // Kept in the order in which they were scheduled
+    pub messages: Vec<ScheduledMessage>,
+    pub data: Leap<u8>,
+    // Messages are copied here so they can be processed like any other.  The
+    // buffer is moved out while a message is being processed, so the size
+    // limit is kept separately.
+    pub buffer: Vec<u8>,
+    pub max_message_size: usize
+}
+
+impl Scheduler {
+    pub fn with_capacity(max_messages: usize, data_size: usize,
+                         max_message_size: usize) -> Scheduler {
+        let mut buffer = Vec::with_capacity(max_message_size);
+        buffer.resize(max_message_size, 0u8);
+
+        Scheduler {
+            messages: Vec::with_capacity(max_messages),
+            data: Leap::with_capacity(data_size),
+            buffer: buffer,
+            max_message_size: max_message_size
+        }
+    }
+
+    pub fn push_from_reader<T>(&mut self, size: usize, reader: &mut T)
+            -> ArtResult<usize> where T: Read {
+        let start = self.data.tail;
+        for i in range(0, size) {
+            let result = self.push_byte_from_reader(reader);
+
+            if result.is_err() {
+                self.data.free(start, i);
+                return Err(result.err().unwrap());
+            }
+        }
+        Ok(start)
+    }
+
+    fn push_byte_from_reader<T>(&mut self, reader: &mut T) -> ArtResult<()>
+            where T: Read {
+        let byte = try!(reader.read_u8());
+        try!(self.data.push(byte));
+        Ok(())
+    }
+
+    // Removes the earliest message which is due before the beat.  Messages
+    // due on the same beat are returned in the order they were scheduled.
+    pub fn pop_due(&mut self, beat: f64) -> Option<ScheduledMessage> {
+        let mut due: Option<usize> = None;
+        for (index, message) in self.messages.iter().enumerate() {
+            if message.beat >= beat {
+                continue;
+            }
+
+            due = match due {
+                Some(other) if self.messages[other].beat <= message.beat => {
+                    Some(other)
+                },
+                _ => Some(index)
+            };
+        }
+        due.map(|index| self.messages.remove(index))
+    }
+
+    // Copies the message into the buffer, and frees its data
+    pub fn take(&mut self, message: &ScheduledMessage) -> usize {
+        debug_assert!(message.size <= self.buffer.len());
+        for (byte, value) in self.buffer.iter_mut().zip(
+                self.data.iter(message.index).take(message.size)) {
+            *byte = *value;
+        }
+        self.data.free(message.index, message.size);
+        message.size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{WriteBytesExt, BigEndian};
+
+    use opcode::ControlOpcodeType;
+    use test_util::{vm, write_sine_expression};
+
+    use super::{Scheduler, ScheduledMessage};
+
+    fn write_schedule_message(writer: &mut Vec<u8>, beat: f64,
+                              message: &[u8]) {
+        writer.write_u32::<BigEndian>(
+            ControlOpcodeType::ScheduleMessage as u32
+        ).unwrap();
+        writer.write_f64::<BigEndian>(beat).unwrap();
+        writer.write_u32::<BigEndian>(message.len() as u32).unwrap();
+        writer.push_all(message);
+    }
+
+    fn message(beat: f64, index: usize) -> ScheduledMessage {
+        ScheduledMessage {
+            beat: beat,
+            index: index,
+            size: 0
+        }
+    }
+
+    #[test]
+    fn pops_earliest_due_message() {
+        let mut scheduler = Scheduler::with_capacity(4, 0, 0);
+        scheduler.messages.push(message(2.0, 0));
+        scheduler.messages.push(message(1.0, 1));
+        scheduler.messages.push(message(1.0, 2));
+        scheduler.messages.push(message(5.0, 3));
+
+        assert_eq!(scheduler.pop_due(3.0).unwrap().index, 1);
+        assert_eq!(scheduler.pop_due(3.0).unwrap().index, 2);
+        assert_eq!(scheduler.pop_due(3.0).unwrap().index, 0);
+        assert!(scheduler.pop_due(3.0).is_none());
+        assert_eq!(scheduler.messages.len(), 1);
+    }
+
+    #[test]
+    fn scheduled_message_can_schedule_another() {
+        let mut vm = vm();
+
+        let mut inner = Vec::new();
+        write_sine_expression(&mut inner, 1);
+        let mut outer = Vec::new();
+        write_schedule_message(&mut outer, 1.0, &inner[..]);
+        let mut byte_code = Vec::new();
+        write_schedule_message(&mut byte_code, 0.0, &outer[..]);
+        vm.process_byte_code(&byte_code[..]).unwrap();
+
+        vm.schedule();
+        assert_eq!(vm.scheduler.messages.len(), 1);
+        assert!(!vm.expressions.contains_key(&1));
+
+        vm.clock.beat = 1.0;
+        vm.schedule();
+        assert_eq!(vm.scheduler.messages.len(), 0);
+        assert!(vm.expressions.contains_key(&1));
+        assert_eq!(vm.scheduler.data.len(), 0);
+    }
+}
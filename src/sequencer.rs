@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+
+use types::ArtResult;
+use errors::ArtError;
+use leap::Leap;
+use opcode_reader::OpcodeReader;
+use midi::{MidiMessage, NUM_CHANNELS};
+
+// Status byte used for tempo changes, which are stored alongside the MIDI
+// messages
+pub const TEMPO_STATUS: u32 = 0xFF;
+// Microseconds per quarter note, which is 120 BPM
+pub const DEFAULT_TEMPO: u32 = 500000;
+
+// The most MIDI messages which are played in a single block.  Any more are
+// left until the next block.
+pub const MAX_BLOCK_MESSAGES: usize = 256;
+
+const NOTE_WORDS: usize = (NUM_CHANNELS as usize * 128) / 64;
+
+// A MIDI message or tempo change at a position in ticks.  The message is
+// packed as [status, data_1, data_2, 0], or for tempo changes as
+// [TEMPO_STATUS, tempo], with the tempo in microseconds per quarter note.
+#[derive(Copy, Debug)]
+pub struct SequenceEvent {
+    pub tick: u32,
+    pub message: u32
+}
+
+impl SequenceEvent {
+    pub fn midi(tick: u32, status: u8, data_1: u8, data_2: u8)
+            -> SequenceEvent {
+        SequenceEvent {
+            tick: tick,
+            message: (status as u32) << 24 | (data_1 as u32) << 16 |
+                     (data_2 as u32) << 8
+        }
+    }
+
+    pub fn tempo(tick: u32, tempo: u32) -> SequenceEvent {
+        SequenceEvent {
+            tick: tick,
+            message: TEMPO_STATUS << 24 | (tempo & 0xFFFFFF)
+        }
+    }
+
+    pub fn get_tempo(&self) -> Option<u32> {
+        if self.message >> 24 == TEMPO_STATUS {
+            Some(self.message & 0xFFFFFF)
+        }
+        else {
+            None
+        }
+    }
+
+    pub fn get_midi(&self) -> Option<MidiMessage> {
+        MidiMessage::parse((self.message >> 24) as u8,
+                           (self.message >> 16) as u8,
+                           (self.message >> 8) as u8)
+    }
+}
+
+#[derive(Copy)]
+pub struct Sequence {
+    // Where the events are kept in the event store
+    pub index: usize,
+    pub num_events: usize,
+    pub ticks_per_quarter: u32,
+    pub length: u32,
+    pub playing: bool,
+    pub looping: bool,
+    pub tempo: u32,
+    pub position: f64,
+    // The next event to play, as an index into the event store and a count
+    // from the start of the sequence
+    cursor: usize,
+    played: usize,
+    // One bit for each channel and note which the sequence has started, so
+    // they can be released when it stops
+    notes: [u64; NOTE_WORDS]
+}
+
+impl Sequence {
+    pub fn new(index: usize, num_events: usize, ticks_per_quarter: u32,
+               length: u32) -> Sequence {
+        Sequence {
+            index: index,
+            num_events: num_events,
+            ticks_per_quarter: ticks_per_quarter,
+            length: length,
+            playing: false,
+            looping: false,
+            tempo: DEFAULT_TEMPO,
+            position: 0.0,
+            cursor: index,
+            played: 0,
+            notes: [0; NOTE_WORDS]
+        }
+    }
+
+    pub fn rewind(&mut self) {
+        self.position = 0.0;
+        self.cursor = self.index;
+        self.played = 0;
+        self.tempo = DEFAULT_TEMPO;
+    }
+
+    // Calls release with the channel and note of every note the sequence
+    // has started, and forgets about them
+    pub fn release_notes<F>(&mut self, mut release: F)
+            where F: FnMut(u32, u32) {
+        for word in range(0, NOTE_WORDS) {
+            let bits = self.notes[word];
+            for bit in range(0, 64) {
+                if bits & (1 << bit) != 0 {
+                    let key = (word * 64 + bit) as u32;
+                    release(key / 128, key % 128);
+                }
+            }
+            self.notes[word] = 0;
+        }
+    }
+
+    fn track_note(&mut self, message: &MidiMessage) {
+        let (key, on) = match *message {
+            MidiMessage::NoteOn { channel, note, .. } => {
+                (channel * 128 + note, true)
+            },
+            MidiMessage::NoteOff { channel, note } => {
+                (channel * 128 + note, false)
+            },
+            _ => return
+        };
+
+        let word = key as usize / 64;
+        let bit = 1u64 << (key as usize % 64);
+        if on {
+            self.notes[word] |= bit;
+        }
+        else {
+            self.notes[word] &= !bit;
+        }
+    }
+
+    fn ticks_per_second(&self) -> f64 {
+        self.ticks_per_quarter as f64 * 1000000.0 / self.tempo as f64
+    }
+}
+
+pub struct Sequencer {
+    pub sequences: HashMap<u32, Sequence>,
+    pub events: Leap<SequenceEvent>,
+    // MIDI messages which are due in the current block
+    pub messages: Vec<MidiMessage>,
+    // Sequences which reached their end in the current block
+    pub ended: Vec<u32>
+}
+
+impl Sequencer {
+    pub fn with_capacity(max_sequences: usize, max_events: usize)
+            -> Sequencer {
+        Sequencer {
+            sequences: HashMap::with_capacity(max_sequences),
+            events: Leap::with_capacity(max_events),
+            messages: Vec::with_capacity(MAX_BLOCK_MESSAGES),
+            ended: Vec::with_capacity(max_sequences)
+        }
+    }
+
+    // Reads a sequence's events into the event store, returning the index of
+    // the first event
+    pub fn push_from_reader<T>(&mut self, num_events: usize, reader: &mut T)
+            -> ArtResult<usize> where T: OpcodeReader {
+        if num_events > self.events.capacity() - self.events.len() {
+            return Err(ArtError::BufferOverflow);
+        }
+
+        let start = self.events.tail;
+        let mut tick = 0;
+        for i in range(0, num_events) {
+            let result = self.push_event_from_reader(reader, tick);
+
+            match result {
+                Ok(event_tick) => tick = event_tick,
+                Err(error) => {
+                    self.events.free(start, i);
+                    return Err(error);
+                }
+            }
+        }
+        Ok(start)
+    }
+
+    // Events must be in time order, so each is checked against the tick of
+    // the previous event
+    fn push_event_from_reader<T>(&mut self, reader: &mut T, tick: u32)
+            -> ArtResult<u32> where T: OpcodeReader {
+        let event = try!(reader.read_sequence_event());
+        if event.tick < tick {
+            return Err(ArtError::InvalidByteCode);
+        }
+        try!(self.events.push(event));
+        Ok(event.tick)
+    }
+
+    pub fn get_mut(&mut self, sequence_id: u32) -> ArtResult<&mut Sequence> {
+        self.sequences.get_mut(&sequence_id).ok_or(
+            ArtError::SequenceNotFound {
+                sequence_id: sequence_id
+            }
+        )
+    }
+
+    // Plays the events which are due in every playing sequence, then moves
+    // them on by block_time seconds.  Tempo changes take effect from the
+    // next block.
+    pub fn advance(&mut self, block_time: f64) {
+        'sequences: for (&id, sequence) in self.sequences.iter_mut() {
+            if !sequence.playing {
+                continue;
+            }
+
+            loop {
+                while sequence.played < sequence.num_events {
+                    let event = match self.events.get(sequence.cursor) {
+                        Some(event) => *event,
+                        None => {
+                            // The events have been lost, so there is nothing
+                            // more to play
+                            error!("Missing sequence event: sequence_id={}",
+                                   id);
+                            sequence.playing = false;
+                            debug_assert!(
+                                self.ended.len() < self.ended.capacity()
+                            );
+                            self.ended.push(id);
+                            continue 'sequences;
+                        }
+                    };
+                    if event.tick as f64 > sequence.position {
+                        break;
+                    }
+
+                    if let Some(tempo) = event.get_tempo() {
+                        sequence.tempo = tempo;
+                    }
+                    else if let Some(message) = event.get_midi() {
+                        // Leave the rest of the events for the next block,
+                        // but keep the sequences moving
+                        if self.messages.len() == self.messages.capacity() {
+                            break;
+                        }
+                        sequence.track_note(&message);
+                        self.messages.push(message);
+                    }
+
+                    sequence.cursor = self.events.next(sequence.cursor);
+                    sequence.played += 1;
+                }
+
+                let finished = sequence.played == sequence.num_events &&
+                               sequence.position >= sequence.length as f64;
+                if !finished {
+                    break;
+                }
+
+                if sequence.looping && sequence.length > 0 {
+                    let position = sequence.position - sequence.length as f64;
+                    let tempo = sequence.tempo;
+                    sequence.rewind();
+                    sequence.position = position;
+                    sequence.tempo = tempo;
+                }
+                else {
+                    sequence.playing = false;
+                    debug_assert!(self.ended.len() < self.ended.capacity());
+                    self.ended.push(id);
+                    break;
+                }
+            }
+
+            if sequence.playing {
+                sequence.position += block_time * sequence.ticks_per_second();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use byteorder::{WriteBytesExt, BigEndian};
+
+    use errors::ArtError;
+
+    use super::{Sequencer, Sequence, SequenceEvent, MAX_BLOCK_MESSAGES};
+
+    fn push_sequence(sequencer: &mut Sequencer, events: &[SequenceEvent])
+            -> Sequence {
+        let mut byte_code = Vec::new();
+        for event in events.iter() {
+            byte_code.write_u32::<BigEndian>(event.tick).unwrap();
+            byte_code.write_u32::<BigEndian>(event.message).unwrap();
+        }
+
+        let mut reader = Cursor::new(&byte_code[..]);
+        let index = sequencer.push_from_reader(events.len(), &mut reader)
+                             .unwrap();
+        let mut sequence = Sequence::new(index, events.len(), 96, 960);
+        sequence.playing = true;
+        sequence
+    }
+
+    fn notes(count: usize) -> Vec<SequenceEvent> {
+        range(0, count).map(|i| {
+            SequenceEvent::midi(0, 0x90, (i % 128) as u8, 100)
+        }).collect()
+    }
+
+    #[test]
+    fn rejects_too_many_events() {
+        let mut sequencer = Sequencer::with_capacity(1, 4);
+        let mut byte_code = Vec::new();
+        for _ in range(0, 5) {
+            byte_code.write_u32::<BigEndian>(0).unwrap();
+            byte_code.write_u32::<BigEndian>(0x903C6400).unwrap();
+        }
+
+        let mut reader = Cursor::new(&byte_code[..]);
+        match sequencer.push_from_reader(5, &mut reader) {
+            Err(ArtError::BufferOverflow) => {},
+            _ => panic!("Expected overflow")
+        }
+        assert_eq!(sequencer.events.len(), 0);
+    }
+
+    #[test]
+    fn plays_due_events() {
+        let mut sequencer = Sequencer::with_capacity(1, 16);
+        let events = [
+            SequenceEvent::midi(0, 0x90, 60, 100),
+            SequenceEvent::midi(96, 0x80, 60, 0)
+        ];
+        let sequence = push_sequence(&mut sequencer, &events);
+        sequencer.sequences.insert(0, sequence);
+
+        // 120 BPM at 96 ticks per quarter note is 192 ticks per second
+        sequencer.advance(0.25);
+        assert_eq!(sequencer.messages.len(), 1);
+        assert_eq!(sequencer.sequences[&0].position, 48.0);
+
+        sequencer.messages.clear();
+        sequencer.advance(0.25);
+        assert_eq!(sequencer.messages.len(), 0);
+
+        sequencer.advance(0.25);
+        assert_eq!(sequencer.messages.len(), 1);
+    }
+
+    #[test]
+    fn full_block_keeps_sequences_moving() {
+        let mut sequencer = Sequencer::with_capacity(2,
+                                                     4 * MAX_BLOCK_MESSAGES);
+        let first = push_sequence(&mut sequencer,
+                                  &notes(MAX_BLOCK_MESSAGES + 10)[..]);
+        let second = push_sequence(&mut sequencer, &notes(10)[..]);
+        sequencer.sequences.insert(0, first);
+        sequencer.sequences.insert(1, second);
+
+        sequencer.advance(0.25);
+        assert_eq!(sequencer.messages.len(), MAX_BLOCK_MESSAGES);
+        for sequence in sequencer.sequences.values() {
+            assert!(sequence.playing);
+            assert_eq!(sequence.position, 48.0);
+        }
+
+        // The remaining events are played in the next block
+        sequencer.messages.clear();
+        sequencer.advance(0.25);
+        assert_eq!(sequencer.messages.len(), 20);
+    }
+}
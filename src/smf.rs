@@ -0,0 +1,281 @@
+use std::io::{self, Read, Cursor};
+use std::error::FromError;
+use std::fs::File;
+use std::path::AsPath;
+
+use byteorder::{ReadBytesExt, WriteBytesExt, BigEndian};
+
+use types::ArtResult;
+use errors::ArtError;
+use opcode::ControlOpcodeType;
+use sequencer::SequenceEvent;
+
+// Reads Standard MIDI Files on the client side, so they can be sent to the
+// VM's sequencer with AddSequence
+pub struct MidiFile {
+    pub ticks_per_quarter: u32,
+    // The position in ticks of the end of the longest track
+    pub length: u32,
+    // Events from every track, in time order
+    pub events: Vec<SequenceEvent>
+}
+
+impl MidiFile {
+    pub fn load<P: AsPath + ?Sized>(path: &P) -> ArtResult<MidiFile> {
+        let mut file = try!(File::open(path));
+        let mut data = Vec::new();
+        try!(file.read_to_end(&mut data));
+        MidiFile::parse(&data)
+    }
+
+    pub fn parse(data: &[u8]) -> ArtResult<MidiFile> {
+        let mut reader = Cursor::new(data);
+        try!(read_chunk_type(&mut reader, b"MThd"));
+        let header_size = try!(reader.read_u32::<BigEndian>());
+        if header_size < 6 {
+            return Err(invalid("Invalid MIDI file header"));
+        }
+
+        let _format = try!(reader.read_u16::<BigEndian>());
+        let num_tracks = try!(reader.read_u16::<BigEndian>());
+        let division = try!(reader.read_u16::<BigEndian>());
+        if division & 0x8000 != 0 || division == 0 {
+            return Err(invalid("SMPTE time division is not supported"));
+        }
+        reader.set_position(8 + header_size as u64);
+
+        let mut events = Vec::new();
+        let mut length = 0;
+        for _ in range(0, num_tracks) {
+            let track_length = try!(read_track(&mut reader, &mut events));
+            if track_length > length {
+                length = track_length;
+            }
+        }
+
+        // Keep the order of events which happen at the same time
+        events.sort_by(|a, b| a.tick.cmp(&b.tick));
+
+        Ok(
+            MidiFile {
+                ticks_per_quarter: division as u32,
+                length: length,
+                events: events
+            }
+        )
+    }
+
+    // Writes an AddSequence opcode containing the file's events
+    pub fn to_byte_code(&self, sequence_id: u32) -> Vec<u8> {
+        let mut byte_code = Vec::with_capacity(20 + self.events.len() * 8);
+        byte_code.write_u32::<BigEndian>(
+            ControlOpcodeType::AddSequence as u32
+        ).unwrap();
+        byte_code.write_u32::<BigEndian>(sequence_id).unwrap();
+        byte_code.write_u32::<BigEndian>(self.ticks_per_quarter).unwrap();
+        byte_code.write_u32::<BigEndian>(self.length).unwrap();
+        byte_code.write_u32::<BigEndian>(self.events.len() as u32).unwrap();
+        for event in self.events.iter() {
+            byte_code.write_u32::<BigEndian>(event.tick).unwrap();
+            byte_code.write_u32::<BigEndian>(event.message).unwrap();
+        }
+        byte_code
+    }
+}
+
+fn invalid(description: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, description, None)
+}
+
+fn read_chunk_type(reader: &mut Cursor<&[u8]>, expected: &[u8; 4])
+        -> Result<(), io::Error> {
+    let mut chunk_type = [0u8; 4];
+    for byte in chunk_type.iter_mut() {
+        *byte = try!(reader.read_u8());
+    }
+
+    if &chunk_type != expected {
+        return Err(invalid("Unexpected MIDI file chunk"));
+    }
+    Ok(())
+}
+
+fn read_variable_length(reader: &mut Cursor<&[u8]>) -> Result<u32, io::Error> {
+    let mut value = 0u32;
+    for _ in range(0, 4) {
+        let byte = try!(reader.read_u8());
+        value = value << 7 | (byte & 0x7F) as u32;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(invalid("Invalid variable length quantity"))
+}
+
+// Reads a track's events, returning the length of the track in ticks
+fn read_track(reader: &mut Cursor<&[u8]>, events: &mut Vec<SequenceEvent>)
+        -> ArtResult<u32> {
+    try!(read_chunk_type(reader, b"MTrk"));
+    let size = try!(reader.read_u32::<BigEndian>());
+    let end = reader.position() + size as u64;
+
+    let mut tick = 0u32;
+    let mut running_status = 0u8;
+    while reader.position() < end {
+        let delta = try!(read_variable_length(reader));
+        tick = try!(tick.checked_add(delta).ok_or(ArtError::InvalidByteCode));
+
+        let mut status = try!(reader.read_u8());
+        let data_1 = if status & 0x80 == 0 {
+            // Running status, so this is actually the first data byte
+            if running_status == 0 {
+                return Err(
+                    FromError::from_error(
+                        invalid("Missing MIDI status byte")
+                    )
+                );
+            }
+            let data_1 = status;
+            status = running_status;
+            data_1
+        }
+        else if status < 0xF0 {
+            running_status = status;
+            try!(reader.read_u8())
+        }
+        else {
+            0
+        };
+
+        match status {
+            0xFF => {
+                let meta_type = try!(reader.read_u8());
+                let meta_size = try!(read_variable_length(reader));
+                let meta_end = reader.position() + meta_size as u64;
+                match meta_type {
+                    0x51 if meta_size == 3 => {
+                        let tempo = try!(reader.read_uint::<BigEndian>(3));
+                        events.push(SequenceEvent::tempo(tick, tempo as u32));
+                    },
+                    // End of track
+                    0x2F => break,
+                    _ => {}
+                }
+                reader.set_position(meta_end);
+            },
+            0xF0 | 0xF7 => {
+                // System exclusive messages are skipped
+                let sysex_size = try!(read_variable_length(reader));
+                let position = reader.position();
+                reader.set_position(position + sysex_size as u64);
+            },
+            _ if status < 0xF0 => {
+                // Program change and channel pressure only have one data
+                // byte
+                let data_2 = match status & 0xF0 {
+                    0xC0 | 0xD0 => 0,
+                    _ => try!(reader.read_u8())
+                };
+                events.push(SequenceEvent::midi(tick, status, data_1,
+                                                data_2));
+            },
+            _ => {
+                return Err(
+                    FromError::from_error(
+                        invalid("Unsupported MIDI file event")
+                    )
+                );
+            }
+        }
+    }
+
+    reader.set_position(end);
+    Ok(tick)
+}
+
+#[cfg(test)]
+mod tests {
+    use byteorder::{WriteBytesExt, BigEndian};
+
+    use errors::ArtError;
+
+    use super::MidiFile;
+
+    fn midi_file(track: &[u8]) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.push_all(b"MThd");
+        data.write_u32::<BigEndian>(6).unwrap();
+        data.write_u16::<BigEndian>(0).unwrap();
+        data.write_u16::<BigEndian>(1).unwrap();
+        data.write_u16::<BigEndian>(96).unwrap();
+        data.push_all(b"MTrk");
+        data.write_u32::<BigEndian>(track.len() as u32).unwrap();
+        data.push_all(track);
+        data
+    }
+
+    #[test]
+    fn parses_events() {
+        let data = midi_file(&[
+            // Tempo of 500000 microseconds per quarter note
+            0x00, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20,
+            0x00, 0x90, 0x3C, 0x64,
+            // Running status, with a delta of 96 ticks
+            0x60, 0x3C, 0x00,
+            // Program change only has one data byte
+            0x00, 0xC0, 0x05,
+            0x00, 0xFF, 0x2F, 0x00
+        ]);
+        let file = MidiFile::parse(&data[..]).unwrap();
+        assert_eq!(file.ticks_per_quarter, 96);
+        assert_eq!(file.length, 96);
+        assert_eq!(file.events.len(), 4);
+
+        assert_eq!(file.events[0].tick, 0);
+        assert_eq!(file.events[0].get_tempo(), Some(500000));
+        assert_eq!(file.events[1].tick, 0);
+        assert_eq!(file.events[1].message, 0x903C6400);
+        assert_eq!(file.events[2].tick, 96);
+        assert_eq!(file.events[2].message, 0x903C0000);
+        assert_eq!(file.events[3].tick, 96);
+        assert_eq!(file.events[3].message, 0xC0050000);
+    }
+
+    #[test]
+    fn parses_variable_length_deltas() {
+        let data = midi_file(&[
+            0x81, 0x00, 0x90, 0x3C, 0x64,
+            0x00, 0xFF, 0x2F, 0x00
+        ]);
+        let file = MidiFile::parse(&data[..]).unwrap();
+        assert_eq!(file.events[0].tick, 128);
+    }
+
+    #[test]
+    fn rejects_tick_overflow() {
+        let mut track = Vec::new();
+        track.push_all(&[0x00, 0x90, 0x3C, 0x64]);
+        for _ in range(0, 17) {
+            // The largest delta, using running status
+            track.push_all(&[0xFF, 0xFF, 0xFF, 0x7F, 0x3C, 0x64]);
+        }
+
+        match MidiFile::parse(&midi_file(&track[..])[..]) {
+            Err(ArtError::InvalidByteCode) => {},
+            _ => panic!("Expected invalid byte code")
+        }
+    }
+
+    #[test]
+    fn rejects_missing_status() {
+        let data = midi_file(&[0x00, 0x3C, 0x64]);
+        assert!(MidiFile::parse(&data[..]).is_err());
+    }
+
+    #[test]
+    fn rejects_smpte_division() {
+        let mut data = midi_file(&[0x00, 0xFF, 0x2F, 0x00]);
+        data[12] = 0xE7;
+        assert!(MidiFile::parse(&data[..]).is_err());
+    }
+}
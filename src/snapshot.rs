@@ -0,0 +1,514 @@
+use std::cmp;
+use std::io::{self, Read, Write, Cursor};
+use std::fs::File;
+use std::path::Path;
+
+use byteorder::{WriteBytesExt, BigEndian};
+
+use types::{ArtResult, ExpressionMap, UnitMap, ParameterMap};
+use errors::ArtError;
+use protocol;
+use opcode::{ControlOpcode, DspOpcode};
+use opcode_writer::OpcodeWriter;
+use expression::{Expression, ExpressionState, is_internal_id};
+use parameter::{ParameterMode, Smoothing};
+use notification::{Notification, SNAPSHOT_CHUNK_SIZE};
+use unit_factory::UnitFactory;
+use graph::Graph;
+use leap::Leap;
+
+// A snapshot is written as the payload of a message which rebuilds the
+// state of the VM when it is sent back, so restoring a session is the same
+// as sending any other message.  Expressions created by the VM itself, such
+// as voices, aren't included.
+//
+// The snapshot is written in one go on the audio thread, then sent to the
+// client a chunk at a time over the following blocks, as space in the output
+// channel allows.
+pub struct Snapshot {
+    data: Vec<u8>,
+    snapshot_id: u32,
+    size: usize,
+    // How much of the snapshot has been sent, and the sequence number of the
+    // next chunk
+    position: usize,
+    sequence: u32,
+    pending: bool
+}
+
+impl Snapshot {
+    pub fn with_capacity(capacity: usize) -> Snapshot {
+        let mut data = Vec::with_capacity(capacity);
+        data.resize(capacity, 0u8);
+
+        Snapshot {
+            data: data,
+            snapshot_id: 0,
+            size: 0,
+            position: 0,
+            sequence: 0,
+            pending: false
+        }
+    }
+
+    // Whether a snapshot is still being sent
+    pub fn is_pending(&self) -> bool {
+        self.pending
+    }
+
+    // Writes the state of the VM.  Unit state is only included if the unit
+    // data is given.
+    pub fn save(&mut self, snapshot_id: u32, expressions: &ExpressionMap,
+                store: &Leap<DspOpcode>, factory: &UnitFactory,
+                units: &UnitMap, parameters: &ParameterMap, graph: &Graph,
+                data: Option<&Leap<f32>>) -> ArtResult<()> {
+        debug_assert!(!self.pending);
+        let size = {
+            let mut writer = Cursor::new(&mut self.data[..]);
+            let result = Snapshot::write(&mut writer, expressions, store,
+                                         factory, units, parameters, graph,
+                                         data);
+            if result.is_err() {
+                // The cursor only fails when the snapshot doesn't fit
+                return Err(ArtError::BufferOverflow);
+            }
+            writer.position() as usize
+        };
+
+        self.snapshot_id = snapshot_id;
+        self.size = size;
+        self.position = 0;
+        self.sequence = 0;
+        self.pending = true;
+        Ok(())
+    }
+
+    fn write<W: OpcodeWriter>(writer: &mut W, expressions: &ExpressionMap,
+                              store: &Leap<DspOpcode>, factory: &UnitFactory,
+                              units: &UnitMap, parameters: &ParameterMap,
+                              graph: &Graph, data: Option<&Leap<f32>>)
+            -> Result<(), io::Error> {
+        for expression in expressions.values() {
+            if is_internal_id(expression.id) ||
+                    expression.state == ExpressionState::Free {
+                continue;
+            }
+            try!(
+                Snapshot::write_expression(writer, expression, store, factory,
+                                           units, parameters, data)
+            );
+        }
+
+        // Edges are added once every expression exists
+        for edge in graph.edges() {
+            if is_internal_id(edge.from) || is_internal_id(edge.to) {
+                continue;
+            }
+            try!(
+                writer.write_control_opcode(
+                    &ControlOpcode::AddEdge {
+                        from: edge.from,
+                        to: edge.to
+                    }
+                )
+            );
+        }
+        Ok(())
+    }
+
+    fn write_expression<W: OpcodeWriter>(writer: &mut W,
+                                         expression: &Expression,
+                                         store: &Leap<DspOpcode>,
+                                         factory: &UnitFactory,
+                                         units: &UnitMap,
+                                         parameters: &ParameterMap,
+                                         data: Option<&Leap<f32>>)
+            -> Result<(), io::Error> {
+        let id = expression.id;
+        try!(
+            writer.write_control_opcode(
+                &ControlOpcode::AddExpression {
+                    expression_id: id,
+                    num_opcodes: expression.num_opcodes as u32,
+                    name: expression.name
+                }
+            )
+        );
+
+        let opcodes = store.iter(expression.index)
+                           .take(expression.num_opcodes);
+        for opcode in opcodes {
+            try!(writer.write_dsp_opcode(opcode));
+        }
+
+        if expression.state == ExpressionState::Paused {
+            try!(
+                writer.write_control_opcode(
+                    &ControlOpcode::PauseExpression { expression_id: id }
+                )
+            );
+        }
+
+        if expression.muted {
+            try!(
+                writer.write_control_opcode(
+                    &ControlOpcode::MuteExpression {
+                        expression_id: id,
+                        mute: true
+                    }
+                )
+            );
+        }
+
+        if expression.solo {
+            try!(
+                writer.write_control_opcode(
+                    &ControlOpcode::SoloExpression {
+                        expression_id: id,
+                        solo: true
+                    }
+                )
+            );
+        }
+
+        let opcodes = store.iter(expression.index)
+                           .take(expression.num_opcodes);
+        for opcode in opcodes {
+            if let &DspOpcode::Unit { unit_id, type_id, num_arguments,
+                                      .. } = opcode {
+                try!(
+                    Snapshot::write_parameters(writer, (id, unit_id),
+                                               type_id, factory, parameters)
+                );
+
+                if let Some(data) = data {
+                    try!(
+                        Snapshot::write_unit_data(writer, (id, unit_id),
+                                                  num_arguments as usize,
+                                                  units, data)
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Writes the parameters which differ from a newly constructed unit
+    fn write_parameters<W: OpcodeWriter>(writer: &mut W, id: (u32, u32),
+                                         type_id: u32, factory: &UnitFactory,
+                                         parameters: &ParameterMap)
+            -> Result<(), io::Error> {
+        let (eid, uid) = id;
+        let definition = factory.get_definition(type_id);
+        for pid in range(0, definition.parameters.len() as u32) {
+            debug_assert!(parameters.contains_key(&(eid, uid, pid)));
+            let parameter = &parameters[&(eid, uid, pid)];
+
+            // Setting a trigger would fire it
+            let is_trigger = match parameter.definition.mode {
+                ParameterMode::Trigger => true,
+                _ => false
+            };
+            if !is_trigger && parameter.value != parameter.definition.default {
+                try!(
+                    writer.write_control_opcode(
+                        &ControlOpcode::SetParameter {
+                            expression_id: eid,
+                            unit_id: uid,
+                            parameter_id: pid,
+                            value: parameter.value
+                        }
+                    )
+                );
+            }
+
+            // Written after the value, so the value isn't smoothed when the
+            // snapshot is restored
+            if parameter.smoothing_time > 0.0 ||
+                    parameter.smoothing != Smoothing::Linear {
+                try!(
+                    writer.write_control_opcode(
+                        &ControlOpcode::SetSmoothing {
+                            expression_id: eid,
+                            unit_id: uid,
+                            parameter_id: pid,
+                            time: parameter.smoothing_time,
+                            smoothing: parameter.smoothing
+                        }
+                    )
+                );
+            }
+
+            if let Some((bus_id, rate)) = parameter.mapped_bus {
+                try!(
+                    writer.write_control_opcode(
+                        &ControlOpcode::MapParameter {
+                            expression_id: eid,
+                            unit_id: uid,
+                            parameter_id: pid,
+                            bus_id: bus_id,
+                            rate: rate
+                        }
+                    )
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn write_unit_data<W: OpcodeWriter>(writer: &mut W, id: (u32, u32),
+                                        num_arguments: usize, units: &UnitMap,
+                                        data: &Leap<f32>)
+            -> Result<(), io::Error> {
+        debug_assert!(units.contains_key(&id));
+        let unit = &units[&id];
+        let index = match unit.data_index {
+            Some(index) => index,
+            None => return Ok(())
+        };
+
+        let (eid, uid) = id;
+        let size = unit.definition.data_size.size(num_arguments);
+        try!(
+            writer.write_control_opcode(
+                &ControlOpcode::SetUnitData {
+                    expression_id: eid,
+                    unit_id: uid,
+                    num_values: size as u32
+                }
+            )
+        );
+
+        for value in data.iter(index).take(size) {
+            try!(writer.write_f32::<BigEndian>(*value));
+        }
+        Ok(())
+    }
+
+    // The next notification to send, or None if there is nothing waiting to
+    // be sent
+    pub fn next_notification(&self) -> Option<Notification> {
+        if !self.pending {
+            return None;
+        }
+
+        if self.position == self.size {
+            return Some(
+                Notification::SnapshotEnded {
+                    snapshot_id: self.snapshot_id,
+                    size: self.size as u32
+                }
+            );
+        }
+
+        let end = cmp::min(self.position + SNAPSHOT_CHUNK_SIZE, self.size);
+        let mut data = [0u8; SNAPSHOT_CHUNK_SIZE];
+        (&mut data[..end - self.position]).clone_from_slice(
+            &self.data[self.position..end]
+        );
+
+        Some(
+            Notification::SnapshotData {
+                snapshot_id: self.snapshot_id,
+                sequence: self.sequence,
+                size: (end - self.position) as u32,
+                data: data
+            }
+        )
+    }
+
+    // Moves on once the notification from next_notification has been sent
+    pub fn advance(&mut self) {
+        if self.position == self.size {
+            self.pending = false;
+            return;
+        }
+
+        self.position = cmp::min(self.position + SNAPSHOT_CHUNK_SIZE,
+                                 self.size);
+        self.sequence += 1;
+    }
+}
+
+// Collects the chunks of a snapshot on the client side
+pub struct SnapshotReceiver {
+    snapshot_id: u32,
+    data: Vec<u8>,
+    next_sequence: u32
+}
+
+impl SnapshotReceiver {
+    pub fn new(snapshot_id: u32) -> SnapshotReceiver {
+        SnapshotReceiver {
+            snapshot_id: snapshot_id,
+            data: Vec::new(),
+            next_sequence: 0
+        }
+    }
+
+    // Adds a notification to the snapshot, returning whether the snapshot is
+    // complete.  Notifications for other snapshots are ignored.
+    pub fn push(&mut self, notification: &Notification) -> ArtResult<bool> {
+        match *notification {
+            Notification::SnapshotData { snapshot_id, sequence, size,
+                                         data } => {
+                if snapshot_id != self.snapshot_id {
+                    return Ok(false);
+                }
+
+                if sequence != self.next_sequence {
+                    return Err(
+                        ArtError::IncompleteMessage {
+                            expected_sequence: self.next_sequence,
+                            actual_sequence: sequence
+                        }
+                    );
+                }
+
+                self.data.extend(data[..size as usize].iter().cloned());
+                self.next_sequence += 1;
+                Ok(false)
+            },
+            Notification::SnapshotEnded { snapshot_id, size } => {
+                if snapshot_id != self.snapshot_id {
+                    return Ok(false);
+                }
+
+                if size as usize != self.data.len() {
+                    return Err(ArtError::InvalidByteCode);
+                }
+                Ok(true)
+            },
+            Notification::SnapshotFailed { snapshot_id } => {
+                if snapshot_id != self.snapshot_id {
+                    return Ok(false);
+                }
+                Err(ArtError::BufferOverflow)
+            },
+            _ => Ok(false)
+        }
+    }
+
+    // The snapshot as a complete message, which restores it when sent to
+    // the VM
+    pub fn message(&self) -> Vec<u8> {
+        protocol::write_header(&self.data[..])
+    }
+}
+
+// Snapshots are saved with the message header, so the version of the
+// protocol they were written with is checked when they are loaded
+pub fn save(path: &Path, message: &[u8]) -> ArtResult<()> {
+    try!(protocol::read_header(message));
+    let mut file = try!(File::create(path));
+    try!(file.write_all(message));
+    Ok(())
+}
+
+// Reads a snapshot, returning the message which restores it
+pub fn load(path: &Path) -> ArtResult<Vec<u8>> {
+    let mut file = try!(File::open(path));
+    let mut message = Vec::new();
+    try!(file.read_to_end(&mut message));
+    try!(protocol::read_header(&message[..]));
+    Ok(message)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use byteorder::{WriteBytesExt, BigEndian};
+
+    use protocol;
+    use opcode::{ControlOpcode, ControlOpcodeType};
+    use opcode_writer::OpcodeWriter;
+    use notification::{Notification, NotificationReader,
+                       MAX_NOTIFICATION_SIZE, SNAPSHOT_CHUNK_SIZE};
+    use test_util::{vm, write_sine_expression};
+
+    use super::SnapshotReceiver;
+
+    #[test]
+    fn snapshot_restores_into_new_vm() {
+        let mut source = vm();
+        let mut byte_code = Vec::new();
+        write_sine_expression(&mut byte_code, 1);
+        write_sine_expression(&mut byte_code, 2);
+        byte_code.write_control_opcode(
+            &ControlOpcode::SetParameter {
+                expression_id: 1,
+                unit_id: 0,
+                parameter_id: 0,
+                value: 220.0
+            }
+        ).unwrap();
+        byte_code.write_control_opcode(
+            &ControlOpcode::MuteExpression {
+                expression_id: 2,
+                mute: true
+            }
+        ).unwrap();
+        byte_code.write_control_opcode(
+            &ControlOpcode::AddEdge { from: 1, to: 2 }
+        ).unwrap();
+        byte_code.write_u32::<BigEndian>(
+            ControlOpcodeType::SaveSnapshot as u32
+        ).unwrap();
+        byte_code.write_u32::<BigEndian>(7).unwrap();
+        byte_code.write_u32::<BigEndian>(1).unwrap();
+        source.process_byte_code(&byte_code[..]).unwrap();
+
+        // Pass the chunks to the receiver as the client would
+        let mut receiver = SnapshotReceiver::new(7);
+        let mut buffer = [0u8; MAX_NOTIFICATION_SIZE];
+        let mut complete = false;
+        while !complete {
+            assert!(source.snapshot.is_pending());
+            source.send_snapshot();
+            let output = source.output_receiver.as_mut().unwrap();
+            while let Some(size) = output.try_recv(&mut buffer).unwrap() {
+                let mut reader = Cursor::new(&buffer[..size]);
+                let notification = reader.read_notification().unwrap();
+                complete = receiver.push(&notification).unwrap();
+            }
+        }
+        assert!(!source.snapshot.is_pending());
+
+        let message = receiver.message();
+        let mut restored = vm();
+        let byte_code = protocol::read_header(&message[..]).unwrap();
+        restored.process_byte_code(byte_code).unwrap();
+
+        assert_eq!(restored.expressions.len(), 2);
+        assert!(!restored.expressions[&1].muted);
+        assert!(restored.expressions[&2].muted);
+        assert_eq!(restored.parameters[&(1, 0, 0)].value, 220.0);
+        assert_eq!(restored.graph.edges().len(), 1);
+        assert_eq!(restored.graph.edges()[0].from, 1);
+        assert_eq!(restored.graph.edges()[0].to, 2);
+
+        for &id in [(1, 0), (2, 0)].iter() {
+            let source_index = source.units[&id].data_index.unwrap();
+            let restored_index = restored.units[&id].data_index.unwrap();
+            let source_data: Vec<f32> =
+                source.unit_data.iter(source_index).take(1).cloned().collect();
+            let restored_data: Vec<f32> =
+                restored.unit_data.iter(restored_index).take(1).cloned()
+                                  .collect();
+            assert_eq!(source_data, restored_data);
+        }
+    }
+
+    #[test]
+    fn receiver_rejects_missing_chunk() {
+        let mut receiver = SnapshotReceiver::new(1);
+        let notification = Notification::SnapshotData {
+            snapshot_id: 1,
+            sequence: 1,
+            size: 0,
+            data: [0u8; SNAPSHOT_CHUNK_SIZE]
+        };
+        assert!(receiver.push(&notification).is_err());
+    }
+}
@@ -0,0 +1,175 @@
+use std::num::Float;
+use std::default::Default;
+use std::collections::HashMap;
+use std::sync::mpsc;
+
+use types::{ArtResult, ByteCodeReceiver, Rate, BusMap, ParameterMap,
+            UnitConstructor};
+use options::Options;
+use constants::Constants;
+use unit::{Unit, TickAdjuncts, DoneAction};
+use channel_stack::ChannelStack;
+use leap::Leap;
+use clock::Clock;
+use wavetable::WavetableStore;
+use opcode::{ControlOpcode, DspOpcode};
+use opcode_writer::OpcodeWriter;
+use name::Name;
+use vm_inner::VmInner;
+use unit_factory::UnitFactory;
+
+// Helpers for tests which run messages through a VM without a device
+
+pub fn vm() -> VmInner {
+    vm_with_options(Default::default())
+}
+
+pub fn vm_with_options(options: Options) -> VmInner {
+    let (_, receiver) = mpsc::channel();
+    VmInner::new(&options, ByteCodeReceiver::Channel(receiver))
+}
+
+// The type id of a unit, found by name
+pub fn type_id(name: &str) -> u32 {
+    let factory = UnitFactory::new();
+    let mut type_id = 0;
+    while factory.is_registered(type_id) {
+        if factory.get_definition(type_id).name == name {
+            return type_id;
+        }
+        type_id += 1;
+    }
+    panic!("Unit not registered: {}", name);
+}
+
+pub fn sine(unit_id: u32) -> DspOpcode {
+    source(unit_id, 0)
+}
+
+// A unit with no inputs and a single output
+pub fn source(unit_id: u32, type_id: u32) -> DspOpcode {
+    DspOpcode::Unit {
+        unit_id: unit_id,
+        type_id: type_id,
+        input_channels: 0,
+        output_channels: 1,
+        stack_parameters: 0,
+        num_arguments: 0,
+        name: Name::empty()
+    }
+}
+
+// Writes an expression containing a single sine oscillator
+pub fn write_sine_expression(writer: &mut Vec<u8>, expression_id: u32) {
+    writer.write_control_opcode(
+        &ControlOpcode::AddExpression {
+            expression_id: expression_id,
+            num_opcodes: 1,
+            name: Name::empty()
+        }
+    ).unwrap();
+    writer.write_dsp_opcode(&sine(0)).unwrap();
+}
+
+// Ticks a single unit outside of a VM
+pub struct UnitHarness {
+    pub unit: Unit,
+    pub parameters: ParameterMap,
+    pub data: Leap<f32>,
+    pub constants: Constants,
+    pub stack_data: Vec<f32>,
+    // The done action requested during the last tick
+    pub done_action: DoneAction,
+    bus_data: Vec<f32>,
+    bus_map: BusMap,
+    wavetables: WavetableStore
+}
+
+impl UnitHarness {
+    pub fn new(constructor: UnitConstructor, input_channels: u32,
+               output_channels: u32, stack_parameters: u32,
+               arguments: &[f32]) -> UnitHarness {
+        let mut data = Leap::with_capacity(1024);
+        let unit = constructor((0, 0), input_channels, output_channels,
+                               arguments, &mut data);
+        let mut parameters = HashMap::with_capacity(32);
+        unit.construct_parameters(&mut parameters, stack_parameters);
+
+        let mut stack_data = Vec::with_capacity(32 * 64);
+        stack_data.resize(32 * 64, 0f32);
+        let mut bus_data = Vec::with_capacity(4 * 64);
+        bus_data.resize(4 * 64, 0f32);
+
+        UnitHarness {
+            unit: unit,
+            parameters: parameters,
+            data: data,
+            constants: constants(),
+            stack_data: stack_data,
+            done_action: DoneAction::None,
+            bus_data: bus_data,
+            bus_map: HashMap::new(),
+            wavetables: WavetableStore::with_capacity(0, 0)
+        }
+    }
+
+    pub fn set(&mut self, parameter_id: u32, value: f32) {
+        let constants = self.constants;
+        self.parameters.get_mut(&(0, 0, parameter_id)).unwrap()
+                       .set(value, &constants);
+    }
+
+    // Ticks the unit with stack as the top of the stack: its input channels
+    // followed by any parameters read from the stack.  Returns the output.
+    pub fn tick(&mut self, stack: &[f32]) -> ArtResult<&[f32]> {
+        let output_samples = match self.unit.definition.output_rate {
+            Some(Rate::Audio) => {
+                self.unit.layout.output as usize * self.constants.block_size
+            },
+            Some(Rate::Control) => self.unit.layout.output as usize,
+            None => 0
+        };
+
+        {
+            let mut unit_stack = ChannelStack::new(&mut self.stack_data[..]);
+            let index = try!(unit_stack.push(stack.len()));
+            unit_stack.write(index, stack);
+
+            let mut busses = ChannelStack::new(&mut self.bus_data[..]);
+            let mut adjuncts = TickAdjuncts {
+                busses: &mut busses,
+                bus_map: &mut self.bus_map,
+                parameters: &mut self.parameters,
+                data: &mut self.data,
+                gain: 1.0,
+                gain_delta: 0.0,
+                done_action: DoneAction::None,
+                level: 0.0,
+                clock: Clock::new(),
+                wavetables: &self.wavetables
+            };
+            try!(self.unit.tick(&mut unit_stack, &mut adjuncts,
+                                &self.constants));
+            self.done_action = adjuncts.done_action;
+        }
+        Ok(&self.stack_data[..output_samples])
+    }
+}
+
+pub fn assert_near(a: f32, b: f32) {
+    assert!((a - b).abs() < 1e-5, "{} != {}", a, b);
+}
+
+pub fn constants() -> Constants {
+    Constants {
+        input_channels: 2,
+        output_channels: 2,
+        block_size: 64,
+        block_size_inverse: 1.0 / 64.0,
+        audio_rate: 44100.0,
+        audio_rate_inverse: 1.0 / 44100.0,
+        control_rate: 44100.0 / 64.0,
+        control_rate_inverse: 64.0 / 44100.0,
+        seed: 1
+    }
+}
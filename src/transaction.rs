@@ -0,0 +1,369 @@
+use types::{ArtResult, ExpressionMap};
+use errors::ArtError;
+use opcode::ControlOpcode;
+use name::Name;
+
+#[derive(Copy)]
+pub struct TransactionItem {
+    pub opcode: ControlOpcode,
+    // Where the opcode's variable length data was staged.  This is an index
+    // into the expression store for opcodes followed by DSP opcodes, into
+    // the sequencer's event store for AddSequence, into the scheduler's data
+    // store for ScheduleMessage, into the wavetable store for LoadWavetable,
+    // or the position in the message for NoteOn and SetUnitData.
+    pub index: Option<usize>
+}
+
+// Space which has been claimed by the expressions staged in a transaction,
+// but not yet used.  Space freed by removals or replacements earlier in the
+// same message is deliberately not credited back: removed and replaced
+// expressions fade out before they are freed, so their space is still in
+// use when the rest of the message is applied.
+#[derive(Copy)]
+pub struct Reservation {
+    pub expressions: usize,
+    pub units: usize,
+    pub parameters: usize,
+    pub data: usize,
+    pub edges: usize,
+    // Voices which will be stolen to make way for the voices staged in the
+    // transaction.  Each frees an expression when the map is full.
+    pub stolen_voices: usize
+}
+
+impl Reservation {
+    pub fn new() -> Reservation {
+        Reservation {
+            expressions: 0,
+            units: 0,
+            parameters: 0,
+            data: 0,
+            edges: 0,
+            stolen_voices: 0
+        }
+    }
+}
+
+// A message is validated in full and staged in a transaction before any of
+// its opcodes are applied, so either all of them take effect or none do
+pub struct Transaction {
+    pub items: Vec<TransactionItem>,
+    pub reservation: Reservation,
+    // Expressions which have been added (true) or removed (false) so far,
+    // oldest first
+    changes: Vec<(u32, bool)>
+}
+
+impl Transaction {
+    pub fn with_capacity(capacity: usize) -> Transaction {
+        Transaction {
+            items: Vec::with_capacity(capacity),
+            reservation: Reservation::new(),
+            changes: Vec::with_capacity(capacity)
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+        self.changes.clear();
+        self.reservation = Reservation::new();
+    }
+
+    pub fn push(&mut self, opcode: ControlOpcode, index: Option<usize>)
+            -> ArtResult<()> {
+        if self.items.len() == self.items.capacity() {
+            return Err(ArtError::BufferOverflow);
+        }
+
+        self.items.push(
+            TransactionItem {
+                opcode: opcode,
+                index: index
+            }
+        );
+        Ok(())
+    }
+
+    pub fn add_expression(&mut self, id: u32) -> ArtResult<()> {
+        self.change_expression(id, true)
+    }
+
+    pub fn remove_expression(&mut self, id: u32) -> ArtResult<()> {
+        self.change_expression(id, false)
+    }
+
+    fn change_expression(&mut self, id: u32, exists: bool) -> ArtResult<()> {
+        if self.changes.len() == self.changes.capacity() {
+            return Err(ArtError::BufferOverflow);
+        }
+        self.changes.push((id, exists));
+        Ok(())
+    }
+
+    // Whether the expression will exist once the opcodes staged so far have
+    // been applied
+    pub fn expression_exists(&self, id: u32, expressions: &ExpressionMap)
+            -> bool {
+        for &(change_id, exists) in self.changes.iter().rev() {
+            if change_id == id {
+                return exists;
+            }
+        }
+        expressions.contains_key(&id)
+    }
+
+    // Finds the staged opcodes for a template registered in this transaction.
+    // Returns None if the template was unregistered later in the
+    // transaction.
+    pub fn find_template(&self, id: u32) -> Option<Option<(usize, usize)>> {
+        for item in self.items.iter().rev() {
+            match item.opcode {
+                ControlOpcode::RegisterTemplate { template_id,
+                                                  num_opcodes, .. } => {
+                    if template_id == id {
+                        debug_assert!(item.index.is_some());
+                        return Some(Some((item.index.unwrap(),
+                                          num_opcodes as usize)));
+                    }
+                },
+                ControlOpcode::UnregisterTemplate { template_id } => {
+                    if template_id == id {
+                        return Some(None);
+                    }
+                },
+                _ => {}
+            }
+        }
+        None
+    }
+
+    // The number of edges into or out of an expression added in this
+    // transaction
+    pub fn count_edges(&self, id: u32) -> usize {
+        self.items.iter().filter(|item| {
+            match item.opcode {
+                ControlOpcode::AddEdge { from, to } => from == id || to == id,
+                _ => false
+            }
+        }).count()
+    }
+
+    // The polyphony of a template registered in this transaction
+    pub fn find_template_max_voices(&self, id: u32) -> Option<u32> {
+        for item in self.items.iter().rev() {
+            if let ControlOpcode::RegisterTemplate { template_id, max_voices,
+                                                     .. } = item.opcode {
+                if template_id == id {
+                    return Some(max_voices);
+                }
+            }
+        }
+        None
+    }
+
+    // Finds the template mapped to a MIDI channel in this transaction.
+    // Returns Some(None) if the channel was unmapped later in the
+    // transaction.
+    pub fn find_note_mapping(&self, midi_channel: u32) -> Option<Option<u32>> {
+        for item in self.items.iter().rev() {
+            match item.opcode {
+                ControlOpcode::MapNotes { channel, template_id, .. } => {
+                    if channel == midi_channel {
+                        return Some(Some(template_id));
+                    }
+                },
+                ControlOpcode::UnmapNotes { channel } => {
+                    if channel == midi_channel {
+                        return Some(None);
+                    }
+                },
+                _ => {}
+            }
+        }
+        None
+    }
+
+    // Whether the sequence will exist once the opcodes staged so far have
+    // been applied, or None if the transaction doesn't change it
+    pub fn sequence_exists(&self, id: u32) -> Option<bool> {
+        for item in self.items.iter().rev() {
+            match item.opcode {
+                ControlOpcode::AddSequence { sequence_id, .. } => {
+                    if sequence_id == id {
+                        return Some(true);
+                    }
+                },
+                ControlOpcode::RemoveSequence { sequence_id } => {
+                    if sequence_id == id {
+                        return Some(false);
+                    }
+                },
+                _ => {}
+            }
+        }
+        None
+    }
+
+    // Whether the wavetable will exist once the opcodes staged so far have
+    // been applied, or None if the transaction doesn't change it
+    pub fn wavetable_exists(&self, id: u32) -> Option<bool> {
+        for item in self.items.iter().rev() {
+            match item.opcode {
+                ControlOpcode::LoadWavetable { wavetable_id, .. } => {
+                    if wavetable_id == id {
+                        return Some(true);
+                    }
+                },
+                ControlOpcode::RemoveWavetable { wavetable_id } => {
+                    if wavetable_id == id {
+                        return Some(false);
+                    }
+                },
+                _ => {}
+            }
+        }
+        None
+    }
+
+    // Finds the staged opcodes for an expression added in this transaction
+    pub fn find_expression(&self, id: u32) -> Option<(usize, usize)> {
+        for item in self.items.iter().rev() {
+            match item.opcode {
+                ControlOpcode::AddExpression { expression_id,
+                                               num_opcodes, .. } |
+                ControlOpcode::ReplaceExpression { expression_id,
+                                                   num_opcodes, .. } => {
+                    if expression_id == id {
+                        debug_assert!(item.index.is_some());
+                        return Some((item.index.unwrap(),
+                                     num_opcodes as usize));
+                    }
+                },
+                _ => {}
+            }
+        }
+        None
+    }
+
+    // The name given to an expression added in this transaction
+    pub fn expression_name(&self, id: u32) -> Option<Name> {
+        for item in self.items.iter().rev() {
+            if let ControlOpcode::AddExpression { expression_id, name,
+                                                  .. } = item.opcode {
+                if expression_id == id {
+                    return Some(name);
+                }
+            }
+        }
+        None
+    }
+
+    // Finds an expression which was added with the name in this
+    // transaction, and which still has the name and exists once the
+    // opcodes staged so far have been applied
+    pub fn find_named_expression(&self, name: &Name,
+                                 expressions: &ExpressionMap) -> Option<u32> {
+        for item in self.items.iter().rev() {
+            if let ControlOpcode::AddExpression { expression_id,
+                                                  name: added_name,
+                                                  .. } = item.opcode {
+                if added_name == *name &&
+                        self.expression_name(expression_id) == Some(*name) &&
+                        self.expression_exists(expression_id, expressions) {
+                    return Some(expression_id);
+                }
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use errors::ArtError;
+    use opcode::ControlOpcode;
+    use opcode_writer::OpcodeWriter;
+    use expression::Expression;
+    use test_util::{vm, write_sine_expression};
+
+    use super::Transaction;
+
+    #[test]
+    fn push_fails_when_full() {
+        let mut transaction = Transaction::with_capacity(1);
+        let opcode = ControlOpcode::RemoveExpression { expression_id: 0 };
+        transaction.push(opcode, None).unwrap();
+        match transaction.push(opcode, None) {
+            Err(ArtError::BufferOverflow) => {},
+            _ => panic!("Expected overflow")
+        }
+    }
+
+    #[test]
+    fn tracks_expression_changes() {
+        let mut expressions = HashMap::new();
+        expressions.insert(1, Expression::new(1, 0, 0));
+
+        let mut transaction = Transaction::with_capacity(4);
+        transaction.remove_expression(1).unwrap();
+        transaction.add_expression(2).unwrap();
+        assert!(!transaction.expression_exists(1, &expressions));
+        assert!(transaction.expression_exists(2, &expressions));
+
+        transaction.add_expression(1).unwrap();
+        assert!(transaction.expression_exists(1, &expressions));
+
+        transaction.clear();
+        assert!(transaction.expression_exists(1, &expressions));
+        assert!(!transaction.expression_exists(2, &expressions));
+    }
+
+    #[test]
+    fn failed_message_changes_nothing() {
+        let mut vm = vm();
+        let mut byte_code = Vec::new();
+        write_sine_expression(&mut byte_code, 1);
+        vm.process_byte_code(&byte_code[..]).unwrap();
+
+        // The second expression is staged, but the edge to a missing
+        // expression fails the whole message
+        let mut byte_code = Vec::new();
+        write_sine_expression(&mut byte_code, 2);
+        byte_code.write_control_opcode(
+            &ControlOpcode::AddEdge { from: 2, to: 3 }
+        ).unwrap();
+        match vm.process_byte_code(&byte_code[..]) {
+            Err(ArtError::ExpressionNotFound { expression_id: 3 }) => {},
+            _ => panic!("Expected missing expression")
+        }
+
+        assert!(vm.expressions.contains_key(&1));
+        assert!(!vm.expressions.contains_key(&2));
+        assert_eq!(vm.expression_store.len(), 1);
+        assert_eq!(vm.transaction.items.len(), 0);
+    }
+
+    #[test]
+    fn rollback_frees_staged_opcodes() {
+        let mut vm = vm();
+        let capacity = vm.expression_store.capacity();
+
+        // Fail enough messages to fill the store if staged opcodes leaked
+        for _ in range(0, capacity + 1) {
+            let mut byte_code = Vec::new();
+            write_sine_expression(&mut byte_code, 1);
+            byte_code.write_control_opcode(
+                &ControlOpcode::AddEdge { from: 1, to: 2 }
+            ).unwrap();
+            assert!(vm.process_byte_code(&byte_code[..]).is_err());
+            assert_eq!(vm.expression_store.len(), 0);
+        }
+
+        let mut byte_code = Vec::new();
+        write_sine_expression(&mut byte_code, 1);
+        vm.process_byte_code(&byte_code[..]).unwrap();
+        assert!(vm.expressions.contains_key(&1));
+    }
+}
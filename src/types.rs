@@ -1,19 +1,89 @@
+use std::cmp;
+use std::num::Float;
 use std::collections::HashMap;
 use std::sync::mpsc::Receiver;
 
 use errors::ArtError;
+use ring_buffer::RingBufferReceiver;
+use message_buffer::MessageBuffer;
 
 use expression::Expression;
 use unit::Unit;
 use parameter::Parameter;
 use leap::Leap;
 
+pub const BYTE_CODE_SIZE: usize = 1024;
+
+// A chunk of a message.  Messages larger than BYTE_CODE_SIZE are split into
+// several chunks with consecutive sequence numbers, starting from zero.
 #[derive(Copy)]
 pub struct ByteCode {
-    pub data: [u8; 1024],
-    pub size: usize
+    pub data: [u8; BYTE_CODE_SIZE],
+    pub size: usize,
+    pub sequence: u32,
+    pub last: bool
+}
+
+impl ByteCode {
+    pub fn chunks(message: &[u8]) -> Vec<ByteCode> {
+        let mut chunks = Vec::new();
+        let mut start = 0;
+        loop {
+            let end = cmp::min(start + BYTE_CODE_SIZE, message.len());
+            let mut chunk = ByteCode {
+                data: [0u8; BYTE_CODE_SIZE],
+                size: end - start,
+                sequence: chunks.len() as u32,
+                last: end == message.len()
+            };
+            (&mut chunk.data[..end - start]).clone_from_slice(
+                &message[start..end]
+            );
+            chunks.push(chunk);
+
+            if chunk.last {
+                return chunks;
+            }
+            start = end;
+        }
+    }
+}
+
+pub enum ByteCodeReceiver {
+    Channel(Receiver<ByteCode>),
+    RingBuffer(RingBufferReceiver)
+}
+
+impl ByteCodeReceiver {
+    // Reads the next chunk into message, returning the size of the chunk, or
+    // None if there was nothing to read
+    pub fn try_recv(&mut self, message: &mut MessageBuffer)
+            -> ArtResult<Option<usize>> {
+        match *self {
+            ByteCodeReceiver::Channel(ref receiver) => {
+                let byte_code = match receiver.try_recv() {
+                    Ok(byte_code) => byte_code,
+                    Err(_) => return Ok(None)
+                };
+
+                if byte_code.size > BYTE_CODE_SIZE {
+                    return Err(ArtError::InvalidByteCode);
+                }
+
+                try!(
+                    message.push_chunk(&byte_code.data[..byte_code.size],
+                                       byte_code.sequence, byte_code.last)
+                );
+                Ok(Some(byte_code.size))
+            },
+            ByteCodeReceiver::RingBuffer(ref mut receiver) => {
+                // The ring buffer supports variable length messages, so
+                // never needs to split them
+                message.write_complete(|data| receiver.try_recv(data))
+            }
+        }
+    }
 }
-pub type ByteCodeReceiver = Receiver<ByteCode>;
 
 pub type ExpressionId = u32;
 pub type UnitId = u32;
@@ -25,7 +95,8 @@ pub type UnitMap = HashMap<(ExpressionId, UnitId), Unit>;
 pub type ParameterMap = HashMap<(ExpressionId, UnitId, ParameterId), Parameter>;
 pub type BusMap = HashMap<u32, usize>;
 
-pub type UnitConstructor = fn((u32, u32), u32, u32, &mut Leap<f32>) -> Unit;
+pub type UnitConstructor = fn((u32, u32), u32, u32, &[f32], &mut Leap<f32>)
+                             -> Unit;
 
 pub type ArtResult<T> = Result<T, ArtError>;
 
@@ -35,8 +106,97 @@ pub enum Rate {
     Control
 }
 
+// The quietest level reached by decibel curves with a minimum of zero
+pub const MIN_DECIBELS: f32 = -60.0;
+
+#[derive(Copy, Clone, RustcEncodable, Debug, FromPrimitive, PartialEq)]
+pub enum Curve {
+    Linear,
+    Exponential,
+    // Linear in decibels, for amplitudes
+    Decibel
+}
+
+impl Curve {
+    // Maps a value in [0, 1] onto the range [minimum, maximum]
+    pub fn scale(&self, value: f32, minimum: f32, maximum: f32) -> f32 {
+        match *self {
+            Curve::Linear => minimum + (maximum - minimum) * value,
+            Curve::Exponential => minimum * (maximum / minimum).powf(value),
+            Curve::Decibel => {
+                if value <= 0.0 {
+                    return minimum;
+                }
+
+                let low = if minimum > 0.0 {
+                    20.0 * minimum.log10()
+                }
+                else {
+                    MIN_DECIBELS
+                };
+                let high = 20.0 * maximum.log10();
+                10f32.powf((low + (high - low) * value) / 20.0)
+            }
+        }
+    }
+
+    // Exponential curves can't cross or touch zero, and decibel curves are
+    // for levels, so can't be negative
+    pub fn is_valid_range(&self, minimum: f32, maximum: f32) -> bool {
+        match *self {
+            Curve::Linear => true,
+            Curve::Exponential => minimum * maximum > 0.0,
+            Curve::Decibel => minimum >= 0.0 && maximum > 0.0
+        }
+    }
+}
+
 #[derive(Copy, Clone)]
 pub struct StackRecord {
     pub channels: u32,
     pub rate: Rate
 }
+
+#[cfg(test)]
+mod tests {
+    use std::num::Float;
+
+    use super::{Curve, MIN_DECIBELS};
+
+    fn assert_close(a: f32, b: f32) {
+        assert!((a - b).abs() < 1e-4, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn linear_scale() {
+        assert_close(Curve::Linear.scale(0.0, -1.0, 3.0), -1.0);
+        assert_close(Curve::Linear.scale(0.5, -1.0, 3.0), 1.0);
+        assert_close(Curve::Linear.scale(1.0, -1.0, 3.0), 3.0);
+    }
+
+    #[test]
+    fn exponential_scale() {
+        assert_close(Curve::Exponential.scale(0.0, 20.0, 20000.0), 20.0);
+        assert_close(Curve::Exponential.scale(0.5, 100.0, 400.0), 200.0);
+        assert_close(Curve::Exponential.scale(1.0, 20.0, 20000.0), 20000.0);
+    }
+
+    #[test]
+    fn decibel_scale() {
+        assert_close(Curve::Decibel.scale(0.0, 0.0, 1.0), 0.0);
+        assert_close(Curve::Decibel.scale(1.0, 0.0, 1.0), 1.0);
+
+        // Halfway between the minimum level and full scale
+        let halfway = 10f32.powf(MIN_DECIBELS / 40.0);
+        assert_close(Curve::Decibel.scale(0.5, 0.0, 1.0), halfway);
+    }
+
+    #[test]
+    fn valid_ranges() {
+        assert!(Curve::Linear.is_valid_range(-1.0, 1.0));
+        assert!(Curve::Exponential.is_valid_range(-2.0, -1.0));
+        assert!(!Curve::Exponential.is_valid_range(0.0, 1.0));
+        assert!(Curve::Decibel.is_valid_range(0.0, 1.0));
+        assert!(!Curve::Decibel.is_valid_range(-1.0, 1.0));
+    }
+}
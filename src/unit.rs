@@ -1,10 +1,14 @@
 use std::cmp;
+use std::num::{Float, FromPrimitive};
 
 use rustc_serialize::{Encodable, Encoder};
 use types::{ArtResult, Rate, BusMap, ParameterMap};
+use errors::ArtError;
 use constants::Constants;
 
 use channel_stack::ChannelStack;
+use clock::Clock;
+use wavetable::WavetableStore;
 use parameter::{Parameter, ParameterDefinition};
 use leap::Leap;
 
@@ -17,14 +21,16 @@ pub struct Unit {
 }
 
 impl Unit {
-    pub fn construct_parameters(&self, parameters: &mut ParameterMap) {
+    pub fn construct_parameters(&self, parameters: &mut ParameterMap,
+                                stack_parameters: u32) {
         debug_assert!(parameters.len() + self.definition.parameters.len() <=
                       parameters.capacity());
         let (eid, uid) = self.id;
         for (pid, parameter) in
                 self.definition.parameters.iter().enumerate() {
-            parameters.insert((eid, uid, pid as u32),
-                              Parameter::new(parameter));
+            let mut parameter = Parameter::new(parameter);
+            parameter.stacked = pid < 32 && stack_parameters & (1 << pid) != 0;
+            parameters.insert((eid, uid, pid as u32), parameter);
         }
     }
 
@@ -35,13 +41,10 @@ impl Unit {
         }
     }
 
-    pub fn free_data(&self, data: &mut Leap<f32>) {
-        let data_size = match self.definition.data_size {
-            DataSize::Fixed(size) => size,
-            DataSize::None => 0
-        };
+    pub fn free_data(&self, data: &mut Leap<f32>, num_arguments: usize) {
         if let Some(data_index) = self.data_index {
-            data.free(data_index, data_size);
+            data.free(data_index,
+                      self.definition.data_size.size(num_arguments));
         }
     }
 
@@ -64,18 +67,33 @@ impl Unit {
         };
 
         let samples = cmp::max(input_samples, output_samples);
+        let (parameter_samples, stacked_samples) = self.parameter_samples(
+            adjuncts.parameters, constants
+        );
 
+        let stacked_index = try!(stack.pop(stacked_samples));
         let index = try!(stack.pop(input_samples));
         try!(stack.push(output_samples));
 
+        // Move the parameters taken from the stack above the space used by
+        // the block and the other parameters, so they aren't overwritten.
+        // They can overlap, so copy from the end.
+        let scratch_index = index + samples + parameter_samples;
+        if scratch_index + stacked_samples > stack.data.len() {
+            return Err(ArtError::StackOverflow);
+        }
+        for i in range(0, stacked_samples).rev() {
+            stack.data[scratch_index + i] = stack.data[stacked_index + i];
+        }
+
         // Split the stack into the unit half, and half for the parameters
         let (mut unit_stack, mut parameter_stack) = stack.split_at_mut(
             index + samples
         );
 
         let mut block = unit_stack.get_mut(index, samples);
-        try!(self.tick_parameters(&mut parameter_stack, adjuncts,
-                                  constants));
+        try!(self.tick_parameters(&mut parameter_stack, parameter_samples,
+                                  adjuncts, constants));
         try!(
             (self.definition.tick)(self, block, &mut parameter_stack,
                                    adjuncts, constants)
@@ -83,15 +101,34 @@ impl Unit {
         Ok(())
     }
 
+    // The samples taken up by all of the parameters, and by those which are
+    // read from the stack
+    fn parameter_samples(&self, parameters: &ParameterMap,
+                         constants: &Constants) -> (usize, usize) {
+        let (eid, uid) = self.id;
+        let mut samples = 0;
+        let mut stacked_samples = 0;
+        for (pid, definition) in self.definition.parameters.iter()
+                                                           .enumerate() {
+            debug_assert!(parameters.contains_key(&(eid, uid, pid as u32)));
+            let parameter_samples = definition.samples(constants);
+            samples += parameter_samples;
+            if parameters[&(eid, uid, pid as u32)].stacked {
+                stacked_samples += parameter_samples;
+            }
+        }
+        (samples, stacked_samples)
+    }
+
+    // Parameters read from the stack are copied from where they were left
+    // by tick, starting at stacked_index
     fn tick_parameters(&self, stack: &mut ChannelStack,
+                       mut stacked_index: usize,
                        adjuncts: &mut TickAdjuncts,
                        constants: &Constants) -> ArtResult<()> {
         let (eid, uid) = self.id;
         for (pid, parameter) in self.definition.parameters.iter().enumerate() {
-            let samples = match parameter.rate {
-                Rate::Audio => constants.block_size,
-                Rate::Control => 1
-            };
+            let samples = parameter.samples(constants);
 
             let index = try!(stack.push(samples));
             let (_, mut channel) = stack.split_at_mut(index);
@@ -103,7 +140,18 @@ impl Unit {
             let parameter = adjuncts.parameters.get_mut(
                 &(eid, uid, pid as u32)
             ).unwrap();
-            try!(parameter.read(&mut channel, adjuncts.busses, constants));
+
+            if parameter.stacked {
+                let offset = stacked_index - index;
+                for i in range(0, samples) {
+                    channel.data[i] = channel.data[offset + i];
+                }
+                stacked_index += samples;
+                continue;
+            }
+
+            try!(parameter.read(&mut channel, adjuncts.busses,
+                                adjuncts.bus_map, constants));
         }
         Ok(())
     }
@@ -128,7 +176,56 @@ pub struct TickAdjuncts<'a> {
     pub busses: &'a mut ChannelStack<'a>,
     pub bus_map: &'a mut BusMap,
     pub parameters: &'a mut ParameterMap,
-    pub data: &'a mut Leap<f32>
+    pub data: &'a mut Leap<f32>,
+    // The gain of the expression being ticked, and its change per sample
+    pub gain: f32,
+    pub gain_delta: f32,
+    // Set by units to tell the VM what to do with the expression once it has
+    // finished ticking
+    pub done_action: DoneAction,
+    // The peak level written to the busses by the expression, before the
+    // gain is applied
+    pub level: f32,
+    pub clock: Clock,
+    pub wavetables: &'a WavetableStore
+}
+
+impl<'a> TickAdjuncts<'a> {
+    // The most drastic action requested by any unit wins
+    pub fn done(&mut self, action: DoneAction) {
+        if action as u32 > self.done_action as u32 {
+            self.done_action = action;
+        }
+    }
+
+    // Applies the expression's gain to a block which is about to be written
+    // to a bus
+    pub fn apply_gain(&self, block: &mut [f32], channels: usize) {
+        if self.gain == 1.0 && self.gain_delta == 0.0 {
+            return;
+        }
+
+        if channels == 0 {
+            return;
+        }
+
+        let mut gain = self.gain;
+        for frame in block.chunks_mut(channels) {
+            for sample in frame.iter_mut() {
+                *sample *= gain;
+            }
+            gain += self.gain_delta;
+        }
+    }
+
+    pub fn measure_level(&mut self, block: &[f32]) {
+        for sample in block.iter() {
+            let level = sample.abs();
+            if level > self.level {
+                self.level = level;
+            }
+        }
+    }
 }
 
 pub type TickFunction = fn(
@@ -136,12 +233,55 @@ pub type TickFunction = fn(
     adjuncts: &mut TickAdjuncts, constants: &Constants
 ) -> ArtResult<()>;
 
+#[derive(Copy, PartialEq, FromPrimitive, Debug)]
+pub enum DoneAction {
+    None,
+    Pause,
+    Free
+}
+
+impl DoneAction {
+    // Done actions are usually set using a parameter
+    pub fn from_parameter(value: f32) -> DoneAction {
+        if value < 0.0 {
+            return DoneAction::None;
+        }
+        FromPrimitive::from_u32(value.round() as u32).unwrap_or(
+            DoneAction::None
+        )
+    }
+}
+
+// The most arguments which can be passed to a unit's constructor
+pub const MAX_ARGUMENTS: usize = 64;
+
 #[derive(Copy)]
 pub enum DataSize {
     Fixed(usize),
+    // A fixed size, plus one value for each argument
+    Arguments(usize),
     None
 }
 
+impl DataSize {
+    pub fn size(&self, num_arguments: usize) -> usize {
+        match *self {
+            DataSize::Fixed(size) => size,
+            DataSize::Arguments(size) => size + num_arguments,
+            DataSize::None => 0
+        }
+    }
+
+    // The values which hold the unit's state, rather than a copy of its
+    // arguments
+    pub fn state_size(&self) -> usize {
+        match *self {
+            DataSize::Fixed(size) | DataSize::Arguments(size) => size,
+            DataSize::None => 0
+        }
+    }
+}
+
 #[derive(Copy)]
 pub struct UnitDefinition {
     pub name: &'static str,
@@ -193,3 +333,181 @@ impl Encodable for UnitDefinition {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use types::{ArtResult, Rate, Curve};
+    use errors::ArtError;
+    use constants::Constants;
+    use parameter::{ParameterDefinition, ParameterMode, UNBOUNDED};
+    use channel_stack::ChannelStack;
+    use leap::Leap;
+    use dsp::parameter::parameter::ParameterAr;
+    use test_util::UnitHarness;
+
+    use super::{Unit, UnitDefinition, UnitKind, ChannelLayout, DataSize,
+                TickAdjuncts};
+
+    static PROBE_PARAMETERS: [ParameterDefinition; 4] = [
+        ParameterDefinition {
+            name: "first",
+            default: 0f32,
+            rate: Rate::Control,
+            mode: ParameterMode::Normal,
+            minimum: -UNBOUNDED,
+            maximum: UNBOUNDED,
+            clamped: false,
+            unit: "",
+            curve: Curve::Linear,
+            description: ""
+        },
+        ParameterDefinition {
+            name: "second",
+            default: 0f32,
+            rate: Rate::Audio,
+            mode: ParameterMode::Interpolate,
+            minimum: -UNBOUNDED,
+            maximum: UNBOUNDED,
+            clamped: false,
+            unit: "",
+            curve: Curve::Linear,
+            description: ""
+        },
+        ParameterDefinition {
+            name: "third",
+            default: 0f32,
+            rate: Rate::Audio,
+            mode: ParameterMode::Interpolate,
+            minimum: -UNBOUNDED,
+            maximum: UNBOUNDED,
+            clamped: false,
+            unit: "",
+            curve: Curve::Linear,
+            description: ""
+        },
+        ParameterDefinition {
+            name: "fourth",
+            default: 0f32,
+            rate: Rate::Control,
+            mode: ParameterMode::Normal,
+            minimum: -UNBOUNDED,
+            maximum: UNBOUNDED,
+            clamped: false,
+            unit: "",
+            curve: Curve::Linear,
+            description: ""
+        }
+    ];
+
+    // The samples taken up by the probe's parameters
+    const PROBE_SAMPLES: usize = 1 + 64 + 64 + 1;
+
+    // Outputs its parameter channels, one per control rate output channel
+    static PROBE: UnitDefinition = UnitDefinition {
+        name: "probe",
+        kind: UnitKind::Processor,
+        input_rate: Some(Rate::Control),
+        output_rate: Some(Rate::Control),
+        default_layout: ChannelLayout {
+            input: 1,
+            output: PROBE_SAMPLES as u32
+        },
+        parameters: &PROBE_PARAMETERS,
+        tick: probe_tick,
+        data_size: DataSize::None
+    };
+
+    fn probe(id: (u32, u32), input_channels: u32, output_channels: u32,
+             _: &[f32], _: &mut Leap<f32>) -> Unit {
+        Unit {
+            definition: &PROBE,
+            id: id,
+            layout: ChannelLayout {
+                input: input_channels,
+                output: output_channels
+            },
+            data_index: None
+        }
+    }
+
+    fn probe_tick(_: &mut Unit, block: &mut[f32],
+                  parameters: &mut ChannelStack, _: &mut TickAdjuncts,
+                  _: &Constants) -> ArtResult<()> {
+        for i in range(0, block.len()) {
+            block[i] = parameters.data[i];
+        }
+        Ok(())
+    }
+
+    fn probe_harness(stack_parameters: u32) -> UnitHarness {
+        UnitHarness::new(probe, 1, PROBE_SAMPLES as u32, stack_parameters,
+                         &[])
+    }
+
+    #[test]
+    fn stacked_parameter_survives_wider_output() {
+        // The stacked frequency sits where the two channel output block is
+        // written, so it has to be moved out of the way first
+        let mut harness = UnitHarness::new(ParameterAr::new, 0, 2, 1, &[]);
+        let ramp: Vec<f32> = range(0, 64).map(|i| i as f32).collect();
+        let output = harness.tick(&ramp[..]).unwrap();
+        for i in range(0, 64) {
+            assert_eq!(output[2 * i], i as f32);
+            assert_eq!(output[2 * i + 1], i as f32);
+        }
+    }
+
+    #[test]
+    fn stacked_parameters_are_taken_in_order() {
+        let mut harness = probe_harness(1 << 0 | 1 << 3);
+        // The input, followed by the first and fourth parameters
+        let output = harness.tick(&[0.0, 1.0, 4.0]).unwrap();
+        assert_eq!(output[0], 1.0);
+        assert!(output[1..PROBE_SAMPLES - 1].iter().all(|x| *x == 0.0));
+        assert_eq!(output[PROBE_SAMPLES - 1], 4.0);
+    }
+
+    #[test]
+    fn stacked_audio_parameter_next_to_interpolated() {
+        let mut harness = probe_harness(1 << 2);
+        harness.set(1, 2.0);
+        harness.set(3, 3.0);
+
+        let mut stack = vec![0.0];
+        stack.extend(range(0, 64).map(|i| 100.0 + i as f32));
+        harness.tick(&stack[..]).unwrap();
+        // The second parameter has finished interpolating after a block
+        let output = harness.tick(&stack[..]).unwrap();
+        assert_eq!(output[0], 0.0);
+        for i in range(0, 64) {
+            assert_eq!(output[1 + i], 2.0);
+            assert_eq!(output[65 + i], 100.0 + i as f32);
+        }
+        assert_eq!(output[129], 3.0);
+    }
+
+    #[test]
+    fn stacked_parameter_overflows_when_scratch_does_not_fit() {
+        // The block, the parameters and the scratch copy of the stacked
+        // parameter take 128 + 64 + 64 samples
+        let mut harness = UnitHarness::new(ParameterAr::new, 0, 2, 1, &[]);
+        harness.stack_data.truncate(255);
+        match harness.tick(&[0f32; 64]) {
+            Err(ArtError::StackOverflow) => {},
+            _ => panic!("Expected stack overflow")
+        }
+
+        let mut harness = UnitHarness::new(ParameterAr::new, 0, 2, 1, &[]);
+        harness.stack_data.truncate(256);
+        assert!(harness.tick(&[0f32; 64]).is_ok());
+    }
+
+    #[test]
+    fn state_size_leaves_out_arguments() {
+        let size = DataSize::Arguments(6);
+        assert_eq!(size.size(9), 15);
+        assert_eq!(size.state_size(), 6);
+        assert_eq!(DataSize::Fixed(3).state_size(), 3);
+        assert_eq!(DataSize::None.state_size(), 0);
+    }
+}
@@ -6,13 +6,26 @@ use leap::Leap;
 
 use dsp::oscillators::sine::{self, SineAr, SineKr};
 use dsp::oscillators::saw::{self, SawAr, SawKr};
+use dsp::oscillators::pulse::{self, PulseAr, PulseKr};
+use dsp::oscillators::triangle::{self, TriangleAr, TriangleKr};
+use dsp::oscillators::wavetable::{self, WavetableAr};
+use dsp::oscillators::fm_operator::{self, FmOperatorAr};
 use dsp::bus::bus_in::{self, BusInAr, BusInKr};
 use dsp::bus::bus_out::{self, BusOutAr, BusOutKr};
 use dsp::parameter::parameter::{self, ParameterAr, ParameterKr};
 use dsp::parameter::parameter_writer::{self, ParameterWriterAr,
                                        ParameterWriterKr};
 use dsp::envelopes::ar_envelope::{self, ArEnvelopeAr, ArEnvelopeKr};
+use dsp::envelopes::adsr_envelope::{self, AdsrEnvelopeAr, AdsrEnvelopeKr};
+use dsp::envelopes::breakpoint_envelope::{self, BreakpointEnvelopeAr,
+                                          BreakpointEnvelopeKr};
 use dsp::rate::kr_to_ar::{self, KrToAr};
+use dsp::clock::clock::{self, ClockAr, ClockKr};
+use dsp::sequencers::step_sequencer::{self, StepSequencer};
+use dsp::noise::white_noise::{self, WhiteNoiseAr, WhiteNoiseKr};
+use dsp::noise::pink_noise::{self, PinkNoiseAr, PinkNoiseKr};
+use dsp::noise::brown_noise::{self, BrownNoiseAr, BrownNoiseKr};
+use dsp::noise::lf_noise::{self, LfNoiseAr, LfNoiseKr};
 
 #[derive(Copy)]
 pub struct UnitFactoryItem {
@@ -37,6 +50,12 @@ impl UnitFactory {
         factory.register(&sine::DEFINITION_KR, SineKr::new);
         factory.register(&saw::DEFINITION_AR, SawAr::new);
         factory.register(&saw::DEFINITION_KR, SawKr::new);
+        factory.register(&pulse::DEFINITION_AR, PulseAr::new);
+        factory.register(&pulse::DEFINITION_KR, PulseKr::new);
+        factory.register(&triangle::DEFINITION_AR, TriangleAr::new);
+        factory.register(&triangle::DEFINITION_KR, TriangleKr::new);
+        factory.register(&wavetable::DEFINITION_AR, WavetableAr::new);
+        factory.register(&fm_operator::DEFINITION_AR, FmOperatorAr::new);
         factory.register(&bus_in::DEFINITION_AR, BusInAr::new);
         factory.register(&bus_in::DEFINITION_KR, BusInKr::new);
         factory.register(&bus_out::DEFINITION_AR, BusOutAr::new);
@@ -50,6 +69,23 @@ impl UnitFactory {
         factory.register(&ar_envelope::DEFINITION_AR, ArEnvelopeAr::new);
         factory.register(&ar_envelope::DEFINITION_KR, ArEnvelopeKr::new);
         factory.register(&kr_to_ar::DEFINITION, KrToAr::new);
+        factory.register(&clock::DEFINITION_AR, ClockAr::new);
+        factory.register(&clock::DEFINITION_KR, ClockKr::new);
+        factory.register(&step_sequencer::DEFINITION, StepSequencer::new);
+        factory.register(&adsr_envelope::DEFINITION_AR, AdsrEnvelopeAr::new);
+        factory.register(&adsr_envelope::DEFINITION_KR, AdsrEnvelopeKr::new);
+        factory.register(&breakpoint_envelope::DEFINITION_AR,
+                         BreakpointEnvelopeAr::new);
+        factory.register(&breakpoint_envelope::DEFINITION_KR,
+                         BreakpointEnvelopeKr::new);
+        factory.register(&white_noise::DEFINITION_AR, WhiteNoiseAr::new);
+        factory.register(&white_noise::DEFINITION_KR, WhiteNoiseKr::new);
+        factory.register(&pink_noise::DEFINITION_AR, PinkNoiseAr::new);
+        factory.register(&pink_noise::DEFINITION_KR, PinkNoiseKr::new);
+        factory.register(&brown_noise::DEFINITION_AR, BrownNoiseAr::new);
+        factory.register(&brown_noise::DEFINITION_KR, BrownNoiseKr::new);
+        factory.register(&lf_noise::DEFINITION_AR, LfNoiseAr::new);
+        factory.register(&lf_noise::DEFINITION_KR, LfNoiseKr::new);
         factory
     }
 
@@ -69,11 +105,11 @@ impl UnitFactory {
 
     pub fn create(&mut self, id: (u32, u32), type_id: u32,
                   input_channels: u32, output_channels: u32,
-                  data: &mut Leap<f32>) -> Unit {
+                  arguments: &[f32], data: &mut Leap<f32>) -> Unit {
         debug_assert!(self.is_registered(type_id));
         (self.units[type_id as usize].constructor)(id, input_channels,
                                                    output_channels,
-                                                   data)
+                                                   arguments, data)
     }
 
     pub fn get_definition(&self, type_id: u32) -> &UnitDefinition {
@@ -83,3 +119,98 @@ impl UnitFactory {
 }
 
 
+
+#[cfg(test)]
+mod tests {
+    use std::f32::consts::PI_2;
+
+    use types::Curve;
+    use parameter::ParameterDefinition;
+
+    use super::UnitFactory;
+
+    fn find_parameter<'a>(factory: &'a UnitFactory, unit: &str,
+                          parameter: &str) -> &'a ParameterDefinition {
+        let mut type_id = 0;
+        while factory.is_registered(type_id) {
+            let definition = factory.get_definition(type_id);
+            if definition.name == unit {
+                return definition.parameters.iter()
+                                 .find(|p| p.name == parameter)
+                                 .unwrap();
+            }
+            type_id += 1;
+        }
+        panic!("Unit not registered: {}", unit);
+    }
+
+    #[test]
+    fn parameter_defaults_are_within_range() {
+        let factory = UnitFactory::new();
+        let mut type_id = 0;
+        while factory.is_registered(type_id) {
+            let definition = factory.get_definition(type_id);
+            for parameter in definition.parameters.iter() {
+                assert!(parameter.minimum <= parameter.maximum);
+                assert!(!parameter.clamped ||
+                            parameter.clamp(parameter.default) ==
+                                parameter.default,
+                        "{}.{}", definition.name, parameter.name);
+                assert!(parameter.curve.is_valid_range(parameter.minimum,
+                                                       parameter.maximum),
+                        "{}.{}", definition.name, parameter.name);
+            }
+            type_id += 1;
+        }
+    }
+
+    #[test]
+    fn oscillator_frequency_is_audible_range() {
+        let factory = UnitFactory::new();
+        let units = ["sine_ar", "saw_ar", "pulse_ar", "triangle_ar",
+                     "wavetable_ar", "fm_operator_ar"];
+        for unit in units.iter() {
+            let frequency = find_parameter(&factory, *unit, "frequency");
+            assert_eq!(frequency.minimum, 20.0);
+            assert_eq!(frequency.maximum, 20000.0);
+            assert_eq!(frequency.unit, "Hz");
+            assert_eq!(frequency.curve, Curve::Exponential);
+            assert!(!frequency.clamped);
+        }
+    }
+
+    #[test]
+    fn oscillator_phase_is_one_cycle() {
+        let factory = UnitFactory::new();
+        let units = ["saw_ar", "saw_kr", "pulse_ar", "pulse_kr",
+                     "triangle_ar", "triangle_kr", "wavetable_ar"];
+        for unit in units.iter() {
+            let phase = find_parameter(&factory, *unit, "phase");
+            assert_eq!(phase.minimum, 0.0);
+            assert_eq!(phase.maximum, 1.0);
+            assert_eq!(phase.unit, "cycles");
+            assert_eq!(phase.curve, Curve::Linear);
+            assert!(!phase.clamped);
+        }
+
+        for unit in ["sine_ar", "sine_kr"].iter() {
+            let phase = find_parameter(&factory, *unit, "phase");
+            assert_eq!(phase.minimum, 0.0);
+            assert_eq!(phase.maximum, PI_2);
+            assert_eq!(phase.unit, "rad");
+            assert!(!phase.clamped);
+        }
+    }
+
+    #[test]
+    fn unclamped_parameters_accept_values_outside_range() {
+        let factory = UnitFactory::new();
+        let frequency = find_parameter(&factory, "sine_ar", "frequency");
+        assert_eq!(frequency.clamp(-440.0), -440.0);
+        assert_eq!(frequency.clamp(30000.0), 30000.0);
+
+        let seed = find_parameter(&factory, "lf_noise_ar", "seed");
+        assert!(seed.clamped);
+        assert_eq!(seed.clamp(seed.maximum + 1.0), seed.maximum);
+    }
+}
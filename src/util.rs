@@ -29,4 +29,134 @@ pub fn user_data_dir() -> Option<PathBuf> {
                                             "User Data"]))
 }
 
+// Interpolates from start to end as position moves from 0 to 1.  A curve of
+// 0 is linear, positive curves start slowly and negative curves start
+// quickly, in the same way as an exponential segment.
+pub fn interpolate_curve(start: f32, end: f32, position: f32, curve: f32)
+        -> f32 {
+    if curve.abs() < 0.001 {
+        return start + (end - start) * position;
+    }
 
+    let scale = (1.0 - (curve * position).exp()) / (1.0 - curve.exp());
+    start + (end - start) * scale
+}
+
+// The PolyBLEP residual for a step of -2 at t = 0, where t is the position
+// through the cycle and dt is the distance moved per sample.  Subtracting it
+// from a naive ramp removes most of the aliasing caused by the step.
+pub fn poly_blep(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let x = t / dt;
+        2.0 * x - x * x - 1.0
+    }
+    else if t > 1.0 - dt {
+        let x = (t - 1.0) / dt;
+        x * x + 2.0 * x + 1.0
+    }
+    else {
+        0.0
+    }
+}
+
+// The integral of poly_blep, which smooths a change in slope of 2 per sample
+// at t = 0
+pub fn poly_blamp(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let x = t / dt - 1.0;
+        -x * x * x / 3.0
+    }
+    else if t > 1.0 - dt {
+        let x = (t - 1.0) / dt + 1.0;
+        x * x * x / 3.0
+    }
+    else {
+        0.0
+    }
+}
+
+// The fraction of the last sample which has passed since a signal rose
+// through zero, moving from last to current.  The signal is treated as a
+// straight line between the two.
+pub fn edge_fraction(last: f32, current: f32) -> f32 {
+    current / (current - last)
+}
+
+// Copies a single channel held in the first samples of the block into each
+// of the interleaved channels.  Works backwards so the single channel is
+// never overwritten before it has been read.
+pub fn expand_channels(block: &mut [f32], channels: usize, samples: usize) {
+    for i in range(0, samples).rev() {
+        let value = block[i];
+        for j in range(0, channels) {
+            block[i * channels + j] = value;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_util::assert_near;
+
+    use super::{modulo, poly_blep, poly_blamp, edge_fraction,
+                interpolate_curve, expand_channels};
+
+    #[test]
+    fn modulo_wraps_negative_values() {
+        assert_near(modulo(-0.25f32, 1.0), 0.75);
+        assert_near(modulo(2.5f32, 1.0), 0.5);
+    }
+
+    #[test]
+    fn poly_blep_is_zero_away_from_the_step() {
+        let dt = 0.1;
+        assert_eq!(poly_blep(0.5, dt), 0.0);
+        assert_near(poly_blep(dt, dt), 0.0);
+        assert_near(poly_blep(1.0 - dt, dt), 0.0);
+    }
+
+    #[test]
+    fn poly_blep_closes_the_saw_step() {
+        // A naive ramp jumps from 1 to -1 as t wraps; with the residual
+        // subtracted both sides of the wrap meet at 0
+        let dt = 0.01;
+        let saw = |t: f32| 2.0 * t - 1.0 - poly_blep(t, dt);
+        assert_near(saw(0.0), 0.0);
+        assert_near(saw(1.0 - 1e-7), 0.0);
+    }
+
+    #[test]
+    fn poly_blamp_is_continuous() {
+        let dt = 0.1;
+        assert_near(poly_blamp(0.0, dt), 1.0 / 3.0);
+        assert_near(poly_blamp(1.0 - 1e-7, dt), 1.0 / 3.0);
+        assert_near(poly_blamp(dt, dt), 0.0);
+        assert_near(poly_blamp(1.0 - dt, dt), 0.0);
+        assert_eq!(poly_blamp(0.5, dt), 0.0);
+    }
+
+    #[test]
+    fn edge_fraction_finds_the_crossing() {
+        assert_near(edge_fraction(-1.0, 1.0), 0.5);
+        assert_near(edge_fraction(-3.0, 1.0), 0.25);
+        assert_near(edge_fraction(0.0, 1.0), 1.0);
+    }
+
+    #[test]
+    fn interpolate_curve_hits_both_ends() {
+        for &curve in [-4.0, 0.0, 4.0].iter() {
+            assert_near(interpolate_curve(1.0, 3.0, 0.0, curve), 1.0);
+            assert_near(interpolate_curve(1.0, 3.0, 1.0, curve), 3.0);
+        }
+        assert_near(interpolate_curve(1.0, 3.0, 0.5, 0.0), 2.0);
+        assert!(interpolate_curve(0.0, 1.0, 0.5, 4.0) < 0.5);
+        assert!(interpolate_curve(0.0, 1.0, 0.5, -4.0) > 0.5);
+    }
+
+    #[test]
+    fn expand_channels_interleaves() {
+        let mut block = [1.0, 2.0, 3.0, 0.0, 0.0, 0.0];
+        expand_channels(&mut block, 2, 3);
+        assert_eq!(block, [1.0, 1.0, 2.0, 2.0, 3.0, 3.0]);
+    }
+}
@@ -1,9 +1,10 @@
 use types::{ArtResult, StackRecord, Rate, ExpressionMap, UnitMap, ParameterMap};
 use errors::ArtError;
 use opcode::DspOpcode;
-use unit::{UnitDefinition, DataSize};
+use unit::{UnitDefinition, DataSize, MAX_ARGUMENTS};
 use unit_factory::UnitFactory;
 use leap::Leap;
+use transaction::Reservation;
 
 pub struct ExpressionValidator;
 
@@ -12,27 +13,67 @@ impl ExpressionValidator {
                     stack_record: &mut Vec<StackRecord>,
                     unit_factory: &UnitFactory, expression_map: &ExpressionMap,
                     unit_map: &UnitMap, parameter_map: &ParameterMap,
-                    data: &Leap<f32>)
+                    data: &Leap<f32>, reservation: &mut Reservation)
             -> ArtResult<()> {
         try!(
-            ExpressionValidator::validate_expression_count(1, expression_map)
+            ExpressionValidator::validate_expression_count(
+                reservation.expressions + 1, reservation.stolen_voices,
+                expression_map
+            )
         );
+        try!(
+            ExpressionValidator::validate_unit_names(index, num_opcodes,
+                                                     store)
+        );
+
+        stack_record.clear();
 
         let mut unit_count = 0;
         let mut parameter_count = 0;
+        let mut data_count = 0;
+        // The number of Argument opcodes still expected by the last unit
+        let mut arguments = 0;
 
         for opcode in store.iter(index).take(num_opcodes) {
+            match opcode {
+                &DspOpcode::Argument { .. } => {
+                    if arguments == 0 {
+                        return Err(ArtError::InvalidByteCode);
+                    }
+                    arguments -= 1;
+                    continue;
+                },
+                _ => {
+                    if arguments != 0 {
+                        return Err(ArtError::InvalidByteCode);
+                    }
+                }
+            }
+
             match opcode {
                 &DspOpcode::Unit { type_id, input_channels,
-                                   output_channels, .. } => {
+                                   output_channels, stack_parameters,
+                                   num_arguments, .. } => {
                     try!(
                         UnitValidator::validate_type(type_id, unit_factory)
                     );
 
+                    let num_arguments = num_arguments as usize;
+                    if num_arguments > MAX_ARGUMENTS {
+                        return Err(ArtError::InvalidByteCode);
+                    }
+                    arguments = num_arguments;
+
                     let definition = unit_factory.get_definition(type_id);
                     try!(
                         UnitValidator::validate_data(
-                            definition, data
+                            definition, num_arguments, data,
+                            reservation.data + data_count
+                        )
+                    );
+                    try!(
+                        UnitValidator::validate_stack_parameters(
+                            stack_parameters, definition, stack_record
                         )
                     );
                     try!(
@@ -43,6 +84,7 @@ impl ExpressionValidator {
                     );
                     unit_count += 1;
                     parameter_count += definition.parameters.len();
+                    data_count += definition.data_size.size(num_arguments);
                 },
                 &DspOpcode::Add { channels, rate } |
                 &DspOpcode::Multiply { channels, rate } => {
@@ -50,27 +92,66 @@ impl ExpressionValidator {
                         OperatorValidator::validate_stack(channels, rate,
                                                           stack_record)
                     );
-                }
+                },
+                &DspOpcode::Argument { .. } => {}
             }
         }
 
+        if arguments != 0 {
+            return Err(ArtError::InvalidByteCode);
+        }
+
         try!(
-            ExpressionValidator::validate_unit_count(unit_count, unit_map)
+            ExpressionValidator::validate_unit_count(
+                reservation.units + unit_count, unit_map
+            )
         );
         try!(
-            ExpressionValidator::validate_parameter_count(parameter_count,
-                                                          parameter_map)
+            ExpressionValidator::validate_parameter_count(
+                reservation.parameters + parameter_count, parameter_map
+            )
         );
+
+        reservation.expressions += 1;
+        reservation.units += unit_count;
+        reservation.parameters += parameter_count;
+        reservation.data += data_count;
         Ok(())
     }
 }
 
 impl ExpressionValidator {
+    fn validate_unit_names(index: usize, num_opcodes: usize,
+                           store: &Leap<DspOpcode>) -> ArtResult<()> {
+        let opcodes = store.iter(index).take(num_opcodes).enumerate();
+        for (position, opcode) in opcodes {
+            let name = match opcode {
+                &DspOpcode::Unit { name, .. } if !name.is_empty() => name,
+                _ => continue
+            };
+
+            let duplicate = store.iter(index).take(position).any(|opcode| {
+                match opcode {
+                    &DspOpcode::Unit { name: other, .. } => other == name,
+                    _ => false
+                }
+            });
+
+            if duplicate {
+                return Err(ArtError::DuplicateName { name: name });
+            }
+        }
+        Ok(())
+    }
+
     // Should be generic
-    fn validate_expression_count(expression_count: usize,
+    // Stolen voices are removed straight away when the map is full, so each
+    // makes space for another expression
+    fn validate_expression_count(expression_count: usize, stolen: usize,
                                  expression_map: &ExpressionMap)
            -> ArtResult<()> {
-        if expression_map.len() + expression_count > expression_map.capacity() {
+        if expression_map.len() + expression_count >
+                expression_map.capacity() + stolen {
             return Err(
                 ArtError::BufferOverflow
             );
@@ -113,11 +194,13 @@ impl UnitValidator {
         Ok(())
     }
 
-    fn validate_data(definition: &UnitDefinition, data: &Leap<f32>)
+    fn validate_data(definition: &UnitDefinition, num_arguments: usize,
+                     data: &Leap<f32>, reserved: usize)
             -> ArtResult<()> {
         match definition.data_size {
-            DataSize::Fixed(size) => {
-                if data.len() + size <= data.capacity() {
+            DataSize::Fixed(_) | DataSize::Arguments(_) => {
+                let size = definition.data_size.size(num_arguments);
+                if data.len() + reserved + size <= data.capacity() {
                     Ok(())
                 }
                 else {
@@ -128,6 +211,35 @@ impl UnitValidator {
         }
     }
 
+    // Parameters read from the stack are pushed after the unit's input, in
+    // order, so are popped last first.  Each takes a single channel at the
+    // parameter's rate.
+    fn validate_stack_parameters(stack_parameters: u32,
+                                 definition: &UnitDefinition,
+                                 stack_record: &mut Vec<StackRecord>)
+            -> ArtResult<()> {
+        let num_parameters = definition.parameters.len();
+        if num_parameters < 32 && stack_parameters >> num_parameters != 0 {
+            return Err(ArtError::InvalidByteCode);
+        }
+
+        for (pid, parameter) in definition.parameters.iter().enumerate()
+                                                            .rev() {
+            if pid >= 32 || stack_parameters & (1 << pid) == 0 {
+                continue;
+            }
+
+            if stack_record.len() == 0 {
+                return Err(ArtError::StackUnderflow);
+            }
+
+            let record = stack_record.pop().unwrap();
+            try!(UnitValidator::validate_channels(1, record.channels));
+            try!(UnitValidator::validate_rate(parameter.rate, record.rate));
+        }
+        Ok(())
+    }
+
     fn validate_stack(input_channels: u32, output_channels: u32,
                       definition: &UnitDefinition,
                       stack_record: &mut Vec<StackRecord>) -> ArtResult<()> {
@@ -5,6 +5,9 @@ use types::{ArtResult, ByteCodeReceiver};
 use options::Options;
 use device::{Device, Stream};
 use vm_inner::VmInner;
+use ring_buffer::RingBufferReceiver;
+#[cfg(feature = "realtime_audit")]
+use realtime_audit;
 
 pub struct Vm {
     inner: VmInner,
@@ -28,6 +31,12 @@ impl Vm {
         Ok(())
     }
 
+    // The receiving end of the channel which the VM uses to send
+    // notifications back to the client.  Can only be taken once.
+    pub fn output_channel(&mut self) -> Option<RingBufferReceiver> {
+        self.inner.output_receiver.take()
+    }
+
     pub fn start(&mut self) -> ArtResult<Stream> {
         let _ = self.inner.write_info_file();
         let constants = self.inner.constants;
@@ -42,5 +51,9 @@ impl Vm {
 impl Drop for Vm {
     fn drop(&mut self) {
         Device::uninit().unwrap();
+
+        // The stream has stopped, so nothing else is recording violations
+        #[cfg(feature = "realtime_audit")]
+        realtime_audit::report();
     }
 }
@@ -1,4 +1,5 @@
 use std::mem;
+use std::cmp;
 use std::io::{self, Cursor, Write};
 use std::fs::{create_dir_all, File, PathExt};
 use std::collections::HashMap;
@@ -9,24 +10,45 @@ use portaudio::stream::{StreamCallbackResult, StreamTimeInfo,
                         StreamCallbackFlags};
 
 use util;
+use protocol;
+#[cfg(feature = "realtime_audit")]
+use realtime_audit;
 use types::{ByteCodeReceiver, UnitMap, ExpressionMap, ParameterMap, BusMap,
-            StackRecord, ArtResult};
-use unit::TickAdjuncts;
+            StackRecord, ArtResult, BYTE_CODE_SIZE};
+use unit::{TickAdjuncts, DoneAction};
+use parameter::Parameter;
 use errors::ArtError;
 use options::Options;
 use opcode::{ControlOpcode, DspOpcode};
 use opcode_reader::OpcodeReader;
 use unit_factory::UnitFactory;
 use channel_stack::ChannelStack;
+use message_buffer::MessageBuffer;
 use graph::Graph;
-use expression::{Expression, ExpressionState};
+use expression::{Expression, ExpressionState, INTERNAL_ID_FLAG,
+                 is_internal_id};
+use ring_buffer::{self, RingBufferSender, RingBufferReceiver, HEADER_SIZE};
+use notification::{Notification, NotificationWriter, MAX_NOTIFICATION_SIZE};
 use leap::Leap;
 use expression_store::ExpressionStore;
 use validator::ExpressionValidator;
+use transaction::{Transaction, TransactionItem};
+use voice_allocator::{VoiceAllocator, Template, NO_GATE};
+use sequencer::{Sequencer, Sequence};
+use clock::Clock;
+use scheduler::Scheduler;
+use wavetable::{WavetableStore, Wavetable};
+use midi::{self, MidiMap, MidiMessage, ControllerMapping, NUM_CHANNELS,
+           NUM_CONTROLLERS};
 use constants::Constants;
+use name::Name;
+use snapshot::Snapshot;
 
 pub struct VmInner {
     pub input_channel: ByteCodeReceiver,
+    pub message: MessageBuffer,
+    pub output_channel: RingBufferSender,
+    pub output_receiver: Option<RingBufferReceiver>,
     pub constants: Constants,
     pub unit_factory: UnitFactory,
     pub expressions: ExpressionMap,
@@ -39,7 +61,16 @@ pub struct VmInner {
     pub stack_data: Vec<f32>,
     pub bus_data: Vec<f32>,
     pub unit_data: Leap<f32>,
-    pub stack_record: Vec<StackRecord>
+    pub stack_record: Vec<StackRecord>,
+    pub transaction: Transaction,
+    pub voice_allocator: VoiceAllocator,
+    pub midi_map: MidiMap,
+    pub sequencer: Sequencer,
+    pub clock: Clock,
+    pub scheduler: Scheduler,
+    pub wavetables: WavetableStore,
+    pub snapshot: Snapshot,
+    pub next_internal_id: u32
 }
 
 impl VmInner {
@@ -51,8 +82,15 @@ impl VmInner {
         let mut bus_data = Vec::with_capacity(options.bus_stack_size);
         bus_data.resize(options.bus_stack_size, 0f32);
 
+        let (output_channel, output_receiver) = ring_buffer::ring_buffer(
+            options.output_buffer_size
+        );
+
         VmInner {
             input_channel: input_channel,
+            message: MessageBuffer::with_capacity(options.max_message_size),
+            output_channel: output_channel,
+            output_receiver: Some(output_receiver),
             constants: Constants {
                 input_channels: options.input_channels,
                 output_channels: options.output_channels,
@@ -63,7 +101,8 @@ impl VmInner {
                 control_rate: options.sample_rate as f32 /
                               options.block_size as f32,
                 control_rate_inverse: options.block_size as f32 /
-                                      options.sample_rate as f32
+                                      options.sample_rate as f32,
+                seed: options.seed
             },
             unit_factory: UnitFactory::new(),
             expression_store: Leap::with_capacity(
@@ -90,13 +129,48 @@ impl VmInner {
             unit_data: Leap::with_capacity(1024),
             stack_record: Vec::with_capacity(
                 options.max_stack_depth as usize
-            )
+            ),
+            transaction: Transaction::with_capacity(
+                options.max_transaction_opcodes as usize
+            ),
+            voice_allocator: VoiceAllocator::with_capacity(
+                options.max_templates as usize,
+                options.max_expressions as usize
+            ),
+            midi_map: MidiMap::with_capacity(
+                options.max_midi_mappings as usize
+            ),
+            sequencer: Sequencer::with_capacity(
+                options.max_sequences as usize,
+                options.max_sequence_events as usize
+            ),
+            clock: Clock::new(),
+            scheduler: Scheduler::with_capacity(
+                options.max_scheduled_messages as usize,
+                options.schedule_size,
+                options.max_message_size
+            ),
+            wavetables: WavetableStore::with_capacity(
+                options.max_wavetables as usize,
+                options.wavetable_size
+            ),
+            // A snapshot is sent back as a single message, so it is limited
+            // to the size of a message
+            snapshot: Snapshot::with_capacity(
+                options.max_message_size - protocol::HEADER_SIZE
+            ),
+            next_internal_id: 0
         }
     }
 
     fn tick(&mut self, adc_block: &[f32], dac_block: &mut [f32])
             -> StreamCallbackResult {
+        #[cfg(feature = "realtime_audit")]
+        let _audit = realtime_audit::enter_tick();
+
         self.read();
+        self.schedule();
+        self.sequence();
         for id in self.expressions.keys() {
             self.expression_ids.push(*id);
         }
@@ -105,38 +179,549 @@ impl VmInner {
         self.graph.topological_sort(&mut self.expressions,
                                     &mut self.expression_ids);
         self.run(adc_block, dac_block);
+        let block_time = self.block_time();
+        self.clock.advance(block_time);
         self.clean();
+        self.wavetables.update();
+        self.send_snapshot();
         StreamCallbackResult::Continue
     }
 
     /* Phases */
     pub fn read(&mut self) {
-        let result = self.input_channel.try_recv();
-        if let Ok(byte_code) = result {
-            let result = self.process(&byte_code.data[..byte_code.size]);
-            result.unwrap_or_else(|error| error!("{}", error));
+        // Chunks are reassembled in the message buffer, and each message is
+        // only processed once its final chunk has arrived.  Every waiting
+        // chunk is read, up to a buffer's worth of data per block.
+        let mut message = mem::replace(&mut self.message,
+                                       MessageBuffer::new());
+        let mut remaining = message.capacity();
+        while remaining > 0 {
+            match self.input_channel.try_recv(&mut message) {
+                Ok(Some(size)) => {
+                    remaining -= cmp::min(cmp::max(size, 1), remaining);
+                    if message.is_complete() {
+                        let result = self.process(message.get());
+                        result.unwrap_or_else(|error| error!("{}", error));
+                    }
+                },
+                Ok(None) => break,
+                Err(error) => {
+                    // Rejected chunks still count towards the limit, so a
+                    // stream of bad chunks can't stall the block
+                    error!("{}", error);
+                    remaining -= cmp::min(BYTE_CODE_SIZE, remaining);
+                }
+            }
+        }
+        self.message = message;
+    }
+
+    fn process(&mut self, message: &[u8]) -> ArtResult<()> {
+        let byte_code = try!(protocol::read_header(message));
+        self.process_byte_code(byte_code)
+    }
+
+    pub fn process_byte_code(&mut self, byte_code: &[u8]) -> ArtResult<()> {
+        let result = self.prepare(byte_code);
+        if result.is_err() {
+            self.rollback();
+            return result;
         }
+
+        self.commit(byte_code);
+        Ok(())
     }
 
-    fn process(&mut self, byte_code: &[u8]) -> ArtResult<()> {
+    // Reads and validates every opcode in the message, staging them in the
+    // transaction without changing the state of the VM
+    fn prepare(&mut self, byte_code: &[u8]) -> ArtResult<()> {
+        self.transaction.clear();
         let mut reader = Cursor::new(byte_code);
         while reader.position() != byte_code.len() as u64 {
             let opcode = try!(reader.read_control_opcode());
-            try!(self.process_opcode(opcode, &mut reader));
+            try!(self.prepare_opcode(opcode, &mut reader));
         }
         Ok(())
     }
 
-    fn process_opcode(&mut self, opcode: ControlOpcode,
+    fn prepare_opcode(&mut self, opcode: ControlOpcode,
                       reader: &mut Cursor<&[u8]>) -> ArtResult<()> {
         match opcode {
-            ControlOpcode::AddExpression { expression_id, num_opcodes } => {
+            ControlOpcode::AddExpression { expression_id, num_opcodes,
+                                           name } => {
+                if let Some(id) = self.find_named_expression(&name) {
+                    if id != expression_id {
+                        return Err(ArtError::DuplicateName { name: name });
+                    }
+                }
+
+                let num_opcodes = num_opcodes as usize;
+                let index = try!(
+                    self.expression_store.push_from_reader(num_opcodes,
+                                                           reader)
+                );
+
+                let result = self.transaction.push(opcode, Some(index));
+                if result.is_err() {
+                    let _ = self.expression_store.free(index, num_opcodes);
+                    return result;
+                }
+
+                try!(
+                    ExpressionValidator::validate(
+                        index, num_opcodes, &self.expression_store,
+                        &mut self.stack_record, &self.unit_factory,
+                        &self.expressions, &self.units, &self.parameters,
+                        &self.unit_data, &mut self.transaction.reservation
+                    )
+                );
+                self.transaction.add_expression(expression_id)
+            },
+            ControlOpcode::ReplaceExpression { expression_id, num_opcodes,
+                                               .. } => {
+                // Staged in the same way as AddExpression.  The old version
+                // keeps running alongside the new one until it has faded out,
+                // which the validator allows for by reserving space for an
+                // extra expression.  The old version also keeps copies of the
+                // expression's edges.
+                let edges = self.graph.count_edges(expression_id) +
+                            self.transaction.count_edges(expression_id);
+                if self.graph.len() + self.transaction.reservation.edges +
+                        edges > self.graph.capacity() {
+                    return Err(ArtError::BufferOverflow);
+                }
+                self.transaction.reservation.edges += edges;
+
                 let num_opcodes = num_opcodes as usize;
-                let start = try!(
+                let index = try!(
                     self.expression_store.push_from_reader(num_opcodes,
                                                            reader)
                 );
-                self.add_expression(expression_id, start, num_opcodes)
+
+                let result = self.transaction.push(opcode, Some(index));
+                if result.is_err() {
+                    let _ = self.expression_store.free(index, num_opcodes);
+                    return result;
+                }
+
+                try!(
+                    ExpressionValidator::validate(
+                        index, num_opcodes, &self.expression_store,
+                        &mut self.stack_record, &self.unit_factory,
+                        &self.expressions, &self.units, &self.parameters,
+                        &self.unit_data, &mut self.transaction.reservation
+                    )
+                );
+                self.transaction.add_expression(expression_id)
+            },
+            ControlOpcode::RemoveExpression { expression_id } => {
+                try!(self.validate_expression(expression_id));
+                try!(self.transaction.remove_expression(expression_id));
+                self.transaction.push(opcode, None)
+            },
+            ControlOpcode::RegisterTemplate { template_id, num_opcodes,
+                                              gate_unit_id,
+                                              gate_parameter_id, .. } => {
+                self.prepare_template(opcode, template_id,
+                                      num_opcodes as usize,
+                                      (gate_unit_id, gate_parameter_id),
+                                      reader)
+            },
+            ControlOpcode::UnregisterTemplate { template_id } => {
+                try!(self.find_template(template_id));
+                self.transaction.push(opcode, None)
+            },
+            ControlOpcode::NoteOn { template_id, note_id, num_parameters } => {
+                self.prepare_note_on(opcode, template_id, note_id,
+                                     num_parameters, reader)
+            },
+            ControlOpcode::NoteOff { note_id } => {
+                if midi::is_midi_note_id(note_id) {
+                    return Err(ArtError::InvalidByteCode);
+                }
+
+                // Notes which aren't playing are ignored
+                self.transaction.push(opcode, None)
+            },
+            ControlOpcode::Midi { status, data_1, data_2 } => {
+                self.prepare_midi(opcode, status, data_1, data_2)
+            },
+            ControlOpcode::MapController { channel, controller, expression_id,
+                                           unit_id, parameter_id, minimum,
+                                           maximum, curve } => {
+                if channel >= NUM_CHANNELS || controller >= NUM_CONTROLLERS ||
+                        !curve.is_valid_range(minimum, maximum) {
+                    return Err(ArtError::InvalidByteCode);
+                }
+                {
+                    let controllers = &self.midi_map.controllers;
+                    if !controllers.contains_key(&(channel, controller)) &&
+                            controllers.len() >= controllers.capacity() {
+                        return Err(ArtError::BufferOverflow);
+                    }
+                }
+
+                try!(
+                    self.validate_parameter((expression_id, unit_id,
+                                             parameter_id))
+                );
+                self.transaction.push(opcode, None)
+            },
+            ControlOpcode::UnmapController { channel, controller } => {
+                if channel >= NUM_CHANNELS || controller >= NUM_CONTROLLERS {
+                    return Err(ArtError::InvalidByteCode);
+                }
+                self.transaction.push(opcode, None)
+            },
+            ControlOpcode::MapNotes { channel, template_id, pitch_unit_id,
+                                      pitch_parameter_id, velocity_unit_id,
+                                      velocity_parameter_id } => {
+                self.prepare_map_notes(opcode, channel, template_id,
+                                       (pitch_unit_id, pitch_parameter_id),
+                                       (velocity_unit_id,
+                                        velocity_parameter_id))
+            },
+            ControlOpcode::UnmapNotes { channel } => {
+                if channel >= NUM_CHANNELS {
+                    return Err(ArtError::InvalidByteCode);
+                }
+                self.transaction.push(opcode, None)
+            },
+            ControlOpcode::AddSequence { sequence_id, ticks_per_quarter,
+                                         num_events, .. } => {
+                self.prepare_sequence(opcode, sequence_id, ticks_per_quarter,
+                                      num_events as usize, reader)
+            },
+            ControlOpcode::SetTempo { tempo } => {
+                if !(tempo > 0.0) {
+                    return Err(ArtError::InvalidByteCode);
+                }
+                self.transaction.push(opcode, None)
+            },
+            ControlOpcode::SetTimeSignature { beats_per_bar, beat_unit } => {
+                if beats_per_bar == 0 || beat_unit == 0 {
+                    return Err(ArtError::InvalidByteCode);
+                }
+                self.transaction.push(opcode, None)
+            },
+            ControlOpcode::ScheduleMessage { size, .. } => {
+                self.prepare_scheduled_message(opcode, size as usize, reader)
+            },
+            ControlOpcode::LoadWavetable { wavetable_id, num_tables,
+                                           table_size } => {
+                let num_tables = num_tables as usize;
+                let table_size = table_size as usize;
+                if num_tables == 0 || !Wavetable::is_valid_size(table_size) {
+                    return Err(ArtError::InvalidByteCode);
+                }
+
+                {
+                    let wavetables = &self.wavetables.wavetables;
+                    if !wavetables.contains_key(&wavetable_id) &&
+                            wavetables.len() >= wavetables.capacity() {
+                        return Err(ArtError::BufferOverflow);
+                    }
+                }
+
+                let index = try!(
+                    self.wavetables.push_from_reader(num_tables, table_size,
+                                                     reader)
+                );
+
+                let result = self.transaction.push(opcode, Some(index));
+                if result.is_err() {
+                    self.wavetables.free_staged(index);
+                }
+                result
+            },
+            ControlOpcode::RemoveWavetable { wavetable_id } => {
+                let exists = match self.transaction.wavetable_exists(
+                    wavetable_id
+                ) {
+                    Some(exists) => exists,
+                    None => self.wavetables.get(wavetable_id).is_some()
+                };
+
+                if !exists {
+                    return Err(
+                        ArtError::WavetableNotFound {
+                            wavetable_id: wavetable_id
+                        }
+                    );
+                }
+                self.transaction.push(opcode, None)
+            },
+            ControlOpcode::RemoveSequence { sequence_id } |
+            ControlOpcode::StartSequence { sequence_id, .. } |
+            ControlOpcode::StopSequence { sequence_id } |
+            ControlOpcode::LoopSequence { sequence_id, .. } => {
+                try!(self.validate_sequence(sequence_id));
+                self.transaction.push(opcode, None)
+            },
+            ControlOpcode::PauseExpression { expression_id } |
+            ControlOpcode::ResumeExpression { expression_id } |
+            ControlOpcode::MuteExpression { expression_id, .. } |
+            ControlOpcode::SoloExpression { expression_id, .. } |
+            ControlOpcode::GetExpression { expression_id } => {
+                try!(self.validate_expression(expression_id));
+                self.transaction.push(opcode, None)
+            },
+            ControlOpcode::SetParameter { expression_id, unit_id,
+                                          parameter_id, .. } |
+            ControlOpcode::MapParameter { expression_id, unit_id,
+                                          parameter_id, .. } |
+            ControlOpcode::UnmapParameter { expression_id, unit_id,
+                                            parameter_id } |
+            ControlOpcode::GetParameter { expression_id, unit_id,
+                                          parameter_id } => {
+                try!(
+                    self.validate_parameter((expression_id, unit_id,
+                                             parameter_id))
+                );
+                self.transaction.push(opcode, None)
+            },
+            ControlOpcode::SetSmoothing { expression_id, unit_id,
+                                          parameter_id, time, .. } |
+            ControlOpcode::RampParameter { expression_id, unit_id,
+                                           parameter_id, duration: time,
+                                           .. } => {
+                if !(time >= 0.0) {
+                    return Err(ArtError::InvalidByteCode);
+                }
+
+                try!(
+                    self.validate_parameter((expression_id, unit_id,
+                                             parameter_id))
+                );
+                self.transaction.push(opcode, None)
+            },
+            ControlOpcode::SetParameterByName { expression, unit, parameter,
+                                                value } => {
+                // Staged as a SetParameter, so the names are only looked up
+                // once
+                let (expression_id, unit_id, parameter_id) = try!(
+                    self.resolve_parameter(&expression, &unit, &parameter)
+                );
+                let opcode = ControlOpcode::SetParameter {
+                    expression_id: expression_id,
+                    unit_id: unit_id,
+                    parameter_id: parameter_id,
+                    value: value
+                };
+                self.transaction.push(opcode, None)
+            },
+            ControlOpcode::SaveSnapshot { .. } => {
+                self.transaction.push(opcode, None)
+            },
+            ControlOpcode::SetUnitData { expression_id, unit_id,
+                                         num_values } => {
+                self.prepare_unit_data(opcode, (expression_id, unit_id),
+                                       num_values as usize, reader)
+            },
+            ControlOpcode::AddEdge { from, to } => {
+                try!(self.validate_expression(from));
+                try!(self.validate_expression(to));
+
+                let edges = self.graph.len() +
+                            self.transaction.reservation.edges;
+                if edges >= self.graph.capacity() {
+                    return Err(ArtError::BufferOverflow);
+                }
+                self.transaction.reservation.edges += 1;
+                self.transaction.push(opcode, None)
+            }
+        }
+    }
+
+    fn validate_expression(&self, expression_id: u32) -> ArtResult<()> {
+        if !self.transaction.expression_exists(expression_id,
+                                               &self.expressions) {
+            return Err(
+                ArtError::ExpressionNotFound {
+                    expression_id: expression_id
+                }
+            );
+        }
+        Ok(())
+    }
+
+    fn validate_parameter(&self, id: (u32, u32, u32)) -> ArtResult<()> {
+        let (eid, uid, pid) = id;
+        let error = ArtError::ParameterNotFound {
+            expression_id: eid,
+            unit_id: uid,
+            parameter_id: pid
+        };
+
+        if !self.transaction.expression_exists(eid, &self.expressions) {
+            return Err(error);
+        }
+
+        match self.transaction.find_expression(eid) {
+            Some((index, num_opcodes)) => {
+                // The expression is added by this transaction, so its
+                // parameters don't exist yet
+                if !self.has_parameter(index, num_opcodes, uid, pid) {
+                    return Err(error);
+                }
+                Ok(())
+            },
+            None => {
+                if !self.parameters.contains_key(&id) {
+                    return Err(error);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    // Finds an expression by name, taking account of the transaction
+    fn find_named_expression(&self, name: &Name) -> Option<u32> {
+        if name.is_empty() {
+            return None;
+        }
+
+        let staged = self.transaction.find_named_expression(name,
+                                                            &self.expressions);
+        if staged.is_some() {
+            return staged;
+        }
+
+        // Expressions which are re-added by the transaction take their new
+        // name
+        self.expressions.values().find(|expression| {
+            expression.name == *name &&
+                self.transaction.expression_name(expression.id).is_none() &&
+                self.transaction.expression_exists(expression.id,
+                                                   &self.expressions)
+        }).map(|expression| expression.id)
+    }
+
+    // Finds the opcodes of an expression which will exist once the opcodes
+    // staged so far have been applied
+    pub fn find_expression_opcodes(&self, expression_id: u32)
+            -> (usize, usize) {
+        match self.transaction.find_expression(expression_id) {
+            Some(opcodes) => opcodes,
+            None => {
+                debug_assert!(self.expressions.contains_key(&expression_id));
+                let expression = &self.expressions[&expression_id];
+                (expression.index, expression.num_opcodes)
+            }
+        }
+    }
+
+    // Finds the ids of a parameter from the names of its expression and
+    // unit, and the name in its definition
+    fn resolve_parameter(&self, expression: &Name, unit: &Name,
+                         parameter: &Name) -> ArtResult<(u32, u32, u32)> {
+        let expression_id = try!(
+            self.find_named_expression(expression).ok_or(
+                ArtError::NameNotFound {
+                    name: *expression
+                }
+            )
+        );
+
+        let (index, num_opcodes) = self.find_expression_opcodes(
+            expression_id
+        );
+        for opcode in self.expression_store.iter(index).take(num_opcodes) {
+            if let &DspOpcode::Unit { unit_id, type_id, name, .. } = opcode {
+                if name != *unit {
+                    continue;
+                }
+
+                let definition = self.unit_factory.get_definition(type_id);
+                let parameter_id = try!(
+                    definition.parameters.iter().position(|definition| {
+                        definition.name == parameter.as_str()
+                    }).ok_or(
+                        ArtError::NameNotFound {
+                            name: *parameter
+                        }
+                    )
+                );
+                return Ok((expression_id, unit_id, parameter_id as u32));
+            }
+        }
+
+        Err(
+            ArtError::NameNotFound {
+                name: *unit
+            }
+        )
+    }
+
+    // Checks whether the expression stored at index has a parameter, using
+    // the unit definitions
+    pub fn has_parameter(&self, index: usize, num_opcodes: usize, unit_id: u32,
+                     parameter_id: u32) -> bool {
+        self.expression_store.iter(index).take(num_opcodes).any(|opcode| {
+            match opcode {
+                &DspOpcode::Unit { unit_id: uid, type_id, .. } => {
+                    let definition = self.unit_factory.get_definition(type_id);
+                    uid == unit_id &&
+                        (parameter_id as usize) < definition.parameters.len()
+                },
+                _ => false
+            }
+        })
+    }
+
+    // Applies every opcode staged in the transaction
+    fn commit(&mut self, byte_code: &[u8]) {
+        let items = mem::replace(&mut self.transaction.items,
+                                 Vec::with_capacity(0));
+        for item in items.iter() {
+            // Everything has been validated, so this should never fail
+            let result = self.process_opcode(item, byte_code);
+            debug_assert!(result.is_ok());
+            result.unwrap_or_else(|error| error!("{}", error));
+        }
+        self.transaction.items = items;
+        self.transaction.clear();
+    }
+
+    // Frees the variable length data staged by the transaction
+    fn rollback(&mut self) {
+        for item in self.transaction.items.iter().rev() {
+            match item.opcode {
+                ControlOpcode::AddExpression { num_opcodes, .. } |
+                ControlOpcode::ReplaceExpression { num_opcodes, .. } |
+                ControlOpcode::RegisterTemplate { num_opcodes, .. } => {
+                    debug_assert!(item.index.is_some());
+                    let _ = self.expression_store.free(item.index.unwrap(),
+                                                       num_opcodes as usize);
+                },
+                ControlOpcode::AddSequence { num_events, .. } => {
+                    debug_assert!(item.index.is_some());
+                    self.sequencer.events.free(item.index.unwrap(),
+                                               num_events as usize);
+                },
+                ControlOpcode::ScheduleMessage { size, .. } => {
+                    debug_assert!(item.index.is_some());
+                    self.scheduler.data.free(item.index.unwrap(),
+                                             size as usize);
+                },
+                ControlOpcode::LoadWavetable { .. } => {
+                    debug_assert!(item.index.is_some());
+                    self.wavetables.free_staged(item.index.unwrap());
+                },
+                _ => {}
+            }
+        }
+        self.transaction.clear();
+    }
+
+    fn process_opcode(&mut self, item: &TransactionItem, byte_code: &[u8])
+            -> ArtResult<()> {
+        match item.opcode {
+            ControlOpcode::AddExpression { expression_id, num_opcodes,
+                                           name } => {
+                debug_assert!(item.index.is_some());
+                self.add_expression(expression_id, item.index.unwrap(),
+                                    num_opcodes as usize, name)
             },
             ControlOpcode::RemoveExpression { expression_id } => {
                 self.remove_expression(expression_id)
@@ -146,9 +731,224 @@ impl VmInner {
                 self.set_parameter((expression_id, unit_id, parameter_id),
                                    value)
             },
+            ControlOpcode::SetSmoothing { expression_id, unit_id,
+                                          parameter_id, time, smoothing } => {
+                let parameter = try!(
+                    self.get_parameter_mut((expression_id, unit_id,
+                                            parameter_id))
+                );
+                parameter.smoothing = smoothing;
+                parameter.smoothing_time = time;
+                Ok(())
+            },
+            ControlOpcode::RampParameter { expression_id, unit_id,
+                                           parameter_id, value, duration } => {
+                let constants = self.constants;
+                let parameter = try!(
+                    self.get_parameter_mut((expression_id, unit_id,
+                                            parameter_id))
+                );
+                parameter.ramp(value, duration, &constants);
+                Ok(())
+            },
+            ControlOpcode::MapParameter { expression_id, unit_id,
+                                          parameter_id, bus_id, rate } => {
+                debug!("Mapping parameter: expression_id={}, unit_id={}, \
+                        parameter_id={}, bus_id={}", expression_id, unit_id,
+                       parameter_id, bus_id);
+                let parameter = try!(
+                    self.get_parameter_mut((expression_id, unit_id,
+                                            parameter_id))
+                );
+                parameter.mapped_bus = Some((bus_id, rate));
+                Ok(())
+            },
+            ControlOpcode::UnmapParameter { expression_id, unit_id,
+                                            parameter_id } => {
+                let parameter = try!(
+                    self.get_parameter_mut((expression_id, unit_id,
+                                            parameter_id))
+                );
+                parameter.mapped_bus = None;
+                Ok(())
+            },
+            ControlOpcode::GetParameter { expression_id, unit_id,
+                                          parameter_id } => {
+                self.send_parameter((expression_id, unit_id, parameter_id))
+            },
+            ControlOpcode::GetExpression { expression_id } => {
+                self.send_expression(expression_id)
+            },
+            // Staged as SetParameter
+            ControlOpcode::SetParameterByName { .. } => unreachable!(),
+            ControlOpcode::SaveSnapshot { snapshot_id, unit_data } => {
+                self.save_snapshot(snapshot_id, unit_data);
+                Ok(())
+            },
+            ControlOpcode::SetUnitData { expression_id, unit_id,
+                                         num_values } => {
+                debug_assert!(item.index.is_some());
+                let mut reader = Cursor::new(byte_code);
+                reader.set_position(item.index.unwrap() as u64);
+                self.set_unit_data((expression_id, unit_id),
+                                   num_values as usize, &mut reader)
+            },
             ControlOpcode::AddEdge { from, to } => {
                 self.graph.add_edge(from, to);
                 Ok(())
+            },
+            ControlOpcode::ReplaceExpression { expression_id, num_opcodes,
+                                               crossfade, carry_state } => {
+                debug_assert!(item.index.is_some());
+                self.replace_expression(expression_id, item.index.unwrap(),
+                                        num_opcodes as usize, crossfade,
+                                        carry_state)
+            },
+            ControlOpcode::PauseExpression { expression_id } => {
+                self.set_expression_state(expression_id,
+                                          ExpressionState::Paused)
+            },
+            ControlOpcode::ResumeExpression { expression_id } => {
+                self.set_expression_state(expression_id, ExpressionState::Run)
+            },
+            ControlOpcode::MuteExpression { expression_id, mute } => {
+                let expression = try!(self.get_expression_mut(expression_id));
+                expression.muted = mute;
+                Ok(())
+            },
+            ControlOpcode::SoloExpression { expression_id, solo } => {
+                let expression = try!(self.get_expression_mut(expression_id));
+                expression.solo = solo;
+                Ok(())
+            },
+            ControlOpcode::RegisterTemplate { template_id, num_opcodes,
+                                              max_voices, steal_mode,
+                                              gate_unit_id,
+                                              gate_parameter_id } => {
+                debug_assert!(item.index.is_some());
+                let gate = if gate_unit_id == NO_GATE {
+                    None
+                }
+                else {
+                    Some((gate_unit_id, gate_parameter_id))
+                };
+
+                self.register_template(
+                    template_id,
+                    Template {
+                        index: item.index.unwrap(),
+                        num_opcodes: num_opcodes as usize,
+                        max_voices: max_voices,
+                        steal_mode: steal_mode,
+                        gate: gate
+                    }
+                )
+            },
+            ControlOpcode::UnregisterTemplate { template_id } => {
+                self.unregister_template(template_id)
+            },
+            ControlOpcode::NoteOn { template_id, note_id, num_parameters } => {
+                debug_assert!(item.index.is_some());
+                let mut reader = Cursor::new(byte_code);
+                reader.set_position(item.index.unwrap() as u64);
+                self.note_on(template_id, note_id, num_parameters,
+                             &mut reader)
+            },
+            ControlOpcode::NoteOff { note_id } => {
+                self.note_off(note_id)
+            },
+            ControlOpcode::Midi { status, data_1, data_2 } => {
+                match MidiMessage::parse(status, data_1, data_2) {
+                    Some(message) => self.midi(message),
+                    None => {
+                        debug!("Ignoring MIDI message: status={:#x}", status);
+                        Ok(())
+                    }
+                }
+            },
+            ControlOpcode::MapController { channel, controller, expression_id,
+                                           unit_id, parameter_id, minimum,
+                                           maximum, curve } => {
+                self.midi_map.map_controller(
+                    channel, controller,
+                    ControllerMapping {
+                        expression_id: expression_id,
+                        unit_id: unit_id,
+                        parameter_id: parameter_id,
+                        minimum: minimum,
+                        maximum: maximum,
+                        curve: curve
+                    }
+                )
+            },
+            ControlOpcode::UnmapController { channel, controller } => {
+                self.midi_map.controllers.remove(&(channel, controller));
+                Ok(())
+            },
+            ControlOpcode::MapNotes { channel, template_id, pitch_unit_id,
+                                      pitch_parameter_id, velocity_unit_id,
+                                      velocity_parameter_id } => {
+                self.map_notes(channel, template_id,
+                               (pitch_unit_id, pitch_parameter_id),
+                               (velocity_unit_id, velocity_parameter_id))
+            },
+            ControlOpcode::UnmapNotes { channel } => {
+                self.midi_map.notes[channel as usize] = None;
+                Ok(())
+            },
+            ControlOpcode::AddSequence { sequence_id, ticks_per_quarter,
+                                         length, num_events } => {
+                debug_assert!(item.index.is_some());
+                self.add_sequence(
+                    sequence_id,
+                    Sequence::new(item.index.unwrap(), num_events as usize,
+                                  ticks_per_quarter, length)
+                )
+            },
+            ControlOpcode::RemoveSequence { sequence_id } => {
+                self.remove_sequence(sequence_id)
+            },
+            ControlOpcode::StartSequence { sequence_id, restart } => {
+                self.start_sequence(sequence_id, restart)
+            },
+            ControlOpcode::StopSequence { sequence_id } => {
+                self.stop_sequence(sequence_id)
+            },
+            ControlOpcode::LoopSequence { sequence_id, looping } => {
+                let sequence = try!(self.sequencer.get_mut(sequence_id));
+                sequence.looping = looping;
+                Ok(())
+            },
+            ControlOpcode::SetTempo { tempo } => {
+                debug!("Setting tempo: tempo={}", tempo);
+                self.clock.tempo = tempo as f64;
+                Ok(())
+            },
+            ControlOpcode::SetTimeSignature { beats_per_bar, beat_unit } => {
+                self.clock.beats_per_bar = beats_per_bar;
+                self.clock.beat_unit = beat_unit;
+                Ok(())
+            },
+            ControlOpcode::ScheduleMessage { beat, size } => {
+                debug_assert!(item.index.is_some());
+                self.schedule_message(beat, item.index.unwrap(),
+                                      size as usize)
+            },
+            ControlOpcode::LoadWavetable { wavetable_id, num_tables,
+                                           table_size } => {
+                debug_assert!(item.index.is_some());
+                debug!("Loading wavetable: wavetable_id={}, index={}",
+                       wavetable_id, item.index.unwrap());
+                self.wavetables.insert(
+                    wavetable_id,
+                    Wavetable::new(item.index.unwrap(), num_tables as usize,
+                                   table_size as usize)
+                );
+                Ok(())
+            },
+            ControlOpcode::RemoveWavetable { wavetable_id } => {
+                debug!("Removing wavetable: wavetable_id={}", wavetable_id);
+                self.wavetables.remove(wavetable_id)
             }
         }
     }
@@ -176,27 +976,61 @@ impl VmInner {
         let expression_ids = mem::replace(&mut self.expression_ids,
                                           Vec::with_capacity(0));
 
+        let soloing = self.expressions.values().any(|expression| {
+            expression.solo
+        });
+
         let mut adjuncts = TickAdjuncts {
             busses: &mut busses,
             bus_map: &mut self.bus_map,
             parameters: &mut self.parameters,
-            data: &mut self.unit_data
+            data: &mut self.unit_data,
+            gain: 1.0,
+            gain_delta: 0.0,
+            done_action: DoneAction::None,
+            level: 0.0,
+            clock: self.clock,
+            wavetables: &self.wavetables
         };
 
         for id in expression_ids.iter() {
             debug_assert!(self.expressions.contains_key(id));
             let expression = self.expressions.get_mut(id).unwrap();
             let mut stack = ChannelStack::new(&mut self.stack_data);
+            let audible = !expression.muted && (!soloing || expression.solo);
+            if audible {
+                adjuncts.gain = expression.gain;
+                adjuncts.gain_delta = expression.gain_delta;
+            }
+            else {
+                adjuncts.gain = 0.0;
+                adjuncts.gain_delta = 0.0;
+            }
+            adjuncts.level = 0.0;
             let result = expression.tick(
                 &self.expression_store, &mut stack, &mut self.units,
                 &mut adjuncts, &self.constants
             );
+            expression.level = adjuncts.level;
 
             if result.is_err() {
                 expression.state = ExpressionState::Free;
                 debug!("Expression tick failed: reason={}",
                        result.err().unwrap());
             }
+
+            match adjuncts.done_action {
+                DoneAction::None => {},
+                DoneAction::Pause => {
+                    expression.state = ExpressionState::Paused;
+                },
+                DoneAction::Free => {
+                    expression.state = ExpressionState::Free;
+                }
+            }
+            adjuncts.done_action = DoneAction::None;
+
+            expression.advance_fade(&self.constants);
         }
         self.expression_ids = expression_ids;
         adjuncts.busses.read(dac_index, dac_block);
@@ -216,6 +1050,24 @@ impl VmInner {
         for id in expression_ids.iter() {
             debug_assert!(self.expressions.contains_key(id));
             self.remove_expression(*id).unwrap();
+
+            if let Some(voice) = self.voice_allocator.remove_expression(*id) {
+                self.notify(
+                    Notification::VoiceFreed {
+                        note_id: voice.note_id
+                    }
+                );
+            }
+
+            // Let the client know that the expression has ended, unless it
+            // was created by the VM itself
+            if !is_internal_id(*id) {
+                self.notify(
+                    Notification::ExpressionFreed {
+                        expression_id: *id
+                    }
+                );
+            }
         }
         self.expression_ids = expression_ids;
 
@@ -233,25 +1085,17 @@ impl VmInner {
     }
 
     /* Control instructions */
-    pub fn add_expression(&mut self, id: u32, index: usize, num_opcodes: usize)
-            -> ArtResult<()> {
-        debug!("Adding expression: id={:?}, index={:?}", id, index);
-        let result = ExpressionValidator::validate(
-            index, num_opcodes, &self.expression_store, &mut self.stack_record,
-            &self.unit_factory, &self.expressions, &self.units,
-            &self.parameters, &self.unit_data
-        );
-
-        if result.is_err() {
-            let _ = self.expression_store.free(index, num_opcodes);
-            return result;
-        }
-
+    // The expression's opcodes must already have been validated
+    pub fn add_expression(&mut self, id: u32, index: usize, num_opcodes: usize,
+                          name: Name) -> ArtResult<()> {
+        debug!("Adding expression: id={:?}, index={:?}, name={:?}", id, index,
+               name);
         if self.expressions.contains_key(&id) {
             self.remove_expression(id).unwrap();
         }
 
-        let expression = Expression::new(id, index, num_opcodes );
+        let mut expression = Expression::new(id, index, num_opcodes);
+        expression.name = name;
 
         let _ = expression.construct_units(
             &self.expression_store, &mut self.unit_factory, &mut self.units,
@@ -263,6 +1107,104 @@ impl VmInner {
         Ok(())
     }
 
+    // Runs the new version of the expression alongside the old one, fading
+    // between them over crossfade seconds.  The old version is moved to an
+    // internal id, and freed once it has faded out.
+    pub fn replace_expression(&mut self, id: u32, index: usize,
+                              num_opcodes: usize, crossfade: f32,
+                              carry_state: bool) -> ArtResult<()> {
+        if !self.expressions.contains_key(&id) {
+            return self.add_expression(id, index, num_opcodes, Name::empty());
+        }
+
+        debug!("Replacing expression: id={:?}, index={:?}, crossfade={:?}",
+               id, index, crossfade);
+
+        let old_id = self.allocate_expression_id();
+        let mut old_expression = self.expressions.remove(&id).unwrap();
+        old_expression.rename(old_id, &self.expression_store,
+                              &self.unit_factory, &mut self.units,
+                              &mut self.parameters);
+        old_expression.fade(0.0, crossfade, &self.constants);
+        // The new version takes over the name
+        let name = mem::replace(&mut old_expression.name, Name::empty());
+        self.expressions.insert(old_id, old_expression);
+        // Keep the old version in the same place in the graph while it
+        // fades out, so it doesn't read stale blocks from the busses
+        self.graph.copy_edges(id, old_id);
+
+        try!(self.add_expression(id, index, num_opcodes, name));
+
+        if carry_state {
+            self.carry_state(&old_expression, id);
+        }
+
+        let expression = self.expressions.get_mut(&id).unwrap();
+        expression.gain = 0.0;
+        expression.fade(1.0, crossfade, &self.constants);
+        Ok(())
+    }
+
+    // Copies the state of units in the old expression to units in the new
+    // expression with the same unit_id, type_id and number of arguments.
+    // Copies of the arguments are left alone, so changes to them take
+    // effect.
+    fn carry_state(&mut self, old_expression: &Expression, id: u32) {
+        let new_expression = *self.expressions.get(&id).unwrap();
+        for opcode in self.expression_store.iter(new_expression.index)
+                                           .take(new_expression.num_opcodes) {
+            let (unit_id, type_id, num_arguments) = match opcode {
+                &DspOpcode::Unit { unit_id, type_id, num_arguments, .. } => {
+                    (unit_id, type_id, num_arguments)
+                },
+                _ => continue
+            };
+
+            let matches = self.expression_store.iter(old_expression.index)
+                                               .take(old_expression.num_opcodes)
+                                               .any(|old_opcode| {
+                match old_opcode {
+                    &DspOpcode::Unit { unit_id: old_unit_id,
+                                       type_id: old_type_id,
+                                       num_arguments: old_num_arguments,
+                                       .. } => {
+                        old_unit_id == unit_id && old_type_id == type_id &&
+                            old_num_arguments == num_arguments
+                    },
+                    _ => false
+                }
+            });
+
+            if !matches {
+                continue;
+            }
+
+            let old_unit = self.units[&(old_expression.id, unit_id)];
+            let new_unit = self.units[&(id, unit_id)];
+            if let (Some(old_index), Some(new_index)) = (old_unit.data_index,
+                                                         new_unit.data_index) {
+                let size = new_unit.definition.data_size.state_size();
+                for i in range(0, size) {
+                    let value = *self.unit_data.iter(old_index)
+                                               .nth(i).unwrap();
+                    *self.unit_data.iter_mut(new_index).nth(i).unwrap() =
+                        value;
+                }
+            }
+        }
+    }
+
+    pub fn allocate_expression_id(&mut self) -> u32 {
+        loop {
+            let id = INTERNAL_ID_FLAG | self.next_internal_id;
+            self.next_internal_id = (self.next_internal_id + 1) &
+                                    !INTERNAL_ID_FLAG;
+            if !self.expressions.contains_key(&id) {
+                return id;
+            }
+        }
+    }
+
     pub fn remove_expression(&mut self, expression_id: u32) -> ArtResult<()> {
         let expression = try!(
             self.expressions.remove(&expression_id).ok_or(
@@ -276,27 +1218,163 @@ impl VmInner {
         Ok(())
     }
 
+    pub fn set_expression_state(&mut self, expression_id: u32,
+                                state: ExpressionState) -> ArtResult<()> {
+        debug!("Setting expression state: expression_id={}", expression_id);
+        let expression = try!(self.get_expression_mut(expression_id));
+        if expression.state != ExpressionState::Free {
+            expression.state = state;
+        }
+        Ok(())
+    }
+
+    fn get_expression_mut(&mut self, expression_id: u32)
+            -> ArtResult<&mut Expression> {
+        self.expressions.get_mut(&expression_id).ok_or(
+            ArtError::ExpressionNotFound {
+                expression_id: expression_id
+            }
+        )
+    }
+
     pub fn set_parameter(&mut self, id: (u32, u32, u32), value: f32)
             -> ArtResult<()> {
         let (eid, uid, pid) = id;
         debug!("Setting parameter: expression_id={}, unit_id={},
                 parameter_id={}, value={}", eid, uid, pid, value);
 
-        let parameter = try!(
-            self.parameters.get_mut(&id).ok_or(
-                ArtError::ParameterNotFound {
-                    expression_id: eid,
-                    unit_id: uid,
-                    parameter_id: pid
-                }
-            )
-        );
+        let constants = self.constants;
+        let parameter = try!(self.get_parameter_mut(id));
+        parameter.set(value, &constants);
+        Ok(())
+    }
+
+    fn get_parameter_mut(&mut self, id: (u32, u32, u32))
+            -> ArtResult<&mut Parameter> {
+        let (eid, uid, pid) = id;
+        self.parameters.get_mut(&id).ok_or(
+            ArtError::ParameterNotFound {
+                expression_id: eid,
+                unit_id: uid,
+                parameter_id: pid
+            }
+        )
+    }
+
+    fn send_parameter(&mut self, id: (u32, u32, u32)) -> ArtResult<()> {
+        let (eid, uid, pid) = id;
+        let notification = {
+            let parameter = try!(self.get_parameter_mut(id));
+            Notification::ParameterValue {
+                expression_id: eid,
+                unit_id: uid,
+                parameter_id: pid,
+                value: parameter.value,
+                current_value: parameter.current_value(),
+                mode: parameter.definition.mode,
+                mapped_bus: parameter.mapped_bus
+            }
+        };
+        self.notify(notification);
+        Ok(())
+    }
+
+    // Sends the state of the expression, then its opcodes one at a time so
+    // each notification stays within MAX_NOTIFICATION_SIZE
+    fn send_expression(&mut self, expression_id: u32) -> ArtResult<()> {
+        let expression = *try!(self.get_expression_mut(expression_id));
+        let info = Notification::ExpressionInfo {
+            expression_id: expression_id,
+            name: expression.name,
+            state: expression.state,
+            muted: expression.muted,
+            solo: expression.solo,
+            gain: expression.gain,
+            level: expression.level,
+            num_opcodes: expression.num_opcodes as u32
+        };
 
-        parameter.value = value;
+        // Check the whole reply fits before sending any of it, so the client
+        // never receives part of an expression
+        let mut size = HEADER_SIZE + try!(VmInner::notification_size(&info));
+        for (index, opcode) in self.expression_store
+                                   .iter(expression.index)
+                                   .take(expression.num_opcodes)
+                                   .enumerate() {
+            let notification = Notification::ExpressionOpcode {
+                expression_id: expression_id,
+                index: index as u32,
+                opcode: *opcode
+            };
+            size += HEADER_SIZE + try!(
+                VmInner::notification_size(&notification)
+            );
+        }
+
+        if size > self.output_channel.free_space() {
+            error!("Expression does not fit in output channel: \
+                    expression_id={}, size={}", expression_id, size);
+            self.notify(
+                Notification::ExpressionFailed {
+                    expression_id: expression_id
+                }
+            );
+            return Ok(());
+        }
 
+        self.notify(info);
+        let channel = &mut self.output_channel;
+        let opcodes = self.expression_store.iter(expression.index)
+                                           .take(expression.num_opcodes);
+        for (index, opcode) in opcodes.enumerate() {
+            VmInner::notify_channel(
+                channel,
+                Notification::ExpressionOpcode {
+                    expression_id: expression_id,
+                    index: index as u32,
+                    opcode: *opcode
+                }
+            );
+        }
         Ok(())
     }
 
+    pub fn notify(&mut self, notification: Notification) {
+        VmInner::notify_channel(&mut self.output_channel, notification);
+    }
+
+    // Sends a notification, dropping it if it can't be sent
+    fn notify_channel(channel: &mut RingBufferSender,
+                      notification: Notification) {
+        if let Err(error) = VmInner::send_notification(channel,
+                                                       notification) {
+            debug!("Dropping notification: error={}, notification={:?}",
+                   error, notification);
+        }
+    }
+
+    pub fn send_notification(channel: &mut RingBufferSender,
+                         notification: Notification) -> ArtResult<()> {
+        let mut buffer = [0u8; MAX_NOTIFICATION_SIZE];
+        let size = try!(VmInner::write_notification(&mut buffer,
+                                                    &notification));
+        channel.send(&buffer[..size])
+    }
+
+    // The number of bytes the notification takes up, not counting the
+    // channel's header
+    fn notification_size(notification: &Notification) -> ArtResult<usize> {
+        let mut buffer = [0u8; MAX_NOTIFICATION_SIZE];
+        VmInner::write_notification(&mut buffer, notification)
+    }
+
+    fn write_notification(buffer: &mut [u8; MAX_NOTIFICATION_SIZE],
+                          notification: &Notification) -> ArtResult<usize> {
+        let mut writer = Cursor::new(&mut buffer[..]);
+        try!(writer.write_notification(notification));
+        Ok(writer.position() as usize)
+    }
+
     pub fn write_info_file(&self) -> ArtResult<()> {
         let json = try!(json::encode(self));
 
@@ -336,29 +1414,39 @@ impl<'a, 'b> FnMut<
 
 impl Encodable for VmInner {
     fn encode<S: Encoder>(&self, encoder: &mut S) -> Result<(), S::Error> {
-        encoder.emit_struct("VmInner", 5, |encoder| {
+        encoder.emit_struct("VmInner", 7, |encoder| {
+            try!(
+                encoder.emit_struct_field("magic", 0, |encoder|
+                    protocol::MAGIC.encode(encoder)
+                )
+            );
+            try!(
+                encoder.emit_struct_field("protocol_version", 1, |encoder|
+                    protocol::VERSION.encode(encoder)
+                )
+            );
             try!(
-                encoder.emit_struct_field("input_channels", 0, |encoder|
+                encoder.emit_struct_field("input_channels", 2, |encoder|
                     self.constants.input_channels.encode(encoder)
                 )
             );
             try!(
-                encoder.emit_struct_field("output_channels", 1, |encoder|
+                encoder.emit_struct_field("output_channels", 3, |encoder|
                     self.constants.output_channels.encode(encoder)
                 )
             );
             try!(
-                encoder.emit_struct_field("sample_rate", 2, |encoder|
+                encoder.emit_struct_field("sample_rate", 4, |encoder|
                     self.constants.audio_rate.encode(encoder)
                 )
             );
             try!(
-                encoder.emit_struct_field("block_size", 3, |encoder|
+                encoder.emit_struct_field("block_size", 5, |encoder|
                     self.constants.block_size.encode(encoder)
                 )
             );
             try!(
-                encoder.emit_struct_field("units", 4, |encoder|
+                encoder.emit_struct_field("units", 6, |encoder|
                     self.unit_factory.units.encode(encoder)
                 )
             );
@@ -367,5 +1455,591 @@ impl Encodable for VmInner {
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::default::Default;
+    use std::io::Cursor;
+
+    use byteorder::{WriteBytesExt, BigEndian};
+
+    use options::Options;
+    use expression::{ExpressionState, is_internal_id};
+    use voice_allocator::{StealMode, NO_GATE};
+    use types::Rate;
+    use name::Name;
+    use opcode::{ControlOpcode, ControlOpcodeType, DspOpcode};
+    use opcode_writer::OpcodeWriter;
+    use notification::{Notification, NotificationReader,
+                       MAX_NOTIFICATION_SIZE};
+    use unit::DoneAction;
+    use test_util::{vm, vm_with_options, sine, source, type_id,
+                    write_sine_expression};
+    use super::VmInner;
+
+    fn get_expression(byte_code: &mut Vec<u8>, expression_id: u32) {
+        byte_code.write_u32::<BigEndian>(
+            ControlOpcodeType::GetExpression as u32
+        ).unwrap();
+        byte_code.write_u32::<BigEndian>(expression_id).unwrap();
+    }
+
+    fn replace_with_sine(byte_code: &mut Vec<u8>, expression_id: u32,
+                         crossfade: f32, carry_state: bool) {
+        byte_code.write_u32::<BigEndian>(
+            ControlOpcodeType::ReplaceExpression as u32
+        ).unwrap();
+        byte_code.write_u32::<BigEndian>(expression_id).unwrap();
+        byte_code.write_u32::<BigEndian>(1).unwrap();
+        byte_code.write_f32::<BigEndian>(crossfade).unwrap();
+        byte_code.write_u32::<BigEndian>(carry_state as u32).unwrap();
+        byte_code.write_dsp_opcode(&sine(0)).unwrap();
+    }
+
+    fn register_template(byte_code: &mut Vec<u8>, template_id: u32,
+                         max_voices: u32, unit: &DspOpcode) {
+        byte_code.write_u32::<BigEndian>(
+            ControlOpcodeType::RegisterTemplate as u32
+        ).unwrap();
+        byte_code.write_u32::<BigEndian>(template_id).unwrap();
+        byte_code.write_u32::<BigEndian>(1).unwrap();
+        byte_code.write_u32::<BigEndian>(max_voices).unwrap();
+        byte_code.write_u32::<BigEndian>(StealMode::Oldest as u32).unwrap();
+        byte_code.write_u32::<BigEndian>(NO_GATE).unwrap();
+        byte_code.write_u32::<BigEndian>(0).unwrap();
+        byte_code.write_dsp_opcode(unit).unwrap();
+    }
+
+    fn note_on(byte_code: &mut Vec<u8>, template_id: u32, note_id: u32) {
+        byte_code.write_u32::<BigEndian>(
+            ControlOpcodeType::NoteOn as u32
+        ).unwrap();
+        byte_code.write_u32::<BigEndian>(template_id).unwrap();
+        byte_code.write_u32::<BigEndian>(note_id).unwrap();
+        byte_code.write_u32::<BigEndian>(0).unwrap();
+    }
+
+    // A short envelope which takes the done action as it finishes
+    fn envelope() -> DspOpcode {
+        source(0, type_id("ar_envelope_ar"))
+    }
+
+    // Attack, release and done action for the envelope
+    fn envelope_parameters(done_action: DoneAction) -> [(u32, f32); 3] {
+        [(1, 0.001), (2, 0.001), (3, done_action as u32 as f32)]
+    }
+
+    fn write_envelope_expression(byte_code: &mut Vec<u8>,
+                                 expression_id: u32,
+                                 done_action: DoneAction) {
+        byte_code.write_control_opcode(
+            &ControlOpcode::AddExpression {
+                expression_id: expression_id,
+                num_opcodes: 1,
+                name: Name::empty()
+            }
+        ).unwrap();
+        byte_code.write_dsp_opcode(&envelope()).unwrap();
+        for &(parameter_id, value) in
+                envelope_parameters(done_action).iter() {
+            byte_code.write_control_opcode(
+                &ControlOpcode::SetParameter {
+                    expression_id: expression_id,
+                    unit_id: 0,
+                    parameter_id: parameter_id,
+                    value: value
+                }
+            ).unwrap();
+        }
+    }
+
+    // Runs the VM for a block with silent input
+    fn tick(vm: &mut VmInner) {
+        tick_with_input(vm, 0.0);
+    }
+
+    // Runs the VM for a block with every input sample set to value
+    fn tick_with_input(vm: &mut VmInner, value: f32) {
+        let adc_block = [value; 128];
+        let mut dac_block = [0f32; 128];
+        vm.tick(&adc_block, &mut dac_block);
+    }
+
+    // Reads every waiting notification from the VM's output channel
+    fn notifications(vm: &mut VmInner) -> Vec<Notification> {
+        let receiver = vm.output_receiver.as_mut().unwrap();
+        let mut buffer = [0u8; MAX_NOTIFICATION_SIZE];
+        let mut notifications = Vec::new();
+        while let Some(size) = receiver.try_recv(&mut buffer).unwrap() {
+            let mut reader = Cursor::new(&buffer[..size]);
+            notifications.push(reader.read_notification().unwrap());
+        }
+        notifications
+    }
+
+    #[test]
+    fn get_expression_sends_info_and_opcodes() {
+        let mut vm = vm();
+        let mut byte_code = Vec::new();
+        write_sine_expression(&mut byte_code, 1);
+        get_expression(&mut byte_code, 1);
+        vm.process_byte_code(&byte_code[..]).unwrap();
+
+        let notifications = notifications(&mut vm);
+        assert_eq!(notifications.len(), 2);
+        match notifications[0] {
+            Notification::ExpressionInfo { expression_id: 1,
+                                           num_opcodes: 1, .. } => {},
+            _ => panic!("Expected expression info")
+        }
+        match notifications[1] {
+            Notification::ExpressionOpcode { expression_id: 1, index: 0,
+                                             .. } => {},
+            _ => panic!("Expected expression opcode")
+        }
+    }
+
+    #[test]
+    fn get_expression_fails_when_reply_does_not_fit() {
+        // Room for the info, but not for the opcode which follows it
+        let mut vm = vm_with_options(
+            Options {
+                output_buffer_size: 64,
+                .. Default::default()
+            }
+        );
+        let mut byte_code = Vec::new();
+        write_sine_expression(&mut byte_code, 1);
+        get_expression(&mut byte_code, 1);
+        vm.process_byte_code(&byte_code[..]).unwrap();
+
+        let notifications = notifications(&mut vm);
+        assert_eq!(notifications.len(), 1);
+        match notifications[0] {
+            Notification::ExpressionFailed { expression_id: 1 } => {},
+            _ => panic!("Expected expression failed")
+        }
+    }
+
+    #[test]
+    fn resume_keeps_expression_muted() {
+        let mut vm = vm();
+        let mut byte_code = Vec::new();
+        write_sine_expression(&mut byte_code, 1);
+        byte_code.write_control_opcode(
+            &ControlOpcode::MuteExpression {
+                expression_id: 1,
+                mute: true
+            }
+        ).unwrap();
+        byte_code.write_control_opcode(
+            &ControlOpcode::PauseExpression { expression_id: 1 }
+        ).unwrap();
+        byte_code.write_u32::<BigEndian>(
+            ControlOpcodeType::ResumeExpression as u32
+        ).unwrap();
+        byte_code.write_u32::<BigEndian>(1).unwrap();
+        vm.process_byte_code(&byte_code[..]).unwrap();
+        assert!(vm.expressions[&1].muted);
+
+        let mut byte_code = Vec::new();
+        byte_code.write_control_opcode(
+            &ControlOpcode::MuteExpression {
+                expression_id: 1,
+                mute: false
+            }
+        ).unwrap();
+        vm.process_byte_code(&byte_code[..]).unwrap();
+        assert!(!vm.expressions[&1].muted);
+    }
+
+    #[test]
+    fn replaced_paused_expression_is_freed() {
+        let mut vm = vm();
+        let mut byte_code = Vec::new();
+        write_sine_expression(&mut byte_code, 1);
+        byte_code.write_control_opcode(
+            &ControlOpcode::PauseExpression { expression_id: 1 }
+        ).unwrap();
+        replace_with_sine(&mut byte_code, 1, 0.1, false);
+        vm.process_byte_code(&byte_code[..]).unwrap();
+        assert_eq!(vm.expressions.len(), 2);
+
+        tick(&mut vm);
+        assert_eq!(vm.expressions.len(), 1);
+        assert!(vm.expressions.contains_key(&1));
+        assert_eq!(vm.units.len(), 1);
+    }
+
+    #[test]
+    fn unlimited_template_never_steals() {
+        let mut vm = vm();
+        let mut byte_code = Vec::new();
+        register_template(&mut byte_code, 1, 0, &sine(0));
+        for note_id in range(0, 3) {
+            note_on(&mut byte_code, 1, note_id);
+        }
+        vm.process_byte_code(&byte_code[..]).unwrap();
+
+        assert_eq!(vm.voice_allocator.active_voices(1), 3);
+        assert_eq!(vm.expressions.len(), 3);
+        assert!(notifications(&mut vm).is_empty());
+    }
+
+    #[test]
+    fn stolen_paused_voice_is_freed() {
+        let mut vm = vm();
+        let mut byte_code = Vec::new();
+        register_template(&mut byte_code, 1, 1, &sine(0));
+        note_on(&mut byte_code, 1, 0);
+        vm.process_byte_code(&byte_code[..]).unwrap();
+
+        let victim = *vm.expressions.keys().next().unwrap();
+        vm.expressions.get_mut(&victim).unwrap().state =
+            ExpressionState::Paused;
+
+        let mut byte_code = Vec::new();
+        note_on(&mut byte_code, 1, 1);
+        vm.process_byte_code(&byte_code[..]).unwrap();
+        assert_eq!(vm.voice_allocator.active_voices(1), 1);
+
+        tick(&mut vm);
+        assert!(!vm.expressions.contains_key(&victim));
+        assert_eq!(vm.expressions.len(), 1);
+    }
+
+    fn internal_id(vm: &VmInner) -> u32 {
+        *vm.expressions.keys().find(|id| is_internal_id(**id)).unwrap()
+    }
+
+    #[test]
+    fn replace_expression_crossfades() {
+        let mut vm = vm();
+        let mut byte_code = Vec::new();
+        write_sine_expression(&mut byte_code, 1);
+        vm.process_byte_code(&byte_code[..]).unwrap();
+
+        // 441 samples, so the fade finishes during the seventh block
+        let mut byte_code = Vec::new();
+        replace_with_sine(&mut byte_code, 1, 0.01, false);
+        vm.process_byte_code(&byte_code[..]).unwrap();
+        assert_eq!(vm.expressions.len(), 2);
+        let old_id = internal_id(&vm);
+        assert_eq!(vm.expressions[&1].gain, 0.0);
+        assert_eq!(vm.expressions[&1].target_gain, 1.0);
+        assert_eq!(vm.expressions[&old_id].gain, 1.0);
+        assert_eq!(vm.expressions[&old_id].target_gain, 0.0);
+
+        for _ in range(0, 6) {
+            tick(&mut vm);
+        }
+        assert_eq!(vm.expressions.len(), 2);
+        let gain = vm.expressions[&1].gain;
+        let old_gain = vm.expressions[&old_id].gain;
+        assert!(gain > 0.0 && gain < 1.0);
+        assert!((gain + old_gain - 1.0).abs() < 1e-4);
+
+        tick(&mut vm);
+        assert_eq!(vm.expressions.len(), 1);
+        assert_eq!(vm.expressions[&1].gain, 1.0);
+        assert_eq!(vm.units.len(), 1);
+        // The old version had an internal id, so the client isn't told
+        assert!(notifications(&mut vm).is_empty());
+    }
+
+    fn set_phase(vm: &mut VmInner, expression_id: u32, value: f32) {
+        let index = vm.units[&(expression_id, 0)].data_index.unwrap();
+        *vm.unit_data.iter_mut(index).next().unwrap() = value;
+    }
+
+    fn phase(vm: &VmInner, expression_id: u32) -> f32 {
+        let index = vm.units[&(expression_id, 0)].data_index.unwrap();
+        *vm.unit_data.get(index).unwrap()
+    }
+
+    #[test]
+    fn replace_expression_carries_state() {
+        let mut vm = vm();
+        let mut byte_code = Vec::new();
+        write_sine_expression(&mut byte_code, 1);
+        write_sine_expression(&mut byte_code, 2);
+        vm.process_byte_code(&byte_code[..]).unwrap();
+        set_phase(&mut vm, 1, 0.25);
+        set_phase(&mut vm, 2, 0.25);
+
+        let mut byte_code = Vec::new();
+        replace_with_sine(&mut byte_code, 1, 0.01, true);
+        replace_with_sine(&mut byte_code, 2, 0.01, false);
+        vm.process_byte_code(&byte_code[..]).unwrap();
+
+        assert_eq!(phase(&vm, 1), 0.25);
+        assert_eq!(phase(&vm, 2), 0.0);
+    }
+
+    #[test]
+    fn replaced_expression_keeps_its_edges() {
+        let mut vm = vm();
+        let mut byte_code = Vec::new();
+        write_sine_expression(&mut byte_code, 1);
+        write_sine_expression(&mut byte_code, 2);
+        byte_code.write_control_opcode(
+            &ControlOpcode::AddEdge { from: 1, to: 2 }
+        ).unwrap();
+        vm.process_byte_code(&byte_code[..]).unwrap();
+
+        let mut byte_code = Vec::new();
+        replace_with_sine(&mut byte_code, 2, 0.01, false);
+        vm.process_byte_code(&byte_code[..]).unwrap();
+        let old_id = internal_id(&vm);
+
+        let edges: Vec<(u32, u32)> = vm.graph.edges().iter().map(|edge| {
+            (edge.from, edge.to)
+        }).collect();
+        assert_eq!(edges, [(1, 2), (1, old_id)]);
+
+        // The copies go once the old version has faded out
+        for _ in range(0, 7) {
+            tick(&mut vm);
+        }
+        assert_eq!(vm.graph.len(), 1);
+    }
+
+    #[test]
+    fn replace_expression_fails_when_edges_do_not_fit() {
+        let mut vm = vm_with_options(
+            Options {
+                max_edges: 1,
+                .. Default::default()
+            }
+        );
+        let mut byte_code = Vec::new();
+        write_sine_expression(&mut byte_code, 1);
+        write_sine_expression(&mut byte_code, 2);
+        byte_code.write_control_opcode(
+            &ControlOpcode::AddEdge { from: 1, to: 2 }
+        ).unwrap();
+        vm.process_byte_code(&byte_code[..]).unwrap();
+
+        let mut byte_code = Vec::new();
+        replace_with_sine(&mut byte_code, 2, 0.01, false);
+        assert!(vm.process_byte_code(&byte_code[..]).is_err());
+        assert_eq!(vm.expressions.len(), 2);
+    }
+
+    // Runs an expression until its envelope has finished
+    fn run_envelope(done_action: DoneAction) -> VmInner {
+        let mut vm = vm();
+        let mut byte_code = Vec::new();
+        write_envelope_expression(&mut byte_code, 1, done_action);
+        vm.process_byte_code(&byte_code[..]).unwrap();
+        for _ in range(0, 8) {
+            tick(&mut vm);
+        }
+        vm
+    }
+
+    #[test]
+    fn done_action_none_keeps_expression_running() {
+        let mut vm = run_envelope(DoneAction::None);
+
+        assert_eq!(vm.expressions[&1].state, ExpressionState::Run);
+        assert_eq!(notifications(&mut vm).len(), 0);
+    }
+
+    #[test]
+    fn done_action_pause_pauses_expression() {
+        let mut vm = run_envelope(DoneAction::Pause);
+
+        assert_eq!(vm.expressions[&1].state, ExpressionState::Paused);
+        assert_eq!(notifications(&mut vm).len(), 0);
+    }
+
+    #[test]
+    fn done_action_free_frees_expression_and_notifies() {
+        let mut vm = run_envelope(DoneAction::Free);
+
+        assert!(!vm.expressions.contains_key(&1));
+        let notifications = notifications(&mut vm);
+        assert_eq!(notifications.len(), 1);
+        match notifications[0] {
+            Notification::ExpressionFreed { expression_id: 1 } => {},
+            _ => panic!("Expected expression freed")
+        }
+    }
 
+    #[test]
+    fn done_action_free_frees_voice_and_notifies() {
+        let mut vm = vm();
+        let mut byte_code = Vec::new();
+        register_template(&mut byte_code, 1, 0, &envelope());
+        byte_code.write_u32::<BigEndian>(
+            ControlOpcodeType::NoteOn as u32
+        ).unwrap();
+        byte_code.write_u32::<BigEndian>(1).unwrap();
+        byte_code.write_u32::<BigEndian>(7).unwrap();
+        byte_code.write_u32::<BigEndian>(3).unwrap();
+        for &(parameter_id, value) in
+                envelope_parameters(DoneAction::Free).iter() {
+            byte_code.write_u32::<BigEndian>(0).unwrap();
+            byte_code.write_u32::<BigEndian>(parameter_id).unwrap();
+            byte_code.write_f32::<BigEndian>(value).unwrap();
+        }
+        vm.process_byte_code(&byte_code[..]).unwrap();
+        assert_eq!(vm.voice_allocator.active_voices(1), 1);
+
+        for _ in range(0, 8) {
+            tick(&mut vm);
+        }
+
+        // The voice's expression is internal, so only the voice is reported
+        assert_eq!(vm.voice_allocator.active_voices(1), 0);
+        assert_eq!(vm.expressions.len(), 0);
+        let notifications = notifications(&mut vm);
+        assert_eq!(notifications.len(), 1);
+        match notifications[0] {
+            Notification::VoiceFreed { note_id: 7 } => {},
+            _ => panic!("Expected voice freed")
+        }
+    }
+
+    // Maps the frequency of the sine in an expression to an audio rate bus
+    fn map_frequency(byte_code: &mut Vec<u8>, expression_id: u32,
+                     bus_id: u32) {
+        byte_code.write_control_opcode(
+            &ControlOpcode::MapParameter {
+                expression_id: expression_id,
+                unit_id: 0,
+                parameter_id: 0,
+                bus_id: bus_id,
+                rate: Rate::Audio
+            }
+        ).unwrap();
+    }
+
+    fn unmap_frequency(byte_code: &mut Vec<u8>, expression_id: u32) {
+        byte_code.write_u32::<BigEndian>(
+            ControlOpcodeType::UnmapParameter as u32
+        ).unwrap();
+        byte_code.write_u32::<BigEndian>(expression_id).unwrap();
+        byte_code.write_u32::<BigEndian>(0).unwrap();
+        byte_code.write_u32::<BigEndian>(0).unwrap();
+    }
 
+    fn remove_expression(byte_code: &mut Vec<u8>, expression_id: u32) {
+        byte_code.write_u32::<BigEndian>(
+            ControlOpcodeType::RemoveExpression as u32
+        ).unwrap();
+        byte_code.write_u32::<BigEndian>(expression_id).unwrap();
+    }
+
+    // The frequency most recently read by the sine in an expression
+    fn frequency(vm: &VmInner, expression_id: u32) -> f32 {
+        vm.parameters[&(expression_id, 0, 0)].current_value()
+    }
+
+    // A sine in expression 1 with its frequency mapped to the input bus
+    fn mapped_vm() -> VmInner {
+        let mut vm = vm();
+        let mut byte_code = Vec::new();
+        write_sine_expression(&mut byte_code, 1);
+        map_frequency(&mut byte_code, 1, 0);
+        vm.process_byte_code(&byte_code[..]).unwrap();
+        vm
+    }
+
+    #[test]
+    fn mapped_parameter_follows_bus() {
+        let mut vm = mapped_vm();
+        tick_with_input(&mut vm, 220.0);
+        assert_eq!(frequency(&vm, 1), 220.0);
+        tick_with_input(&mut vm, 330.0);
+        assert_eq!(frequency(&vm, 1), 330.0);
+    }
+
+    #[test]
+    fn unmapped_parameter_returns_to_its_value() {
+        let mut vm = mapped_vm();
+        tick_with_input(&mut vm, 220.0);
+
+        let mut byte_code = Vec::new();
+        unmap_frequency(&mut byte_code, 1);
+        vm.process_byte_code(&byte_code[..]).unwrap();
+        tick_with_input(&mut vm, 220.0);
+        assert_eq!(frequency(&vm, 1), 440.0);
+    }
+
+    #[test]
+    fn freed_expression_drops_its_mapping() {
+        let mut vm = mapped_vm();
+        tick_with_input(&mut vm, 220.0);
+
+        let mut byte_code = Vec::new();
+        remove_expression(&mut byte_code, 1);
+        vm.process_byte_code(&byte_code[..]).unwrap();
+        assert!(!vm.parameters.contains_key(&(1, 0, 0)));
+        tick_with_input(&mut vm, 220.0);
+
+        // A new expression with the same id starts out unmapped
+        let mut byte_code = Vec::new();
+        write_sine_expression(&mut byte_code, 1);
+        vm.process_byte_code(&byte_code[..]).unwrap();
+        assert!(vm.parameters[&(1, 0, 0)].mapped_bus.is_none());
+        tick_with_input(&mut vm, 220.0);
+        assert_eq!(frequency(&vm, 1), 440.0);
+    }
+
+    #[test]
+    fn mapped_parameter_returns_to_its_value_when_writer_is_freed() {
+        let mut vm = vm();
+        let mut byte_code = Vec::new();
+        write_sine_expression(&mut byte_code, 1);
+        map_frequency(&mut byte_code, 1, 5);
+
+        // Expression 2 writes a constant to bus 5 before expression 1 runs
+        byte_code.write_control_opcode(
+            &ControlOpcode::AddExpression {
+                expression_id: 2,
+                num_opcodes: 2,
+                name: Name::empty()
+            }
+        ).unwrap();
+        byte_code.write_dsp_opcode(&source(0, type_id("parameter_ar")))
+                 .unwrap();
+        byte_code.write_dsp_opcode(
+            &DspOpcode::Unit {
+                unit_id: 1,
+                type_id: type_id("bus_out_ar"),
+                input_channels: 1,
+                output_channels: 0,
+                stack_parameters: 0,
+                num_arguments: 0,
+                name: Name::empty()
+            }
+        ).unwrap();
+        for &(unit_id, value) in [(0, 220.0), (1, 5.0)].iter() {
+            byte_code.write_control_opcode(
+                &ControlOpcode::SetParameter {
+                    expression_id: 2,
+                    unit_id: unit_id,
+                    parameter_id: 0,
+                    value: value
+                }
+            ).unwrap();
+        }
+        byte_code.write_control_opcode(
+            &ControlOpcode::AddEdge { from: 2, to: 1 }
+        ).unwrap();
+        vm.process_byte_code(&byte_code[..]).unwrap();
+
+        // The written value ramps in over the first block
+        tick(&mut vm);
+        tick(&mut vm);
+        assert_eq!(frequency(&vm, 1), 220.0);
+
+        let mut byte_code = Vec::new();
+        remove_expression(&mut byte_code, 2);
+        vm.process_byte_code(&byte_code[..]).unwrap();
+        tick(&mut vm);
+        assert!(vm.parameters[&(1, 0, 0)].mapped_bus.is_some());
+        assert_eq!(frequency(&vm, 1), 440.0);
+    }
+}
@@ -0,0 +1,163 @@
+use types::ArtResult;
+use errors::ArtError;
+use opcode::ControlOpcode;
+use midi::{self, MidiMessage, NoteMapping, NUM_CHANNELS, NO_UNIT};
+use vm_inner::VmInner;
+
+// MIDI messages arrive either in Midi opcodes or from sequences.  Notes
+// start voices from the template mapped to their channel, and controllers
+// set the parameters mapped to them.
+impl VmInner {
+    pub fn prepare_midi(&mut self, opcode: ControlOpcode, status: u8,
+                        data_1: u8, data_2: u8) -> ArtResult<()> {
+        if status & 0x80 == 0 {
+            return Err(ArtError::InvalidByteCode);
+        }
+
+        let message = MidiMessage::parse(status, data_1, data_2);
+        if let Some(MidiMessage::NoteOn { channel, .. }) = message {
+            // Notes on unmapped channels are ignored, so space for a voice
+            // is only needed when there is a template to play
+            let template = self.find_note_template(channel).and_then(
+                |template_id| {
+                    self.find_template(template_id).ok().map(
+                        |(index, num_opcodes)| {
+                            (template_id, index, num_opcodes)
+                        }
+                    )
+                }
+            );
+            if let Some((template_id, index, num_opcodes)) = template {
+                try!(self.validate_voice(template_id, index, num_opcodes));
+            }
+        }
+        self.transaction.push(opcode, None)
+    }
+
+    // The pitch and velocity are (unit_id, parameter_id) pairs, with a
+    // unit_id of NO_UNIT when they aren't mapped
+    pub fn prepare_map_notes(&mut self, opcode: ControlOpcode, channel: u32,
+                             template_id: u32, pitch: (u32, u32),
+                             velocity: (u32, u32)) -> ArtResult<()> {
+        if channel >= NUM_CHANNELS {
+            return Err(ArtError::InvalidByteCode);
+        }
+
+        let (index, num_opcodes) = try!(self.find_template(template_id));
+        for &(unit_id, parameter_id) in [pitch, velocity].iter() {
+            if unit_id != NO_UNIT &&
+                    !self.has_parameter(index, num_opcodes, unit_id,
+                                        parameter_id) {
+                return Err(
+                    ArtError::ParameterNotFound {
+                        expression_id: template_id,
+                        unit_id: unit_id,
+                        parameter_id: parameter_id
+                    }
+                );
+            }
+        }
+        self.transaction.push(opcode, None)
+    }
+
+    pub fn map_notes(&mut self, channel: u32, template_id: u32,
+                     pitch: (u32, u32), velocity: (u32, u32))
+            -> ArtResult<()> {
+        let mapped = |(unit_id, parameter_id): (u32, u32)| {
+            if unit_id == NO_UNIT {
+                None
+            }
+            else {
+                Some((unit_id, parameter_id))
+            }
+        };
+
+        self.midi_map.notes[channel as usize] = Some(
+            NoteMapping {
+                template_id: template_id,
+                pitch: mapped(pitch),
+                velocity: mapped(velocity)
+            }
+        );
+        Ok(())
+    }
+
+    // Finds the template mapped to a MIDI channel, taking account of the
+    // transaction
+    pub fn find_note_template(&self, channel: u32) -> Option<u32> {
+        match self.transaction.find_note_mapping(channel) {
+            Some(template_id) => template_id,
+            None => {
+                self.midi_map.notes[channel as usize].map(|mapping| {
+                    mapping.template_id
+                })
+            }
+        }
+    }
+
+    pub fn midi(&mut self, message: MidiMessage) -> ArtResult<()> {
+        debug!("MIDI message: message={:?}", message);
+        match message {
+            MidiMessage::NoteOn { channel, note, velocity } => {
+                let mapping = match self.midi_map.notes[channel as usize] {
+                    Some(mapping) => mapping,
+                    None => return Ok(())
+                };
+
+                // The template may have been unregistered since the channel
+                // was mapped
+                if !self.voice_allocator.templates.contains_key(
+                        &mapping.template_id) {
+                    return Ok(());
+                }
+
+                let expression_id = try!(
+                    self.start_voice(mapping.template_id,
+                                     midi::note_id(channel, note))
+                );
+
+                if let Some((unit_id, parameter_id)) = mapping.pitch {
+                    self.set_mapped_parameter(
+                        (expression_id, unit_id, parameter_id),
+                        midi::note_to_frequency(note)
+                    );
+                }
+
+                if let Some((unit_id, parameter_id)) = mapping.velocity {
+                    self.set_mapped_parameter(
+                        (expression_id, unit_id, parameter_id),
+                        velocity as f32 / 127.0
+                    );
+                }
+                Ok(())
+            },
+            MidiMessage::NoteOff { channel, note } => {
+                self.note_off(midi::note_id(channel, note))
+            },
+            MidiMessage::Controller { channel, controller, value } => {
+                let mapping = match self.midi_map.controllers.get(
+                        &(channel, controller)) {
+                    Some(mapping) => *mapping,
+                    None => return Ok(())
+                };
+
+                let value = mapping.curve.scale(value, mapping.minimum,
+                                                mapping.maximum);
+                self.set_mapped_parameter(
+                    (mapping.expression_id, mapping.unit_id,
+                     mapping.parameter_id),
+                    value
+                );
+                Ok(())
+            }
+        }
+    }
+
+    // Mappings can outlive the expressions they point at, so missing
+    // parameters are ignored
+    fn set_mapped_parameter(&mut self, id: (u32, u32, u32), value: f32) {
+        if self.parameters.contains_key(&id) {
+            self.set_parameter(id, value).unwrap();
+        }
+    }
+}
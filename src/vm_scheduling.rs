@@ -0,0 +1,220 @@
+use std::mem;
+use std::io::Cursor;
+
+use types::ArtResult;
+use errors::ArtError;
+use opcode::ControlOpcode;
+use notification::Notification;
+use validator::ExpressionValidator;
+use transaction::Reservation;
+use sequencer::Sequence;
+use scheduler::ScheduledMessage;
+use midi::{self, MidiMessage};
+use vm_inner::VmInner;
+
+// Messages scheduled for a beat and sequences of MIDI events both follow the
+// clock.  Each block plays whatever falls due before the block ends.
+impl VmInner {
+    pub fn prepare_sequence(&mut self, opcode: ControlOpcode,
+                            sequence_id: u32, ticks_per_quarter: u32,
+                            num_events: usize, reader: &mut Cursor<&[u8]>)
+            -> ArtResult<()> {
+        if ticks_per_quarter == 0 {
+            return Err(ArtError::InvalidByteCode);
+        }
+
+        {
+            let sequences = &self.sequencer.sequences;
+            if !sequences.contains_key(&sequence_id) &&
+                    sequences.len() >= sequences.capacity() {
+                return Err(ArtError::BufferOverflow);
+            }
+        }
+
+        let index = try!(self.sequencer.push_from_reader(num_events, reader));
+
+        let result = self.transaction.push(opcode, Some(index));
+        if result.is_err() {
+            self.sequencer.events.free(index, num_events);
+        }
+        result
+    }
+
+    // The message is only validated once it is due, as the state of the VM
+    // will have changed by then
+    pub fn prepare_scheduled_message(&mut self, opcode: ControlOpcode,
+                                     size: usize, reader: &mut Cursor<&[u8]>)
+            -> ArtResult<()> {
+        if size > self.scheduler.max_message_size {
+            return Err(ArtError::BufferOverflow);
+        }
+
+        {
+            let staged = self.transaction.items.iter().filter(|item| {
+                match item.opcode {
+                    ControlOpcode::ScheduleMessage { .. } => true,
+                    _ => false
+                }
+            }).count();
+            let messages = &self.scheduler.messages;
+            if messages.len() + staged >= messages.capacity() {
+                return Err(ArtError::BufferOverflow);
+            }
+        }
+
+        let index = try!(self.scheduler.push_from_reader(size, reader));
+
+        let result = self.transaction.push(opcode, Some(index));
+        if result.is_err() {
+            self.scheduler.data.free(index, size);
+        }
+        result
+    }
+
+    pub fn validate_sequence(&self, sequence_id: u32) -> ArtResult<()> {
+        let exists = match self.transaction.sequence_exists(sequence_id) {
+            Some(exists) => exists,
+            None => self.sequencer.sequences.contains_key(&sequence_id)
+        };
+
+        if !exists {
+            return Err(
+                ArtError::SequenceNotFound {
+                    sequence_id: sequence_id
+                }
+            );
+        }
+        Ok(())
+    }
+
+    // Processes the scheduled messages which are due in this block.  Messages
+    // can schedule further messages, so only those which were already
+    // waiting are processed, in case they schedule themselves again.
+    pub fn schedule(&mut self) {
+        let end = self.clock.beat +
+                  self.block_time() * self.clock.beats_per_second();
+        for _ in range(0, self.scheduler.messages.len()) {
+            let message = match self.scheduler.pop_due(end) {
+                Some(message) => message,
+                None => break
+            };
+
+            let size = self.scheduler.take(&message);
+            let buffer = mem::replace(&mut self.scheduler.buffer,
+                                      Vec::with_capacity(0));
+            let result = self.process_byte_code(&buffer[..size]);
+            result.unwrap_or_else(|error| error!("{}", error));
+            self.scheduler.buffer = buffer;
+        }
+    }
+
+    pub fn schedule_message(&mut self, beat: f64, index: usize, size: usize)
+            -> ArtResult<()> {
+        debug_assert!(self.scheduler.messages.len() <
+                      self.scheduler.messages.capacity());
+        self.scheduler.messages.push(
+            ScheduledMessage {
+                beat: beat,
+                index: index,
+                size: size
+            }
+        );
+        Ok(())
+    }
+
+    pub fn sequence(&mut self) {
+        let block_time = self.block_time();
+        self.sequencer.advance(block_time);
+
+        let messages = mem::replace(&mut self.sequencer.messages,
+                                    Vec::with_capacity(0));
+        for message in messages.iter() {
+            let result = self.sequence_midi(*message);
+            result.unwrap_or_else(|error| error!("{}", error));
+        }
+        self.sequencer.messages = messages;
+        self.sequencer.messages.clear();
+
+        let ended = mem::replace(&mut self.sequencer.ended,
+                                 Vec::with_capacity(0));
+        for id in ended.iter() {
+            self.notify(
+                Notification::SequenceEnded {
+                    sequence_id: *id
+                }
+            );
+        }
+        self.sequencer.ended = ended;
+        self.sequencer.ended.clear();
+    }
+
+    // The length of a block in seconds
+    pub fn block_time(&self) -> f64 {
+        self.constants.block_size as f64 *
+            self.constants.audio_rate_inverse as f64
+    }
+
+    // Sequenced notes haven't been through the validator, so make sure there
+    // is space for a new voice before playing them
+    fn sequence_midi(&mut self, message: MidiMessage) -> ArtResult<()> {
+        if let MidiMessage::NoteOn { channel, .. } = message {
+            if let Some(mapping) = self.midi_map.notes[channel as usize] {
+                if let Ok(template) = self.voice_allocator.get_template(
+                        mapping.template_id) {
+                    try!(
+                        ExpressionValidator::validate(
+                            template.index, template.num_opcodes,
+                            &self.expression_store, &mut self.stack_record,
+                            &self.unit_factory, &self.expressions,
+                            &self.units, &self.parameters, &self.unit_data,
+                            &mut Reservation::new()
+                        )
+                    );
+                }
+            }
+        }
+        self.midi(message)
+    }
+
+    pub fn add_sequence(&mut self, sequence_id: u32, sequence: Sequence)
+            -> ArtResult<()> {
+        debug!("Adding sequence: sequence_id={}, index={}", sequence_id,
+               sequence.index);
+        if self.sequencer.sequences.contains_key(&sequence_id) {
+            try!(self.remove_sequence(sequence_id));
+        }
+
+        debug_assert!(self.sequencer.sequences.len() <
+                      self.sequencer.sequences.capacity());
+        self.sequencer.sequences.insert(sequence_id, sequence);
+        Ok(())
+    }
+
+    pub fn remove_sequence(&mut self, sequence_id: u32) -> ArtResult<()> {
+        debug!("Removing sequence: sequence_id={}", sequence_id);
+        try!(self.stop_sequence(sequence_id));
+        let sequence = self.sequencer.sequences.remove(&sequence_id).unwrap();
+        self.sequencer.events.free(sequence.index, sequence.num_events);
+        Ok(())
+    }
+
+    pub fn start_sequence(&mut self, sequence_id: u32, restart: bool)
+            -> ArtResult<()> {
+        let sequence = try!(self.sequencer.get_mut(sequence_id));
+        if restart {
+            sequence.rewind();
+        }
+        sequence.playing = true;
+        Ok(())
+    }
+
+    pub fn stop_sequence(&mut self, sequence_id: u32) -> ArtResult<()> {
+        let mut sequence = *try!(self.sequencer.get_mut(sequence_id));
+        sequence.playing = false;
+        sequence.release_notes(|channel, note| {
+            let _ = self.note_off(midi::note_id(channel, note));
+        });
+        *self.sequencer.get_mut(sequence_id).unwrap() = sequence;
+        Ok(())
+    }
+}
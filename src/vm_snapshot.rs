@@ -0,0 +1,127 @@
+use std::mem;
+use std::io::Cursor;
+
+use byteorder::{ReadBytesExt, BigEndian};
+
+use types::ArtResult;
+use errors::ArtError;
+use opcode::{ControlOpcode, DspOpcode};
+use notification::Notification;
+use vm_inner::VmInner;
+
+// Snapshots are saved on the audio thread and sent to the client over the
+// following blocks.  Restoring one is an ordinary message, apart from
+// SetUnitData, which puts back the state of the units.
+impl VmInner {
+    // The values are read from the message when the opcode is applied, so
+    // only their position is staged
+    pub fn prepare_unit_data(&mut self, opcode: ControlOpcode,
+                             id: (u32, u32), num_values: usize,
+                             reader: &mut Cursor<&[u8]>) -> ArtResult<()> {
+        let (eid, uid) = id;
+        let size = try!(self.unit_data_size(eid, uid));
+        if num_values != size {
+            return Err(ArtError::InvalidByteCode);
+        }
+
+        let position = reader.position() as usize;
+        let end = position + size * mem::size_of::<f32>();
+        if end > reader.get_ref().len() {
+            return Err(ArtError::InvalidByteCode);
+        }
+        reader.set_position(end as u64);
+        self.transaction.push(opcode, Some(position))
+    }
+
+    // The size of a unit's data, taking account of the transaction
+    fn unit_data_size(&self, expression_id: u32, unit_id: u32)
+            -> ArtResult<usize> {
+        let error = ArtError::UnitNotFound {
+            expression_id: expression_id,
+            unit_id: unit_id
+        };
+
+        if !self.transaction.expression_exists(expression_id,
+                                               &self.expressions) {
+            return Err(error);
+        }
+
+        let (index, num_opcodes) = self.find_expression_opcodes(
+            expression_id
+        );
+        for opcode in self.expression_store.iter(index).take(num_opcodes) {
+            if let &DspOpcode::Unit { unit_id: uid, type_id, num_arguments,
+                                      .. } = opcode {
+                if uid == unit_id {
+                    let definition = self.unit_factory.get_definition(type_id);
+                    return Ok(
+                        definition.data_size.size(num_arguments as usize)
+                    );
+                }
+            }
+        }
+        Err(error)
+    }
+
+    pub fn save_snapshot(&mut self, snapshot_id: u32, unit_data: bool) {
+        debug!("Saving snapshot: snapshot_id={}, unit_data={}", snapshot_id,
+               unit_data);
+        let result = if self.snapshot.is_pending() {
+            Err(ArtError::BufferOverflow)
+        }
+        else {
+            let data = if unit_data {
+                Some(&self.unit_data)
+            }
+            else {
+                None
+            };
+            self.snapshot.save(snapshot_id, &self.expressions,
+                               &self.expression_store, &self.unit_factory,
+                               &self.units, &self.parameters, &self.graph,
+                               data)
+        };
+
+        if let Err(error) = result {
+            error!("Could not save snapshot: {}", error);
+            self.notify(
+                Notification::SnapshotFailed {
+                    snapshot_id: snapshot_id
+                }
+            );
+        }
+    }
+
+    // Sends as much of the waiting snapshot as fits in the output channel.
+    // The rest is sent on later blocks.
+    pub fn send_snapshot(&mut self) {
+        while let Some(notification) = self.snapshot.next_notification() {
+            if VmInner::send_notification(&mut self.output_channel,
+                                          notification).is_err() {
+                return;
+            }
+            self.snapshot.advance();
+        }
+    }
+
+    // Replaces the state of a unit with values read from the message
+    pub fn set_unit_data(&mut self, id: (u32, u32), num_values: usize,
+                         reader: &mut Cursor<&[u8]>) -> ArtResult<()> {
+        let (eid, uid) = id;
+        let unit = try!(
+            self.units.get(&id).ok_or(
+                ArtError::UnitNotFound {
+                    expression_id: eid,
+                    unit_id: uid
+                }
+            )
+        );
+
+        if let Some(index) = unit.data_index {
+            for value in self.unit_data.iter_mut(index).take(num_values) {
+                *value = try!(reader.read_f32::<BigEndian>());
+            }
+        }
+        Ok(())
+    }
+}
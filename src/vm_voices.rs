@@ -0,0 +1,296 @@
+use std::io::Cursor;
+
+use types::ArtResult;
+use errors::ArtError;
+use opcode::ControlOpcode;
+use opcode_reader::OpcodeReader;
+use notification::Notification;
+use validator::ExpressionValidator;
+use transaction::Reservation;
+use voice_allocator::{Template, NO_GATE, STEAL_FADE_TIME};
+use midi::{self, MidiMessage};
+use name::Name;
+use vm_inner::VmInner;
+
+// Voices are expressions created from templates by NoteOn opcodes and MIDI
+// notes.  Each voice is tracked by the voice allocator until it is released
+// or stolen.
+impl VmInner {
+    // Templates are never run themselves, so no space is reserved for them
+    pub fn prepare_template(&mut self, opcode: ControlOpcode,
+                            template_id: u32, num_opcodes: usize,
+                            gate: (u32, u32), reader: &mut Cursor<&[u8]>)
+            -> ArtResult<()> {
+        {
+            let templates = &self.voice_allocator.templates;
+            if !templates.contains_key(&template_id) &&
+                    templates.len() >= templates.capacity() {
+                return Err(ArtError::BufferOverflow);
+            }
+        }
+
+        let index = try!(
+            self.expression_store.push_from_reader(num_opcodes, reader)
+        );
+
+        let result = self.transaction.push(opcode, Some(index));
+        if result.is_err() {
+            let _ = self.expression_store.free(index, num_opcodes);
+            return result;
+        }
+
+        try!(
+            ExpressionValidator::validate(
+                index, num_opcodes, &self.expression_store,
+                &mut self.stack_record, &self.unit_factory, &self.expressions,
+                &self.units, &self.parameters, &self.unit_data,
+                &mut Reservation::new()
+            )
+        );
+
+        let (gate_unit_id, gate_parameter_id) = gate;
+        if gate_unit_id != NO_GATE &&
+                !self.has_parameter(index, num_opcodes, gate_unit_id,
+                                    gate_parameter_id) {
+            return Err(
+                ArtError::ParameterNotFound {
+                    expression_id: template_id,
+                    unit_id: gate_unit_id,
+                    parameter_id: gate_parameter_id
+                }
+            );
+        }
+        Ok(())
+    }
+
+    // The parameters are read again when the opcode is applied, so only
+    // their position in the message is staged
+    pub fn prepare_note_on(&mut self, opcode: ControlOpcode, template_id: u32,
+                           note_id: u32, num_parameters: u32,
+                           reader: &mut Cursor<&[u8]>) -> ArtResult<()> {
+        if midi::is_midi_note_id(note_id) {
+            return Err(ArtError::InvalidByteCode);
+        }
+
+        let (index, num_opcodes) = try!(self.find_template(template_id));
+        try!(self.validate_voice(template_id, index, num_opcodes));
+
+        let position = reader.position() as usize;
+        for _ in range(0, num_parameters) {
+            let (unit_id, parameter_id, _) = try!(
+                reader.read_note_parameter()
+            );
+            if !self.has_parameter(index, num_opcodes, unit_id,
+                                   parameter_id) {
+                return Err(
+                    ArtError::ParameterNotFound {
+                        expression_id: template_id,
+                        unit_id: unit_id,
+                        parameter_id: parameter_id
+                    }
+                );
+            }
+        }
+        self.transaction.push(opcode, Some(position))
+    }
+
+    // Finds the opcodes of a template, taking account of the transaction
+    pub fn find_template(&self, template_id: u32) -> ArtResult<(usize, usize)> {
+        match self.transaction.find_template(template_id) {
+            Some(Some(opcodes)) => Ok(opcodes),
+            Some(None) => {
+                Err(
+                    ArtError::TemplateNotFound {
+                        template_id: template_id
+                    }
+                )
+            },
+            None => {
+                let template = try!(
+                    self.voice_allocator.get_template(template_id)
+                );
+                Ok((template.index, template.num_opcodes))
+            }
+        }
+    }
+
+    // Each voice is a new expression.  If starting the voice will steal
+    // another then the stolen voice's expression can make way for it.
+    pub fn validate_voice(&mut self, template_id: u32, index: usize,
+                      num_opcodes: usize) -> ArtResult<()> {
+        let max_voices = match self.transaction.find_template_max_voices(
+                template_id) {
+            Some(max_voices) => max_voices,
+            None => try!(self.voice_allocator.get_template(template_id))
+                        .max_voices
+        };
+
+        let voices = self.voice_allocator.active_voices(template_id) +
+                     self.staged_voices(template_id);
+        let steals = max_voices > 0 && voices >= max_voices;
+        if steals {
+            self.transaction.reservation.stolen_voices += 1;
+        }
+
+        ExpressionValidator::validate(
+            index, num_opcodes, &self.expression_store,
+            &mut self.stack_record, &self.unit_factory, &self.expressions,
+            &self.units, &self.parameters, &self.unit_data,
+            &mut self.transaction.reservation
+        )
+    }
+
+    // The number of voices of the template started by the opcodes staged so
+    // far
+    fn staged_voices(&self, template_id: u32) -> u32 {
+        self.transaction.items.iter().filter(|item| {
+            match item.opcode {
+                ControlOpcode::NoteOn { template_id: id, .. } => {
+                    id == template_id
+                },
+                ControlOpcode::Midi { status, data_1, data_2 } => {
+                    match MidiMessage::parse(status, data_1, data_2) {
+                        Some(MidiMessage::NoteOn { channel, .. }) => {
+                            self.find_note_template(channel) ==
+                                Some(template_id)
+                        },
+                        _ => false
+                    }
+                },
+                _ => false
+            }
+        }).count() as u32
+    }
+
+    pub fn register_template(&mut self, template_id: u32, template: Template)
+            -> ArtResult<()> {
+        debug!("Registering template: template_id={}, index={}",
+               template_id, template.index);
+        debug_assert!(
+            self.voice_allocator.templates.contains_key(&template_id) ||
+            self.voice_allocator.templates.len() <
+                self.voice_allocator.templates.capacity()
+        );
+
+        let old_template = self.voice_allocator.templates.insert(template_id,
+                                                                 template);
+        if let Some(old_template) = old_template {
+            self.expression_store.free(old_template.index,
+                                       old_template.num_opcodes);
+        }
+        Ok(())
+    }
+
+    // Voices which are already playing are unaffected
+    pub fn unregister_template(&mut self, template_id: u32) -> ArtResult<()> {
+        debug!("Unregistering template: template_id={}", template_id);
+        let template = try!(
+            self.voice_allocator.templates.remove(&template_id).ok_or(
+                ArtError::TemplateNotFound {
+                    template_id: template_id
+                }
+            )
+        );
+        self.expression_store.free(template.index, template.num_opcodes);
+        Ok(())
+    }
+
+    pub fn note_on(&mut self, template_id: u32, note_id: u32,
+                   num_parameters: u32, reader: &mut Cursor<&[u8]>)
+            -> ArtResult<()> {
+        debug!("Note on: template_id={}, note_id={}", template_id, note_id);
+        let expression_id = try!(self.start_voice(template_id, note_id));
+
+        for _ in range(0, num_parameters) {
+            let (unit_id, parameter_id, value) = try!(
+                reader.read_note_parameter()
+            );
+            try!(
+                self.set_parameter((expression_id, unit_id, parameter_id),
+                                   value)
+            );
+        }
+        Ok(())
+    }
+
+    // Creates a voice from the template, stealing another voice if the
+    // template is already at full polyphony.  Returns the id of the voice's
+    // expression.
+    pub fn start_voice(&mut self, template_id: u32, note_id: u32)
+            -> ArtResult<u32> {
+        let template = try!(self.voice_allocator.get_template(template_id));
+
+        if template.max_voices > 0 &&
+                self.voice_allocator.active_voices(template_id) >=
+                    template.max_voices {
+            let victim = self.voice_allocator.find_victim(
+                template_id, template.steal_mode, &self.expressions
+            );
+            if let Some(victim) = victim {
+                let expression_id = self.voice_allocator.voices[victim]
+                                                        .expression_id;
+                self.steal_voice(expression_id);
+
+                // The validator counted on the stolen voice making space, so
+                // it can't wait to fade out if the map is full
+                if self.expressions.len() >= self.expressions.capacity() {
+                    try!(self.remove_expression(expression_id));
+                }
+            }
+        }
+
+        let index = try!(
+            self.expression_store.copy(template.index, template.num_opcodes)
+        );
+        let expression_id = self.allocate_expression_id();
+        try!(
+            self.add_expression(expression_id, index, template.num_opcodes,
+                                Name::empty())
+        );
+        try!(
+            self.voice_allocator.add(note_id, template_id, expression_id,
+                                     template.gate)
+        );
+        Ok(expression_id)
+    }
+
+    pub fn note_off(&mut self, note_id: u32) -> ArtResult<()> {
+        debug!("Note off: note_id={}", note_id);
+        let index = match self.voice_allocator.find(note_id) {
+            Some(index) => index,
+            None => return Ok(())
+        };
+
+        self.voice_allocator.voices[index].released = true;
+        let voice = self.voice_allocator.voices[index];
+
+        match voice.gate {
+            // The voice should free itself using a done action
+            Some((unit_id, parameter_id)) => {
+                self.set_parameter((voice.expression_id, unit_id,
+                                    parameter_id), 0.0)
+            },
+            None => {
+                self.steal_voice(voice.expression_id);
+                Ok(())
+            }
+        }
+    }
+
+    // Quickly fades out a voice, and forgets about it straight away so its
+    // note can be reused
+    fn steal_voice(&mut self, expression_id: u32) {
+        if let Some(voice) = self.voice_allocator.remove_expression(
+                expression_id) {
+            self.notify(
+                Notification::VoiceFreed {
+                    note_id: voice.note_id
+                }
+            );
+        }
+
+        if let Some(expression) = self.expressions.get_mut(&expression_id) {
+            expression.fade(0.0, STEAL_FADE_TIME, &self.constants);
+        }
+    }
+}
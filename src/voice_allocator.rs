@@ -0,0 +1,215 @@
+use std::collections::HashMap;
+
+use types::{ArtResult, ExpressionMap};
+use errors::ArtError;
+
+// Unit id used in RegisterTemplate when the template has no gate parameter
+pub const NO_GATE: u32 = !0;
+
+// Time in seconds over which stolen voices are faded out
+pub const STEAL_FADE_TIME: f32 = 0.005;
+
+#[derive(Copy, PartialEq, FromPrimitive, Debug)]
+pub enum StealMode {
+    Oldest,
+    Quietest
+}
+
+// An expression which is copied to create each voice
+#[derive(Copy)]
+pub struct Template {
+    pub index: usize,
+    pub num_opcodes: usize,
+    pub max_voices: u32,
+    pub steal_mode: StealMode,
+    // The (unit_id, parameter_id) which is set to zero on NoteOff
+    pub gate: Option<(u32, u32)>
+}
+
+#[derive(Copy)]
+pub struct Voice {
+    pub note_id: u32,
+    pub template_id: u32,
+    pub expression_id: u32,
+    pub gate: Option<(u32, u32)>,
+    pub started: u64,
+    pub released: bool
+}
+
+pub struct VoiceAllocator {
+    pub templates: HashMap<u32, Template>,
+    pub voices: Vec<Voice>,
+    count: u64
+}
+
+impl VoiceAllocator {
+    pub fn with_capacity(max_templates: usize, max_voices: usize)
+            -> VoiceAllocator {
+        VoiceAllocator {
+            templates: HashMap::with_capacity(max_templates),
+            voices: Vec::with_capacity(max_voices),
+            count: 0
+        }
+    }
+
+    pub fn get_template(&self, template_id: u32) -> ArtResult<Template> {
+        self.templates.get(&template_id).map(|template| *template).ok_or(
+            ArtError::TemplateNotFound {
+                template_id: template_id
+            }
+        )
+    }
+
+    pub fn active_voices(&self, template_id: u32) -> u32 {
+        self.voices.iter().filter(|voice| {
+            voice.template_id == template_id
+        }).count() as u32
+    }
+
+    // Finds the most recent voice which is still playing the note
+    pub fn find(&self, note_id: u32) -> Option<usize> {
+        let mut found: Option<usize> = None;
+        for (index, voice) in self.voices.iter().enumerate() {
+            if voice.note_id != note_id || voice.released {
+                continue;
+            }
+
+            found = match found {
+                Some(other) if self.voices[other].started > voice.started => {
+                    Some(other)
+                },
+                _ => Some(index)
+            };
+        }
+        found
+    }
+
+    // Chooses a voice of the template to make way for a new one.  Voices
+    // which have already been released are always stolen first.
+    pub fn find_victim(&self, template_id: u32, steal_mode: StealMode,
+                       expressions: &ExpressionMap) -> Option<usize> {
+        let level = |voice: &Voice| {
+            expressions.get(&voice.expression_id).map_or(
+                0.0, |expression| expression.level
+            )
+        };
+
+        let mut victim: Option<usize> = None;
+        for (index, voice) in self.voices.iter().enumerate() {
+            if voice.template_id != template_id {
+                continue;
+            }
+
+            let better = match victim {
+                None => true,
+                Some(best) => {
+                    let best = &self.voices[best];
+                    if voice.released != best.released {
+                        voice.released
+                    }
+                    else {
+                        match steal_mode {
+                            StealMode::Oldest => voice.started < best.started,
+                            StealMode::Quietest => level(voice) < level(best)
+                        }
+                    }
+                }
+            };
+
+            if better {
+                victim = Some(index);
+            }
+        }
+        victim
+    }
+
+    pub fn add(&mut self, note_id: u32, template_id: u32,
+               expression_id: u32, gate: Option<(u32, u32)>)
+            -> ArtResult<()> {
+        if self.voices.len() == self.voices.capacity() {
+            return Err(ArtError::BufferOverflow);
+        }
+
+        self.voices.push(
+            Voice {
+                note_id: note_id,
+                template_id: template_id,
+                expression_id: expression_id,
+                gate: gate,
+                started: self.count,
+                released: false
+            }
+        );
+        self.count += 1;
+        Ok(())
+    }
+
+    pub fn remove_expression(&mut self, expression_id: u32) -> Option<Voice> {
+        let index = self.voices.iter().position(|voice| {
+            voice.expression_id == expression_id
+        });
+        index.map(|index| self.voices.swap_remove(index))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use expression::Expression;
+
+    use super::{VoiceAllocator, Voice, StealMode};
+
+    fn voice(expression_id: u32, started: u64, released: bool) -> Voice {
+        Voice {
+            note_id: expression_id,
+            template_id: 0,
+            expression_id: expression_id,
+            gate: None,
+            started: started,
+            released: released
+        }
+    }
+
+    #[test]
+    fn steals_oldest_after_many_notes() {
+        // Beyond 2^24 the start counts can't be told apart as f32 values
+        let mut allocator = VoiceAllocator::with_capacity(1, 4);
+        allocator.voices.push(voice(0, (1 << 25) + 1, false));
+        allocator.voices.push(voice(1, 1 << 25, false));
+        allocator.voices.push(voice(2, (1 << 25) + 2, false));
+
+        let expressions = HashMap::new();
+        assert_eq!(allocator.find_victim(0, StealMode::Oldest, &expressions),
+                   Some(1));
+    }
+
+    #[test]
+    fn steals_released_voices_first() {
+        let mut allocator = VoiceAllocator::with_capacity(1, 4);
+        allocator.voices.push(voice(0, 0, false));
+        allocator.voices.push(voice(1, 1, true));
+
+        let expressions = HashMap::new();
+        assert_eq!(allocator.find_victim(0, StealMode::Oldest, &expressions),
+                   Some(1));
+    }
+
+    #[test]
+    fn steals_quietest() {
+        let mut allocator = VoiceAllocator::with_capacity(1, 4);
+        let mut expressions = HashMap::new();
+        for (id, &level) in [0.5f32, 0.1, 0.8].iter().enumerate() {
+            let id = id as u32;
+            allocator.voices.push(voice(id, id as u64, false));
+            let mut expression = Expression::new(id, 0, 0);
+            expression.level = level;
+            expressions.insert(id, expression);
+        }
+
+        assert_eq!(
+            allocator.find_victim(0, StealMode::Quietest, &expressions),
+            Some(1)
+        );
+    }
+}
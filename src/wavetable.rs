@@ -0,0 +1,437 @@
+use std::io::Read;
+use std::cmp;
+use std::num::Float;
+use std::f32::consts::PI;
+use std::collections::HashMap;
+
+use byteorder::{ReadBytesExt, BigEndian};
+
+use types::ArtResult;
+use errors::ArtError;
+
+// Mip levels are generated down to this size
+pub const MIN_TABLE_SIZE: usize = 8;
+
+// The number of non-zero taps on each side of the half band filter used to
+// generate the mip levels
+const FILTER_TAPS: usize = 8;
+
+// The number of samples which WavetableStore::update generates or moves
+// each block
+const SAMPLES_PER_BLOCK: usize = 16384;
+
+// A set of single cycle tables, each followed by its mip levels.  Every
+// level is half the size of the one before, with the top half of its
+// harmonics removed.
+#[derive(Copy)]
+pub struct Wavetable {
+    pub index: usize,
+    pub num_tables: usize,
+    pub table_size: usize,
+    pub num_levels: usize,
+    // The number of mip levels generated so far, counting every table's
+    // level 1 before moving on to level 2
+    generated: usize
+}
+
+impl Wavetable {
+    pub fn new(index: usize, num_tables: usize, table_size: usize)
+            -> Wavetable {
+        Wavetable {
+            index: index,
+            num_tables: num_tables,
+            table_size: table_size,
+            num_levels: Wavetable::count_levels(table_size),
+            generated: 0
+        }
+    }
+
+    pub fn is_valid_size(table_size: usize) -> bool {
+        table_size >= MIN_TABLE_SIZE && table_size.is_power_of_two()
+    }
+
+    fn count_levels(table_size: usize) -> usize {
+        let mut num_levels = 0;
+        while table_size >> num_levels >= MIN_TABLE_SIZE {
+            num_levels += 1;
+        }
+        num_levels
+    }
+
+    // The number of samples taken up by a table and its mip levels
+    fn stride(table_size: usize) -> usize {
+        let num_levels = Wavetable::count_levels(table_size);
+        2 * (table_size - (table_size >> num_levels))
+    }
+
+    pub fn size(num_tables: usize, table_size: usize) -> usize {
+        num_tables * Wavetable::stride(table_size)
+    }
+
+    // Where a mip level of a table starts in the store's data
+    pub fn level_index(&self, table: usize, level: usize) -> usize {
+        self.index + table * Wavetable::stride(self.table_size) +
+        2 * (self.table_size - (self.table_size >> level))
+    }
+
+    // The number of mip levels which can be read, including the tables
+    // themselves
+    pub fn ready_levels(&self) -> usize {
+        1 + self.generated / self.num_tables
+    }
+
+    // The first mip level which has no harmonics above the Nyquist
+    // frequency when played at frequency.  Until the mip levels have been
+    // generated the highest ready level is used instead.
+    pub fn level(&self, frequency: f32, audio_rate: f32) -> usize {
+        let ratio = self.table_size as f32 * frequency.abs() / audio_rate;
+        if ratio <= 1.0 {
+            return 0;
+        }
+
+        let level = ratio.log2().ceil() as usize;
+        cmp::min(level, self.ready_levels() - 1)
+    }
+}
+
+pub struct WavetableStore {
+    pub wavetables: HashMap<u32, Wavetable>,
+    pub data: Vec<f32>,
+    // The end of the data in use, including data staged by the transaction
+    pub tail: usize,
+    // Set when a wavetable is replaced or removed, leaving a gap in the data
+    fragmented: bool,
+    // The end of the data which has been compacted so far
+    compacted: usize
+}
+
+impl WavetableStore {
+    pub fn with_capacity(max_wavetables: usize, size: usize)
+            -> WavetableStore {
+        let mut data = Vec::with_capacity(size);
+        data.resize(size, 0f32);
+
+        WavetableStore {
+            wavetables: HashMap::with_capacity(max_wavetables),
+            data: data,
+            tail: 0,
+            fragmented: false,
+            compacted: 0
+        }
+    }
+
+    pub fn get(&self, wavetable_id: u32) -> Option<&Wavetable> {
+        self.wavetables.get(&wavetable_id)
+    }
+
+    // Reads num_tables tables of table_size samples onto the end of the data.
+    // Their mip levels are generated later by update.
+    pub fn push_from_reader<T>(&mut self, num_tables: usize,
+                               table_size: usize, reader: &mut T)
+            -> ArtResult<usize> where T: Read {
+        let size = Wavetable::size(num_tables, table_size);
+        if self.tail + size > self.data.len() {
+            return Err(ArtError::BufferOverflow);
+        }
+
+        let wavetable = Wavetable::new(self.tail, num_tables, table_size);
+        for table in range(0, num_tables) {
+            let index = wavetable.level_index(table, 0);
+            for sample in self.data[index..index + table_size].iter_mut() {
+                *sample = try!(reader.read_f32::<BigEndian>());
+            }
+        }
+
+        let start = self.tail;
+        self.tail += size;
+        Ok(start)
+    }
+
+    // Drops data staged from index onwards
+    pub fn free_staged(&mut self, index: usize) {
+        self.tail = index;
+    }
+
+    pub fn insert(&mut self, wavetable_id: u32, wavetable: Wavetable) {
+        if self.wavetables.insert(wavetable_id, wavetable).is_some() {
+            self.set_fragmented();
+        }
+    }
+
+    pub fn remove(&mut self, wavetable_id: u32) -> ArtResult<()> {
+        try!(
+            self.wavetables.remove(&wavetable_id).ok_or(
+                ArtError::WavetableNotFound {
+                    wavetable_id: wavetable_id
+                }
+            )
+        );
+        self.set_fragmented();
+        Ok(())
+    }
+
+    fn set_fragmented(&mut self) {
+        self.fragmented = true;
+        self.compacted = 0;
+    }
+
+    // Moves on the mip level generation and compaction by a block's worth
+    // of work, so neither holds up the audio callback.  Space freed by
+    // replaced and removed wavetables is only available to new ones once
+    // the compaction has caught up.
+    pub fn update(&mut self) {
+        let mut budget = SAMPLES_PER_BLOCK;
+        self.generate_levels(&mut budget);
+        self.compact(&mut budget);
+    }
+
+    // Each step generates a single mip level of a single table
+    fn generate_levels(&mut self, budget: &mut usize) {
+        for wavetable in self.wavetables.values_mut() {
+            let steps = wavetable.num_tables * (wavetable.num_levels - 1);
+            while *budget > 0 && wavetable.generated < steps {
+                let table = wavetable.generated % wavetable.num_tables;
+                let level = wavetable.generated / wavetable.num_tables;
+                let size = wavetable.table_size >> level;
+                downsample(&mut self.data[..],
+                           wavetable.level_index(table, level), size);
+                wavetable.generated += 1;
+                *budget -= cmp::min(size / 2, *budget);
+            }
+        }
+    }
+
+    // Closes the gaps left by replaced and removed wavetables by moving the
+    // remaining ones down, keeping them in the same order.  Each step moves a
+    // whole wavetable, so units never read from one which is half moved.
+    fn compact(&mut self, budget: &mut usize) {
+        while self.fragmented && *budget > 0 {
+            // The lowest wavetable which hasn't been compacted yet
+            let mut next = None;
+            for (id, wavetable) in self.wavetables.iter() {
+                if wavetable.index < self.compacted {
+                    continue;
+                }
+
+                match next {
+                    Some((index, _)) if index < wavetable.index => {},
+                    _ => next = Some((wavetable.index, *id))
+                }
+            }
+
+            let id = match next {
+                Some((_, id)) => id,
+                None => {
+                    self.tail = self.compacted;
+                    self.fragmented = false;
+                    return;
+                }
+            };
+
+            let wavetable = self.wavetables.get_mut(&id).unwrap();
+            let size = Wavetable::size(wavetable.num_tables,
+                                       wavetable.table_size);
+            if wavetable.index != self.compacted {
+                for i in range(0, size) {
+                    self.data[self.compacted + i] =
+                        self.data[wavetable.index + i];
+                }
+                wavetable.index = self.compacted;
+                *budget -= cmp::min(size, *budget);
+            }
+            self.compacted += size;
+        }
+    }
+
+    // Reads from a mip level of a table, with linear interpolation.  Phase
+    // runs from 0 to 1 over the cycle.
+    pub fn read(&self, wavetable: &Wavetable, table: usize, level: usize,
+                phase: f32) -> f32 {
+        let index = wavetable.level_index(table, level);
+        let size = wavetable.table_size >> level;
+        let position = phase * size as f32;
+        let floor = position.floor();
+        let fraction = position - floor;
+        let i = floor as usize & (size - 1);
+        let a = self.data[index + i];
+        let b = self.data[index + ((i + 1) & (size - 1))];
+        a + (b - a) * fraction
+    }
+}
+
+// Filters out the top half of the harmonics in a table, writing every
+// other sample into the space which follows it.  The tables are single
+// cycles, so the filter wraps around their ends.
+fn downsample(data: &mut [f32], index: usize, size: usize) {
+    let mut coefficients = [0f32; FILTER_TAPS];
+    let mut sum = 0.5;
+    for (i, coefficient) in coefficients.iter_mut().enumerate() {
+        // Half band sinc, with a Blackman window
+        let tap = (2 * i + 1) as f32;
+        let x = tap * PI / 2.0;
+        let width = (4 * FILTER_TAPS) as f32;
+        let window = 0.42 + 0.5 * (2.0 * PI * tap / width).cos() +
+                     0.08 * (4.0 * PI * tap / width).cos();
+        *coefficient = 0.5 * x.sin() / x * window;
+        sum += 2.0 * *coefficient;
+    }
+
+    let (source, destination) = data.split_at_mut(index + size);
+    let source = &source[index..];
+    let mask = size - 1;
+    for i in range(0, size / 2) {
+        let center = 2 * i;
+        let mut value = 0.5 * source[center];
+        for (j, coefficient) in coefficients.iter().enumerate() {
+            let tap = 2 * j + 1;
+            value += *coefficient * (source[(center + tap) & mask] +
+                                     source[(center + size - tap) & mask]);
+        }
+        destination[i] = value / sum;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::num::Float;
+    use std::f32::consts::PI_2;
+
+    use byteorder::{WriteBytesExt, BigEndian};
+
+    use super::{Wavetable, WavetableStore};
+
+    fn load(store: &mut WavetableStore, wavetable_id: u32,
+            tables: &[&[f32]]) {
+        let table_size = tables[0].len();
+        let mut bytes = Vec::new();
+        for table in tables.iter() {
+            for sample in table.iter() {
+                bytes.write_f32::<BigEndian>(*sample).unwrap();
+            }
+        }
+
+        let mut reader = Cursor::new(&bytes[..]);
+        let index = store.push_from_reader(tables.len(), table_size,
+                                           &mut reader).unwrap();
+        store.insert(wavetable_id,
+                     Wavetable::new(index, tables.len(), table_size));
+    }
+
+    #[test]
+    fn level_index_follows_tables_and_levels() {
+        // Levels of 16 and 8 samples
+        let wavetable = Wavetable::new(100, 2, 16);
+        assert_eq!(wavetable.num_levels, 2);
+        assert_eq!(wavetable.level_index(0, 0), 100);
+        assert_eq!(wavetable.level_index(0, 1), 116);
+        assert_eq!(wavetable.level_index(1, 0), 124);
+        assert_eq!(wavetable.level_index(1, 1), 140);
+        assert_eq!(Wavetable::size(2, 16), 48);
+    }
+
+    #[test]
+    fn level_removes_harmonics_above_nyquist() {
+        let mut wavetable = Wavetable::new(0, 1, 64);
+        assert_eq!(wavetable.num_levels, 4);
+        wavetable.generated = 3;
+
+        // 64 harmonics fit below Nyquist up to 689 Hz
+        assert_eq!(wavetable.level(100.0, 44100.0), 0);
+        assert_eq!(wavetable.level(-100.0, 44100.0), 0);
+        assert_eq!(wavetable.level(1000.0, 44100.0), 1);
+        assert_eq!(wavetable.level(2000.0, 44100.0), 2);
+        assert_eq!(wavetable.level(20000.0, 44100.0), 3);
+    }
+
+    #[test]
+    fn level_waits_for_generated_levels() {
+        let mut wavetable = Wavetable::new(0, 2, 64);
+        assert_eq!(wavetable.level(20000.0, 44100.0), 0);
+
+        // Level 1 is only ready once it exists for both tables
+        wavetable.generated = 1;
+        assert_eq!(wavetable.level(20000.0, 44100.0), 0);
+        wavetable.generated = 2;
+        assert_eq!(wavetable.level(20000.0, 44100.0), 1);
+    }
+
+    #[test]
+    fn read_interpolates_and_wraps() {
+        let mut store = WavetableStore::with_capacity(1, 64);
+        let table = [0f32, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0];
+        load(&mut store, 1, &[&table[..]]);
+        let wavetable = *store.get(1).unwrap();
+
+        assert_eq!(store.read(&wavetable, 0, 0, 0.0), 0.0);
+        assert_eq!(store.read(&wavetable, 0, 0, 0.5), 4.0);
+        assert_eq!(store.read(&wavetable, 0, 0, 0.5625), 4.5);
+        // Between the last sample and the first
+        assert_eq!(store.read(&wavetable, 0, 0, 0.9375), 3.5);
+        assert_eq!(store.read(&wavetable, 0, 0, 1.25), 2.0);
+    }
+
+    #[test]
+    fn update_generates_mip_levels() {
+        // A single cycle sine, plus a component at the Nyquist frequency
+        // which the first mip level can't hold
+        let size = 32;
+        let table: Vec<f32> = range(0, size).map(|i| {
+            let sign = if i % 2 == 0 { 1.0 } else { -1.0 };
+            (PI_2 * i as f32 / size as f32).sin() + sign
+        }).collect();
+
+        let mut store = WavetableStore::with_capacity(1, 256);
+        load(&mut store, 1, &[&table[..]]);
+        assert_eq!(store.get(1).unwrap().ready_levels(), 1);
+
+        store.update();
+        let wavetable = *store.get(1).unwrap();
+        assert_eq!(wavetable.ready_levels(), wavetable.num_levels);
+
+        let index = wavetable.level_index(0, 1);
+        for i in range(0, size / 2) {
+            let expected = (PI_2 * i as f32 / (size / 2) as f32).sin();
+            assert!((store.data[index + i] - expected).abs() < 0.001);
+        }
+    }
+
+    #[test]
+    fn update_spreads_mip_levels_over_blocks() {
+        let table = [0f32; 16384];
+        let mut store = WavetableStore::with_capacity(1, 8 * 16384);
+        load(&mut store, 1, &[&table[..], &table[..], &table[..]]);
+
+        store.update();
+        assert_eq!(store.get(1).unwrap().ready_levels(), 1);
+
+        let num_levels = store.get(1).unwrap().num_levels;
+        let mut blocks = 1;
+        while store.get(1).unwrap().ready_levels() < num_levels {
+            store.update();
+            blocks += 1;
+        }
+        assert!(blocks > 1);
+    }
+
+    #[test]
+    fn update_compacts_removed_wavetables() {
+        let first = [1f32; 8];
+        let second = [2f32; 8];
+        let mut store = WavetableStore::with_capacity(2, 64);
+        load(&mut store, 1, &[&first[..]]);
+        load(&mut store, 2, &[&second[..]]);
+        let size = Wavetable::size(1, 8);
+        assert_eq!(store.tail, 2 * size);
+
+        store.remove(1).unwrap();
+        store.update();
+
+        let wavetable = *store.get(2).unwrap();
+        assert_eq!(wavetable.index, 0);
+        assert_eq!(store.tail, size);
+        for i in range(0, 8) {
+            assert_eq!(store.read(&wavetable, 0, 0, i as f32 / 8.0), 2.0);
+        }
+    }
+}